@@ -0,0 +1,15 @@
+// Types of the auxiliary vector entries the kernel passes to programs on their initial stack,
+// the vector consists of (type, value) pairs and is terminated by `AT_NULL`.
+
+pub const AT_NULL: usize = 0;
+/// The address of the program headers
+pub const AT_PHDR: usize = 3;
+/// The size of a program header
+pub const AT_PHENT: usize = 4;
+/// The number of program headers
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+/// The base address of the interpreter
+pub const AT_BASE: usize = 7;
+/// The entry point of the program itself
+pub const AT_ENTRY: usize = 9;
@@ -0,0 +1,24 @@
+pub const EPERM: usize = 1;
+pub const ENOENT: usize = 2;
+pub const ESRCH: usize = 3;
+pub const EIO: usize = 5;
+pub const ENOMEM: usize = 12;
+pub const EFAULT: usize = 14;
+pub const E2BIG: usize = 7;
+pub const EAGAIN: usize = 11;
+pub const EWOULDBLOCK: usize = EAGAIN;
+pub const EBUSY: usize = 16;
+pub const EEXIST: usize = 17;
+pub const ENODEV: usize = 19;
+pub const ENOTDIR: usize = 20;
+pub const EISDIR: usize = 21;
+pub const EINVAL: usize = 22;
+pub const EFBIG: usize = 27;
+pub const ENOSPC: usize = 28;
+pub const EROFS: usize = 30;
+pub const ENAMETOOLONG: usize = 36;
+pub const ENOSYS: usize = 38;
+pub const ENOTEMPTY: usize = 39;
+pub const ENODATA: usize = 61;
+pub const ENOTSUP: usize = 95;
+pub const ETIMEDOUT: usize = 110;
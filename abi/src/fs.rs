@@ -0,0 +1,95 @@
+/// The maximum length of a file name including the terminating nul byte
+pub const NAME_MAX: usize = 256;
+
+// open flags
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
+// flock operations, LOCK_NB can be combined with LOCK_SH and LOCK_EX
+pub const LOCK_SH: u32 = 1;
+pub const LOCK_EX: u32 = 2;
+pub const LOCK_NB: u32 = 4;
+pub const LOCK_UN: u32 = 8;
+
+/// The maximum length of an extended attribute's name, names have to start with `user.` or `security.`
+pub const XATTR_NAME_MAX: usize = 255;
+/// Executables with this extended attribute set to 1 are meant to run with root privileges, the kernel
+/// doesn't honor it yet as processes have no credentials
+pub const XATTR_CAP_ROOT: &str = "security.cap_root";
+
+// file types stored in the upper bits of `Stat::mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFREG: u32 = 0o100000;
+
+// file types of directory entries
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+    /// Modification time in seconds
+    pub mtime: u64,
+}
+
+/// `Stat` followed by the fields which were added later, as the layout of `Stat` can't change.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatV2 {
+    pub stat: Stat,
+    /// The device this file represents if it's a device file, see `makedev`
+    pub rdev: u64,
+}
+
+/// Packs a device's major and minor number into a single id.
+#[inline]
+pub const fn makedev(major: u32, minor: u32) -> u64 {
+    ((major as u64) << 32) | minor as u64
+}
+
+#[inline]
+pub const fn major(dev: u64) -> u32 {
+    (dev >> 32) as u32
+}
+
+#[inline]
+pub const fn minor(dev: u64) -> u32 {
+    dev as u32
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Dirent {
+    pub ino: u64,
+    pub kind: u8,
+    /// The length of the name without the terminating nul byte
+    pub name_len: u8,
+    pub name: [u8; NAME_MAX],
+}
+
+impl Dirent {
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+}
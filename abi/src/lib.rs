@@ -0,0 +1,17 @@
+#![no_std]
+
+// Everything in here is part of the interface between the kernel and userspace,
+// existing values and struct layouts must never change, only new ones may be added.
+
+pub mod syscall;
+pub mod errno;
+pub mod fs;
+pub mod auxv;
+
+/// The file descriptors every process starts out with
+pub const STDIN_FD: usize = 0;
+pub const STDOUT_FD: usize = 1;
+pub const STDERR_FD: usize = 2;
+
+/// The maximum length of the hostname in bytes
+pub const HOST_NAME_MAX: usize = 64;
@@ -0,0 +1,22 @@
+// Syscall numbers, they are passed in rax and the arguments in rdi, rsi, rdx, r10, r8 and r9.
+// The result is returned in rax. Buffers have to be mapped and accessible from user mode,
+// otherwise the syscall fails with EFAULT.
+
+/// write(fd, buf, len)
+pub const WRITE: usize = 1;
+/// getenv(name, name_len, buf, buf_len), returns the length of the value or `usize::MAX` if the
+/// variable isn't set and the negated errno on errors. The value is only copied if it fits into the buffer.
+pub const GETENV: usize = 2;
+/// sync(), writes all cached filesystem data back to the devices
+pub const SYNC: usize = 3;
+/// getxattr(path, path_len, name, name_len, buf, buf_len), returns the length of the value or the
+/// negated errno. The value is only copied if it fits into the buffer.
+pub const GETXATTR: usize = 4;
+/// setxattr(path, path_len, name, name_len, value, value_len), returns 0 or the negated errno
+pub const SETXATTR: usize = 5;
+/// sethostname(name, name_len), returns 0 or the negated errno. The name may be at most
+/// `HOST_NAME_MAX` bytes long.
+pub const SETHOSTNAME: usize = 6;
+/// gethostname(buf, buf_len), returns the length of the hostname or the negated errno.
+/// The hostname is only copied if it fits into the buffer.
+pub const GETHOSTNAME: usize = 7;
@@ -0,0 +1,31 @@
+#!/bin/sh
+# Runs the tests which are expected to panic or to raise a cpu exception (the `harness = false`
+# tests in Cargo.toml), each in its own qemu instance. A test passes if the kernel exits qemu with
+# the success code and its serial output contains the text of the test's `// expect-output:` line.
+
+cd "$(dirname "$0")/.." || exit 1
+
+tests=$(sed -n '/^\[\[test\]\]/,/^$/p' Cargo.toml | awk -F'"' '/^name/ { name = $2 } /^harness = false/ { print name }')
+failed=0
+
+for test in $tests; do
+    expected=$(sed -n 's|^// expect-output: ||p' "tests/$test.rs")
+    output=$(cargo test --test "$test" 2>&1)
+    status=$?
+    if [ $status -ne 0 ]; then
+        echo "$test: FAILED (exit status $status)"
+        echo "$output"
+        failed=$((failed + 1))
+    elif [ -n "$expected" ] && ! echo "$output" | grep -qF "$expected"; then
+        echo "$test: FAILED (serial output doesn't contain \"$expected\")"
+        echo "$output"
+        failed=$((failed + 1))
+    else
+        echo "$test: ok"
+    fi
+done
+
+if [ $failed -ne 0 ]; then
+    echo "$failed isolated test(s) failed"
+    exit 1
+fi
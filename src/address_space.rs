@@ -0,0 +1,92 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+
+// Every process runs in an address space. All address spaces share the kernel's mappings, so
+// kernel threads don't need their own: they keep running in whatever address space was active
+// before, which saves us the cr3 write and the tlb flush that comes with it.
+
+static KERNEL: Once<Arc<AddressSpace>> = Once::new();
+/// The level 4 table which is currently loaded, 0 if we haven't switched yet
+static ACTIVE: AtomicU64 = AtomicU64::new(0);
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_table: PhysFrame,
+    flags: Cr3Flags,
+    kernel_only: bool,
+}
+
+impl AddressSpace {
+
+    /// Creates an address space from a level 4 table which contains the kernel's mappings.
+    pub fn new(level_4_table: PhysFrame, flags: Cr3Flags) -> Self {
+        Self {
+            level_4_table,
+            flags,
+            kernel_only: false,
+        }
+    }
+
+    /// The address space the kernel was booted in, this is shared by all kernel threads.
+    pub fn kernel() -> Arc<AddressSpace> {
+        KERNEL.call_once(|| {
+            let (level_4_table, flags) = Cr3::read();
+            Arc::new(Self {
+                level_4_table,
+                flags,
+                kernel_only: true,
+            })
+        }).clone()
+    }
+
+    #[inline]
+    pub fn level_4_table(&self) -> PhysFrame {
+        self.level_4_table
+    }
+
+    /// Whether this address space only contains the kernel's mappings
+    #[inline]
+    pub fn is_kernel_only(&self) -> bool {
+        self.kernel_only
+    }
+
+}
+
+/// Makes `space` the active address space, switching to the kernel address space or to the one
+/// which is already active doesn't touch cr3.
+pub fn switch_to(space: &AddressSpace) {
+    if space.kernel_only {
+        return;
+    }
+    let table = space.level_4_table.start_address().as_u64();
+    let active = ACTIVE.load(Ordering::Relaxed);
+    if active == table || (active == 0 && Cr3::read().0 == space.level_4_table) {
+        ACTIVE.store(table, Ordering::Relaxed);
+        return;
+    }
+    unsafe { Cr3::write(space.level_4_table, space.flags); }
+    ACTIVE.store(table, Ordering::Relaxed);
+    SWITCHES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of times cr3 was actually written
+pub fn switch_count() -> u64 {
+    SWITCHES.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_kernel_switch_is_lazy() {
+    let switches = switch_count();
+    let kernel = AddressSpace::kernel();
+    switch_to(&kernel);
+    // an address space using the active tables doesn't need a switch either
+    let (table, flags) = Cr3::read();
+    switch_to(&AddressSpace::new(table, flags));
+    switch_to(&kernel);
+    crate::kassert_eq!(switch_count(), switches);
+    crate::kassert_eq!(Cr3::read().0, table);
+}
@@ -1,10 +1,16 @@
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::VirtAddr;
 use crate::allocators::fixed_size_block::FixedSizeBlockAllocator;
+use crate::{oom, scheduler};
 
 mod fixed_size_block;
+#[cfg(feature = "heap-profile")]
+pub mod profile;
 
 /*
 #[global_allocator]
@@ -30,7 +36,57 @@ impl<A> Locked<A> {
 }
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Locked<Heap> = Locked::new(Heap::empty());
+
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "fault-injection")]
+        if crate::fault_inject::should_fail(crate::fault_inject::FailPoint::Heap) {
+            return ptr::null_mut();
+        }
+        loop {
+            // the heap lock has to be released before invoking the oom killer as killing a process frees its memory
+            let allocated = self.lock().allocate_first_fit(layout);
+            match allocated {
+                Ok(ptr) => {
+                    scheduler::charge_current(layout.size());
+                    #[cfg(feature = "heap-profile")]
+                    profile::record_alloc(ptr.as_ptr(), layout.size());
+                    return ptr.as_ptr();
+                },
+                Err(_) => {
+                    if !oom::out_of_memory(layout) {
+                        return ptr::null_mut();
+                    }
+                    // memory was freed, retry the allocation
+                },
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // drop the sample before the address can be handed out again
+        #[cfg(feature = "heap-profile")]
+        profile::record_dealloc(ptr);
+        self.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+        scheduler::uncharge_current(layout.size());
+    }
+}
+
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Returns the heap's usage or `None` if the heap is currently locked.
+pub fn try_heap_stats() -> Option<HeapStats> {
+    ALLOCATOR.inner.try_lock().map(|heap| HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    })
+}
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 // pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
@@ -56,6 +112,7 @@ pub fn init_heap(
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush()
         };
+        scheduler::cond_resched();
     }
 
     unsafe {
@@ -0,0 +1,199 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::arch::without_interrupts;
+use crate::memory;
+
+// A sampling heap profiler, built with the `heap-profile` feature. Every `SAMPLE_INTERVAL`th allocation gets recorded
+// together with the return addresses found by walking the frame pointers, the record is dropped
+// again when the allocation is freed. So the table always describes the live allocations and
+// sites which keep accumulating memory stand out. The addresses can be resolved with addr2line.
+// The kernel has to be built with frame pointers for this (see the feature in Cargo.toml), other
+// builds don't pay for them. Without them the recorded sites are cut short or meaningless.
+// The table lives in a static as the profiler must not allocate itself.
+
+/// Record one out of this many allocations
+pub const SAMPLE_INTERVAL: usize = 8;
+/// The number of return addresses recorded per allocation, the outermost ones belong to the
+/// allocation shims of the alloc crate (e.g. `__rust_alloc` and `RawVec`)
+pub const SITE_DEPTH: usize = 8;
+const SLOTS: usize = 512;
+/// Keep some slots free, so probing for pointers which aren't sampled stops early
+pub const MAX_SAMPLES: usize = SLOTS * 3 / 4;
+/// Frames larger than this are considered to be a corrupt frame pointer chain
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+pub type Site = [usize; SITE_DEPTH];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// 0 marks a free slot
+    pub ptr: usize,
+    pub size: usize,
+    pub site: Site,
+}
+
+const EMPTY: Sample = Sample {
+    ptr: 0,
+    size: 0,
+    site: [0; SITE_DEPTH],
+};
+
+/// An open addressing hash table keyed by the allocation's address
+struct SampleTable {
+    slots: [Sample; SLOTS],
+    len: usize,
+}
+
+static SAMPLES: Mutex<SampleTable> = Mutex::new(SampleTable {
+    slots: [EMPTY; SLOTS],
+    len: 0,
+});
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Samples which didn't fit into the table
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn home_slot(ptr: usize) -> usize {
+    // allocations are at least 8 byte aligned, the low bits carry no information
+    ((ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) % SLOTS
+}
+
+impl SampleTable {
+
+    fn insert(&mut self, sample: Sample) -> bool {
+        if self.len >= MAX_SAMPLES {
+            return false;
+        }
+        let mut idx = home_slot(sample.ptr);
+        while self.slots[idx].ptr != 0 {
+            idx = (idx + 1) % SLOTS;
+        }
+        self.slots[idx] = sample;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, ptr: usize) {
+        let mut idx = home_slot(ptr);
+        while self.slots[idx].ptr != ptr {
+            if self.slots[idx].ptr == 0 {
+                // not sampled
+                return;
+            }
+            idx = (idx + 1) % SLOTS;
+        }
+        self.len -= 1;
+        // move the following entries of the probe sequence back, so lookups don't stop at the hole
+        let mut hole = idx;
+        let mut next = idx;
+        loop {
+            self.slots[hole] = EMPTY;
+            loop {
+                next = (next + 1) % SLOTS;
+                let entry = self.slots[next].ptr;
+                if entry == 0 {
+                    return;
+                }
+                // the distances of the hole and of the entry from the entry's home slot
+                let home = home_slot(entry);
+                if (hole + SLOTS - home) % SLOTS < (next + SLOTS - home) % SLOTS {
+                    break;
+                }
+            }
+            self.slots[hole] = self.slots[next];
+            hole = next;
+        }
+    }
+
+}
+
+/// Checks that the 16 bytes of a stack frame record can be read without faulting.
+fn is_frame_readable(rbp: usize) -> bool {
+    rbp != 0 && rbp % 8 == 0 && [rbp, rbp + 15].iter().all(|addr| {
+        VirtAddr::try_new(*addr as u64).map_or(false, |addr| memory::lookup(addr).is_some())
+    })
+}
+
+/// Walks the frame pointer chain starting at the caller of the allocator.
+#[inline(always)]
+fn caller_site() -> Site {
+    let mut site = [0; SITE_DEPTH];
+    let mut rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp); }
+    for addr in site.iter_mut() {
+        if !is_frame_readable(rbp) {
+            break;
+        }
+        // a frame record consists of the caller's rbp followed by the return address
+        let (next, ret) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        *addr = ret;
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+    site
+}
+
+#[inline(always)]
+pub(super) fn record_alloc(ptr: *mut u8, size: usize) {
+    if ALLOCATIONS.fetch_add(1, Ordering::Relaxed) % SAMPLE_INTERVAL != 0 {
+        return;
+    }
+    let sample = Sample {
+        ptr: ptr as usize,
+        size,
+        site: caller_site(),
+    };
+    if !without_interrupts(|| SAMPLES.lock().insert(sample)) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(super) fn record_dealloc(ptr: *mut u8) {
+    without_interrupts(|| SAMPLES.lock().remove(ptr as usize));
+}
+
+/// Calls `f` with every recorded live allocation, `f` must not allocate from the heap.
+pub fn for_each_sample(mut f: impl FnMut(&Sample)) {
+    without_interrupts(|| {
+        for sample in SAMPLES.lock().slots.iter().filter(|sample| sample.ptr != 0) {
+            f(sample);
+        }
+    });
+}
+
+/// The number of samples which were lost because the table was full
+pub fn dropped_samples() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_sample_table() {
+    // addresses outside of the heap, so they can't clash with the samples of real allocations
+    const BASE: usize = 0x1000_0000_0000;
+    let mut ptrs = [BASE + 0x1000, BASE + 0x2008, 0, 0, 0];
+    // the last three share their home slot
+    let mut colliding = (1..100_000).map(|idx| BASE + idx * 8).filter(|ptr| home_slot(*ptr) == home_slot(BASE + 8));
+    for ptr in ptrs[2..].iter_mut() {
+        *ptr = colliding.next().unwrap();
+    }
+    // the table must not be locked while allocating
+    without_interrupts(|| {
+        let mut table = SAMPLES.lock();
+        let len = table.len;
+        for ptr in ptrs.iter() {
+            crate::kassert!(table.insert(Sample { ptr: *ptr, ..EMPTY }));
+        }
+        table.remove(BASE + 0x3000);
+        crate::kassert_eq!(table.len, len + ptrs.len());
+        // removing the first entry of the probe sequence must keep the others reachable
+        for ptr in ptrs.iter() {
+            table.remove(*ptr);
+        }
+        crate::kassert_eq!(table.len, len);
+        crate::kassert!(ptrs.iter().all(|ptr| table.slots.iter().all(|sample| sample.ptr != *ptr)));
+    });
+}
@@ -0,0 +1,169 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use crate::arch::x86::cpuid::has_cpuid;
+use crate::arch::x86::topology;
+use crate::log_warn;
+
+// Every cpu has to behave the same, so the application processors are checked against the
+// features of the boot processor when they come online and get the same features enabled.
+// FIXME: We don't start the application processors yet, `bring_up_ap` has to be called on
+//  each of them once we do.
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_BIOS_SIGN_ID: u32 = 0x8b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+
+static BSP_FEATURES: Once<CpuFeatures> = Once::new();
+
+/// The features of a single cpu and how they are configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub nx: bool,
+    pub syscall: bool,
+    pub x2apic: bool,
+    pub sse4_2: bool,
+    pub xsave: bool,
+    pub efer: EferFlags,
+    pub x2apic_enabled: bool,
+    pub microcode: u32,
+}
+
+impl CpuFeatures {
+
+    /// Reads the features of the cpu we are running on.
+    pub fn current() -> Self {
+        let (mut nx, mut syscall, mut x2apic, mut sse4_2, mut xsave) = (false, false, false, false, false);
+        if has_cpuid() {
+            let cpuid = CpuId::new();
+            if let Some(info) = cpuid.get_feature_info() {
+                x2apic = info.has_x2apic();
+                sse4_2 = info.has_sse42();
+                xsave = info.has_xsave();
+            }
+            if let Some(info) = cpuid.get_extended_processor_and_feature_identifiers() {
+                nx = info.has_execute_disable();
+                syscall = info.has_syscall_sysret();
+            }
+        }
+        let x2apic_enabled = x2apic && unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_X2APIC_ENABLE != 0;
+        Self {
+            nx,
+            syscall,
+            x2apic,
+            sse4_2,
+            xsave,
+            efer: Efer::read(),
+            x2apic_enabled,
+            microcode: microcode_revision(),
+        }
+    }
+
+    /// Returns the names of all features `self` has enabled or relies on which `ap` doesn't support.
+    pub fn missing_on(&self, ap: &CpuFeatures) -> Vec<&'static str> {
+        let mut missing = vec![];
+        if self.efer.contains(EferFlags::NO_EXECUTE_ENABLE) && !ap.nx {
+            missing.push("nx");
+        }
+        if self.efer.contains(EferFlags::SYSTEM_CALL_EXTENSIONS) && !ap.syscall {
+            missing.push("syscall");
+        }
+        if self.x2apic_enabled && !ap.x2apic {
+            missing.push("x2apic");
+        }
+        // the memory functions and crc32c pick their implementation once for all cpus
+        if self.sse4_2 && !ap.sse4_2 {
+            missing.push("sse4.2");
+        }
+        if self.xsave && !ap.xsave {
+            missing.push("xsave");
+        }
+        missing
+    }
+
+}
+
+/// Returns the loaded microcode revision, 0 if the vendor isn't known.
+fn microcode_revision() -> u32 {
+    if !has_cpuid() {
+        return 0;
+    }
+    let vendor = match CpuId::new().get_vendor_info() {
+        Some(vendor) => vendor,
+        None => return 0,
+    };
+    match vendor.as_str() {
+        "GenuineIntel" => unsafe {
+            // the revision gets loaded into the msr by executing cpuid leaf 1
+            Msr::new(IA32_BIOS_SIGN_ID).write(0);
+            let _ = core::arch::x86_64::__cpuid(1);
+            (Msr::new(IA32_BIOS_SIGN_ID).read() >> 32) as u32
+        },
+        // the msr is the read-only patch level on amd, writing it may fault
+        "AuthenticAMD" => unsafe { Msr::new(IA32_BIOS_SIGN_ID).read() as u32 },
+        _ => 0,
+    }
+}
+
+/// Records the features of the boot processor, this has to be called once during boot.
+pub fn init_bsp() {
+    BSP_FEATURES.call_once(CpuFeatures::current);
+}
+
+pub fn bsp_features() -> Option<&'static CpuFeatures> {
+    BSP_FEATURES.get()
+}
+
+/// Has to be called on every application processor when it comes online, this checks that it
+/// supports everything the boot processor uses and configures it the same way. Returns the
+/// missing features if the cpu can't be brought online.
+pub fn bring_up_ap(cpu: usize) -> Result<(), Vec<&'static str>> {
+    let bsp = match bsp_features() {
+        Some(bsp) => bsp,
+        None => return Err(vec!["boot processor features"]),
+    };
+    let ap = CpuFeatures::current();
+    let missing = bsp.missing_on(&ap);
+    if !missing.is_empty() {
+        log_warn!("cpu {} lacks the features {:?}, not bringing it online", cpu, missing);
+        return Err(missing);
+    }
+    unsafe {
+        Efer::write(bsp.efer);
+        if bsp.x2apic_enabled && !ap.x2apic_enabled {
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | APIC_BASE_X2APIC_ENABLE);
+        }
+    }
+    if ap.microcode != bsp.microcode {
+        log_warn!("cpu {} runs microcode revision {:#x}, the boot processor runs {:#x}", cpu, ap.microcode, bsp.microcode);
+    }
+    topology::init_cpu(cpu);
+    Ok(())
+}
+
+#[test_case]
+fn test_missing_features() {
+    let bsp = CpuFeatures {
+        nx: true,
+        syscall: true,
+        x2apic: true,
+        sse4_2: true,
+        xsave: false,
+        efer: EferFlags::NO_EXECUTE_ENABLE | EferFlags::LONG_MODE_ENABLE,
+        x2apic_enabled: false,
+        microcode: 1,
+    };
+    crate::kassert!(bsp.missing_on(&bsp).is_empty());
+    let ap = CpuFeatures {
+        nx: false,
+        // syscall isn't enabled on the bsp, so the ap doesn't need it
+        syscall: false,
+        x2apic: false,
+        ..bsp
+    };
+    crate::kassert_eq!(bsp.missing_on(&ap), vec!["nx"]);
+}
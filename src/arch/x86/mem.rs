@@ -0,0 +1,423 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use raw_cpuid::CpuId;
+use spin::{Mutex, MutexGuard};
+use crate::arch::{disable_interrupts, enable_interrupts, is_interrupts_enabled};
+use crate::arch::x86::cpuid::has_cpuid;
+
+// The kernel is compiled with soft-float, so the compiler never touches the vector registers.
+// The routines here use them explicitly and save the previous register state around every use,
+// so the task they run in (which may use the vector registers) doesn't observe any changes.
+// The scheduler saves and restores the vector registers of every task on a switch.
+
+/// Copies smaller than this are done using `rep movsb` as saving the vector state isn't worth it
+const VECTOR_THRESHOLD: usize = 512;
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemImpl {
+    Rep = 0,
+    Sse2 = 1,
+    Avx = 2,
+}
+
+static MEM_IMPL: AtomicU8 = AtomicU8::new(MemImpl::Rep as u8);
+
+const CR0_MONITOR_COPROCESSOR: usize = 1 << 1;
+const CR0_EMULATION: usize = 1 << 2;
+const CR4_OSFXSR: usize = 1 << 9;
+const CR4_OSXMMEXCPT: usize = 1 << 10;
+const CR4_OSXSAVE: usize = 1 << 18;
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Detects the available vector extensions, enables them and selects the fastest implementation.
+pub fn init() {
+    if !has_cpuid() {
+        return;
+    }
+    let cpuid = CpuId::new();
+    let features = match cpuid.get_feature_info() {
+        Some(features) => features,
+        None => return,
+    };
+    if !features.has_sse2() || !features.has_fxsave_fxstor() {
+        return;
+    }
+    let mut selected = MemImpl::Sse2;
+    unsafe {
+        let mut cr0: usize;
+        asm!("mov {}, cr0", out(reg) cr0);
+        cr0 = (cr0 & !CR0_EMULATION) | CR0_MONITOR_COPROCESSOR;
+        asm!("mov cr0, {}", in(reg) cr0);
+        let mut cr4: usize;
+        asm!("mov {}, cr4", out(reg) cr4);
+        cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if features.has_xsave() && features.has_avx() {
+            cr4 |= CR4_OSXSAVE;
+        }
+        asm!("mov cr4, {}", in(reg) cr4);
+        if cr4 & CR4_OSXSAVE != 0 {
+            let xcr0 = XCR0_X87 | XCR0_SSE | XCR0_AVX;
+            asm!("xsetbv", in("ecx") 0, in("eax") xcr0 as u32, in("edx") (xcr0 >> 32) as u32);
+            selected = MemImpl::Avx;
+        }
+    }
+    MEM_IMPL.store(selected as u8, Ordering::Release);
+}
+
+pub fn selected_impl() -> MemImpl {
+    match MEM_IMPL.load(Ordering::Acquire) {
+        1 => MemImpl::Sse2,
+        2 => MemImpl::Avx,
+        _ => MemImpl::Rep,
+    }
+}
+
+/// Large enough for the x87, SSE and AVX state components in the fxsave and the xsave layout
+#[repr(C, align(64))]
+pub struct VectorState([u8; 1024]);
+
+impl VectorState {
+
+    /// The register state a task starts with, all floating point exceptions are masked.
+    pub(crate) fn new() -> Self {
+        let mut area = [0; 1024];
+        // the xsave header has to be zeroed, this only sets up the control words of the legacy area
+        area[0..2].copy_from_slice(&0x037f_u16.to_le_bytes()); // FCW
+        area[24..28].copy_from_slice(&0x1f80_u32.to_le_bytes()); // MXCSR
+        Self(area)
+    }
+
+}
+
+/// Saves the vector registers of the task which is switched out, the scheduler has to do this
+/// as tasks may use them (and get interrupted) at any time. Does nothing if they aren't enabled.
+pub fn save_vector_state(state: &mut VectorState) {
+    let area = state.0.as_mut_ptr();
+    unsafe {
+        match selected_impl() {
+            MemImpl::Avx => asm!("xsave [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack)),
+            MemImpl::Sse2 => asm!("fxsave [{}]", in(reg) area, options(nostack)),
+            MemImpl::Rep => {},
+        }
+    }
+}
+
+/// Loads the vector registers of the task which is switched in.
+pub fn restore_vector_state(state: &VectorState) {
+    let area = state.0.as_ptr();
+    unsafe {
+        match selected_impl() {
+            MemImpl::Avx => asm!("xrstor [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack)),
+            MemImpl::Sse2 => asm!("fxrstor [{}]", in(reg) area, options(nostack)),
+            MemImpl::Rep => {},
+        }
+    }
+}
+
+lazy_static! {
+    /// The vector registers of the code which was running when the kernel started using them,
+    /// this is too large to live on the (4 KiB) kernel stacks.
+    static ref KERNEL_FPU_STATE: Mutex<VectorState> = Mutex::new(VectorState::new());
+}
+
+/// Saves the vector register state for the duration of its lifetime and keeps interrupts
+/// disabled, so we can't get preempted by anything that expects its own register contents.
+struct KernelFpuGuard {
+    state: MutexGuard<'static, VectorState>,
+    interrupts: bool,
+}
+
+impl KernelFpuGuard {
+
+    /// Returns `None` if the vector registers are in use already, e.g. because a page fault
+    /// interrupted another copy. The caller has to fall back to the `rep` routines then.
+    #[inline]
+    fn new() -> Option<Self> {
+        let interrupts = is_interrupts_enabled();
+        unsafe { disable_interrupts(); }
+        match KERNEL_FPU_STATE.try_lock() {
+            Some(mut state) => {
+                save_vector_state(&mut state);
+                Some(Self {
+                    state,
+                    interrupts,
+                })
+            },
+            None => {
+                if interrupts {
+                    unsafe { enable_interrupts(); }
+                }
+                None
+            },
+        }
+    }
+
+}
+
+impl Drop for KernelFpuGuard {
+    fn drop(&mut self) {
+        restore_vector_state(&self.state);
+        if self.interrupts {
+            unsafe { enable_interrupts(); }
+        }
+    }
+}
+
+#[inline]
+unsafe fn rep_movsb(dst: *mut u8, src: *const u8, len: usize) {
+    asm!(
+    "rep movsb",
+    inout("rdi") dst => _,
+    inout("rsi") src => _,
+    inout("rcx") len => _,
+    options(nostack, preserves_flags)
+    );
+}
+
+#[inline]
+unsafe fn rep_stosb(dst: *mut u8, val: u8, len: usize) {
+    asm!(
+    "rep stosb",
+    inout("rdi") dst => _,
+    inout("rcx") len => _,
+    in("al") val,
+    options(nostack, preserves_flags)
+    );
+}
+
+#[target_feature(enable = "avx")]
+unsafe fn memcpy_avx(dst: *mut u8, src: *const u8, blocks: usize) {
+    asm!(
+    "2:",
+    "vmovdqu ymm0, [{src}]",
+    "vmovdqu ymm1, [{src} + 32]",
+    "vmovdqu [{dst}], ymm0",
+    "vmovdqu [{dst} + 32], ymm1",
+    "add {src}, 64",
+    "add {dst}, 64",
+    "dec {blocks}",
+    "jnz 2b",
+    "vzeroupper",
+    src = inout(reg) src => _,
+    dst = inout(reg) dst => _,
+    blocks = inout(reg) blocks => _,
+    out("ymm0") _,
+    out("ymm1") _,
+    options(nostack)
+    );
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn memcpy_sse2(dst: *mut u8, src: *const u8, blocks: usize) {
+    asm!(
+    "2:",
+    "movdqu xmm0, [{src}]",
+    "movdqu xmm1, [{src} + 16]",
+    "movdqu xmm2, [{src} + 32]",
+    "movdqu xmm3, [{src} + 48]",
+    "movdqu [{dst}], xmm0",
+    "movdqu [{dst} + 16], xmm1",
+    "movdqu [{dst} + 32], xmm2",
+    "movdqu [{dst} + 48], xmm3",
+    "add {src}, 64",
+    "add {dst}, 64",
+    "dec {blocks}",
+    "jnz 2b",
+    src = inout(reg) src => _,
+    dst = inout(reg) dst => _,
+    blocks = inout(reg) blocks => _,
+    out("xmm0") _,
+    out("xmm1") _,
+    out("xmm2") _,
+    out("xmm3") _,
+    options(nostack)
+    );
+}
+
+/// Copies `len` bytes from `src` to `dst`, the regions must not overlap.
+///
+/// # Safety
+///
+/// `src` has to be valid for reads and `dst` valid for writes of `len` bytes, and the two
+/// regions must not overlap. Neither pointer has to be aligned.
+pub unsafe fn memcpy(dst: *mut u8, src: *const u8, len: usize) {
+    let implementation = selected_impl();
+    if len < VECTOR_THRESHOLD || implementation == MemImpl::Rep {
+        rep_movsb(dst, src, len);
+        return;
+    }
+    let blocks = len / 64;
+    {
+        let _guard = match KernelFpuGuard::new() {
+            Some(guard) => guard,
+            None => {
+                rep_movsb(dst, src, len);
+                return;
+            },
+        };
+        match implementation {
+            MemImpl::Avx => memcpy_avx(dst, src, blocks),
+            _ => memcpy_sse2(dst, src, blocks),
+        }
+    }
+    let done = blocks * 64;
+    rep_movsb(dst.add(done), src.add(done), len - done);
+}
+
+#[target_feature(enable = "avx")]
+unsafe fn memset_avx(dst: *mut u8, pattern: *const u8, blocks: usize) {
+    asm!(
+    "vmovdqu ymm0, [{pattern}]",
+    "2:",
+    "vmovdqu [{dst}], ymm0",
+    "vmovdqu [{dst} + 32], ymm0",
+    "add {dst}, 64",
+    "dec {blocks}",
+    "jnz 2b",
+    "vzeroupper",
+    pattern = in(reg) pattern,
+    dst = inout(reg) dst => _,
+    blocks = inout(reg) blocks => _,
+    out("ymm0") _,
+    options(nostack)
+    );
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn memset_sse2(dst: *mut u8, pattern: *const u8, blocks: usize) {
+    asm!(
+    "movdqu xmm0, [{pattern}]",
+    "2:",
+    "movdqu [{dst}], xmm0",
+    "movdqu [{dst} + 16], xmm0",
+    "movdqu [{dst} + 32], xmm0",
+    "movdqu [{dst} + 48], xmm0",
+    "add {dst}, 64",
+    "dec {blocks}",
+    "jnz 2b",
+    pattern = in(reg) pattern,
+    dst = inout(reg) dst => _,
+    blocks = inout(reg) blocks => _,
+    out("xmm0") _,
+    options(nostack)
+    );
+}
+
+/// Sets `len` bytes starting at `dst` to `val`.
+///
+/// # Safety
+///
+/// `dst` has to be valid for writes of `len` bytes, it doesn't have to be aligned.
+pub unsafe fn memset(dst: *mut u8, val: u8, len: usize) {
+    let implementation = selected_impl();
+    if len < VECTOR_THRESHOLD || implementation == MemImpl::Rep {
+        rep_stosb(dst, val, len);
+        return;
+    }
+    let pattern = [val; 32];
+    let blocks = len / 64;
+    {
+        let _guard = match KernelFpuGuard::new() {
+            Some(guard) => guard,
+            None => {
+                rep_stosb(dst, val, len);
+                return;
+            },
+        };
+        match implementation {
+            MemImpl::Avx => memset_avx(dst, pattern.as_ptr(), blocks),
+            _ => memset_sse2(dst, pattern.as_ptr(), blocks),
+        }
+    }
+    let done = blocks * 64;
+    rep_stosb(dst.add(done), val, len - done);
+}
+
+#[inline]
+unsafe fn page_zero_rep(page: *mut u8) {
+    asm!(
+    "rep stosq",
+    inout("rdi") page => _,
+    inout("rcx") PAGE_SIZE / 8 => _,
+    in("rax") 0,
+    options(nostack, preserves_flags)
+    );
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn page_zero_sse2(page: *mut u8) {
+    asm!(
+    "pxor xmm0, xmm0",
+    "2:",
+    "movntdq [{page}], xmm0",
+    "movntdq [{page} + 16], xmm0",
+    "movntdq [{page} + 32], xmm0",
+    "movntdq [{page} + 48], xmm0",
+    "add {page}, 64",
+    "dec {blocks}",
+    "jnz 2b",
+    "sfence",
+    page = inout(reg) page => _,
+    blocks = inout(reg) PAGE_SIZE / 64 => _,
+    out("xmm0") _,
+    options(nostack)
+    );
+}
+
+/// Zeroes a whole page, `page` has to be page aligned.
+///
+/// This uses non-temporal stores as freshly zeroed pages usually aren't read right away,
+/// so we don't pollute the caches with them.
+///
+/// # Safety
+///
+/// `page` has to be page aligned and valid for writes of a whole page (4096 bytes), nothing else
+/// may access the page while it gets zeroed.
+// FIXME: The page tables created while mapping are zeroed by the x86_64 crate's mapper, use this
+//  for them (and `memcpy` for copy on write faults) once we have our own mapper and cow mappings.
+pub unsafe fn page_zero(page: *mut u8) {
+    debug_assert_eq!(page as usize % PAGE_SIZE, 0);
+    if selected_impl() == MemImpl::Rep {
+        page_zero_rep(page);
+        return;
+    }
+    match KernelFpuGuard::new() {
+        Some(_guard) => page_zero_sse2(page),
+        None => page_zero_rep(page),
+    }
+}
+
+#[test_case]
+fn test_memcpy_memset() {
+    use alloc::vec;
+
+    let src: alloc::vec::Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    let mut dst = vec![0_u8; 3000];
+    unsafe { memcpy(dst.as_mut_ptr().add(3), src.as_ptr().add(1), 2990); }
+    crate::kassert!(dst[3..2993] == src[1..2991]);
+    crate::kassert_eq!(dst[0], 0);
+    crate::kassert_eq!(dst[2993], 0);
+
+    unsafe { memset(dst.as_mut_ptr().add(5), 0xab, 2000); }
+    crate::kassert!(dst[5..2005].iter().all(|b| *b == 0xab));
+    crate::kassert_eq!(dst[4], src[2]);
+    crate::kassert_eq!(dst[2005], src[2003]);
+}
+
+#[test_case]
+fn test_nested_memcpy() {
+    use alloc::vec;
+
+    // a copy from a page fault in the middle of another copy can't use the vector registers
+    let src = vec![0x5a_u8; 2048];
+    let mut dst = vec![0_u8; 2048];
+    let held = KERNEL_FPU_STATE.lock();
+    unsafe { memcpy(dst.as_mut_ptr(), src.as_ptr(), src.len()); }
+    drop(held);
+    crate::kassert!(dst == src);
+}
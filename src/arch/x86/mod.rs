@@ -1,6 +1,9 @@
 use core::arch::asm;
 
 pub mod cpuid;
+pub mod features;
+pub mod mem;
+pub mod topology;
 
 pub(in crate::arch) mod hal_impls {
     use core::arch::asm;
@@ -47,3 +50,61 @@ pub fn flags() -> usize {
     ) }
     flags
 }
+
+/// Returns a random value, this uses `rdrand` if it's available and falls back
+/// to the time stamp counter otherwise, so it must not be used for cryptographic purposes.
+pub fn random_u64() -> u64 {
+    let has_rdrand = cpuid::has_cpuid() && raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_rdrand());
+    if has_rdrand {
+        // rdrand may fail transiently, intel recommends retrying 10 times
+        for _ in 0..10 {
+            let val: u64;
+            let success: u8;
+            unsafe {
+                asm!(
+                "rdrand {val}",
+                "setc {success}",
+                val = out(reg) val,
+                success = out(reg_byte) success,
+                );
+            }
+            if success != 0 {
+                return val;
+            }
+        }
+    }
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    // mix the bits a bit so consecutive calls don't return nearly identical values
+    tsc.wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(29)
+}
+
+/// Resets the machine by pulsing the cpu reset line through the keyboard controller.
+pub fn reboot() -> ! {
+    unsafe {
+        crate::arch::disable_interrupts();
+        // wait until the controller's input buffer is empty
+        while x86::io::inb(0x64) & 0b10 != 0 {}
+        x86::io::outb(0x64, 0xfe);
+    }
+    // the reset didn't work, there is nothing left we can do
+    loop {
+        unsafe { crate::arch::wait_for_interrupt(); }
+    }
+}
+
+/// Turns the machine off, without acpi this only works on emulators which provide a shortcut for it.
+// FIXME: Enter S5 through the pm1 control register from the FADT once we parse the acpi tables
+pub fn power_off() -> ! {
+    unsafe {
+        crate::arch::disable_interrupts();
+        // qemu, bochs and older qemu versions and virtualbox
+        x86::io::outw(0x604, 0x2000);
+        x86::io::outw(0xb004, 0x2000);
+        x86::io::outw(0x4004, 0x3400);
+    }
+    loop {
+        unsafe { crate::arch::wait_for_interrupt(); }
+    }
+}
@@ -0,0 +1,214 @@
+use core::arch::x86_64::__cpuid_count;
+use spin::Once;
+use crate::arch::x86::cpuid::has_cpuid;
+use crate::percpu::{self, MAX_CPUS, PerCpu};
+
+// The x2apic id of a cpu consists of bit fields for the thread within its core, the core within
+// its package and the package. The width of each field is reported by the extended topology
+// leaves (0x1f, or 0xb on older cpus), which also report the full 32 bit x2apic id. Cpus without
+// them only report the 8 bit xapic id in leaf 1, the field widths are derived from the number of
+// logical processors per package (leaf 1) and cores per package (leaf 4) then. Leaf 4 is reserved
+// on amd, which reports the package width and the threads per core in extended leaves instead.
+// Levels between core and package (modules, tiles and dies) are folded into the core id.
+
+const LEAF_FEATURES: u32 = 1;
+const LEAF_CACHE_PARAMS: u32 = 4;
+const LEAF_TOPOLOGY: u32 = 0xb;
+const LEAF_TOPOLOGY_V2: u32 = 0x1f;
+const LEVEL_INVALID: u32 = 0;
+const LEVEL_SMT: u32 = 1;
+/// Whether leaf 1 reports the number of logical processors per package
+const FEATURE_HTT: u32 = 1 << 28;
+const LEAF_EXT_MAX: u32 = 0x8000_0000;
+const LEAF_EXT_FEATURES: u32 = 0x8000_0001;
+const LEAF_EXT_ADDRESS_SIZES: u32 = 0x8000_0008;
+const LEAF_EXT_APIC_ID: u32 = 0x8000_001e;
+/// Whether the extended apic id leaf is implemented, in ecx of the extended features
+const FEATURE_TOPOLOGY_EXTENSIONS: u32 = 1 << 22;
+/// "AuthenticAMD" as returned in ebx, edx and ecx of leaf 0
+const VENDOR_AMD: [u32; 3] = [0x6874_7541, 0x6974_6e65, 0x444d_4163];
+
+/// Where a cpu is located in the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub apic_id: u32,
+    pub package: u32,
+    pub core: u32,
+    /// The hardware thread within the core, all but one are SMT siblings
+    pub thread: u32,
+}
+
+impl CpuTopology {
+
+    /// Splits the apic id at the given bit positions, the thread id is below `core_shift` and
+    /// the core id below `package_shift`.
+    pub fn from_apic_id(apic_id: u32, core_shift: u32, package_shift: u32) -> Self {
+        let mask = |shift: u32| if shift >= 32 { u32::MAX } else { (1 << shift) - 1 };
+        Self {
+            apic_id,
+            package: apic_id.checked_shr(package_shift).unwrap_or(0),
+            core: (apic_id & mask(package_shift)) >> core_shift.min(31),
+            thread: apic_id & mask(core_shift),
+        }
+    }
+
+    /// Reads the topology of the cpu we are running on.
+    pub fn current() -> Self {
+        if !has_cpuid() {
+            return Self::from_apic_id(0, 0, 0);
+        }
+        let vendor = unsafe { __cpuid_count(0, 0) };
+        let max_leaf = vendor.eax;
+        for leaf in [LEAF_TOPOLOGY_V2, LEAF_TOPOLOGY] {
+            if max_leaf >= leaf {
+                if let Some(topology) = Self::from_topology_leaf(leaf) {
+                    return topology;
+                }
+            }
+        }
+        if [vendor.ebx, vendor.edx, vendor.ecx] == VENDOR_AMD {
+            return Self::from_amd_leaves();
+        }
+        Self::from_legacy_leaves(max_leaf)
+    }
+
+    fn from_topology_leaf(leaf: u32) -> Option<Self> {
+        let mut core_shift = None;
+        let mut package_shift = None;
+        let mut apic_id = 0;
+        // there are at most a handful of levels, don't trust the cpu to ever report an invalid one
+        for sub_leaf in 0..16 {
+            let regs = unsafe { __cpuid_count(leaf, sub_leaf) };
+            let level = (regs.ecx >> 8) & 0xff;
+            if level == LEVEL_INVALID {
+                break;
+            }
+            // the shift of a level is the number of apic id bits used by it and all levels below it
+            let shift = regs.eax & 0x1f;
+            if level == LEVEL_SMT {
+                core_shift = Some(shift);
+            }
+            package_shift = Some(shift);
+            apic_id = regs.edx;
+        }
+        // cpus which don't implement the leaf return zeros for every sub leaf
+        let package_shift = package_shift?;
+        Some(Self::from_apic_id(apic_id, core_shift.unwrap_or(0), package_shift))
+    }
+
+    fn from_legacy_leaves(max_leaf: u32) -> Self {
+        let regs = unsafe { __cpuid_count(LEAF_FEATURES, 0) };
+        let apic_id = regs.ebx >> 24;
+        if regs.edx & FEATURE_HTT == 0 {
+            return Self::from_apic_id(apic_id, 0, 0);
+        }
+        let logical = (regs.ebx >> 16) & 0xff;
+        let cores = if max_leaf >= LEAF_CACHE_PARAMS {
+            (unsafe { __cpuid_count(LEAF_CACHE_PARAMS, 0) }.eax >> 26) + 1
+        } else {
+            1
+        };
+        let package_shift = bits_for(logical);
+        let core_shift = bits_for(logical / cores.max(1));
+        Self::from_apic_id(apic_id, core_shift, package_shift)
+    }
+
+    fn from_amd_leaves() -> Self {
+        let regs = unsafe { __cpuid_count(LEAF_FEATURES, 0) };
+        let apic_id = regs.ebx >> 24;
+        if regs.edx & FEATURE_HTT == 0 {
+            return Self::from_apic_id(apic_id, 0, 0);
+        }
+        let max_ext_leaf = unsafe { __cpuid_count(LEAF_EXT_MAX, 0) }.eax;
+        let mut package_shift = bits_for((regs.ebx >> 16) & 0xff);
+        if max_ext_leaf >= LEAF_EXT_ADDRESS_SIZES {
+            let ecx = unsafe { __cpuid_count(LEAF_EXT_ADDRESS_SIZES, 0) }.ecx;
+            // older cpus leave the apic id size at 0, the field is as wide as needed for the threads then
+            package_shift = match (ecx >> 12) & 0xf {
+                0 => bits_for((ecx & 0xff) + 1),
+                size => size,
+            };
+        }
+        let has_ext_apic_id = max_ext_leaf >= LEAF_EXT_APIC_ID
+            && unsafe { __cpuid_count(LEAF_EXT_FEATURES, 0) }.ecx & FEATURE_TOPOLOGY_EXTENSIONS != 0;
+        let threads_per_core = if has_ext_apic_id {
+            ((unsafe { __cpuid_count(LEAF_EXT_APIC_ID, 0) }.ebx >> 8) & 0xff) + 1
+        } else {
+            1
+        };
+        Self::from_apic_id(apic_id, bits_for(threads_per_core), package_shift)
+    }
+
+    #[inline]
+    pub fn is_sibling_of(&self, other: &CpuTopology) -> bool {
+        self.package == other.package && self.core == other.core && self.thread != other.thread
+    }
+
+}
+
+/// The number of bits needed to number `count` items
+fn bits_for(count: u32) -> u32 {
+    count.max(1).next_power_of_two().trailing_zeros()
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNKNOWN: Once<CpuTopology> = Once::new();
+static TOPOLOGY: PerCpu<Once<CpuTopology>> = PerCpu::new([UNKNOWN; MAX_CPUS]);
+
+/// Records the topology of the cpu we are running on, this has to be called on every cpu when it
+/// comes online.
+pub fn init_cpu(cpu: usize) {
+    if let Some(topology) = TOPOLOGY.get(cpu) {
+        topology.call_once(CpuTopology::current);
+    }
+}
+
+/// Returns the topology of the cpu, `None` if it isn't online yet.
+pub fn get(cpu: usize) -> Option<&'static CpuTopology> {
+    TOPOLOGY.get(cpu).and_then(|topology| topology.get())
+}
+
+/// Calls `f` with every online cpu which shares a core with `cpu`.
+pub fn siblings(cpu: usize, mut f: impl FnMut(usize)) {
+    let topology = match get(cpu) {
+        Some(topology) => topology,
+        None => return,
+    };
+    TOPOLOGY.for_each_online(|other, other_topology| {
+        if other_topology.get().map_or(false, |other_topology| other_topology.is_sibling_of(topology)) {
+            f(other);
+        }
+    });
+}
+
+/// Picks an idle cpu, cpus whose siblings are idle as well are preferred as they don't have to
+/// share their core's execution units. Returns `None` if every cpu is busy.
+pub fn pick_idle_cpu(is_idle: impl Fn(usize) -> bool) -> Option<usize> {
+    let mut fallback = None;
+    for cpu in 0..percpu::online_cpus() {
+        if !is_idle(cpu) {
+            continue;
+        }
+        let mut idle_core = true;
+        siblings(cpu, |sibling| idle_core &= is_idle(sibling));
+        if idle_core {
+            return Some(cpu);
+        }
+        fallback = fallback.or(Some(cpu));
+    }
+    fallback
+}
+
+#[test_case]
+fn test_topology_from_apic_id() {
+    // 2 threads per core, 8 cores per package
+    let topology = CpuTopology::from_apic_id((1 << 5) | (5 << 1) | 1, 1, 5);
+    crate::kassert_eq!((topology.package, topology.core, topology.thread), (1, 5, 1));
+    crate::kassert!(topology.is_sibling_of(&CpuTopology::from_apic_id((1 << 5) | (5 << 1), 1, 5)));
+    crate::kassert!(!topology.is_sibling_of(&CpuTopology::from_apic_id((1 << 5) | (4 << 1) | 1, 1, 5)));
+    // no SMT and a single package
+    let topology = CpuTopology::from_apic_id(3, 0, 32);
+    crate::kassert_eq!((topology.package, topology.core, topology.thread), (0, 3, 0));
+    crate::kassert_eq!(bits_for(6), 3);
+    crate::kassert!(get(0).is_some());
+}
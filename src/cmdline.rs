@@ -0,0 +1,99 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// The kernel command line, a whitespace separated list of `key=value` options and `flag`s.
+// FIXME: Take the command line from the bootloader once we use one which passes it to us,
+//  until then it's baked in at build time through the LEAFOS_CMDLINE environment variable.
+
+lazy_static! {
+    static ref CMDLINE: Mutex<Cmdline> = Mutex::new(Cmdline::parse(option_env!("LEAFOS_CMDLINE").unwrap_or("")));
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cmdline {
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Cmdline {
+
+    pub fn parse(raw: &str) -> Self {
+        let options = raw.split_whitespace().map(|option| match option.split_once('=') {
+            Some((key, value)) => (key.to_string(), Some(value.to_string())),
+            None => (option.to_string(), None),
+        }).collect();
+        Self {
+            options,
+        }
+    }
+
+    /// Returns the value of the last occurrence of `key`, so later options override earlier ones.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options.iter().rev()
+            .find(|(option, _)| option == key)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    pub fn has_flag(&self, key: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == key)
+    }
+
+    /// Returns all `key=value` options whose key starts with `prefix`, the prefix is stripped from the keys.
+    pub fn with_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        let mut ret: Vec<(String, String)> = vec![];
+        for (key, value) in self.options.iter() {
+            if let (Some(key), Some(value)) = (key.strip_prefix(prefix), value) {
+                // later options override earlier ones
+                ret.retain(|(other, _)| other != key);
+                ret.push((key.to_string(), value.clone()));
+            }
+        }
+        ret
+    }
+
+    /// Collects the comma separated values of all occurrences of `key`.
+    pub fn list(&self, key: &str) -> Vec<String> {
+        let mut ret = vec![];
+        for (_, value) in self.options.iter().filter(|(option, _)| option == key) {
+            if let Some(value) = value {
+                ret.extend(value.split(',').filter(|item| !item.is_empty()).map(|item| item.to_string()));
+            }
+        }
+        ret
+    }
+
+}
+
+pub fn get(key: &str) -> Option<String> {
+    CMDLINE.lock().get(key).map(|value| value.to_string())
+}
+
+pub fn has_flag(key: &str) -> bool {
+    CMDLINE.lock().has_flag(key)
+}
+
+pub fn list(key: &str) -> Vec<String> {
+    CMDLINE.lock().list(key)
+}
+
+pub fn with_prefix(prefix: &str) -> Vec<(String, String)> {
+    CMDLINE.lock().with_prefix(prefix)
+}
+
+/// Replaces the command line, this only affects options which are evaluated afterwards.
+pub fn set(raw: &str) {
+    *CMDLINE.lock() = Cmdline::parse(raw);
+}
+
+#[test_case]
+fn test_cmdline_parse() {
+    let cmdline = Cmdline::parse("quiet driver.blacklist=e1000,ahci  driver.blacklist=nvme log=debug log=info");
+    crate::kassert!(cmdline.has_flag("quiet"));
+    crate::kassert!(!cmdline.has_flag("verbose"));
+    crate::kassert_eq!(cmdline.get("log"), Some("info"));
+    crate::kassert_eq!(cmdline.get("quiet"), None);
+    crate::kassert_eq!(cmdline.list("driver.blacklist"), vec!["e1000", "ahci", "nvme"]);
+    crate::kassert!(cmdline.list("driver.force_probe").is_empty());
+}
@@ -0,0 +1,85 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::{log_info, vga_buffer};
+use crate::print::{self, Sink};
+use crate::arch::without_interrupts;
+use crate::shell::{has_shell, SHELL};
+use crate::vga_buffer::WRITER;
+
+// Selects the primary console at boot: the framebuffer if the bootloader set one up, the vga text
+// buffer if there is a vga adapter and the serial port otherwise. `print!` output only goes to the
+// primary console until other sinks get enabled, see `print::Sink`.
+
+static CONSOLE: AtomicU8 = AtomicU8::new(ConsoleKind::VgaText as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConsoleKind {
+    Framebuffer,
+    VgaText,
+    Serial,
+}
+
+impl ConsoleKind {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConsoleKind::Framebuffer => "framebuffer",
+            ConsoleKind::VgaText => "vga text",
+            ConsoleKind::Serial => "serial",
+        }
+    }
+
+    /// The print sink which writes to this console
+    pub fn sink(&self) -> Sink {
+        match self {
+            ConsoleKind::Framebuffer => Sink::Framebuffer,
+            ConsoleKind::VgaText => Sink::Vga,
+            ConsoleKind::Serial => Sink::Serial,
+        }
+    }
+
+}
+
+pub(crate) fn framebuffer_available() -> bool {
+    // FIXME: The bootloader doesn't pass us a framebuffer yet, check its boot info once it does
+    false
+}
+
+/// Selects the primary console, all further output goes to it.
+pub fn init() -> ConsoleKind {
+    let kind = if framebuffer_available() {
+        ConsoleKind::Framebuffer
+    } else if vga_buffer::is_present() {
+        ConsoleKind::VgaText
+    } else {
+        ConsoleKind::Serial
+    };
+    if kind != ConsoleKind::VgaText && vga_buffer::is_present() {
+        WRITER.lock().set_cursor_visible(false);
+    }
+    CONSOLE.store(kind as u8, Ordering::SeqCst);
+    print::set_sinks(&[kind.sink()]);
+    log_info!("using the {} console", kind.name());
+    kind
+}
+
+pub fn kind() -> ConsoleKind {
+    match CONSOLE.load(Ordering::SeqCst) {
+        0 => ConsoleKind::Framebuffer,
+        1 => ConsoleKind::VgaText,
+        _ => ConsoleKind::Serial,
+    }
+}
+
+/// Writes to the vga text buffer, through the shell once it's running so it can keep its prompt intact.
+pub(crate) fn write_vga(args: fmt::Arguments) {
+    use core::fmt::Write;
+    without_interrupts(|| {
+        if has_shell() {
+            SHELL.lock().write_fmt(args).unwrap();
+        } else {
+            WRITER.lock().write_fmt(args).unwrap();
+        }
+    });
+}
@@ -0,0 +1,232 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use crate::arch::enable_interrupts;
+use crate::error_codes::Error;
+use crate::process::Vma;
+use crate::{cmdline, filesystem, log_info, log_warn, memory, scheduler};
+
+// A user process which faults in a way it can't recover from gets killed. If core dumps are
+// enabled (`coredump` on the kernel command line or `set_enabled`), its state is written to
+// `core.<pid>` in its working directory first. The file is an ELF core file like the ones linux
+// writes, so it can be loaded into gdb on the host together with the program's binary.
+// It contains a NT_PRSTATUS note with the registers and a PT_LOAD segment for every memory area
+// of the process, pages which were never touched are written as zeros.
+// FIXME: Only rip, rsp, rflags, cs and ss are known as the exception handlers don't save the
+//  general purpose registers, the others are written as zeros.
+// FIXME: Deliver the signals to the process once we support signal handlers.
+// FIXME: Use the real working directory once processes have one, until then it's `$PWD`.
+
+/// The signals a faulting process dies from, they use the linux numbers as debuggers show them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    Ill = 4,
+    Trap = 5,
+    Bus = 7,
+    Fpe = 8,
+    Segv = 11,
+}
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// The size of `struct elf_prstatus` on x86_64
+const PRSTATUS_SIZE: usize = 336;
+/// The offset of the registers (`struct user_regs_struct`) in it
+const PRSTATUS_REGS: usize = 112;
+const NOTE_NAME: &[u8] = b"CORE\0";
+const PAGE_SIZE: u64 = 4096;
+
+static ENABLED: Once<AtomicBool> = Once::new();
+
+fn enabled_flag() -> &'static AtomicBool {
+    ENABLED.call_once(|| AtomicBool::new(cmdline::has_flag("coredump")))
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    enabled_flag().load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    enabled_flag().store(enabled, Ordering::Relaxed);
+}
+
+/// Gets called by the exception handlers if a user process raised an exception it can't recover
+/// from, dumps its core if that's enabled and terminates it.
+pub fn kill_faulting_process(signal: Signal, stack_frame: &InterruptStackFrame) -> ! {
+    // we never return to the faulting code, so the rest runs like any other kernel code of the process
+    unsafe { enable_interrupts(); }
+    let pid = scheduler::current_task_id();
+    log_warn!("process {} killed by {:?} at {:#x}", pid, signal, stack_frame.instruction_pointer.as_u64());
+    if is_enabled() {
+        match dump_current(signal, stack_frame) {
+            Ok(path) => log_info!("dumped the core of process {} to {}", pid, path),
+            Err(err) => log_warn!("failed to dump the core of process {}: {}", pid, err.description()),
+        }
+    }
+    scheduler::exit_current()
+}
+
+/// Writes the core file of the running process and returns its path.
+pub fn dump_current(signal: Signal, stack_frame: &InterruptStackFrame) -> Result<String, Error> {
+    let pid = scheduler::current_task_id();
+    let vmas = scheduler::process_vmas(pid).ok_or(Error::ESRCH)?;
+    let core = build_core(pid, signal, stack_frame, &vmas);
+    let dir = scheduler::current_env_var("PWD").unwrap_or_else(|| String::from("/"));
+    let path = format!("{}/core.{}", dir.trim_end_matches('/'), pid);
+    filesystem::write_file(&path, &core, false)?;
+    Ok(path)
+}
+
+/// Builds the core file from the memory areas of the current address space.
+fn build_core(pid: u64, signal: Signal, stack_frame: &InterruptStackFrameValue, vmas: &[Vma]) -> Vec<u8> {
+    // the kernel stack lies in the kernel half and isn't part of the process' image
+    let vmas = vmas.iter()
+        .filter(|vma| memory::is_user_range(VirtAddr::new_truncate(vma.start), (vma.end - vma.start) as usize))
+        .collect::<Vec<_>>();
+    let note = prstatus_note(pid, signal, stack_frame);
+    let headers_end = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * (vmas.len() + 1);
+    let mut core = vec![];
+    push_elf_header(&mut core, vmas.len() + 1);
+    push_program_header(&mut core, PT_NOTE, 0, headers_end as u64, 0, note.len() as u64, 4);
+    // the segments follow the note, page aligned so debuggers can map them
+    let mut offset = align_up((headers_end + note.len()) as u64, PAGE_SIZE);
+    for vma in vmas.iter() {
+        let mut flags = PF_R;
+        if vma.writable {
+            flags |= PF_W;
+        }
+        if vma.executable {
+            flags |= PF_X;
+        }
+        push_program_header(&mut core, PT_LOAD, flags, offset, vma.start, vma.end - vma.start, PAGE_SIZE);
+        offset += vma.end - vma.start;
+    }
+    core.extend_from_slice(&note);
+    core.resize(align_up(core.len() as u64, PAGE_SIZE) as usize, 0);
+    for vma in vmas.iter() {
+        let mut page = vma.start;
+        while page < vma.end {
+            let len = (vma.end - page).min(PAGE_SIZE) as usize;
+            let start = core.len();
+            core.resize(start + len, 0);
+            // pages which aren't mapped (yet) stay zero
+            let _ = memory::read_virt(VirtAddr::new(page), &mut core[start..]);
+            page += len as u64;
+        }
+    }
+    core
+}
+
+fn push_elf_header(core: &mut Vec<u8>, program_headers: usize) {
+    // 64 bit, little endian, current version, System V ABI
+    core.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    core.extend_from_slice(&ET_CORE.to_le_bytes());
+    core.extend_from_slice(&EM_X86_64.to_le_bytes());
+    core.extend_from_slice(&1_u32.to_le_bytes());
+    // entry point, program header and section header offset
+    core.extend_from_slice(&0_u64.to_le_bytes());
+    core.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    core.extend_from_slice(&0_u64.to_le_bytes());
+    // flags
+    core.extend_from_slice(&0_u32.to_le_bytes());
+    core.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    core.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    core.extend_from_slice(&(program_headers as u16).to_le_bytes());
+    // no section headers
+    core.extend_from_slice(&[0; 6]);
+}
+
+fn push_program_header(core: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, addr: u64, size: u64, align: u64) {
+    core.extend_from_slice(&kind.to_le_bytes());
+    core.extend_from_slice(&flags.to_le_bytes());
+    core.extend_from_slice(&offset.to_le_bytes());
+    // virtual and physical address
+    core.extend_from_slice(&addr.to_le_bytes());
+    core.extend_from_slice(&0_u64.to_le_bytes());
+    // size in the file and in memory
+    core.extend_from_slice(&size.to_le_bytes());
+    core.extend_from_slice(&size.to_le_bytes());
+    core.extend_from_slice(&align.to_le_bytes());
+}
+
+/// Builds the NT_PRSTATUS note containing the signal and the registers.
+fn prstatus_note(pid: u64, signal: Signal, stack_frame: &InterruptStackFrameValue) -> Vec<u8> {
+    let mut status = [0_u8; PRSTATUS_SIZE];
+    // pr_info.si_signo and pr_cursig
+    status[0..4].copy_from_slice(&(signal as u32).to_le_bytes());
+    status[12..14].copy_from_slice(&(signal as u16).to_le_bytes());
+    // pr_pid
+    status[32..36].copy_from_slice(&(pid as u32).to_le_bytes());
+    // the indices of rip, cs, eflags, rsp and ss in `struct user_regs_struct`
+    let regs = [
+        (16, stack_frame.instruction_pointer.as_u64()),
+        (17, stack_frame.code_segment),
+        (18, stack_frame.cpu_flags),
+        (19, stack_frame.stack_pointer.as_u64()),
+        (20, stack_frame.stack_segment),
+    ];
+    for (idx, value) in regs {
+        let offset = PRSTATUS_REGS + idx * 8;
+        status[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let mut note = vec![];
+    note.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+    note.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(NOTE_NAME);
+    // the name and the description are padded to 4 bytes
+    note.resize(align_up(note.len() as u64, 4) as usize, 0);
+    note.extend_from_slice(&status);
+    note
+}
+
+#[inline]
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+#[test_case]
+fn test_core_layout() {
+    let frame = InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new(0x40_1000),
+        code_segment: 0x23,
+        cpu_flags: 0x202,
+        stack_pointer: VirtAddr::new(0x7000_0000),
+        stack_segment: 0x1b,
+    };
+    let vmas = [Vma {
+        start: 0x10_0000,
+        end: 0x10_2000,
+        writable: true,
+        executable: false,
+        name: String::from("[test]"),
+    }];
+    let core = build_core(7, Signal::Segv, &frame, &vmas);
+    crate::kassert_eq!(&core[0..4], b"\x7fELF");
+    crate::kassert_eq!(u16::from_le_bytes([core[16], core[17]]), ET_CORE);
+    // the note and one load segment
+    crate::kassert_eq!(u16::from_le_bytes([core[56], core[57]]), 2);
+    let load = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE;
+    let offset = u64::from_le_bytes(core[load + 8..load + 16].try_into().unwrap());
+    crate::kassert_eq!(offset % PAGE_SIZE, 0);
+    crate::kassert_eq!(core.len() as u64, offset + 0x2000);
+    let note = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * 2;
+    let rip = note + 20 + PRSTATUS_REGS + 16 * 8;
+    crate::kassert_eq!(u64::from_le_bytes(core[rip..rip + 8].try_into().unwrap()), 0x40_1000);
+}
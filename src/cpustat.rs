@@ -0,0 +1,134 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::percpu::{self, MAX_CPUS, PerCpu};
+use crate::{log_debug, time};
+
+// Every cpu is either busy or idle (halted in the idle loop), the tsc cycles spent in each state
+// get attributed whenever the cpu enters or leaves the idle loop and on every scheduler tick.
+// Once per governor period the load of the period is handed to the governor, which is supposed
+// to pick a P-state for the cpu.
+// FIXME: Add a governor which actually changes the frequency (e.g. through IA32_PERF_CTL) once we
+//  parse the ACPI _PSS tables, the default one only logs the load for now.
+
+/// How often the governor gets called
+const GOVERNOR_PERIOD_US: u64 = 1_000_000;
+
+/// Gets called from the scheduler tick with the cpu and its load in percent over the last period,
+/// it runs in interrupt context.
+pub type Governor = fn(cpu: usize, load: u64);
+
+struct CpuCycles {
+    busy: AtomicU64,
+    idle: AtomicU64,
+    is_idle: AtomicBool,
+    /// The tsc value up to which the cycles were attributed
+    accounted_until: AtomicU64,
+    /// The tsc value and the busy and idle cycles at the start of the current governor period
+    period_start: AtomicU64,
+    period_busy: AtomicU64,
+    period_idle: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CYCLES: CpuCycles = CpuCycles {
+    busy: AtomicU64::new(0),
+    idle: AtomicU64::new(0),
+    is_idle: AtomicBool::new(false),
+    accounted_until: AtomicU64::new(0),
+    period_start: AtomicU64::new(0),
+    period_busy: AtomicU64::new(0),
+    period_idle: AtomicU64::new(0),
+};
+
+static CYCLES: PerCpu<CpuCycles> = PerCpu::new([NO_CYCLES; MAX_CPUS]);
+static GOVERNOR: Mutex<Governor> = Mutex::new(log_governor);
+/// The last load the default governor logged for every cpu
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOAD: AtomicU64 = AtomicU64::new(u64::MAX);
+static LOGGED_LOAD: PerCpu<AtomicU64> = PerCpu::new([NO_LOAD; MAX_CPUS]);
+
+#[inline]
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Attributes the cycles since the last update to the state the cpu was in and switches to `idle`.
+fn update(cycles: &CpuCycles, idle: bool) -> u64 {
+    let now = rdtsc();
+    without_interrupts(|| {
+        let last = cycles.accounted_until.swap(now, Ordering::Relaxed);
+        // the first update only starts the accounting
+        if last != 0 {
+            let state = if cycles.is_idle.load(Ordering::Relaxed) { &cycles.idle } else { &cycles.busy };
+            state.fetch_add(now.saturating_sub(last), Ordering::Relaxed);
+        }
+        cycles.is_idle.store(idle, Ordering::Relaxed);
+    });
+    now
+}
+
+/// Gets called by the idle loop right before halting the cpu.
+pub fn idle_enter() {
+    update(CYCLES.current(), true);
+}
+
+/// Gets called by the idle loop once the cpu woke up again.
+pub fn idle_exit() {
+    update(CYCLES.current(), false);
+}
+
+/// Gets called on every scheduler tick, the cpu is busy running the scheduler now. If it picks
+/// the idle task, the idle loop marks the cpu as idle again before halting.
+pub(crate) fn tick() {
+    let cycles = CYCLES.current();
+    let now = update(cycles, false);
+    let start = cycles.period_start.load(Ordering::Relaxed);
+    let period = time::tsc_hz() * GOVERNOR_PERIOD_US / 1_000_000;
+    if start == 0 || period == 0 {
+        cycles.period_start.store(now, Ordering::Relaxed);
+        cycles.period_busy.store(cycles.busy.load(Ordering::Relaxed), Ordering::Relaxed);
+        cycles.period_idle.store(cycles.idle.load(Ordering::Relaxed), Ordering::Relaxed);
+        return;
+    }
+    if now - start < period {
+        return;
+    }
+    let busy = cycles.busy.load(Ordering::Relaxed);
+    let idle = cycles.idle.load(Ordering::Relaxed);
+    let busy_delta = busy - cycles.period_busy.swap(busy, Ordering::Relaxed);
+    let idle_delta = idle - cycles.period_idle.swap(idle, Ordering::Relaxed);
+    cycles.period_start.store(now, Ordering::Relaxed);
+    let load = busy_delta * 100 / (busy_delta + idle_delta).max(1);
+    let governor = *GOVERNOR.lock();
+    governor(percpu::current_cpu(), load);
+}
+
+/// Replaces the governor which gets called with the load of every cpu.
+pub fn set_governor(governor: Governor) {
+    without_interrupts(|| *GOVERNOR.lock() = governor);
+}
+
+/// The default governor, it logs the load whenever it changed noticeably.
+pub fn log_governor(cpu: usize, load: u64) {
+    let logged = LOGGED_LOAD.get(cpu).unwrap();
+    let last = logged.load(Ordering::Relaxed);
+    if last == u64::MAX || last.max(load) - last.min(load) >= 10 {
+        logged.store(load, Ordering::Relaxed);
+        log_debug!("cpu {} load {}%", cpu, load);
+    }
+}
+
+/// Whether the cpu is halted in the idle loop right now.
+pub fn is_idle(cpu: usize) -> bool {
+    CYCLES.get(cpu).map_or(false, |cycles| cycles.is_idle.load(Ordering::Relaxed))
+}
+
+/// Calls `f` with the index and the busy and idle time in nanoseconds of every online cpu.
+pub fn for_each_cpu(mut f: impl FnMut(usize, u64, u64)) {
+    let hz = time::tsc_hz();
+    let to_ns = |cycles: u64| if hz == 0 { 0 } else { (cycles as u128 * 1_000_000_000 / hz as u128) as u64 };
+    CYCLES.for_each_online(|cpu, cycles| {
+        f(cpu, to_ns(cycles.busy.load(Ordering::Relaxed)), to_ns(cycles.idle.load(Ordering::Relaxed)));
+    });
+}
@@ -0,0 +1,72 @@
+use core::arch::asm;
+use lazy_static::lazy_static;
+use raw_cpuid::CpuId;
+use crate::arch::x86::cpuid::has_cpuid;
+
+const POLY: u32 = 0x82F6_3B78; // reversed castagnoli polynomial
+
+lazy_static! {
+    static ref HAS_SSE42: bool = has_cpuid() && CpuId::new()
+        .get_feature_info()
+        .map_or(false, |features| features.has_sse42());
+
+    static ref TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (idx, entry) in table.iter_mut().enumerate() {
+            let mut crc = idx as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+/// Computes the CRC32c (castagnoli) checksum of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+/// Continues a checksum computation, `crc` is the raw (not inverted) state of the previous call.
+/// This allows computing checksums over data which isn't contiguous.
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    if *HAS_SSE42 {
+        unsafe { update_sse42(crc, data) }
+    } else {
+        update_table(crc, data)
+    }
+}
+
+fn update_table(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Uses the crc32 instruction which implements exactly this polynomial.
+unsafe fn update_sse42(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc as u64;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let val = u64::from_le_bytes(chunk.try_into().unwrap());
+        asm!("crc32 {crc}, {val}", crc = inout(reg) crc, val = in(reg) val, options(pure, nomem, nostack));
+    }
+    let mut crc = crc as u32;
+    for byte in chunks.remainder() {
+        asm!("crc32 {crc:e}, {byte}", crc = inout(reg) crc, byte = in(reg_byte) *byte, options(pure, nomem, nostack));
+    }
+    crc
+}
+
+#[test_case]
+fn test_crc32c_vectors() {
+    crate::kassert_eq!(crc32c(b""), 0);
+    crate::kassert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    crate::kassert_eq!(crc32c(&[0; 32]), 0x8A91_36AA);
+    crate::kassert_eq!(crc32c(&[0xff; 32]), 0x62A8_AB43);
+    // both implementations have to agree, regardless of which one is used by default
+    let data: alloc::vec::Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    crate::kassert_eq!(update_table(!0, &data), crc32c_update(!0, &data));
+}
@@ -0,0 +1,13 @@
+// Software implementations of the checksums and hashes used throughout the kernel.
+// None of these are constant time, so they must not be used with secret data.
+
+// FIXME: Verify the initramfs against a public key embedded in the kernel before unpacking it.
+//  This needs a signature scheme (e.g. ed25519 on top of sha512) and a way to receive the initramfs
+//  in the first place, the bootloader we currently use doesn't load any modules for us, so this
+//  has to wait until we switch to a bootloader which does (e.g. limine).
+
+pub mod sha256;
+pub mod crc32c;
+
+pub use crc32c::crc32c;
+pub use sha256::{sha256, Sha256};
@@ -0,0 +1,152 @@
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+pub const DIGEST_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 64;
+
+/// An incremental SHA-256 hasher for data which isn't available all at once.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; BLOCK_SIZE],
+    buffered: usize,
+    length: u64,
+}
+
+impl Sha256 {
+
+    pub fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            buffer: [0; BLOCK_SIZE],
+            buffered: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if self.buffered > 0 {
+            let len = data.len().min(BLOCK_SIZE - self.buffered);
+            self.buffer[self.buffered..self.buffered + len].copy_from_slice(&data[..len]);
+            self.buffered += len;
+            data = &data[len..];
+            if self.buffered < BLOCK_SIZE {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        let remainder = blocks.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffered = remainder.len();
+    }
+
+    pub fn finalize(mut self) -> [u8; DIGEST_SIZE] {
+        let bit_length = self.length * 8;
+        // append the 1 bit, pad with zeroes and end with the message length in bits
+        let mut padding = [0; BLOCK_SIZE * 2];
+        padding[0] = 0x80;
+        let padding_len = if self.buffered < BLOCK_SIZE - 8 {
+            BLOCK_SIZE - self.buffered
+        } else {
+            BLOCK_SIZE * 2 - self.buffered
+        };
+        padding[padding_len - 8..padding_len].copy_from_slice(&bit_length.to_be_bytes());
+        let length = self.length;
+        self.update(&padding[..padding_len]);
+        self.length = length;
+        let mut ret = [0; DIGEST_SIZE];
+        for (idx, word) in self.state.iter().enumerate() {
+            ret[idx * 4..idx * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        ret
+    }
+
+    fn compress(&mut self, block: &[u8; BLOCK_SIZE]) {
+        let mut w = [0_u32; 64];
+        for idx in 0..16 {
+            w[idx] = u32::from_be_bytes(block[idx * 4..idx * 4 + 4].try_into().unwrap());
+        }
+        for idx in 16..64 {
+            let s0 = w[idx - 15].rotate_right(7) ^ w[idx - 15].rotate_right(18) ^ (w[idx - 15] >> 3);
+            let s1 = w[idx - 2].rotate_right(17) ^ w[idx - 2].rotate_right(19) ^ (w[idx - 2] >> 10);
+            w[idx] = w[idx - 16].wrapping_add(s0).wrapping_add(w[idx - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for idx in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[idx]).wrapping_add(w[idx]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (state, val) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(val);
+        }
+    }
+
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[test_case]
+fn test_sha256_vectors() {
+    fn hex(digest: [u8; DIGEST_SIZE]) -> alloc::string::String {
+        use core::fmt::Write;
+        let mut ret = alloc::string::String::new();
+        for byte in digest {
+            let _ = write!(ret, "{:02x}", byte);
+        }
+        ret
+    }
+
+    crate::kassert_eq!(hex(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    crate::kassert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    crate::kassert_eq!(hex(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                       "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    // feeding the data in pieces must not change the result
+    let mut hasher = Sha256::new();
+    for _ in 0..1000 {
+        hasher.update(b"a");
+    }
+    let mut expected = Sha256::new();
+    expected.update(&[b'a'; 1000]);
+    crate::kassert_eq!(hasher.finalize(), expected.finalize());
+}
@@ -0,0 +1,81 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::drivers::driver::BlockDriverImpl;
+use crate::drivers::registry::DeviceId;
+use crate::error_codes::Error;
+use crate::log_warn;
+
+lazy_static! {
+    static ref BLOCK_DEVICES: Mutex<Vec<BlockDevice>> = Mutex::new(vec![]);
+}
+
+pub struct BlockDevice {
+    pub name: String,
+    pub id: DeviceId,
+    pub driver: Box<dyn BlockDriverImpl<u8> + Send>,
+}
+
+/// Makes a block device available to the rest of the kernel, the device has to be initialized already.
+/// The id has to be allocated from the driver registry.
+pub fn register(name: String, id: DeviceId, driver: Box<dyn BlockDriverImpl<u8> + Send>) {
+    BLOCK_DEVICES.lock().push(BlockDevice {
+        name,
+        id,
+        driver,
+    });
+}
+
+/// Removes the block device, it must not be in use anymore.
+pub fn unregister(name: &str) -> Result<(), Error> {
+    let mut devices = BLOCK_DEVICES.lock();
+    let idx = devices.iter().position(|device| device.name == name).ok_or(Error::ENODEV)?;
+    devices.remove(idx);
+    Ok(())
+}
+
+/// Calls `f` with the block device registered under the given name.
+pub fn with_device<R>(name: &str, f: impl FnOnce(&mut BlockDevice) -> R) -> Option<R> {
+    let mut devices = BLOCK_DEVICES.lock();
+    devices.iter_mut().find(|device| device.name == name).map(f)
+}
+
+pub fn for_each_device(mut f: impl FnMut(&mut BlockDevice)) {
+    for device in BLOCK_DEVICES.lock().iter_mut() {
+        f(device);
+    }
+}
+
+/// Whether the block devices are in use, an interrupt handler must not wait for them then.
+pub fn is_locked() -> bool {
+    BLOCK_DEVICES.is_locked()
+}
+
+// Drivers complete requests synchronously while the caller waits.
+// FIXME: Let interrupt driven drivers signal finished requests with a `Completion`, so the
+//  caller can sleep meanwhile, once there is such a driver
+
+/// Suspends all devices, if one of them fails the ones which were already suspended are resumed again.
+pub fn suspend_all() -> Result<(), Error> {
+    let mut devices = BLOCK_DEVICES.lock();
+    for idx in 0..devices.len() {
+        if let Err(err) = unsafe { devices[idx].driver.suspend() } {
+            log_warn!("{}: suspend failed: {}", devices[idx].name, err);
+            for device in devices[..idx].iter_mut().rev() {
+                unsafe { device.driver.resume(); }
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Resumes all devices in the reverse order they were suspended in.
+pub fn resume_all() {
+    for device in BLOCK_DEVICES.lock().iter_mut().rev() {
+        unsafe { device.driver.resume(); }
+    }
+}
@@ -0,0 +1,300 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use crate::error_codes::Error;
+use crate::{log_warn, memory};
+
+// Devices transfer data from and to physical memory, but a buffer which is contiguous in virtual
+// memory is usually scattered over several frames. A scatter-gather list describes such a buffer
+// as a list of physically contiguous segments, drivers translate it into their descriptor format
+// (e.g. AHCI PRDTs, NVMe PRP lists or the descriptor rings of a nic).
+// FIXME: Build the descriptors of the AHCI, NVMe and nic drivers from this once we have them
+//
+// Parts of the buffer a device can't address (e.g. above 4GiB for devices with 32 bit dma) are
+// replaced by bounce buffers, frames from a pool reserved at boot. The driver has to call
+// `sync_for_device` before starting a transfer and `sync_for_cpu` once it completed, to copy the
+// data between the buffer and the bounce buffers.
+
+/// The number of frames reserved for bounce buffers
+const BOUNCE_FRAMES: usize = 32;
+const FRAME_SIZE: u64 = 4096;
+
+lazy_static! {
+    /// The free bounce buffer frames, they are all below 4GiB
+    static ref BOUNCE_POOL: Mutex<Vec<PhysFrame>> = Mutex::new(vec![]);
+}
+
+/// Reserves the frames of the bounce buffer pool, this has to be called early during boot,
+/// while the frame allocator still hands out low frames.
+pub fn init() {
+    let mut pool = BOUNCE_POOL.lock();
+    while pool.len() < BOUNCE_FRAMES {
+        match memory::allocate_frame() {
+            Some(frame) if frame.start_address().as_u64() + FRAME_SIZE - 1 <= DmaConstraints::DMA32_MASK => pool.push(frame),
+            _ => break,
+        }
+    }
+    if pool.len() < BOUNCE_FRAMES {
+        log_warn!("only reserved {} of {} bounce buffer frames", pool.len(), BOUNCE_FRAMES);
+    }
+}
+
+/// Takes a bounce buffer frame the device can address.
+fn take_bounce_frame(max_addr: u64) -> Option<PhysFrame> {
+    let mut pool = BOUNCE_POOL.lock();
+    let idx = pool.iter().position(|frame| frame.start_address().as_u64() + FRAME_SIZE - 1 <= max_addr)?;
+    Some(pool.swap_remove(idx))
+}
+
+/// A physically contiguous part of a buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: PhysAddr,
+    pub len: usize,
+}
+
+/// What a device's dma engine can handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// The maximum length of a single descriptor
+    pub max_segment_len: usize,
+    /// The maximum number of descriptors per transfer
+    pub max_segments: usize,
+    /// The highest physical address the device can access
+    pub max_addr: u64,
+}
+
+impl DmaConstraints {
+    pub const DMA32_MASK: u64 = u32::MAX as u64;
+
+    pub const UNLIMITED: Self = Self {
+        max_segment_len: usize::MAX,
+        max_segments: usize::MAX,
+        max_addr: u64::MAX,
+    };
+}
+
+/// A part of the buffer which the device accesses through a bounce buffer
+#[derive(Debug)]
+struct Bounce {
+    frame: PhysFrame,
+    /// Where the data is in the original buffer
+    orig: PhysAddr,
+    len: usize,
+}
+
+/// The physical segments of a buffer, the buffer has to stay mapped at the same frames
+/// (i.e. it must not be freed or swapped out) for as long as the device uses the list.
+#[derive(Debug)]
+pub struct SgList {
+    segments: Vec<Segment>,
+    len: usize,
+    bounces: Vec<Bounce>,
+    constraints: DmaConstraints,
+}
+
+impl SgList {
+
+    /// Translates a kernel buffer.
+    pub fn from_kernel(buf: &[u8], constraints: DmaConstraints) -> Result<Self, Error> {
+        Self::translate(VirtAddr::from_ptr(buf.as_ptr()), buf.len(), PageTableFlags::empty(), constraints)
+    }
+
+    /// Translates a buffer of the current process, it has to lie completely in the user half
+    /// of the address space and be accessible from user mode.
+    pub fn from_user(addr: VirtAddr, len: usize, constraints: DmaConstraints) -> Result<Self, Error> {
+        if !memory::is_user_range(addr, len) {
+            return Err(Error::EFAULT);
+        }
+        Self::translate(addr, len, PageTableFlags::USER_ACCESSIBLE, constraints)
+    }
+
+    /// Walks the page tables for every page of the buffer, physically adjacent pages are merged
+    /// into one segment as long as the segment doesn't get too long for the device.
+    fn translate(addr: VirtAddr, len: usize, required: PageTableFlags, constraints: DmaConstraints) -> Result<Self, Error> {
+        // the bounce frames taken so far are returned when dropping the list on errors
+        let mut list = Self {
+            segments: vec![],
+            len,
+            bounces: vec![],
+            constraints,
+        };
+        let mut pos = addr.as_u64();
+        let end = pos.checked_add(len as u64).ok_or(Error::EFAULT)?;
+        while pos < end {
+            let virt = VirtAddr::try_new(pos).map_err(|_| Error::EFAULT)?;
+            let mapping = memory::lookup(virt).ok_or(Error::EFAULT)?;
+            if !mapping.flags.contains(required) {
+                return Err(Error::EFAULT);
+            }
+            let page_offset = pos % mapping.page_size;
+            let chunk = (mapping.page_size - page_offset).min(end - pos) as usize;
+            let phys = mapping.frame + page_offset;
+            if phys.as_u64() + chunk as u64 - 1 <= constraints.max_addr {
+                list.push_range(phys, chunk)?;
+            } else {
+                list.push_bounced(phys, chunk)?;
+            }
+            pos += chunk as u64;
+        }
+        Ok(list)
+    }
+
+    /// Appends a physical range, it gets merged into the last segment if it follows it
+    /// and split at the device's length limit.
+    fn push_range(&mut self, addr: PhysAddr, len: usize) -> Result<(), Error> {
+        let max_len = self.constraints.max_segment_len;
+        let mut remaining = len;
+        let mut pos = addr;
+        if let Some(last) = self.segments.last_mut() {
+            if last.addr + last.len as u64 == addr && last.len < max_len {
+                let extend = remaining.min(max_len - last.len);
+                last.len += extend;
+                remaining -= extend;
+                pos += extend as u64;
+            }
+        }
+        while remaining > 0 {
+            if self.segments.len() == self.constraints.max_segments {
+                return Err(Error::E2BIG);
+            }
+            let len = remaining.min(max_len);
+            self.segments.push(Segment {
+                addr: pos,
+                len,
+            });
+            remaining -= len;
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Appends a physical range the device can't reach through bounce buffers.
+    fn push_bounced(&mut self, addr: PhysAddr, len: usize) -> Result<(), Error> {
+        let mut done = 0;
+        while done < len {
+            let frame = take_bounce_frame(self.constraints.max_addr).ok_or(Error::ENOMEM)?;
+            let chunk = (len - done).min(FRAME_SIZE as usize);
+            self.bounces.push(Bounce {
+                frame,
+                orig: addr + done as u64,
+                len: chunk,
+            });
+            self.push_range(frame.start_address(), chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copies the buffer's data into the bounce buffers, this has to be done before
+    /// the device reads from the buffer.
+    pub fn sync_for_device(&self) {
+        for bounce in self.bounces.iter() {
+            unsafe { copy_phys(bounce.orig, bounce.frame.start_address(), bounce.len); }
+        }
+    }
+
+    /// Copies the data the device wrote into the bounce buffers back into the buffer.
+    pub fn sync_for_cpu(&self) {
+        for bounce in self.bounces.iter() {
+            unsafe { copy_phys(bounce.frame.start_address(), bounce.orig, bounce.len); }
+        }
+    }
+
+    /// Whether parts of the buffer are accessed through bounce buffers
+    #[inline]
+    pub fn is_bounced(&self) -> bool {
+        !self.bounces.is_empty()
+    }
+
+    #[inline]
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The number of bytes described by the list
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+}
+
+impl Drop for SgList {
+    fn drop(&mut self) {
+        let mut pool = BOUNCE_POOL.lock();
+        pool.extend(self.bounces.drain(..).map(|bounce| bounce.frame));
+    }
+}
+
+/// Copies between two physical ranges through the mapping of the complete physical memory.
+unsafe fn copy_phys(src: PhysAddr, dst: PhysAddr, len: usize) {
+    core::ptr::copy_nonoverlapping(memory::phys_to_virt(src).as_ptr::<u8>(), memory::phys_to_virt(dst).as_mut_ptr::<u8>(), len);
+}
+
+#[test_case]
+fn test_sg_list_from_kernel_buffer() {
+    let buf = vec![0_u8; 3 * 4096 + 100];
+    let list = SgList::from_kernel(&buf, DmaConstraints::UNLIMITED).unwrap();
+    crate::kassert_eq!(list.segments().iter().map(|segment| segment.len).sum::<usize>(), buf.len());
+    crate::kassert_eq!(list.segments()[0].addr, memory::lookup(VirtAddr::from_ptr(buf.as_ptr())).map(|mapping| {
+        mapping.frame + VirtAddr::from_ptr(buf.as_ptr()).as_u64() % mapping.page_size
+    }).unwrap());
+    let constraints = DmaConstraints {
+        max_segment_len: 1000,
+        ..DmaConstraints::UNLIMITED
+    };
+    let list = SgList::from_kernel(&buf, constraints).unwrap();
+    crate::kassert!(list.segments().iter().all(|segment| segment.len <= 1000));
+    crate::kassert!(list.segments().len() >= buf.len() / 1000);
+    let constraints = DmaConstraints {
+        max_segment_len: 1000,
+        max_segments: 2,
+        ..DmaConstraints::UNLIMITED
+    };
+    crate::kassert_eq!(SgList::from_kernel(&buf, constraints).map(|list| list.len()), Err(Error::E2BIG));
+    // the heap isn't accessible from user mode
+    crate::kassert!(SgList::from_user(VirtAddr::from_ptr(buf.as_ptr()), buf.len(), DmaConstraints::UNLIMITED).is_err());
+}
+
+#[test_case]
+fn test_sg_list_bounces_unreachable_memory() {
+    // the frame allocator hands out the frames in ascending order, so a fresh frame lies above
+    // the bounce frames reserved during boot and a device which only reaches below it needs them
+    // FIXME: Free the frame again once the frame allocator can free frames
+    let frame = memory::allocate_frame().unwrap();
+    let buf = unsafe { core::slice::from_raw_parts_mut(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), FRAME_SIZE as usize) };
+    buf.fill(0x5a);
+    let max_addr = frame.start_address().as_u64() - 1;
+    crate::kassert!(BOUNCE_POOL.lock().iter().any(|bounce| bounce.start_address().as_u64() + FRAME_SIZE - 1 <= max_addr));
+    let constraints = DmaConstraints {
+        max_addr,
+        ..DmaConstraints::UNLIMITED
+    };
+    let free = BOUNCE_POOL.lock().len();
+    {
+        let list = SgList::from_kernel(buf, constraints).unwrap();
+        crate::kassert!(list.is_bounced());
+        crate::kassert!(list.segments().iter().all(|segment| segment.addr.as_u64() + segment.len as u64 - 1 <= max_addr));
+        list.sync_for_device();
+        for segment in list.segments() {
+            let data = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(segment.addr).as_ptr::<u8>(), segment.len) };
+            crate::kassert!(data.iter().all(|byte| *byte == 0x5a));
+        }
+        // pretend the device wrote to the buffer
+        for segment in list.segments() {
+            unsafe { core::ptr::write_bytes(memory::phys_to_virt(segment.addr).as_mut_ptr::<u8>(), 0xa5, segment.len); }
+        }
+        list.sync_for_cpu();
+    }
+    crate::kassert!(buf.iter().all(|byte| *byte == 0xa5));
+    crate::kassert_eq!(BOUNCE_POOL.lock().len(), free);
+}
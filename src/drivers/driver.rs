@@ -1,6 +1,8 @@
 use alloc::boxed::Box;
+use alloc::string::String;
 use core::marker::PhantomData;
 use x86_64::structures::idt::InterruptDescriptorTable;
+use crate::error_codes::Error;
 
 // TODO: Implement event system to detect driver/device events
 
@@ -10,6 +12,14 @@ pub unsafe trait Driver {
 
     unsafe fn exit(&mut self);
 
+    /// Quiesces the device before the system suspends, returning an error aborts the suspend.
+    unsafe fn suspend(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Brings the device back into the state it had before `suspend`.
+    unsafe fn resume(&mut self) {}
+
 }
 
 pub struct ReadOnly;
@@ -56,6 +66,16 @@ unsafe impl<T, A> Driver for CharDriver<T, A> {
     unsafe fn exit(&mut self) {
         self.0.exit()
     }
+
+    #[inline]
+    unsafe fn suspend(&mut self) -> Result<(), Error> {
+        self.0.suspend()
+    }
+
+    #[inline]
+    unsafe fn resume(&mut self) {
+        self.0.resume()
+    }
 }
 
 impl<T> CharDriver<T, ReadOnly> {
@@ -110,6 +130,37 @@ impl<T> CharDriver<T, ReadWrite> {
 
 }
 
+/// Identification data reported by a block device (e.g. from ATA IDENTIFY DEVICE or NVMe Identify Controller)
+#[derive(Debug, Clone)]
+pub struct DeviceIdentity {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub block_size: usize,
+    pub block_count: u64,
+}
+
+impl DeviceIdentity {
+
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.block_size as u64 * self.block_count
+    }
+
+}
+
+/// Basic health attributes (e.g. from S.M.A.R.T. or the NVMe SMART / Health Information log page),
+/// attributes a device doesn't report are `None`
+#[derive(Debug, Clone, Default)]
+pub struct HealthInfo {
+    pub healthy: bool,
+    pub temperature_celsius: Option<i16>,
+    pub power_on_hours: Option<u64>,
+    pub power_cycles: Option<u64>,
+    pub media_errors: Option<u64>,
+    pub percentage_used: Option<u8>,
+}
+
 pub unsafe trait BlockDriverImpl<T/*, I*/>: Driver { // FIXME: MAYBE: Generic index parameter
 
     unsafe fn write_block(&mut self, block: &[T]);
@@ -120,6 +171,22 @@ pub unsafe trait BlockDriverImpl<T/*, I*/>: Driver { // FIXME: MAYBE: Generic in
 
     unsafe fn read_block_indexed(&mut self, index: usize, block_size: usize) -> Box<[T]>;
 
+    /// Like `write_block_indexed`, but reports whether the write reached the device.
+    unsafe fn try_write_block_indexed(&mut self, index: usize, block: &[T]) -> Result<(), Error> {
+        self.write_block_indexed(index, block);
+        Ok(())
+    }
+
+    /// Queries the device's identification data, returns `None` if the device doesn't support this.
+    unsafe fn identify(&mut self) -> Option<DeviceIdentity> {
+        None
+    }
+
+    /// Queries the device's health attributes, returns `None` if the device doesn't support this.
+    unsafe fn health(&mut self) -> Option<HealthInfo> {
+        None
+    }
+
 }
 
 pub struct BlockDriver<T, A>(Box<dyn BlockDriverImpl<T>>, PhantomData<A>);
@@ -140,6 +207,20 @@ impl<T, A> BlockDriver<T, A> {
 
 }
 
+impl<T, A> BlockDriver<T, A> {
+
+    #[inline]
+    pub unsafe fn identify(&mut self) -> Option<DeviceIdentity> {
+        self.0.identify()
+    }
+
+    #[inline]
+    pub unsafe fn health(&mut self) -> Option<HealthInfo> {
+        self.0.health()
+    }
+
+}
+
 unsafe impl<T, A> Driver for BlockDriver<T, A> {
     #[inline]
     unsafe fn init(&mut self, idt: &mut InterruptDescriptorTable) -> bool {
@@ -150,6 +231,16 @@ unsafe impl<T, A> Driver for BlockDriver<T, A> {
     unsafe fn exit(&mut self) {
         self.0.exit()
     }
+
+    #[inline]
+    unsafe fn suspend(&mut self) -> Result<(), Error> {
+        self.0.suspend()
+    }
+
+    #[inline]
+    unsafe fn resume(&mut self) {
+        self.0.resume()
+    }
 }
 
 impl<T> BlockDriver<T, ReadOnly> {
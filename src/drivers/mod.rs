@@ -1,3 +1,10 @@
 pub mod pic;
 pub mod pit;
 pub mod driver;
+pub mod block;
+pub mod ramdisk;
+pub mod registry;
+pub mod pci;
+pub mod rtc;
+pub mod dma;
+pub mod ps2;
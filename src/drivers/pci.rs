@@ -0,0 +1,211 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use crate::arch::without_interrupts;
+use crate::{cmdline, log_warn, memory};
+
+// Config space is accessed either through the legacy ports 0xcf8/0xcfc, which only reach the
+// first 256 bytes of every function and need a lock as selecting the address and accessing the
+// data are two steps, or through the memory mapped ECAM region. ECAM accesses are single loads
+// and stores, so once a bus is mapped they need no lock and can be used from irq context,
+// e.g. to mask msi vectors.
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// The size of the config space of a function when accessed through ECAM
+pub const CONFIG_SPACE_SIZE: u16 = 4096;
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 256;
+/// Each bus takes 32 devices * 8 functions * 4KiB in the ECAM region
+const BUS_SIZE: u64 = 1 << 20;
+/// The virtual memory the ECAM region gets mapped at, bus by bus as they're accessed. It's in the
+/// kernel half which nothing else maps into, the user half belongs to the programs' segments.
+const ECAM_WINDOW_START: u64 = 0x_ffff_c000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// The ECAM region as described by the MCFG table
+#[derive(Debug, Clone, Copy)]
+pub struct Ecam {
+    pub base: PhysAddr,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+static ECAM_BASE: AtomicU64 = AtomicU64::new(0);
+/// start_bus | end_bus << 8, only valid if the base isn't 0
+static ECAM_BUSES: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const UNMAPPED: AtomicBool = AtomicBool::new(false);
+static BUS_MAPPED: [AtomicBool; 256] = [UNMAPPED; 256];
+static MAP_LOCK: Mutex<()> = Mutex::new(());
+static PORT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Uses the ECAM region from `pci.ecam=<hex base>[,<start bus>-<end bus>]` if it was given.
+// FIXME: Take the region from the MCFG table once we parse the acpi tables
+pub fn init() {
+    let ecam = match cmdline::get("pci.ecam") {
+        Some(ecam) => ecam,
+        None => return,
+    };
+    let mut parts = ecam.split(',');
+    let base = parts.next()
+        .and_then(|base| u64::from_str_radix(base.trim_start_matches("0x"), 16).ok());
+    let buses = parts.next().map_or(Some((0, 255)), |buses| {
+        let (start, end) = buses.split_once('-')?;
+        Some((start.parse::<u8>().ok()?, end.parse::<u8>().ok()?))
+    });
+    match (base, buses) {
+        (Some(base), Some((start_bus, end_bus))) if base != 0 && start_bus <= end_bus => set_ecam(Ecam {
+            base: PhysAddr::new(base),
+            start_bus,
+            end_bus,
+        }),
+        _ => log_warn!("pci: ignoring invalid pci.ecam={}", ecam),
+    }
+}
+
+pub fn set_ecam(ecam: Ecam) {
+    ECAM_BUSES.store(ecam.start_bus as u64 | ((ecam.end_bus as u64) << 8), Ordering::SeqCst);
+    ECAM_BASE.store(ecam.base.as_u64(), Ordering::SeqCst);
+}
+
+pub fn ecam() -> Option<Ecam> {
+    let base = ECAM_BASE.load(Ordering::SeqCst);
+    if base == 0 {
+        return None;
+    }
+    let buses = ECAM_BUSES.load(Ordering::SeqCst);
+    Some(Ecam {
+        base: PhysAddr::new(base),
+        start_bus: buses as u8,
+        end_bus: (buses >> 8) as u8,
+    })
+}
+
+/// Returns the virtual address of the config space of `addr` if it's reachable through ECAM,
+/// the bus gets mapped on its first access, this must not happen in irq context.
+fn ecam_address(addr: PciAddress) -> Option<VirtAddr> {
+    let ecam = ecam()?;
+    if addr.bus < ecam.start_bus || addr.bus > ecam.end_bus {
+        return None;
+    }
+    let bus_window = ECAM_WINDOW_START + addr.bus as u64 * BUS_SIZE;
+    if !BUS_MAPPED[addr.bus as usize].load(Ordering::Acquire) {
+        map_bus(&ecam, addr.bus, bus_window)?;
+    }
+    let function_offset = ((addr.device as u64) << 15) | ((addr.function as u64) << 12);
+    Some(VirtAddr::new(bus_window + function_offset))
+}
+
+#[cold]
+fn map_bus(ecam: &Ecam, bus: u8, window: u64) -> Option<()> {
+    let _guard = MAP_LOCK.lock();
+    // someone else could have mapped it while we waited
+    if BUS_MAPPED[bus as usize].load(Ordering::Acquire) {
+        return Some(());
+    }
+    let bus_base = ecam.base.as_u64() + (bus - ecam.start_bus) as u64 * BUS_SIZE;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+    for offset in (0..BUS_SIZE).step_by(4096) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(window + offset));
+        let frame = PhysFrame::containing_address(PhysAddr::new(bus_base + offset));
+        if memory::map_page(page, frame, flags).is_err() {
+            log_warn!("pci: failed to map the config space of bus {}", bus);
+            // the next access retries from scratch, which would run into the pages mapped so far
+            for mapped in (0..offset).step_by(4096) {
+                memory::unmap_page(Page::containing_address(VirtAddr::new(window + mapped)));
+            }
+            return None;
+        }
+    }
+    BUS_MAPPED[bus as usize].store(true, Ordering::Release);
+    Some(())
+}
+
+fn port_address(addr: PciAddress, offset: u16) -> u32 {
+    (1 << 31) | ((addr.bus as u32) << 16) | ((addr.device as u32) << 11)
+        | ((addr.function as u32) << 8) | (offset as u32 & 0xfc)
+}
+
+/// Reads the dword at the 4 byte aligned `offset` in the config space of `addr`, reads of
+/// registers which don't exist return all ones like the hardware does.
+pub fn read_u32(addr: PciAddress, offset: u16) -> u32 {
+    let offset = offset & !3;
+    if let Some(base) = ecam_address(addr) {
+        if offset >= CONFIG_SPACE_SIZE {
+            return u32::MAX;
+        }
+        return unsafe { core::ptr::read_volatile((base + offset as u64).as_ptr::<u32>()) };
+    }
+    if offset >= LEGACY_CONFIG_SPACE_SIZE {
+        return u32::MAX;
+    }
+    without_interrupts(|| {
+        let _guard = PORT_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(port_address(addr, offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    })
+}
+
+pub fn write_u32(addr: PciAddress, offset: u16, value: u32) {
+    let offset = offset & !3;
+    if let Some(base) = ecam_address(addr) {
+        if offset < CONFIG_SPACE_SIZE {
+            unsafe { core::ptr::write_volatile((base + offset as u64).as_mut_ptr::<u32>(), value); }
+        }
+        return;
+    }
+    if offset >= LEGACY_CONFIG_SPACE_SIZE {
+        return;
+    }
+    without_interrupts(|| {
+        let _guard = PORT_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(port_address(addr, offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    })
+}
+
+pub fn read_u16(addr: PciAddress, offset: u16) -> u16 {
+    (read_u32(addr, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(addr: PciAddress, offset: u16) -> u8 {
+    (read_u32(addr, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Calls `f` with every function present on the buses, this also maps all ECAM buses so later
+/// accesses to the found functions are lock-free.
+pub fn for_each_function(mut f: impl FnMut(PciAddress, u16, u16)) {
+    for bus in 0..=255_u8 {
+        for device in 0..32_u8 {
+            for function in 0..8_u8 {
+                let addr = PciAddress { bus, device, function };
+                let id = read_u32(addr, 0);
+                let vendor = id as u16;
+                if vendor == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                f(addr, vendor, (id >> 16) as u16);
+                // bit 7 of the header type tells whether the device has more than one function
+                if function == 0 && read_u8(addr, 0xe) & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
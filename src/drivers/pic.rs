@@ -1,4 +1,5 @@
 use core::arch::asm;
+use x86_64::instructions::port::Port;
 
 pub unsafe fn disable() {
     asm!(
@@ -7,4 +8,16 @@ pub unsafe fn disable() {
     "out 0x21, al",
     out("eax") _ // we can only use eax (which includes al)
     );
-}
\ No newline at end of file
+}
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_DATA: u16 = 0xa1;
+
+/// Returns the interrupt masks of the master and the slave pic, a set bit masks the line.
+pub unsafe fn read_masks() -> [u8; 2] {
+    [Port::<u8>::new(MASTER_DATA).read(), Port::<u8>::new(SLAVE_DATA).read()]
+}
+
+pub unsafe fn write_masks(masks: [u8; 2]) {
+    Port::<u8>::new(MASTER_DATA).write(masks[0]);
+    Port::<u8>::new(SLAVE_DATA).write(masks[1]);
+}
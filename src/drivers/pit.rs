@@ -100,7 +100,9 @@ pub fn init() {
     set_frequency(PIT_FREQUENCY_HZ);
 }
 
-const PIT_FREQUENCY_HZ: usize = 1000;
+pub const PIT_FREQUENCY_HZ: usize = 1000;
+/// The time between two interrupts of channel 0 in microseconds
+pub const TICK_US: usize = 1000000 / PIT_FREQUENCY_HZ;
 pub const PIT_DIVIDEND: usize = 1193182;
 
 fn set_frequency(frequency: usize) {
@@ -119,4 +121,35 @@ pub fn write_channel0_count(count: u16) {
     set_pit_count(count)
 }
 
+const SPEAKER_PORT: u16 = 0x61;
+const CHANNEL2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL2_OUT: u8 = 1 << 5;
+
+/// Measures the frequency of the time stamp counter in hz by letting channel 2
+/// count down for 10ms, this doesn't need interrupts and leaves channel 0 alone.
+pub fn calibrate_tsc() -> u64 {
+    const CALIBRATION_HZ: usize = 100; // 10ms
+    without_interrupts(|| {
+        let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+        let mut channel2: Port<u8> = Port::new(CHANNEL2);
+        let count = PIT_DIVIDEND / CALIBRATION_HZ;
+        unsafe {
+            // keep the speaker silent while we use its channel
+            let val = speaker.read() & !(SPEAKER_ENABLE | CHANNEL2_GATE);
+            speaker.write(val);
+            write_mode(Channel::Channel2, AccessMode::LoHiByte, OperatingMode::InterruptOnTerminalCount, DataMode::Binary);
+            channel2.write((count & 0xff) as u8);
+            channel2.write(((count >> 8) & 0xff) as u8);
+            // raising the gate starts the countdown
+            speaker.write(val | CHANNEL2_GATE);
+            let start = core::arch::x86_64::_rdtsc();
+            while speaker.read() & CHANNEL2_OUT == 0 {}
+            let end = core::arch::x86_64::_rdtsc();
+            speaker.write(val);
+            (end - start) * CALIBRATION_HZ as u64
+        }
+    })
+}
+
 // FIXME: Finish this implementation with the help from: https://wiki.osdev.org/Programmable_Interval_Timer
@@ -0,0 +1,362 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::{log_info, log_warn, workqueue};
+
+// The 8042 ps/2 controller, usually with a keyboard on the first and a mouse on the second port.
+// Both ports are disabled while the controller tests itself and its ports, afterwards the devices
+// get reset and identified (see https://wiki.osdev.org/%228042%22_PS/2_Controller). Ports which
+// fail any of this stay disabled, so the interrupt handlers never read from a controller or a
+// device which isn't there. The keyboard handler decodes scancode set 1, so the controller has to
+// translate the set 2 scancodes sent by the keyboard.
+// A mouse which gets plugged in announces itself with 0xaa 0x00, so the second port keeps its
+// interrupt enabled even if it's empty and gets probed again once that sequence arrives.
+// FIXME: Decode the mouse packets once there is an input layer to hand them to
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+/// The byte in the output buffer was sent by the device on the second port
+const STATUS_AUX_DATA: u8 = 0x20;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xa7;
+const CMD_ENABLE_PORT2: u8 = 0xa8;
+const CMD_TEST_PORT2: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_PORT1: u8 = 0xab;
+const CMD_DISABLE_PORT1: u8 = 0xad;
+const CMD_ENABLE_PORT1: u8 = 0xae;
+const CMD_WRITE_PORT2: u8 = 0xd4;
+
+const CONFIG_PORT1_IRQ: u8 = 0x01;
+const CONFIG_PORT2_IRQ: u8 = 0x02;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 0x20;
+const CONFIG_TRANSLATION: u8 = 0x40;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEV_IDENTIFY: u8 = 0xf2;
+const DEV_ENABLE_SCANNING: u8 = 0xf4;
+const DEV_DISABLE_SCANNING: u8 = 0xf5;
+const DEV_RESET: u8 = 0xff;
+const DEV_ACK: u8 = 0xfa;
+const DEV_RESEND: u8 = 0xfe;
+const DEV_SELF_TEST_PASSED: u8 = 0xaa;
+const MOUSE_ID: u8 = 0x00;
+const KEYBOARD_ID: u8 = 0xab;
+
+/// How often the status register gets polled before giving up, every read takes about a microsecond
+const POLL_LIMIT: usize = 100_000;
+/// Devices run their self test when being reset, which takes a lot longer
+const RESET_POLL_LIMIT: usize = 1_000_000;
+const COMMAND_RETRIES: usize = 3;
+
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MOUSE_IRQ: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    First = 0,
+    Second = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeviceKind {
+    /// The port doesn't exist, failed its test or has nothing plugged in
+    None = 0,
+    Keyboard = 1,
+    Mouse = 2,
+    WheelMouse = 3,
+    FiveButtonMouse = 4,
+    Unknown = 5,
+}
+
+impl DeviceKind {
+    fn from_u8(kind: u8) -> Self {
+        match kind {
+            1 => DeviceKind::Keyboard,
+            2 => DeviceKind::Mouse,
+            3 => DeviceKind::WheelMouse,
+            4 => DeviceKind::FiveButtonMouse,
+            5 => DeviceKind::Unknown,
+            _ => DeviceKind::None,
+        }
+    }
+}
+
+/// Serializes the command sequences sent to the controller
+static CONTROLLER: Mutex<()> = Mutex::new(());
+static DEVICES: [AtomicU8; 2] = [AtomicU8::new(DeviceKind::None as u8), AtomicU8::new(DeviceKind::None as u8)];
+/// The last byte the second port sent
+static LAST_AUX_BYTE: AtomicU8 = AtomicU8::new(0);
+
+/// The device plugged into the port
+pub fn device(channel: Channel) -> DeviceKind {
+    DeviceKind::from_u8(DEVICES[channel as usize].load(Ordering::Acquire))
+}
+
+fn set_device(channel: Channel, kind: DeviceKind) {
+    DEVICES[channel as usize].store(kind as u8, Ordering::Release);
+}
+
+pub fn has_keyboard() -> bool {
+    device(Channel::First) == DeviceKind::Keyboard
+}
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS).read() }
+}
+
+fn wait_input_empty() -> Result<(), Error> {
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err(Error::ETIMEDOUT)
+}
+
+fn send_command(command: u8) -> Result<(), Error> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(COMMAND).write(command); }
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Error> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(DATA).write(data); }
+    Ok(())
+}
+
+/// Waits for a byte from the controller itself.
+fn read_data() -> Result<u8, Error> {
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { Port::<u8>::new(DATA).read() });
+        }
+        spin_loop();
+    }
+    Err(Error::ETIMEDOUT)
+}
+
+/// Waits for a byte from the device on the given port. Bytes of the first port are dropped while
+/// waiting for the first one, as its interrupt is disabled then. While waiting for the second
+/// port they are left to the keyboard interrupt.
+fn read_response(channel: Channel, limit: usize) -> Result<u8, Error> {
+    for _ in 0..limit {
+        let status = status();
+        if status & STATUS_OUTPUT_FULL != 0 {
+            let aux = status & STATUS_AUX_DATA != 0;
+            if aux == (channel == Channel::Second) {
+                return Ok(unsafe { Port::<u8>::new(DATA).read() });
+            }
+            if channel == Channel::First {
+                unsafe { Port::<u8>::new(DATA).read(); }
+            }
+        }
+        spin_loop();
+    }
+    Err(Error::ETIMEDOUT)
+}
+
+/// Drops whatever is left in the output buffer.
+fn flush() {
+    for _ in 0..16 {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        unsafe { Port::<u8>::new(DATA).read(); }
+    }
+}
+
+fn read_config() -> Result<u8, Error> {
+    send_command(CMD_READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), Error> {
+    send_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+fn test_port(command: u8) -> bool {
+    send_command(command).and_then(|_| read_data()) == Ok(PORT_TEST_PASSED)
+}
+
+/// Sends a command to the device on the given port and waits for it to be acknowledged.
+fn device_command(channel: Channel, command: u8) -> Result<(), Error> {
+    for _ in 0..COMMAND_RETRIES {
+        if channel == Channel::Second {
+            send_command(CMD_WRITE_PORT2)?;
+        }
+        write_data(command)?;
+        match read_response(channel, POLL_LIMIT)? {
+            DEV_ACK => return Ok(()),
+            DEV_RESEND => continue,
+            _ => return Err(Error::EIO),
+        }
+    }
+    Err(Error::EIO)
+}
+
+fn reset(channel: Channel) -> Result<(), Error> {
+    device_command(channel, DEV_RESET)?;
+    if read_response(channel, RESET_POLL_LIMIT)? != DEV_SELF_TEST_PASSED {
+        return Err(Error::EIO);
+    }
+    // mice send their id after the result of the self test
+    if channel == Channel::Second {
+        let _ = read_response(channel, POLL_LIMIT);
+    }
+    Ok(())
+}
+
+fn identify(channel: Channel) -> Result<DeviceKind, Error> {
+    device_command(channel, DEV_DISABLE_SCANNING)?;
+    device_command(channel, DEV_IDENTIFY)?;
+    let kind = match read_response(channel, POLL_LIMIT) {
+        // ancient AT keyboards don't send an id
+        Err(_) => DeviceKind::Keyboard,
+        Ok(MOUSE_ID) => DeviceKind::Mouse,
+        Ok(0x03) => DeviceKind::WheelMouse,
+        Ok(0x04) => DeviceKind::FiveButtonMouse,
+        Ok(KEYBOARD_ID) => {
+            // the second byte depends on the keyboard and on whether translation is enabled
+            let _ = read_response(channel, POLL_LIMIT);
+            DeviceKind::Keyboard
+        },
+        Ok(_) => DeviceKind::Unknown,
+    };
+    Ok(kind)
+}
+
+/// Resets and identifies the device on the port and lets it send data afterwards.
+fn probe(channel: Channel) -> DeviceKind {
+    flush();
+    let result = reset(channel)
+        .and_then(|_| identify(channel))
+        .and_then(|kind| device_command(channel, DEV_ENABLE_SCANNING).map(|_| kind));
+    result.unwrap_or(DeviceKind::None)
+}
+
+fn init_controller() -> Result<(), Error> {
+    // without a controller nothing drives the bus
+    if status() == 0xff {
+        return Err(Error::ENODEV);
+    }
+    send_command(CMD_DISABLE_PORT1)?;
+    send_command(CMD_DISABLE_PORT2)?;
+    flush();
+    let config = read_config()? & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_TRANSLATION);
+    write_config(config)?;
+    send_command(CMD_SELF_TEST)?;
+    if read_data()? != SELF_TEST_PASSED {
+        return Err(Error::EIO);
+    }
+    // some controllers reset themselves during the self test
+    write_config(config)?;
+    // enabling the second port only enables its clock if there is one
+    send_command(CMD_ENABLE_PORT2)?;
+    let dual_channel = read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+    send_command(CMD_DISABLE_PORT2)?;
+
+    let first = test_port(CMD_TEST_PORT1);
+    let second = dual_channel && test_port(CMD_TEST_PORT2);
+    if !first {
+        log_warn!("ps/2: the first port failed its test");
+    }
+    if first {
+        send_command(CMD_ENABLE_PORT1)?;
+        set_device(Channel::First, probe(Channel::First));
+    }
+    if second {
+        send_command(CMD_ENABLE_PORT2)?;
+        set_device(Channel::Second, probe(Channel::Second));
+    }
+    let mut config = read_config()?;
+    if first {
+        config |= CONFIG_PORT1_IRQ | CONFIG_TRANSLATION;
+    }
+    if second {
+        config |= CONFIG_PORT2_IRQ;
+    }
+    write_config(config)?;
+    log_info!("ps/2: {} channel controller, {:?} on the first port, {:?} on the second port",
+              if dual_channel { "dual" } else { "single" }, device(Channel::First), device(Channel::Second));
+    Ok(())
+}
+
+/// Initializes the controller and detects the devices, this has to be called before the
+/// keyboard and mouse interrupts get enabled.
+pub fn init() {
+    let result = without_interrupts(|| {
+        let _guard = CONTROLLER.lock();
+        init_controller()
+    });
+    if let Err(err) = result {
+        log_warn!("ps/2: no usable controller ({}), continuing without keyboard and mouse", err);
+    }
+    if !has_keyboard() {
+        log_warn!("ps/2: no keyboard found");
+    }
+}
+
+/// Reads the byte which raised the keyboard interrupt, returns `None` if there is no keyboard
+/// or the byte isn't meant for it.
+pub fn read_keyboard_byte() -> Option<u8> {
+    if !has_keyboard() || status() & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) != STATUS_OUTPUT_FULL {
+        return None;
+    }
+    Some(unsafe { Port::<u8>::new(DATA).read() })
+}
+
+/// Gets called from the mouse interrupt.
+pub fn handle_aux_interrupt() {
+    if status() & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) != STATUS_OUTPUT_FULL | STATUS_AUX_DATA {
+        return;
+    }
+    let byte = unsafe { Port::<u8>::new(DATA).read() };
+    let previous = LAST_AUX_BYTE.swap(byte, Ordering::Relaxed);
+    // a present mouse could send the same bytes as part of a packet
+    if previous == DEV_SELF_TEST_PASSED && byte == MOUSE_ID && device(Channel::Second) == DeviceKind::None {
+        // the probe gets dropped if the worker is far behind, replugging the mouse retries it
+        let _ = workqueue::queue_irq(probe_second_port);
+    }
+}
+
+/// Probes the second port again after something was plugged in.
+fn probe_second_port() {
+    let _guard = CONTROLLER.lock();
+    let config = match without_interrupts(read_config) {
+        Ok(config) => config,
+        Err(err) => {
+            log_warn!("ps/2: probing the second port failed: {}", err);
+            return;
+        },
+    };
+    let keyboard_enabled = config & CONFIG_PORT1_IRQ != 0;
+    // the keyboard buffers its input while its port is disabled, the responses of the mouse
+    // would be taken by the mouse interrupt
+    let result = send_command(CMD_DISABLE_PORT1)
+        .and_then(|_| write_config(config & !CONFIG_PORT2_IRQ))
+        .map(|_| probe(Channel::Second));
+    let restored = write_config(config).and_then(|_| if keyboard_enabled { send_command(CMD_ENABLE_PORT1) } else { Ok(()) });
+    match result.and_then(|kind| restored.map(|_| kind)) {
+        Ok(kind) => {
+            set_device(Channel::Second, kind);
+            log_info!("ps/2: {:?} plugged into the second port", kind);
+        },
+        Err(err) => log_warn!("ps/2: probing the second port failed: {}", err),
+    }
+}
@@ -0,0 +1,211 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use crate::cmdline;
+use crate::drivers::block;
+use crate::drivers::driver::{BlockDriverImpl, DeviceIdentity, Driver, HealthInfo};
+use crate::drivers::registry::{self, DeviceKind, DriverEntry};
+use crate::error_codes::Error;
+
+pub const BLOCK_SIZE: usize = 512;
+const DEFAULT_SIZE: usize = 128 * 1024;
+
+/// Registers the driver which creates `ram0`, its size in KiB can be set with `ramdisk.size=`.
+pub fn register() {
+    registry::register(DriverEntry {
+        name: "ramdisk",
+        detect: || true,
+        probe: || {
+            let size = match cmdline::get("ramdisk.size") {
+                Some(size) => size.parse::<usize>().map_err(|_| Error::EINVAL)? * 1024,
+                None => DEFAULT_SIZE,
+            };
+            if size < BLOCK_SIZE {
+                return Err(Error::EINVAL);
+            }
+            let id = registry::alloc_device_id("ramdisk", DeviceKind::Block);
+            block::register(format!("ram{}", id.minor), id, Box::new(RamDisk::new(size)));
+            Ok(())
+        },
+    });
+}
+
+/// Registers a ram disk of `size` bytes whose faults can be controlled through the returned
+/// handle, tests use this to run filesystems without touching `ram0`. The device should be
+/// removed with `block::unregister` afterwards.
+pub fn create_test_disk(size: usize) -> (String, Arc<Faults>) {
+    let faults = Arc::new(Faults::new());
+    let id = registry::alloc_device_id("ramdisk", DeviceKind::Block);
+    let name = format!("ram{}", id.minor);
+    block::register(name.clone(), id, Box::new(RamDisk::with_faults(size, faults.clone())));
+    (name, faults)
+}
+
+/// A block device backed by kernel heap memory, its contents are lost on reboot.
+pub struct RamDisk {
+    data: Vec<u8>,
+    // the block the next unindexed read or write operates on
+    position: usize,
+    faults: Option<Arc<Faults>>,
+}
+
+/// Makes a ram disk fail like real hardware, so tests can check how filesystems handle errors.
+/// Each kind of fault triggers on the n-th access after it was armed and then disarms itself.
+pub struct Faults {
+    read_error: AtomicU64,
+    short_read: AtomicU64,
+    write_error: AtomicU64,
+}
+
+const DISARMED: u64 = u64::MAX;
+
+impl Faults {
+
+    pub fn new() -> Self {
+        Self {
+            read_error: AtomicU64::new(DISARMED),
+            short_read: AtomicU64::new(DISARMED),
+            write_error: AtomicU64::new(DISARMED),
+        }
+    }
+
+    /// The read after `reads` successful ones returns nothing
+    pub fn fail_read_after(&self, reads: u64) {
+        self.read_error.store(reads, Ordering::SeqCst);
+    }
+
+    /// The read after `reads` successful ones only returns half a block
+    pub fn short_read_after(&self, reads: u64) {
+        self.short_read.store(reads, Ordering::SeqCst);
+    }
+
+    /// The write after `writes` successful ones fails without modifying the disk
+    pub fn fail_write_after(&self, writes: u64) {
+        self.write_error.store(writes, Ordering::SeqCst);
+    }
+
+    pub fn clear(&self) {
+        self.read_error.store(DISARMED, Ordering::SeqCst);
+        self.short_read.store(DISARMED, Ordering::SeqCst);
+        self.write_error.store(DISARMED, Ordering::SeqCst);
+    }
+
+    /// Counts down the fault, returns true if it triggers now.
+    fn hit(counter: &AtomicU64) -> bool {
+        counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| match left {
+            DISARMED => None,
+            0 => Some(DISARMED),
+            left => Some(left - 1),
+        }) == Ok(0)
+    }
+
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RamDisk {
+
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size / BLOCK_SIZE * BLOCK_SIZE],
+            position: 0,
+            faults: None,
+        }
+    }
+
+    pub fn with_faults(size: usize, faults: Arc<Faults>) -> Self {
+        Self {
+            faults: Some(faults),
+            ..Self::new(size)
+        }
+    }
+
+    fn block_count(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+
+}
+
+unsafe impl Driver for RamDisk {
+    unsafe fn init(&mut self, _idt: &mut InterruptDescriptorTable) -> bool {
+        true
+    }
+
+    unsafe fn exit(&mut self) {}
+}
+
+unsafe impl BlockDriverImpl<u8> for RamDisk {
+    unsafe fn write_block(&mut self, block: &[u8]) {
+        self.write_block_indexed(self.position, block);
+        self.position += 1;
+    }
+
+    unsafe fn write_block_indexed(&mut self, index: usize, block: &[u8]) {
+        if index >= self.block_count() {
+            return;
+        }
+        let start = index * BLOCK_SIZE;
+        let len = block.len().min(BLOCK_SIZE);
+        self.data[start..start + len].copy_from_slice(&block[..len]);
+    }
+
+    unsafe fn read_block(&mut self, block_size: usize) -> Box<[u8]> {
+        let ret = self.read_block_indexed(self.position, block_size);
+        self.position += 1;
+        ret
+    }
+
+    unsafe fn read_block_indexed(&mut self, index: usize, block_size: usize) -> Box<[u8]> {
+        if index >= self.block_count() {
+            return Box::new([]);
+        }
+        let start = index * BLOCK_SIZE;
+        let mut len = block_size.min(BLOCK_SIZE);
+        if let Some(faults) = &self.faults {
+            if Faults::hit(&faults.read_error) {
+                return Box::new([]);
+            }
+            if Faults::hit(&faults.short_read) {
+                len /= 2;
+            }
+        }
+        Box::from(&self.data[start..start + len])
+    }
+
+    unsafe fn try_write_block_indexed(&mut self, index: usize, block: &[u8]) -> Result<(), Error> {
+        if index >= self.block_count() {
+            return Err(Error::EIO);
+        }
+        if self.faults.as_ref().map_or(false, |faults| Faults::hit(&faults.write_error)) {
+            return Err(Error::EIO);
+        }
+        self.write_block_indexed(index, block);
+        Ok(())
+    }
+
+    unsafe fn identify(&mut self) -> Option<DeviceIdentity> {
+        Some(DeviceIdentity {
+            model: String::from("LeafOS RAM disk"),
+            serial: String::from("0"),
+            firmware: String::from(env!("CARGO_PKG_VERSION")),
+            block_size: BLOCK_SIZE,
+            block_count: self.block_count() as u64,
+        })
+    }
+
+    unsafe fn health(&mut self) -> Option<HealthInfo> {
+        Some(HealthInfo {
+            healthy: true,
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,151 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use leafos_abi::fs::makedev;
+use crate::cmdline;
+use crate::error_codes::Error;
+use crate::{log_info, log_warn};
+
+// Drivers register themselves here and get probed in registration order.
+// Probing can be controlled from the kernel command line:
+//  driver.blacklist=a,b     never probe the drivers a and b
+//  driver.force_probe=a,b   probe a and b even if they didn't detect any supported hardware
+
+// Every device gets an id consisting of a major number identifying its driver and a minor number
+// identifying the device within that driver. Drivers listed in `STATIC_MAJORS` always get the same
+// major, all others are assigned one from `DYNAMIC_MAJOR_START` on in the order they register devices.
+
+lazy_static! {
+    static ref DRIVERS: Mutex<Vec<DriverEntry>> = Mutex::new(vec![]);
+    static ref MAJORS: Mutex<Vec<MajorEntry>> = Mutex::new(vec![]);
+}
+
+static STATIC_MAJORS: &[(&str, DeviceKind, u32)] = &[
+    ("ramdisk", DeviceKind::Block, 1),
+];
+
+const DYNAMIC_MAJOR_START: u32 = 240;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Block,
+    Char,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceId {
+
+    /// The id as seen by userspace
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        makedev(self.major, self.minor)
+    }
+
+}
+
+struct MajorEntry {
+    driver: &'static str,
+    kind: DeviceKind,
+    major: u32,
+    next_minor: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    Bound,
+    NoDevice,
+    Blacklisted,
+    Failed(Error),
+}
+
+pub struct DriverEntry {
+    pub name: &'static str,
+    /// Checks whether hardware this driver supports is present
+    pub detect: fn() -> bool,
+    /// Initializes the driver and registers its devices
+    pub probe: fn() -> Result<(), Error>,
+}
+
+/// The name, detect and probe function of a driver
+type ProbeEntry = (&'static str, fn() -> bool, fn() -> Result<(), Error>);
+
+pub fn register(entry: DriverEntry) {
+    DRIVERS.lock().push(entry);
+}
+
+fn is_listed(list: &[String], name: &str) -> bool {
+    list.iter().any(|item| item == name)
+}
+
+/// Probes all registered drivers and returns the result for every driver.
+pub fn probe_all() -> Vec<(&'static str, ProbeResult)> {
+    let blacklist = cmdline::list("driver.blacklist");
+    let force_probe = cmdline::list("driver.force_probe");
+    // copy the entries, so drivers can use the registry while they are being probed
+    let drivers: Vec<ProbeEntry> = DRIVERS.lock().iter()
+        .map(|entry| (entry.name, entry.detect, entry.probe))
+        .collect();
+    let mut results = vec![];
+    for (name, detect, probe) in drivers {
+        let result = if is_listed(&blacklist, name) {
+            log_info!("driver {}: skipped (blacklisted)", name);
+            ProbeResult::Blacklisted
+        } else if !is_listed(&force_probe, name) && !detect() {
+            ProbeResult::NoDevice
+        } else {
+            match probe() {
+                Ok(()) => ProbeResult::Bound,
+                Err(err) => {
+                    log_warn!("driver {}: probe failed: {}", name, err);
+                    ProbeResult::Failed(err)
+                },
+            }
+        };
+        results.push((name, result));
+    }
+    results
+}
+
+/// Allocates the id for a new device of the given driver, minor numbers are handed out in order starting at 0.
+pub fn alloc_device_id(driver: &'static str, kind: DeviceKind) -> DeviceId {
+    let mut majors = MAJORS.lock();
+    let idx = match majors.iter().position(|entry| entry.driver == driver && entry.kind == kind) {
+        Some(idx) => idx,
+        None => {
+            let major = STATIC_MAJORS.iter()
+                .find(|(name, static_kind, _)| *name == driver && *static_kind == kind)
+                .map(|(_, _, major)| *major)
+                .unwrap_or_else(|| DYNAMIC_MAJOR_START + majors.iter()
+                    .filter(|entry| entry.kind == kind && entry.major >= DYNAMIC_MAJOR_START)
+                    .count() as u32);
+            majors.push(MajorEntry {
+                driver,
+                kind,
+                major,
+                next_minor: 0,
+            });
+            majors.len() - 1
+        },
+    };
+    let entry = &mut majors[idx];
+    let id = DeviceId {
+        major: entry.major,
+        minor: entry.next_minor,
+    };
+    entry.next_minor += 1;
+    id
+}
+
+/// Returns the name of the driver the major number belongs to.
+pub fn driver_of(kind: DeviceKind, major: u32) -> Option<&'static str> {
+    MAJORS.lock().iter()
+        .find(|entry| entry.kind == kind && entry.major == major)
+        .map(|entry| entry.driver)
+}
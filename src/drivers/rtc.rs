@@ -0,0 +1,209 @@
+use x86_64::instructions::port::Port;
+use crate::arch::without_interrupts;
+use crate::drivers::pic;
+
+// The cmos real time clock, it keeps the wallclock time while the machine is off and can raise
+// an interrupt on irq 8 once a given time of day is reached.
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Setting this bit in the address port keeps nmis disabled while we access the cmos
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_ALARM_INTERRUPT: u8 = 0x20;
+const STATUS_C_ALARM: u8 = 0x20;
+const HOUR_PM: u8 = 0x80;
+
+pub const IRQ: u8 = 8;
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+        let value = Port::<u8>::new(CMOS_DATA).read();
+        enable_nmi(register);
+        value
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+        Port::<u8>::new(CMOS_DATA).write(value);
+        enable_nmi(register);
+    }
+}
+
+/// The nmi mask is latched in the address port, so it has to be cleared again after every access.
+unsafe fn enable_nmi(register: u8) {
+    Port::<u8>::new(CMOS_ADDRESS).write(register);
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// A point in time in utc
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+
+    /// The seconds since the unix epoch
+    pub fn to_unix(&self) -> u64 {
+        // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64) as u64
+    }
+
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64 + 719468;
+        let secs_of_day = secs % 86400;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (year_of_era + era * 400 + (month <= 2) as i64) as u16;
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+}
+
+fn read_raw() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_register)
+}
+
+/// Reads the current time, the rtc is expected to run in utc.
+pub fn read_time() -> DateTime {
+    let (raw, status_b) = without_interrupts(|| {
+        // read until we get the same values twice, so we don't see an update halfway through
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+    let [mut second, mut minute, hour, mut day, mut month, mut year] = raw;
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = hour & !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 am is midnight
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    // FIXME: Use the century register from the FADT once we parse the acpi tables
+    DateTime {
+        year: 2000 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+/// Lets the rtc raise an interrupt the next time the time of day of `at` is reached, so alarms
+/// more than a day ahead fire early and have to be programmed again.
+pub fn set_alarm(at: DateTime) {
+    without_interrupts(|| {
+        let status_b = read_register(REG_STATUS_B);
+        let encode = |value: u8| if status_b & STATUS_B_BINARY == 0 { to_bcd(value) } else { value };
+        let hour = if status_b & STATUS_B_24_HOUR == 0 {
+            let pm = if at.hour >= 12 { HOUR_PM } else { 0 };
+            encode(if at.hour % 12 == 0 { 12 } else { at.hour % 12 }) | pm
+        } else {
+            encode(at.hour)
+        };
+        write_register(REG_SECONDS_ALARM, encode(at.second));
+        write_register(REG_MINUTES_ALARM, encode(at.minute));
+        write_register(REG_HOURS_ALARM, hour);
+        write_register(REG_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+        // the pending flags have to be cleared, otherwise the rtc won't raise the interrupt
+        read_register(REG_STATUS_C);
+        unmask_irq();
+    });
+}
+
+pub fn clear_alarm() {
+    without_interrupts(|| {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+    });
+}
+
+fn unmask_irq() {
+    unsafe {
+        let mut masks = pic::read_masks();
+        // the slave is connected to line 2 of the master
+        masks[0] &= !(1 << 2);
+        masks[1] &= !(1 << (IRQ - 8));
+        pic::write_masks(masks);
+    }
+}
+
+/// Acknowledges the interrupt, returns whether it was raised by the alarm.
+pub fn acknowledge_interrupt() -> bool {
+    read_register(REG_STATUS_C) & STATUS_C_ALARM != 0
+}
+
+#[test_case]
+fn test_unix_time() {
+    let time = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+    crate::kassert_eq!(time.to_unix(), 1709213862);
+    crate::kassert_eq!(DateTime::from_unix(1709213862), time);
+    crate::kassert_eq!(DateTime::from_unix(0), DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+}
@@ -0,0 +1,560 @@
+use alloc::string::String;
+use core::mem::size_of;
+use core::ptr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::VirtAddr;
+use crate::arch::x86::mem::{memcpy, page_zero};
+use crate::arch::x86::random_u64;
+use crate::memory::USER_SPACE_END;
+
+// https://refspecs.linuxfoundation.org/elf/elf.pdf
+// https://refspecs.linuxfoundation.org/elf/x86_64-abi-0.99.pdf
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_W: u32 = 1 << 1;
+
+const DT_NULL: i64 = 0;
+const DT_PLTRELSZ: i64 = 2;
+const DT_SYMTAB: i64 = 6;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_SYMENT: i64 = 11;
+const DT_PLTREL: i64 = 20;
+const DT_JMPREL: i64 = 23;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const SHN_UNDEF: u16 = 0;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The region position independent executables get loaded into, the actual
+/// base is randomized inside this region.
+const PIE_BASE: u64 = 0x5555_0000_0000;
+const PIE_RANDOM_PAGES: u64 = 1 << 20; // 4 GiB worth of possible load addresses
+/// Interpreters get loaded into their own region so they can't collide with the executable
+const INTERP_BASE: u64 = 0x7f00_0000_0000;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Header {
+    ident: [u8; 16],
+    e_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    ph_off: u64,
+    sh_off: u64,
+    flags: u32,
+    eh_size: u16,
+    ph_ent_size: u16,
+    ph_num: u16,
+    sh_ent_size: u16,
+    sh_num: u16,
+    sh_str_idx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ProgramHeader {
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Dyn {
+    tag: i64,
+    val: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub enum ElfError {
+    InvalidMagic,
+    UnsupportedFormat,
+    Truncated,
+    NoLoadableSegments,
+    BadAddress(u64),
+    UnsupportedRelocation(u32),
+    UnresolvedSymbol(u32),
+    /// The executable requests an interpreter which couldn't be found
+    MissingInterpreter(String),
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        Self::Map(err)
+    }
+}
+
+/// Describes an executable image which was mapped into the current address space.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedElf {
+    /// The address execution has to start at, this is the interpreter's entry if one is present
+    pub entry: u64,
+    /// The entry point of the executable itself (AT_ENTRY)
+    pub program_entry: u64,
+    /// The difference between the link time addresses and the load addresses
+    pub load_bias: u64,
+    /// The location of the program headers in memory (AT_PHDR)
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+    /// The base address of the interpreter (AT_BASE)
+    pub interp_base: Option<u64>,
+}
+
+fn read<T: Copy>(data: &[u8], offset: u64) -> Result<T, ElfError> {
+    let offset = offset as usize;
+    if offset.checked_add(size_of::<T>()).map_or(true, |end| end > data.len()) {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { ptr::read_unaligned(data.as_ptr().add(offset) as *const T) })
+}
+
+struct ParsedElf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> ParsedElf<'a> {
+
+    fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header: Header = read(data, 0)?;
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::InvalidMagic);
+        }
+        if header.ident[4] != ELF_CLASS_64 || header.ident[5] != ELF_DATA_LITTLE_ENDIAN ||
+            header.machine != EM_X86_64 || (header.e_type != ET_EXEC && header.e_type != ET_DYN) ||
+            header.ph_ent_size as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        Ok(Self {
+            data,
+            header,
+        })
+    }
+
+    fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> + '_ {
+        (0..self.header.ph_num as u64).map(move |idx| {
+            let offset = self.header.ph_off.checked_add(idx * size_of::<ProgramHeader>() as u64)
+                .ok_or(ElfError::Truncated)?;
+            read(self.data, offset)
+        })
+    }
+
+    fn interpreter(&self) -> Result<Option<String>, ElfError> {
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type == PT_INTERP {
+                let start = ph.offset as usize;
+                let end = start.checked_add(ph.file_size as usize).ok_or(ElfError::Truncated)?;
+                let raw = self.data.get(start..end).ok_or(ElfError::Truncated)?;
+                // the path is nul terminated
+                let raw = raw.split(|b| *b == 0).next().unwrap_or(raw);
+                return Ok(Some(String::from_utf8_lossy(raw).into_owned()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the page aligned range of link time addresses covered by the loadable segments.
+    fn load_range(&self) -> Result<(u64, u64), ElfError> {
+        let mut min = u64::MAX;
+        let mut max = 0;
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type == PT_LOAD {
+                min = min.min(ph.vaddr);
+                max = max.max(ph.vaddr.checked_add(ph.mem_size).ok_or(ElfError::BadAddress(ph.vaddr))?);
+            }
+        }
+        if min == u64::MAX {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        let max = max.checked_add(PAGE_SIZE - 1).ok_or(ElfError::BadAddress(max))?;
+        Ok((min & !(PAGE_SIZE - 1), max & !(PAGE_SIZE - 1)))
+    }
+
+    /// Returns the first and the last address a loadable segment occupies once loaded at `bias`.
+    /// Segments have to lie completely inside the user half of the address space.
+    fn segment_range(ph: &ProgramHeader, bias: u64) -> Result<(u64, u64), ElfError> {
+        if ph.file_size > ph.mem_size {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        let start = bias.wrapping_add(ph.vaddr);
+        let end = start.checked_add(ph.mem_size.max(1) - 1).ok_or(ElfError::BadAddress(start))?;
+        if end >= USER_SPACE_END {
+            return Err(ElfError::BadAddress(end));
+        }
+        Ok((start, end))
+    }
+
+    /// Returns the part of the file which gets copied into a loadable segment.
+    fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let end = ph.offset.checked_add(ph.file_size).ok_or(ElfError::Truncated)?;
+        self.data.get(ph.offset as usize..end as usize).ok_or(ElfError::Truncated)
+    }
+
+    /// Validates every loadable segment before anything gets mapped.
+    fn check_segments(&self, bias: u64) -> Result<(), ElfError> {
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type == PT_LOAD {
+                Self::segment_range(&ph, bias)?;
+                self.segment_data(&ph)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks the load bias, position dependent executables are always loaded at their link time addresses.
+    fn choose_bias(&self, region_base: u64) -> Result<u64, ElfError> {
+        if self.header.e_type == ET_EXEC {
+            return Ok(0);
+        }
+        let (min, _) = self.load_range()?;
+        let base = region_base + (random_u64() % PIE_RANDOM_PAGES) * PAGE_SIZE;
+        Ok(base.wrapping_sub(min))
+    }
+
+    fn map(&self, bias: u64, user: bool, mapper: &mut impl Mapper<Size4KiB>,
+           frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), ElfError> {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if user {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        self.check_segments(bias)?;
+        // the last page mapped for the previous segment, loadable segments are sorted by address
+        let mut last_mapped = None;
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type != PT_LOAD {
+                continue;
+            }
+            let (start, end) = Self::segment_range(&ph, bias)?;
+            let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+            let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(end));
+            for page in Page::range_inclusive(start_page, end_page) {
+                if let Ok(frame) = mapper.translate_page(page) {
+                    // segments may share a page, anything else which is mapped already mustn't be overwritten
+                    if last_mapped == Some(page) {
+                        continue;
+                    }
+                    return Err(MapToError::PageAlreadyMapped(frame).into());
+                }
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+                    page_zero(page.start_address().as_mut_ptr::<u8>());
+                }
+            }
+            last_mapped = Some(end_page);
+            let src = self.segment_data(&ph)?;
+            unsafe { memcpy(start as *mut u8, src.as_ptr(), src.len()); }
+        }
+        Ok(())
+    }
+
+    /// Write protects all segments which aren't writable, this has to happen after relocation.
+    fn protect(&self, bias: u64, user: bool, mapper: &mut impl Mapper<Size4KiB>) -> Result<(), ElfError> {
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type != PT_LOAD || ph.flags & PF_W != 0 {
+                continue;
+            }
+            let mut flags = PageTableFlags::PRESENT;
+            if user {
+                flags |= PageTableFlags::USER_ACCESSIBLE;
+            }
+            let (start, end) = Self::segment_range(&ph, bias)?;
+            // only protect pages which are completely covered by the segment, as they may be shared otherwise,
+            // segments end below USER_SPACE_END so none of this can overflow
+            let first = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            let last = if ph.mem_size == 0 { first } else { (end + 1) & !(PAGE_SIZE - 1) };
+            let mut addr = first;
+            while addr < last {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+                if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                    flush.flush();
+                }
+                addr += PAGE_SIZE;
+            }
+        }
+        Ok(())
+    }
+
+    fn dynamic_entries(&self, bias: u64) -> Result<Option<DynamicInfo>, ElfError> {
+        let dynamic = match self.program_headers().find(|ph| ph.as_ref().map_or(true, |ph| ph.p_type == PT_DYNAMIC)) {
+            Some(ph) => ph?,
+            None => return Ok(None),
+        };
+        let mut info = DynamicInfo::default();
+        let (min, max) = self.load_range()?;
+        let count = dynamic.mem_size / size_of::<Dyn>() as u64;
+        for idx in 0..count {
+            let addr = dynamic.vaddr.wrapping_add(idx * size_of::<Dyn>() as u64);
+            if addr < min || addr.checked_add(size_of::<Dyn>() as u64).map_or(true, |end| end > max) {
+                return Err(ElfError::BadAddress(addr));
+            }
+            let entry = unsafe { ptr::read_unaligned(bias.wrapping_add(addr) as *const Dyn) };
+            match entry.tag {
+                DT_NULL => break,
+                DT_RELA => info.rela = entry.val,
+                DT_RELASZ => info.rela_size = entry.val,
+                DT_RELAENT => info.rela_ent = entry.val,
+                DT_JMPREL => info.jmp_rel = entry.val,
+                DT_PLTRELSZ => info.plt_rel_size = entry.val,
+                DT_PLTREL => info.plt_rel = entry.val,
+                DT_SYMTAB => info.symtab = entry.val,
+                DT_SYMENT => info.syment = entry.val,
+                _ => {},
+            }
+        }
+        Ok(Some(info))
+    }
+
+    /// Processes the relocations of a static-pie (or any self contained) image.
+    fn relocate(&self, bias: u64) -> Result<(), ElfError> {
+        let info = match self.dynamic_entries(bias)? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        let (min, max) = self.load_range()?;
+        let check = |addr: u64, len: u64| -> Result<u64, ElfError> {
+            if addr < min || addr.checked_add(len).map_or(true, |end| end > max) {
+                return Err(ElfError::BadAddress(addr));
+            }
+            Ok(bias.wrapping_add(addr))
+        };
+        let rela_ent = if info.rela_ent == 0 { size_of::<Rela>() as u64 } else { info.rela_ent };
+        let syment = if info.syment == 0 { size_of::<Symbol>() as u64 } else { info.syment };
+        let tables = [(info.rela, info.rela_size), (info.jmp_rel, if info.plt_rel as i64 == DT_RELA || info.plt_rel == 0 { info.plt_rel_size } else { 0 })];
+        for (table, size) in tables {
+            if table == 0 || size == 0 {
+                continue;
+            }
+            for idx in 0..(size / rela_ent) {
+                let rela = unsafe { ptr::read_unaligned(check(table.wrapping_add(idx * rela_ent), size_of::<Rela>() as u64)? as *const Rela) };
+                let r_type = (rela.info & 0xffff_ffff) as u32;
+                let sym_idx = (rela.info >> 32) as u32;
+                let target = check(rela.offset, size_of::<u64>() as u64)? as *mut u64;
+                let symbol_value = || -> Result<u64, ElfError> {
+                    if info.symtab == 0 {
+                        return Err(ElfError::UnresolvedSymbol(sym_idx));
+                    }
+                    let sym = unsafe { ptr::read_unaligned(check(info.symtab.wrapping_add((sym_idx as u64).wrapping_mul(syment)), size_of::<Symbol>() as u64)? as *const Symbol) };
+                    // FIXME: resolve symbols against other objects once we load shared libraries ourselves
+                    if sym.shndx == SHN_UNDEF {
+                        return Err(ElfError::UnresolvedSymbol(sym_idx));
+                    }
+                    Ok(bias.wrapping_add(sym.value))
+                };
+                let value = match r_type {
+                    R_X86_64_NONE => continue,
+                    R_X86_64_RELATIVE => bias.wrapping_add(rela.addend as u64),
+                    R_X86_64_64 => symbol_value()?.wrapping_add(rela.addend as u64),
+                    R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => symbol_value()?,
+                    other => return Err(ElfError::UnsupportedRelocation(other)),
+                };
+                unsafe { ptr::write_unaligned(target, value); }
+            }
+        }
+        Ok(())
+    }
+
+    fn phdr_addr(&self, bias: u64) -> Result<u64, ElfError> {
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type == PT_PHDR {
+                return Ok(bias.wrapping_add(ph.vaddr));
+            }
+        }
+        // the program headers are usually part of the first loaded segment
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.p_type == PT_LOAD && ph.offset <= self.header.ph_off &&
+                ph.offset.checked_add(ph.file_size).map_or(false, |end| self.header.ph_off < end) {
+                return Ok(bias.wrapping_add(ph.vaddr).wrapping_add(self.header.ph_off - ph.offset));
+            }
+        }
+        Ok(0)
+    }
+
+}
+
+#[derive(Default)]
+struct DynamicInfo {
+    rela: u64,
+    rela_size: u64,
+    rela_ent: u64,
+    jmp_rel: u64,
+    plt_rel_size: u64,
+    plt_rel: u64,
+    symtab: u64,
+    syment: u64,
+}
+
+// FIXME: Only the tests call `load` yet. Tasks are still started from kernel functions and share
+//  the kernel's page tables, so exec can use it once processes get their own address spaces and
+//  programs can be read from the filesystem.
+/// Loads the given executable into the current address space.
+///
+/// Position independent executables get loaded at a randomized base. If the executable
+/// requests an interpreter it gets looked up via `find_interpreter` and loaded as well,
+/// in which case relocating the executable is left to the interpreter. Otherwise
+/// (static and static-pie executables) the relocations are processed here.
+pub fn load<'a>(data: &[u8], user: bool, find_interpreter: &dyn Fn(&str) -> Option<&'a [u8]>,
+                mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<LoadedElf, ElfError> {
+    let elf = ParsedElf::parse(data)?;
+    let bias = elf.choose_bias(PIE_BASE)?;
+    elf.map(bias, user, mapper, frame_allocator)?;
+
+    let program_entry = bias.wrapping_add(elf.header.entry);
+    let (entry, interp_base) = match elf.interpreter()? {
+        Some(path) => {
+            let interp_data = find_interpreter(&path).ok_or(ElfError::MissingInterpreter(path))?;
+            let interp = ParsedElf::parse(interp_data)?;
+            let interp_bias = interp.choose_bias(INTERP_BASE)?;
+            interp.map(interp_bias, user, mapper, frame_allocator)?;
+            // the interpreter has to be able to run before anything else is relocated
+            interp.relocate(interp_bias)?;
+            interp.protect(interp_bias, user, mapper)?;
+            let (interp_min, _) = interp.load_range()?;
+            (interp_bias.wrapping_add(interp.header.entry), Some(interp_bias.wrapping_add(interp_min)))
+        },
+        None => {
+            elf.relocate(bias)?;
+            (program_entry, None)
+        },
+    };
+    elf.protect(bias, user, mapper)?;
+
+    Ok(LoadedElf {
+        entry,
+        program_entry,
+        load_bias: bias,
+        phdr: elf.phdr_addr(bias)?,
+        phent: elf.header.ph_ent_size as u64,
+        phnum: elf.header.ph_num as u64,
+        interp_base,
+    })
+}
+
+/// Builds an executable with the given loadable segments, given as (offset, vaddr, file_size, mem_size).
+#[cfg(test)]
+fn test_image(segments: &[(u64, u64, u64, u64)]) -> alloc::vec::Vec<u8> {
+    let ph_off = size_of::<Header>();
+    let mut data = alloc::vec![0; ph_off + segments.len() * size_of::<ProgramHeader>() + PAGE_SIZE as usize];
+    let mut ident = [0; 16];
+    ident[0..4].copy_from_slice(&ELF_MAGIC);
+    ident[4] = ELF_CLASS_64;
+    ident[5] = ELF_DATA_LITTLE_ENDIAN;
+    let header = Header {
+        ident,
+        e_type: ET_EXEC,
+        machine: EM_X86_64,
+        version: 1,
+        entry: 0x40_0000,
+        ph_off: ph_off as u64,
+        sh_off: 0,
+        flags: 0,
+        eh_size: size_of::<Header>() as u16,
+        ph_ent_size: size_of::<ProgramHeader>() as u16,
+        ph_num: segments.len() as u16,
+        sh_ent_size: 0,
+        sh_num: 0,
+        sh_str_idx: 0,
+    };
+    unsafe { ptr::write_unaligned(data.as_mut_ptr() as *mut Header, header); }
+    for (idx, (offset, vaddr, file_size, mem_size)) in segments.iter().copied().enumerate() {
+        let ph = ProgramHeader { p_type: PT_LOAD, flags: 0, offset, vaddr, paddr: vaddr, file_size, mem_size, align: PAGE_SIZE };
+        unsafe { ptr::write_unaligned(data.as_mut_ptr().add(ph_off + idx * size_of::<ProgramHeader>()) as *mut ProgramHeader, ph); }
+    }
+    data
+}
+
+#[test_case]
+fn test_segments_in_user_half() {
+    let data = test_image(&[(0, 0x40_0000, 0x100, 0x2000)]);
+    let elf = ParsedElf::parse(&data).unwrap();
+    crate::kassert!(elf.check_segments(0).is_ok());
+    crate::kassert_eq!(elf.phdr_addr(0).unwrap(), 0x40_0000 + size_of::<Header>() as u64);
+
+    // the kernel half and segments reaching into it
+    let data = test_image(&[(0, 0xffff_8000_0000_0000, 0x100, 0x1000)]);
+    crate::kassert!(matches!(ParsedElf::parse(&data).unwrap().check_segments(0), Err(ElfError::BadAddress(_))));
+    let data = test_image(&[(0, USER_SPACE_END - 0x1000, 0x100, 0x2000)]);
+    crate::kassert!(matches!(ParsedElf::parse(&data).unwrap().check_segments(0), Err(ElfError::BadAddress(_))));
+    // a bias moving the segment out of the user half
+    let data = test_image(&[(0, 0x40_0000, 0x100, 0x1000)]);
+    crate::kassert!(matches!(ParsedElf::parse(&data).unwrap().check_segments(USER_SPACE_END), Err(ElfError::BadAddress(_))));
+}
+
+#[test_case]
+fn test_overflowing_segments() {
+    let data = test_image(&[(0, 0x40_0000, 0x100, u64::MAX)]);
+    let elf = ParsedElf::parse(&data).unwrap();
+    crate::kassert!(matches!(elf.check_segments(0), Err(ElfError::BadAddress(_))));
+    crate::kassert!(matches!(elf.load_range(), Err(ElfError::BadAddress(_))));
+
+    let data = test_image(&[(u64::MAX, 0x40_0000, 0x100, 0x1000)]);
+    let elf = ParsedElf::parse(&data).unwrap();
+    crate::kassert!(matches!(elf.check_segments(0), Err(ElfError::Truncated)));
+    crate::kassert_eq!(elf.phdr_addr(0).unwrap(), 0);
+
+    let data = test_image(&[(0, u64::MAX - 0x10, 0, 0x8)]);
+    crate::kassert!(matches!(ParsedElf::parse(&data).unwrap().load_range(), Err(ElfError::BadAddress(_))));
+
+    // more file contents than memory and contents beyond the end of the file
+    let data = test_image(&[(0, 0x40_0000, 0x2000, 0x1000)]);
+    crate::kassert!(matches!(ParsedElf::parse(&data).unwrap().check_segments(0), Err(ElfError::UnsupportedFormat)));
+    let data = test_image(&[(0, 0x40_0000, 0x10_0000, 0x10_0000)]);
+    crate::kassert!(matches!(ParsedElf::parse(&data).unwrap().check_segments(0), Err(ElfError::Truncated)));
+}
@@ -0,0 +1,84 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::cmdline;
+
+// Environment variables of processes. Processes inherit the environment of the process which
+// started them, processes started by the kernel get the default environment which can be
+// extended or overridden with `env.<NAME>=<value>` options on the command line.
+
+static DEFAULT_ENV: &[(&str, &str)] = &[
+    ("PATH", "/bin"),
+    ("TERM", "vga"),
+    ("HOME", "/"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    vars: Vec<(String, String)>,
+}
+
+impl Environment {
+
+    pub const fn new() -> Self {
+        Self {
+            vars: vec![],
+        }
+    }
+
+    /// The environment of processes which are started by the kernel
+    pub fn kernel_default() -> Self {
+        let mut env = Self::new();
+        for (name, value) in DEFAULT_ENV {
+            env.set(name, value);
+        }
+        for (name, value) in cmdline::with_prefix("env.") {
+            env.set(&name, &value);
+        }
+        env
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.iter().find(|(var, _)| var == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Sets the variable, names may neither be empty nor contain a `=`.
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0') {
+            return false;
+        }
+        match self.vars.iter_mut().find(|(var, _)| var == name) {
+            Some((_, old)) => *old = value.to_string(),
+            None => self.vars.push((name.to_string(), value.to_string())),
+        }
+        true
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.vars.retain(|(var, _)| var != name);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Returns the variables in the `NAME=value` form they are passed to programs in.
+    pub fn to_envp(&self) -> Vec<String> {
+        self.iter().map(|(name, value)| format!("{}={}", name, value)).collect()
+    }
+
+}
+
+#[test_case]
+fn test_environment() {
+    let mut env = Environment::new();
+    crate::kassert!(env.set("PATH", "/bin"));
+    crate::kassert!(env.set("TERM", "vga"));
+    crate::kassert!(env.set("PATH", "/usr/bin"));
+    crate::kassert!(!env.set("A=B", "c"));
+    crate::kassert_eq!(env.get("PATH"), Some("/usr/bin"));
+    env.unset("TERM");
+    crate::kassert_eq!(env.get("TERM"), None);
+    crate::kassert_eq!(env.to_envp(), vec![String::from("PATH=/usr/bin")]);
+}
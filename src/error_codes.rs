@@ -1,5 +1,68 @@
+use core::fmt;
+use leafos_abi::errno;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(usize)]
 pub enum Error {
-    EIO = 5,
-    ENOSYS = 38,
-}
\ No newline at end of file
+    EPERM = errno::EPERM,
+    ENOENT = errno::ENOENT,
+    ESRCH = errno::ESRCH,
+    EIO = errno::EIO,
+    E2BIG = errno::E2BIG,
+    EAGAIN = errno::EAGAIN,
+    ENOMEM = errno::ENOMEM,
+    EFAULT = errno::EFAULT,
+    EBUSY = errno::EBUSY,
+    EEXIST = errno::EEXIST,
+    ENODEV = errno::ENODEV,
+    ENOTDIR = errno::ENOTDIR,
+    EISDIR = errno::EISDIR,
+    EINVAL = errno::EINVAL,
+    EFBIG = errno::EFBIG,
+    ENOSPC = errno::ENOSPC,
+    EROFS = errno::EROFS,
+    ENAMETOOLONG = errno::ENAMETOOLONG,
+    ENOSYS = errno::ENOSYS,
+    ENOTEMPTY = errno::ENOTEMPTY,
+    ENODATA = errno::ENODATA,
+    ENOTSUP = errno::ENOTSUP,
+    ETIMEDOUT = errno::ETIMEDOUT,
+}
+
+impl Error {
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Error::EPERM => "operation not permitted",
+            Error::ENOENT => "no such file or directory",
+            Error::ESRCH => "no such process",
+            Error::EIO => "input/output error",
+            Error::E2BIG => "argument list too long",
+            Error::EAGAIN => "resource temporarily unavailable",
+            Error::ENOMEM => "cannot allocate memory",
+            Error::EFAULT => "bad address",
+            Error::EBUSY => "device or resource busy",
+            Error::EEXIST => "file exists",
+            Error::ENODEV => "no such device",
+            Error::ENOTDIR => "not a directory",
+            Error::EISDIR => "is a directory",
+            Error::EINVAL => "invalid argument",
+            Error::EFBIG => "file too large",
+            Error::ENOSPC => "no space left on device",
+            Error::EROFS => "read-only file system",
+            Error::ENAMETOOLONG => "file name too long",
+            Error::ENOSYS => "function not implemented",
+            Error::ENOTEMPTY => "directory not empty",
+            Error::ENODATA => "no data available",
+            Error::ENOTSUP => "operation not supported",
+            Error::ETIMEDOUT => "connection timed out",
+        }
+    }
+
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
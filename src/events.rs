@@ -1,14 +1,23 @@
 use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU8, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::DecodedKey;
+use pc_keyboard::{DecodedKey, HandleControl, KeyboardLayout, KeyCode, KeyEvent, KeyState, layouts};
 use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
 
 lazy_static! {
     pub static ref EVENT_HANDLERS: Mutex<EventHandlers> = Mutex::new(EventHandlers::new());
+    static ref HOTKEYS: Mutex<Vec<HotkeyEntry>> = Mutex::new(vec![]);
 }
 
+/// The modifier keys which are currently held down
+static MODIFIERS: AtomicU8 = AtomicU8::new(0);
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
 pub struct KeyboardEvent {
     pub key: DecodedKey,
 }
@@ -38,3 +47,162 @@ impl EventHandlers {
     }
 
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const CTRL: Modifiers = Modifiers(1 << 0);
+    pub const ALT: Modifiers = Modifiers(1 << 1);
+    pub const SHIFT: Modifiers = Modifiers(1 << 2);
+    pub const SYSRQ: Modifiers = Modifiers(1 << 3);
+
+    fn from_key(key: KeyCode) -> Option<Modifiers> {
+        match key {
+            KeyCode::ControlLeft | KeyCode::ControlRight => Some(Self::CTRL),
+            KeyCode::AltLeft | KeyCode::AltRight => Some(Self::ALT),
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => Some(Self::SHIFT),
+            KeyCode::SysRq => Some(Self::SYSRQ),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+/// A key combination, it matches if exactly `modifiers` are held down while `key` gets pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    pub modifiers: Modifiers,
+    pub key: KeyCode,
+}
+
+impl Hotkey {
+    pub const fn new(modifiers: Modifiers, key: KeyCode) -> Self {
+        Self {
+            modifiers,
+            key,
+        }
+    }
+}
+
+/// Hotkey handlers get called from the keyboard interrupt, so they have to be quick
+/// and must not register or unregister hotkeys themselves.
+pub type HotkeyHandler = dyn FnMut(Hotkey) + Sync + Send;
+
+struct HotkeyEntry {
+    hotkey: Hotkey,
+    handler: Box<HotkeyHandler>,
+}
+
+/// Claims the given key combination, fails with `EEXIST` if another component claimed it already.
+pub fn register_hotkey(hotkey: Hotkey, handler: Box<HotkeyHandler>) -> Result<(), Error> {
+    // the keyboard interrupt takes the lock as well
+    without_interrupts(|| {
+        let mut hotkeys = HOTKEYS.lock();
+        if hotkeys.iter().any(|entry| entry.hotkey == hotkey) {
+            return Err(Error::EEXIST);
+        }
+        hotkeys.push(HotkeyEntry {
+            hotkey,
+            handler,
+        });
+        Ok(())
+    })
+}
+
+/// Gets called with every raw key event before it's decoded and delivered to the keyboard handlers,
+/// returns true if the event completed a hotkey and must not be delivered any further.
+pub fn process_hotkeys(event: &KeyEvent) -> bool {
+    if let Some(modifier) = Modifiers::from_key(event.code) {
+        match event.state {
+            KeyState::Up => MODIFIERS.fetch_and(!modifier.0, Ordering::SeqCst),
+            _ => MODIFIERS.fetch_or(modifier.0, Ordering::SeqCst),
+        };
+        return false;
+    }
+    if event.state != KeyState::Down {
+        return false;
+    }
+    let hotkey = Hotkey::new(Modifiers(MODIFIERS.load(Ordering::SeqCst)), event.code);
+    let mut hotkeys = HOTKEYS.lock();
+    match hotkeys.iter_mut().find(|entry| entry.hotkey == hotkey) {
+        Some(entry) => {
+            (entry.handler)(hotkey);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Registers the hotkeys the kernel itself provides.
+pub fn register_default_hotkeys() {
+    let _ = register_hotkey(Hotkey::new(Modifiers::CTRL | Modifiers::ALT, KeyCode::Delete), Box::new(|_| {
+        crate::arch::x86::reboot();
+    }));
+}
+
+/// The keyboard layouts scancodes can be decoded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us,
+    Uk,
+    Jis,
+    Azerty,
+    Dvorak,
+}
+
+impl Layout {
+    const ALL: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::Jis, Layout::Azerty, Layout::Dvorak];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::Jis => "jis",
+            Layout::Azerty => "azerty",
+            Layout::Dvorak => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Self::ALL.iter().copied().find(|layout| layout.name() == name)
+    }
+}
+
+pub fn current_layout() -> Layout {
+    Layout::ALL[LAYOUT.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// Decodes keys with the layout picked by `set_layout`, so it can be changed while the keyboard
+/// driver is running.
+pub struct ConfiguredLayout;
+
+impl KeyboardLayout for ConfiguredLayout {
+    fn map_keycode(keycode: KeyCode, modifiers: &pc_keyboard::Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match current_layout() {
+            Layout::Us => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Jis => layouts::Jis109Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
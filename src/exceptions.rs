@@ -0,0 +1,42 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Counts the processor exceptions by vector, split by whether they were raised in user or kernel mode.
+
+const VECTORS: usize = 32;
+
+static NAMES: [&str; VECTORS] = [
+    "divide error", "debug", "nmi", "breakpoint", "overflow", "bound range exceeded", "invalid opcode",
+    "device not available", "double fault", "coprocessor segment overrun", "invalid tss", "segment not present",
+    "stack segment fault", "general protection fault", "page fault", "reserved", "x87 floating point",
+    "alignment check", "machine check", "simd floating point", "virtualization", "control protection",
+    "reserved", "reserved", "reserved", "reserved", "reserved", "reserved", "hypervisor injection",
+    "vmm communication", "security", "reserved",
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static KERNEL_COUNTS: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static USER_COUNTS: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+
+/// Gets called at the start of every exception handler.
+pub fn record(vector: u8, stack_frame: &InterruptStackFrame) {
+    let user = stack_frame.code_segment & 3 == 3;
+    let counts = if user { &USER_COUNTS } else { &KERNEL_COUNTS };
+    if let Some(count) = counts.get(vector as usize) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The number of exceptions with the vector which were raised in kernel mode
+pub fn kernel_count(vector: u8) -> u64 {
+    KERNEL_COUNTS.get(vector as usize).map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Calls `f` with the vector, its name and the number of exceptions raised in kernel and in user mode.
+pub fn for_each_exception(mut f: impl FnMut(u8, &'static str, u64, u64)) {
+    for vector in 0..VECTORS {
+        f(vector as u8, NAMES[vector], KERNEL_COUNTS[vector].load(Ordering::Relaxed),
+          USER_COUNTS[vector].load(Ordering::Relaxed));
+    }
+}
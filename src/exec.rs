@@ -0,0 +1,93 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use leafos_abi::auxv::{AT_BASE, AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::elf::LoadedElf;
+use crate::error_codes::Error;
+
+const PAGE_SIZE: usize = 4096;
+
+// FIXME: Run programs whose `security.cap_root` attribute (`XATTR_CAP_ROOT`) is set to 1 with
+//  root privileges, as a primitive replacement for setuid bits. This needs processes to have
+//  credentials and programs to get loaded from the filesystem, neither is the case yet.
+
+/// Lays out the initial stack of a program as described by the System V ABI:
+///
+/// argc, argv pointers, NULL, envp pointers, NULL, auxv pairs, AT_NULL, followed by the
+/// argument and environment strings at the very top of the stack.
+///
+/// `stack` is the memory of the stack and `stack_top` the address its end is mapped at,
+/// returns the stack pointer the program has to be started with.
+pub fn build_initial_stack(stack: &mut [u8], stack_top: u64, argv: &[&str], envp: &[String],
+                           elf: Option<&LoadedElf>) -> Result<u64, Error> {
+    let stack_bottom = stack_top - stack.len() as u64;
+    let mut pos = stack.len();
+    let mut push_str = |s: &str| -> Result<u64, Error> {
+        pos = pos.checked_sub(s.len() + 1).ok_or(Error::E2BIG)?;
+        stack[pos..pos + s.len()].copy_from_slice(s.as_bytes());
+        stack[pos + s.len()] = 0;
+        Ok(stack_bottom + pos as u64)
+    };
+    let env_ptrs = envp.iter().map(|var| push_str(var)).collect::<Result<Vec<_>, _>>()?;
+    let arg_ptrs = argv.iter().map(|arg| push_str(arg)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut auxv = vec![(AT_PAGESZ, PAGE_SIZE as u64)];
+    if let Some(elf) = elf {
+        auxv.push((AT_PHDR, elf.phdr));
+        auxv.push((AT_PHENT, elf.phent));
+        auxv.push((AT_PHNUM, elf.phnum));
+        auxv.push((AT_ENTRY, elf.program_entry));
+        if let Some(base) = elf.interp_base {
+            auxv.push((AT_BASE, base));
+        }
+    }
+    auxv.push((AT_NULL, 0));
+
+    let mut words: Vec<u64> = vec![arg_ptrs.len() as u64];
+    words.extend(arg_ptrs.iter());
+    words.push(0);
+    words.extend(env_ptrs.iter());
+    words.push(0);
+    for (kind, value) in auxv {
+        words.push(kind as u64);
+        words.push(value);
+    }
+
+    // rsp has to be 16 byte aligned at the program's entry
+    let table_size = words.len() * size_of::<u64>();
+    let start = (pos.checked_sub(table_size).ok_or(Error::E2BIG)?) & !0xf;
+    for (idx, word) in words.iter().enumerate() {
+        let offset = start + idx * size_of::<u64>();
+        stack[offset..offset + size_of::<u64>()].copy_from_slice(&word.to_ne_bytes());
+    }
+    Ok(stack_bottom + start as u64)
+}
+
+#[test_case]
+fn test_initial_stack() {
+    const TOP: u64 = 0x10000;
+    let mut stack = vec![0u8; 1024];
+    let rsp = build_initial_stack(&mut stack, TOP, &["prog", "-v"], &[String::from("A=1")], None).unwrap();
+    crate::kassert_eq!(rsp % 16, 0);
+    let bottom = TOP - stack.len() as u64;
+    let word = |addr: u64| {
+        let offset = (addr - bottom) as usize;
+        u64::from_ne_bytes(stack[offset..offset + 8].try_into().unwrap())
+    };
+    let string = |addr: u64| {
+        let offset = (addr - bottom) as usize;
+        let len = stack[offset..].iter().position(|byte| *byte == 0).unwrap();
+        String::from_utf8(stack[offset..offset + len].to_vec()).unwrap()
+    };
+    crate::kassert_eq!(word(rsp), 2);
+    crate::kassert_eq!(string(word(rsp + 8)), "prog");
+    crate::kassert_eq!(string(word(rsp + 16)), "-v");
+    crate::kassert_eq!(word(rsp + 24), 0);
+    crate::kassert_eq!(string(word(rsp + 32)), "A=1");
+    crate::kassert_eq!(word(rsp + 40), 0);
+    crate::kassert_eq!((word(rsp + 48), word(rsp + 56)), (AT_PAGESZ as u64, PAGE_SIZE as u64));
+    crate::kassert_eq!(word(rsp + 64), AT_NULL as u64);
+    // the stack doesn't have enough room
+    crate::kassert_eq!(build_initial_stack(&mut stack[..16], TOP, &["prog"], &[], None), Err(Error::E2BIG));
+}
@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::cmdline;
+
+// Makes allocations fail on purpose, so the error paths can be tested. This is only built with the
+// `fault-injection` feature. Every fail point fails every nth call, n can be set at runtime or from the
+// command line with `fail.frame=<n>` and `fail.heap=<n>`, 0 disables it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum FailPoint {
+    FrameAlloc = 0,
+    Heap = 1,
+}
+
+const FAIL_POINTS: usize = 2;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+static EVERY: [AtomicUsize; FAIL_POINTS] = [ZERO; FAIL_POINTS];
+static CALLS: [AtomicUsize; FAIL_POINTS] = [ZERO; FAIL_POINTS];
+static INJECTED: [AtomicUsize; FAIL_POINTS] = [ZERO; FAIL_POINTS];
+
+pub fn init() {
+    for (point, key) in [(FailPoint::FrameAlloc, "fail.frame"), (FailPoint::Heap, "fail.heap")] {
+        if let Some(every) = cmdline::get(key).and_then(|every| every.parse::<usize>().ok()) {
+            fail_every(point, every);
+        }
+    }
+}
+
+/// Makes every nth call of the fail point fail, counting starts anew with this call.
+pub fn fail_every(point: FailPoint, n: usize) {
+    CALLS[point as usize].store(0, Ordering::SeqCst);
+    EVERY[point as usize].store(n, Ordering::SeqCst);
+}
+
+/// Gets called by the fail point, returns whether this call should fail.
+pub fn should_fail(point: FailPoint) -> bool {
+    let every = EVERY[point as usize].load(Ordering::Relaxed);
+    if every == 0 {
+        return false;
+    }
+    let calls = CALLS[point as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    if calls % every == 0 {
+        INJECTED[point as usize].fetch_add(1, Ordering::Relaxed);
+        true
+    } else {
+        false
+    }
+}
+
+/// The number of failures injected so far
+pub fn injected(point: FailPoint) -> usize {
+    INJECTED[point as usize].load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_heap_fail_point() {
+    use core::alloc::Layout;
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let injected_before = injected(FailPoint::Heap);
+    fail_every(FailPoint::Heap, 2);
+    let first = unsafe { alloc::alloc::alloc(layout) };
+    let second = unsafe { alloc::alloc::alloc(layout) };
+    fail_every(FailPoint::Heap, 0);
+    crate::kassert!(!first.is_null());
+    crate::kassert!(second.is_null());
+    crate::kassert_eq!(injected(FailPoint::Heap), injected_before + 1);
+    unsafe { alloc::alloc::dealloc(first, layout); }
+}
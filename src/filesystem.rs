@@ -1,3 +0,0 @@
-pub trait FileSystem {
-    
-}
\ No newline at end of file
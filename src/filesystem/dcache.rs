@@ -0,0 +1,141 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+/// Caches the results of looking up a name in a directory, so resolving a path doesn't have to
+/// read every directory on the way again. Failed lookups are cached as well (negative entries),
+/// as programs tend to probe the same nonexistent paths over and over (e.g. when searching PATH).
+///
+/// Filesystems have to invalidate the entries of names they create, remove or rename.
+pub struct DentryCache {
+    /// The cached entries by the inode of their parent directory and their name
+    dirs: BTreeMap<u64, BTreeMap<String, CachedDentry>>,
+    /// The entries ordered by their last use, the oldest one gets evicted first
+    lru: BTreeMap<u64, (u64, String)>,
+    tick: u64,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedDentry {
+    /// `None` for names which don't exist
+    inode: Option<u64>,
+    last_used: u64,
+}
+
+pub const DEFAULT_CAPACITY: usize = 512;
+
+impl DentryCache {
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            dirs: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns `Some(Some(inode))` if the name is cached, `Some(None)` if it's known not to
+    /// exist and `None` if the directory has to be searched.
+    pub fn lookup(&mut self, parent: u64, name: &str) -> Option<Option<u64>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.dirs.get_mut(&parent).and_then(|entries| entries.get_mut(name)) {
+            Some(entry) => {
+                let key = self.lru.remove(&entry.last_used).unwrap();
+                entry.last_used = tick;
+                self.lru.insert(tick, key);
+                self.hits += 1;
+                Some(entry.inode)
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// Caches the result of a lookup, `None` records that the name doesn't exist.
+    pub fn insert(&mut self, parent: u64, name: &str, inode: Option<u64>) {
+        self.invalidate(parent, name);
+        if self.lru.len() >= self.capacity {
+            let oldest = self.lru.keys().next().copied();
+            if let Some((parent, name)) = oldest.and_then(|tick| self.lru.remove(&tick)) {
+                self.remove_entry(parent, &name);
+            }
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, (parent, String::from(name)));
+        self.dirs.entry(parent).or_default().insert(String::from(name), CachedDentry {
+            inode,
+            last_used: self.tick,
+        });
+    }
+
+    pub fn invalidate(&mut self, parent: u64, name: &str) {
+        if let Some(entry) = self.remove_entry(parent, name) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    /// Drops all entries of the directory, e.g. because it was removed.
+    pub fn invalidate_dir(&mut self, parent: u64) {
+        if let Some(entries) = self.dirs.remove(&parent) {
+            for entry in entries.values() {
+                self.lru.remove(&entry.last_used);
+            }
+        }
+    }
+
+    fn remove_entry(&mut self, parent: u64, name: &str) -> Option<CachedDentry> {
+        let entries = self.dirs.get_mut(&parent)?;
+        let entry = entries.remove(name);
+        if entries.is_empty() {
+            self.dirs.remove(&parent);
+        }
+        entry
+    }
+
+    pub fn clear(&mut self) {
+        self.dirs.clear();
+        self.lru.clear();
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.lru.is_empty()
+    }
+
+    /// The number of lookups which were answered from the cache and which weren't
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+}
+
+#[test_case]
+fn test_dentry_cache() {
+    let mut cache = DentryCache::new(2);
+    crate::kassert_eq!(cache.lookup(1, "bin"), None);
+    cache.insert(1, "bin", Some(2));
+    cache.insert(1, "missing", None);
+    crate::kassert_eq!(cache.lookup(1, "bin"), Some(Some(2)));
+    crate::kassert_eq!(cache.lookup(1, "missing"), Some(None));
+    // "bin" was used less recently than "missing", so it gets evicted
+    cache.insert(2, "sh", Some(3));
+    crate::kassert_eq!(cache.lookup(1, "bin"), None);
+    crate::kassert_eq!(cache.len(), 2);
+    cache.insert(1, "missing", Some(4));
+    crate::kassert_eq!(cache.lookup(1, "missing"), Some(Some(4)));
+    cache.invalidate_dir(2);
+    crate::kassert_eq!(cache.lookup(2, "sh"), None);
+    crate::kassert_eq!(cache.stats(), (3, 3));
+}
@@ -0,0 +1,92 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::drivers::block;
+use crate::drivers::registry::DeviceId;
+use crate::error_codes::Error;
+use crate::filesystem::{DirEntry, FileKind, FileSystem, Metadata};
+
+/// A virtual filesystem containing a device file for every registered block device.
+/// The inode of a device file is derived from its device id, so it stays the same across
+/// boots as long as the device gets the same id.
+pub struct DevFs;
+
+// the root directory uses an inode no device id can map to
+const ROOT_INODE: u64 = u64::MAX;
+
+fn find_device(path: &str) -> Result<(DeviceId, u64), Error> {
+    let name = path.trim_start_matches('/');
+    if name.contains('/') {
+        return Err(Error::ENOTDIR);
+    }
+    block::with_device(name, |device| {
+        let capacity = unsafe { device.driver.identify() }.map_or(0, |identity| identity.capacity());
+        (device.id, capacity)
+    }).ok_or(Error::ENOENT)
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn source(&self) -> &str {
+        "dev"
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, Error> {
+        if path == "/" {
+            return Ok(Metadata {
+                kind: FileKind::Directory,
+                size: 0,
+                inode: ROOT_INODE,
+                mtime: 0,
+                device: None,
+            });
+        }
+        let (id, capacity) = find_device(path)?;
+        Ok(Metadata {
+            kind: FileKind::BlockDevice,
+            size: capacity,
+            inode: id.as_u64(),
+            mtime: 0,
+            device: Some(id),
+        })
+    }
+
+    fn read(&mut self, path: &str, _offset: u64, _buf: &mut [u8]) -> Result<usize, Error> {
+        find_device(path)?;
+        // FIXME: Support raw access to the devices
+        Err(Error::EINVAL)
+    }
+
+    fn write(&mut self, path: &str, _offset: u64, _data: &[u8]) -> Result<usize, Error> {
+        find_device(path)?;
+        Err(Error::EINVAL)
+    }
+
+    fn truncate(&mut self, _path: &str, _size: u64) -> Result<(), Error> {
+        Err(Error::EINVAL)
+    }
+
+    fn create(&mut self, _path: &str, _kind: FileKind) -> Result<(), Error> {
+        Err(Error::EROFS)
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), Error> {
+        Err(Error::EROFS)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        if path != "/" {
+            find_device(path)?;
+            return Err(Error::ENOTDIR);
+        }
+        let mut entries = vec![];
+        block::for_each_device(|device| entries.push(DirEntry {
+            name: String::from(device.name.as_str()),
+            kind: FileKind::BlockDevice,
+        }));
+        Ok(entries)
+    }
+}
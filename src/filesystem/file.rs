@@ -0,0 +1,77 @@
+use alloc::string::String;
+use core::sync::atomic::{AtomicU64, Ordering};
+use leafos_abi::fs::{LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN};
+use crate::error_codes::Error;
+use crate::filesystem::{self, Metadata};
+use crate::filesystem::flock::{self, LockKey, LockKind};
+
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An open file, it refers to the inode the path resolved to when it was opened.
+/// Closing the file (dropping it) releases its lock.
+// FIXME: Hand these out through file descriptors and add open/flock syscalls once processes have fd tables
+pub struct File {
+    id: u64,
+    path: String,
+    key: LockKey,
+}
+
+impl File {
+
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let path = filesystem::normalize(path);
+        let key = filesystem::with_fs(&path, |fs, relative| {
+            let inode = fs.stat(relative)?.inode;
+            Ok((fs.name(), String::from(fs.source()), inode))
+        })?;
+        Ok(Self {
+            id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+            path,
+            key,
+        })
+    }
+
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn stat(&self) -> Result<Metadata, Error> {
+        filesystem::stat(&self.path)
+    }
+
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        filesystem::read(&self.path, offset, buf)
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        filesystem::write(&self.path, offset, data)
+    }
+
+    /// Applies or removes an advisory lock, `operation` is one of `LOCK_SH`, `LOCK_EX` or `LOCK_UN`,
+    /// `LOCK_NB` makes it fail with `EAGAIN` instead of waiting for a conflicting lock.
+    pub fn flock(&self, operation: u32) -> Result<(), Error> {
+        let blocking = operation & LOCK_NB == 0;
+        match operation & !LOCK_NB {
+            LOCK_SH => flock::lock(&self.key, self.id, LockKind::Shared, blocking),
+            LOCK_EX => flock::lock(&self.key, self.id, LockKind::Exclusive, blocking),
+            LOCK_UN => {
+                flock::unlock(&self.key, self.id);
+                Ok(())
+            },
+            _ => Err(Error::EINVAL),
+        }
+    }
+
+    /// The lock this file holds
+    pub fn lock_kind(&self) -> Option<LockKind> {
+        flock::held_lock(&self.key, self.id)
+    }
+
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        flock::unlock(&self.key, self.id);
+    }
+}
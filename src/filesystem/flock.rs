@@ -0,0 +1,110 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::error_codes::Error;
+use crate::sync::WaitQueue;
+
+// Advisory whole-file locks as known from flock(2). The locks belong to the open file and not to
+// the task which took them, so they are shared by everyone using the same `File` and are released
+// when the file gets closed. They don't prevent any access, cooperating programs have to check them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Any number of files can hold a shared lock at the same time
+    Shared,
+    /// Excludes every other lock
+    Exclusive,
+}
+
+/// Identifies the locked inode by the filesystem's name, its source and the inode number
+pub type LockKey = (&'static str, String, u64);
+
+lazy_static! {
+    /// The ids of the files holding a lock on each inode
+    static ref LOCKS: Mutex<BTreeMap<LockKey, Vec<(u64, LockKind)>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Tasks waiting for any lock to be released, they check whether they can get theirs when woken up
+static WAITERS: WaitQueue = WaitQueue::new();
+
+/// Takes the lock for `file` if it doesn't conflict with the ones held by other files,
+/// a lock `file` holds already gets replaced.
+fn try_lock(key: &LockKey, file: u64, kind: LockKind) -> bool {
+    let mut locks = LOCKS.lock();
+    let holders = locks.entry(key.clone()).or_default();
+    let mut others = holders.iter().filter(|(holder, _)| *holder != file);
+    let compatible = match kind {
+        LockKind::Shared => others.all(|(_, kind)| *kind == LockKind::Shared),
+        LockKind::Exclusive => others.next().is_none(),
+    };
+    if compatible {
+        holders.retain(|(holder, _)| *holder != file);
+        holders.push((file, kind));
+    }
+    compatible
+}
+
+/// Locks the inode for `file`, if it conflicts with a lock held by another file this either waits
+/// until it can be taken or fails with `EAGAIN` if `blocking` isn't set.
+pub fn lock(key: &LockKey, file: u64, kind: LockKind, blocking: bool) -> Result<(), Error> {
+    if try_lock(key, file, kind) {
+        return Ok(());
+    }
+    if !blocking {
+        return Err(Error::EAGAIN);
+    }
+    // like on linux converting a lock isn't atomic, the old lock is dropped before waiting,
+    // otherwise two files upgrading their shared locks would wait for each other forever
+    unlock(key, file);
+    WAITERS.wait_until(|| try_lock(key, file, kind));
+    Ok(())
+}
+
+/// Releases the lock `file` holds on the inode, if any.
+pub fn unlock(key: &LockKey, file: u64) {
+    let released = {
+        let mut locks = LOCKS.lock();
+        match locks.get_mut(key) {
+            Some(holders) => {
+                let len = holders.len();
+                holders.retain(|(holder, _)| *holder != file);
+                let released = holders.len() != len;
+                if holders.is_empty() {
+                    locks.remove(key);
+                }
+                released
+            },
+            None => false,
+        }
+    };
+    if released {
+        WAITERS.wake_all();
+    }
+}
+
+/// Returns the lock `file` holds on the inode.
+pub fn held_lock(key: &LockKey, file: u64) -> Option<LockKind> {
+    LOCKS.lock().get(key)?.iter().find(|(holder, _)| *holder == file).map(|(_, kind)| *kind)
+}
+
+#[test_case]
+fn test_flock() {
+    let key = ("testfs", String::from("test0"), 1);
+    lock(&key, 1, LockKind::Shared, false).unwrap();
+    lock(&key, 2, LockKind::Shared, false).unwrap();
+    crate::kassert_eq!(lock(&key, 3, LockKind::Exclusive, false), Err(Error::EAGAIN));
+    // an upgrade which fails without blocking keeps the shared lock
+    crate::kassert_eq!(lock(&key, 1, LockKind::Exclusive, false), Err(Error::EAGAIN));
+    crate::kassert_eq!(held_lock(&key, 1), Some(LockKind::Shared));
+    unlock(&key, 2);
+    lock(&key, 1, LockKind::Exclusive, false).unwrap();
+    crate::kassert_eq!(lock(&key, 2, LockKind::Shared, false), Err(Error::EAGAIN));
+    // other inodes aren't affected
+    lock(&("testfs", String::from("test0"), 2), 2, LockKind::Exclusive, false).unwrap();
+    unlock(&("testfs", String::from("test0"), 2), 2);
+    unlock(&key, 1);
+    crate::kassert_eq!(held_lock(&key, 1), None);
+    crate::kassert!(LOCKS.lock().is_empty());
+}
@@ -0,0 +1,169 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Keeps recently used inodes in memory, so stat and open of the same file don't have to read the
+/// inode table again. Changes to cached inodes are only marked dirty and written back in batches
+/// by the filesystem's `sync`, which gets called periodically by the writeback work.
+///
+/// The cache doesn't do any I/O itself, the filesystem has to write back the dirty inodes it hands out.
+pub struct InodeCache<T> {
+    inodes: BTreeMap<u64, CachedInode<T>>,
+    /// The inodes ordered by their last use, the oldest one gets evicted first
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    capacity: usize,
+    dirty: usize,
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedInode<T> {
+    inode: T,
+    dirty: bool,
+    last_used: u64,
+}
+
+pub const DEFAULT_CAPACITY: usize = 256;
+
+impl<T: Clone> InodeCache<T> {
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inodes: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            dirty: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, ino: u64) -> Option<T> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.inodes.get_mut(&ino) {
+            Some(cached) => {
+                self.lru.remove(&cached.last_used);
+                cached.last_used = tick;
+                self.lru.insert(tick, ino);
+                self.hits += 1;
+                Some(cached.inode.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// Caches the inode, dirty inodes have to be written back before they get dropped.
+    /// If a dirty inode had to be evicted to make room it's returned, so the caller can write it back.
+    #[must_use]
+    pub fn insert(&mut self, ino: u64, inode: T, dirty: bool) -> Option<(u64, T)> {
+        // an inode which is still dirty stays dirty, even if the new version is clean
+        let was_dirty = self.remove(ino).map_or(false, |(_, dirty)| dirty);
+        let mut evicted = None;
+        if self.lru.len() >= self.capacity {
+            let oldest = self.lru.keys().next().copied();
+            if let Some(oldest) = oldest.and_then(|tick| self.lru.remove(&tick)) {
+                let cached = self.inodes.remove(&oldest).unwrap();
+                if cached.dirty {
+                    self.dirty -= 1;
+                    evicted = Some((oldest, cached.inode));
+                }
+            }
+        }
+        self.tick += 1;
+        let dirty = dirty || was_dirty;
+        if dirty {
+            self.dirty += 1;
+        }
+        self.lru.insert(self.tick, ino);
+        self.inodes.insert(ino, CachedInode {
+            inode,
+            dirty,
+            last_used: self.tick,
+        });
+        evicted
+    }
+
+    /// Drops the inode from the cache and returns it together with whether it was dirty.
+    pub fn remove(&mut self, ino: u64) -> Option<(T, bool)> {
+        let cached = self.inodes.remove(&ino)?;
+        self.lru.remove(&cached.last_used);
+        if cached.dirty {
+            self.dirty -= 1;
+        }
+        Some((cached.inode, cached.dirty))
+    }
+
+    /// Returns copies of all dirty inodes ordered by their number and marks them clean.
+    /// Inodes which couldn't be written back have to be marked dirty again with `mark_dirty`.
+    pub fn take_dirty(&mut self) -> Vec<(u64, T)> {
+        self.dirty = 0;
+        self.inodes.iter_mut()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(ino, cached)| {
+                cached.dirty = false;
+                (*ino, cached.inode.clone())
+            })
+            .collect()
+    }
+
+    /// Returns whether the inode is still cached and could be marked dirty.
+    pub fn mark_dirty(&mut self, ino: u64) -> bool {
+        match self.inodes.get_mut(&ino) {
+            Some(cached) => {
+                if !cached.dirty {
+                    cached.dirty = true;
+                    self.dirty += 1;
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.lru.is_empty()
+    }
+
+    #[inline]
+    pub fn dirty_count(&self) -> usize {
+        self.dirty
+    }
+
+    /// The number of lookups which were answered from the cache and which weren't
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+}
+
+#[test_case]
+fn test_inode_cache() {
+    let mut cache = InodeCache::new(2);
+    crate::kassert_eq!(cache.get(1), None);
+    crate::kassert_eq!(cache.insert(1, "root", false), None);
+    crate::kassert_eq!(cache.insert(2, "bin", true), None);
+    crate::kassert_eq!(cache.get(1), Some("root"));
+    // 2 is the least recently used one and it's dirty, so it has to be written back
+    crate::kassert_eq!(cache.insert(3, "sh", false), Some((2, "bin")));
+    crate::kassert_eq!(cache.dirty_count(), 0);
+    crate::kassert_eq!(cache.insert(3, "sh", true), None);
+    crate::kassert_eq!(cache.insert(3, "sh2", false), None);
+    crate::kassert_eq!(cache.take_dirty(), alloc::vec![(3, "sh2")]);
+    crate::kassert!(cache.take_dirty().is_empty());
+    crate::kassert!(cache.mark_dirty(3));
+    crate::kassert!(!cache.mark_dirty(2));
+    crate::kassert_eq!(cache.remove(3), Some(("sh2", true)));
+    crate::kassert_eq!(cache.len(), 1);
+    crate::kassert_eq!(cache.stats(), (1, 1));
+}
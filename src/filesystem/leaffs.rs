@@ -0,0 +1,1061 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::crypto::crc32c;
+use crate::drivers::block;
+use crate::error_codes::Error;
+use crate::filesystem::{DirEntry, FileKind, FileSystem, Metadata};
+use crate::filesystem::dcache::{self, DentryCache};
+use crate::filesystem::icache::{self, InodeCache};
+use crate::{log_warn, scheduler, time};
+
+// LeafFS on-disk layout (all integers are little endian):
+//
+// block 0                  superblock
+// bitmap_start..           allocation bitmap, one bit per block of the whole device
+// inode_start..            inode table, INODES_PER_BLOCK inodes per block
+// data_start..             file and directory contents, as well as extended attribute blocks
+//
+// The extended attributes of an inode are stored together in a single block the inode refers to,
+// which limits their total size to a bit less than BLOCK_SIZE.
+//
+// Every piece of metadata (the superblock, every inode and every directory entry)
+// is protected by a CRC32c checksum, so corruption is detected instead of being
+// silently propagated through the filesystem.
+
+pub const MAGIC: [u8; 8] = *b"LEAFFS\0\0";
+pub const VERSION: u32 = 1;
+pub const BLOCK_SIZE: usize = 512;
+
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+const DIRENT_SIZE: usize = 64;
+pub const MAX_NAME_LEN: usize = 54;
+const MAX_EXTENTS: usize = 8;
+const ROOT_INODE: u32 = 1;
+
+const KIND_FREE: u8 = 0;
+const KIND_FILE: u8 = 1;
+const KIND_DIR: u8 = 2;
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn get_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, val: u32) {
+    buf[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, val: u64) {
+    buf[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Superblock {
+    block_count: u64,
+    inode_count: u32,
+    bitmap_start: u64,
+    bitmap_blocks: u64,
+    inode_start: u64,
+    inode_blocks: u64,
+    data_start: u64,
+    mount_count: u64,
+}
+
+impl Superblock {
+
+    const CRC_OFFSET: usize = 76;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; BLOCK_SIZE];
+        buf[0..8].copy_from_slice(&MAGIC);
+        put_u32(&mut buf, 8, VERSION);
+        put_u32(&mut buf, 12, BLOCK_SIZE as u32);
+        put_u64(&mut buf, 16, self.block_count);
+        put_u32(&mut buf, 24, self.inode_count);
+        put_u64(&mut buf, 28, self.bitmap_start);
+        put_u64(&mut buf, 36, self.bitmap_blocks);
+        put_u64(&mut buf, 44, self.inode_start);
+        put_u64(&mut buf, 52, self.inode_blocks);
+        put_u64(&mut buf, 60, self.data_start);
+        put_u64(&mut buf, 68, self.mount_count);
+        let crc = crc32c(&buf[0..Self::CRC_OFFSET]);
+        put_u32(&mut buf, Self::CRC_OFFSET, crc);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        if buf[0..8] != MAGIC || get_u32(buf, 8) != VERSION || get_u32(buf, 12) != BLOCK_SIZE as u32 {
+            return Err(Error::EINVAL);
+        }
+        if crc32c(&buf[0..Self::CRC_OFFSET]) != get_u32(buf, Self::CRC_OFFSET) {
+            return Err(Error::EIO);
+        }
+        Ok(Self {
+            block_count: get_u64(buf, 16),
+            inode_count: get_u32(buf, 24),
+            bitmap_start: get_u64(buf, 28),
+            bitmap_blocks: get_u64(buf, 36),
+            inode_start: get_u64(buf, 44),
+            inode_blocks: get_u64(buf, 52),
+            data_start: get_u64(buf, 60),
+            mount_count: get_u64(buf, 68),
+        })
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Extent {
+    start: u32,
+    len: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    kind: u8,
+    size: u64,
+    mtime: u64,
+    extents: Vec<Extent>,
+    /// The block holding the extended attributes, 0 if there are none
+    xattr_block: u32,
+}
+
+impl Inode {
+
+    const CRC_OFFSET: usize = 124;
+
+    fn new(kind: u8) -> Self {
+        Self {
+            kind,
+            size: 0,
+            // FIXME: use the wall clock once we have one
+            mtime: time::monotonic_us() / 1_000_000,
+            extents: vec![],
+            xattr_block: 0,
+        }
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf.fill(0);
+        buf[0] = self.kind;
+        buf[1] = self.extents.len() as u8;
+        put_u64(buf, 8, self.size);
+        put_u64(buf, 16, self.mtime);
+        for (idx, extent) in self.extents.iter().enumerate() {
+            put_u32(buf, 24 + idx * 8, extent.start);
+            put_u32(buf, 28 + idx * 8, extent.len);
+        }
+        put_u32(buf, 88, self.xattr_block);
+        let crc = crc32c(&buf[0..Self::CRC_OFFSET]);
+        put_u32(buf, Self::CRC_OFFSET, crc);
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        if buf[0] == KIND_FREE {
+            return Ok(Self {
+                kind: KIND_FREE,
+                size: 0,
+                mtime: 0,
+                extents: vec![],
+                xattr_block: 0,
+            });
+        }
+        let extent_count = buf[1] as usize;
+        if crc32c(&buf[0..Self::CRC_OFFSET]) != get_u32(buf, Self::CRC_OFFSET) || extent_count > MAX_EXTENTS {
+            return Err(Error::EIO);
+        }
+        Ok(Self {
+            kind: buf[0],
+            size: get_u64(buf, 8),
+            mtime: get_u64(buf, 16),
+            extents: (0..extent_count).map(|idx| Extent {
+                start: get_u32(buf, 24 + idx * 8),
+                len: get_u32(buf, 28 + idx * 8),
+            }).collect(),
+            xattr_block: get_u32(buf, 88),
+        })
+    }
+
+    fn allocated_blocks(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.len as u64).sum()
+    }
+
+    /// Maps a block index inside the file to a block on the device
+    fn block_for(&self, mut file_block: u64) -> Option<u64> {
+        for extent in self.extents.iter() {
+            if file_block < extent.len as u64 {
+                return Some(extent.start as u64 + file_block);
+            }
+            file_block -= extent.len as u64;
+        }
+        None
+    }
+
+    fn file_kind(&self) -> FileKind {
+        if self.kind == KIND_DIR {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        }
+    }
+
+}
+
+struct Dirent {
+    inode: u32,
+    kind: u8,
+    name: String,
+}
+
+impl Dirent {
+
+    const CRC_OFFSET: usize = 60;
+
+    fn encode(&self) -> [u8; DIRENT_SIZE] {
+        let mut buf = [0; DIRENT_SIZE];
+        put_u32(&mut buf, 0, self.inode);
+        buf[4] = self.kind;
+        buf[5] = self.name.len() as u8;
+        buf[6..6 + self.name.len()].copy_from_slice(self.name.as_bytes());
+        let crc = crc32c(&buf[0..Self::CRC_OFFSET]);
+        put_u32(&mut buf, Self::CRC_OFFSET, crc);
+        buf
+    }
+
+    /// Returns `None` for unused directory slots.
+    fn decode(buf: &[u8]) -> Result<Option<Self>, Error> {
+        let inode = get_u32(buf, 0);
+        if inode == 0 {
+            return Ok(None);
+        }
+        let name_len = buf[5] as usize;
+        if crc32c(&buf[0..Self::CRC_OFFSET]) != get_u32(buf, Self::CRC_OFFSET) || name_len > MAX_NAME_LEN {
+            return Err(Error::EIO);
+        }
+        Ok(Some(Self {
+            inode,
+            kind: buf[4],
+            name: String::from_utf8_lossy(&buf[6..6 + name_len]).into_owned(),
+        }))
+    }
+
+}
+
+/// The extended attributes of an inode as (name, value) pairs
+type Xattrs = Vec<(String, Vec<u8>)>;
+
+// xattr block: crc (4 bytes), attribute count (2 bytes), followed by the attributes, each one
+// consisting of the name's length (1 byte), the value's length (2 bytes), the name and the value.
+const XATTR_HEADER_SIZE: usize = 6;
+
+fn encode_xattrs(attrs: &Xattrs) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; BLOCK_SIZE];
+    buf[4..6].copy_from_slice(&(attrs.len() as u16).to_le_bytes());
+    let mut pos = XATTR_HEADER_SIZE;
+    for (name, value) in attrs.iter() {
+        let end = pos + 3 + name.len() + value.len();
+        if end > BLOCK_SIZE {
+            return Err(Error::ENOSPC);
+        }
+        buf[pos] = name.len() as u8;
+        buf[pos + 1..pos + 3].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        buf[pos + 3..pos + 3 + name.len()].copy_from_slice(name.as_bytes());
+        buf[pos + 3 + name.len()..end].copy_from_slice(value);
+        pos = end;
+    }
+    let crc = crc32c(&buf[4..]);
+    put_u32(&mut buf, 0, crc);
+    Ok(buf)
+}
+
+fn decode_xattrs(buf: &[u8]) -> Result<Xattrs, Error> {
+    if crc32c(&buf[4..]) != get_u32(buf, 0) {
+        return Err(Error::EIO);
+    }
+    let count = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+    let mut attrs = Vec::with_capacity(count);
+    let mut pos = XATTR_HEADER_SIZE;
+    for _ in 0..count {
+        if pos + 3 > buf.len() {
+            return Err(Error::EIO);
+        }
+        let name_len = buf[pos] as usize;
+        let value_len = u16::from_le_bytes([buf[pos + 1], buf[pos + 2]]) as usize;
+        let end = pos + 3 + name_len + value_len;
+        if end > buf.len() {
+            return Err(Error::EIO);
+        }
+        let name = String::from_utf8_lossy(&buf[pos + 3..pos + 3 + name_len]).into_owned();
+        attrs.push((name, buf[pos + 3 + name_len..end].to_vec()));
+        pos = end;
+    }
+    Ok(attrs)
+}
+
+fn read_device_block(device: &str, block: u64) -> Result<Box<[u8]>, Error> {
+    let data = block::with_device(device, |device| unsafe {
+        device.driver.read_block_indexed(block as usize, BLOCK_SIZE)
+    }).ok_or(Error::ENODEV)?;
+    if data.len() != BLOCK_SIZE {
+        return Err(Error::EIO);
+    }
+    Ok(data)
+}
+
+fn write_device_block(device: &str, block: u64, data: &[u8]) -> Result<(), Error> {
+    block::with_device(device, |device| unsafe {
+        device.driver.try_write_block_indexed(block as usize, data)
+    }).ok_or(Error::ENODEV)?
+}
+
+fn device_block_count(device: &str) -> Result<u64, Error> {
+    let identity = block::with_device(device, |device| unsafe { device.driver.identify() })
+        .ok_or(Error::ENODEV)?
+        .ok_or(Error::ENOSYS)?;
+    Ok(identity.capacity() / BLOCK_SIZE as u64)
+}
+
+/// Creates an empty LeafFS on the given block device.
+pub fn format(device: &str) -> Result<(), Error> {
+    let block_count = device_block_count(device)?;
+    let bits_per_block = (BLOCK_SIZE * 8) as u64;
+    let bitmap_blocks = (block_count + bits_per_block - 1) / bits_per_block;
+    let inode_count = ((block_count / 8).max(16) as usize + INODES_PER_BLOCK - 1) / INODES_PER_BLOCK * INODES_PER_BLOCK;
+    let inode_blocks = (inode_count / INODES_PER_BLOCK) as u64;
+    let data_start = 1 + bitmap_blocks + inode_blocks;
+    if data_start + 1 >= block_count {
+        return Err(Error::ENOSPC);
+    }
+    let sb = Superblock {
+        block_count,
+        inode_count: inode_count as u32,
+        bitmap_start: 1,
+        bitmap_blocks,
+        inode_start: 1 + bitmap_blocks,
+        inode_blocks,
+        data_start,
+        mount_count: 0,
+    };
+
+    // all metadata blocks are in use from the beginning
+    let mut bitmap = vec![0_u8; bitmap_blocks as usize * BLOCK_SIZE];
+    for block in 0..data_start as usize {
+        bitmap[block / 8] |= 1 << (block % 8);
+    }
+    for (idx, chunk) in bitmap.chunks(BLOCK_SIZE).enumerate() {
+        write_device_block(device, sb.bitmap_start + idx as u64, chunk)?;
+    }
+    let zeroes = [0; BLOCK_SIZE];
+    for idx in 0..inode_blocks {
+        write_device_block(device, sb.inode_start + idx, &zeroes)?;
+    }
+    let mut fs = LeafFs {
+        device: String::from(device),
+        sb,
+        bitmap,
+        dcache: Mutex::new(DentryCache::new(dcache::DEFAULT_CAPACITY)),
+        icache: Mutex::new(InodeCache::new(icache::DEFAULT_CAPACITY)),
+    };
+    fs.write_inode_through(ROOT_INODE, &Inode::new(KIND_DIR))?;
+    // the superblock gets written last, so a partially formatted device is never mountable
+    write_device_block(device, 0, &sb.encode())
+}
+
+pub struct LeafFs {
+    device: String,
+    sb: Superblock,
+    bitmap: Vec<u8>,
+    dcache: Mutex<DentryCache>,
+    icache: Mutex<InodeCache<Inode>>,
+}
+
+impl LeafFs {
+
+    pub fn mount(device: &str) -> Result<Self, Error> {
+        let mut sb = Superblock::decode(&read_device_block(device, 0)?)?;
+        let mut bitmap = Vec::with_capacity(sb.bitmap_blocks as usize * BLOCK_SIZE);
+        for idx in 0..sb.bitmap_blocks {
+            bitmap.extend_from_slice(&read_device_block(device, sb.bitmap_start + idx)?);
+        }
+        sb.mount_count += 1;
+        write_device_block(device, 0, &sb.encode())?;
+        Ok(Self {
+            device: String::from(device),
+            sb,
+            bitmap,
+            dcache: Mutex::new(DentryCache::new(dcache::DEFAULT_CAPACITY)),
+            icache: Mutex::new(InodeCache::new(icache::DEFAULT_CAPACITY)),
+        })
+    }
+
+    fn read_block(&self, block: u64) -> Result<Box<[u8]>, Error> {
+        if block >= self.sb.block_count {
+            return Err(Error::EIO);
+        }
+        read_device_block(&self.device, block)
+    }
+
+    fn write_block(&self, block: u64, data: &[u8]) -> Result<(), Error> {
+        if block >= self.sb.block_count {
+            return Err(Error::EIO);
+        }
+        write_device_block(&self.device, block, data)
+    }
+
+    fn inode_location(&self, ino: u32) -> Result<(u64, usize), Error> {
+        if ino == 0 || ino >= self.sb.inode_count {
+            return Err(Error::EIO);
+        }
+        let ino = ino as usize;
+        Ok((self.sb.inode_start + (ino / INODES_PER_BLOCK) as u64, (ino % INODES_PER_BLOCK) * INODE_SIZE))
+    }
+
+    /// Reads the inode from the inode table, bypassing the cache.
+    fn load_inode(&self, ino: u32) -> Result<Inode, Error> {
+        let (block, offset) = self.inode_location(ino)?;
+        let data = self.read_block(block)?;
+        Inode::decode(&data[offset..offset + INODE_SIZE])
+    }
+
+    /// Writes the inode to the inode table, bypassing the cache.
+    fn store_inode(&self, ino: u32, inode: &Inode) -> Result<(), Error> {
+        let (block, offset) = self.inode_location(ino)?;
+        let mut data = self.read_block(block)?;
+        inode.encode(&mut data[offset..offset + INODE_SIZE]);
+        self.write_block(block, &data)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, Error> {
+        if let Some(inode) = self.icache.lock().get(ino as u64) {
+            return Ok(inode);
+        }
+        let inode = self.load_inode(ino)?;
+        self.cache_inode(ino, inode.clone(), false)?;
+        Ok(inode)
+    }
+
+    fn cache_inode(&self, ino: u32, inode: Inode, dirty: bool) -> Result<(), Error> {
+        let evicted = self.icache.lock().insert(ino as u64, inode, dirty);
+        match evicted {
+            Some((evicted, inode)) => self.store_inode(evicted as u32, &inode),
+            None => Ok(()),
+        }
+    }
+
+    /// Updates the inode in memory, it gets written back by the next `sync`. This is only safe for
+    /// changes which don't drop references to blocks, as those could be reused before the inode
+    /// reaches the disk. Losing these changes in a crash at most loses data, or leaks blocks.
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<(), Error> {
+        self.cache_inode(ino, inode.clone(), true)
+    }
+
+    /// Writes the inode to the disk right away, this has to be used when freeing blocks and
+    /// when the inode gets allocated, so a directory entry never points to a free inode.
+    fn write_inode_through(&mut self, ino: u32, inode: &Inode) -> Result<(), Error> {
+        self.store_inode(ino, inode)?;
+        // a previous dirty version is superseded by this one
+        self.icache.lock().remove(ino as u64);
+        self.cache_inode(ino, inode.clone(), false)
+    }
+
+    fn alloc_inode(&mut self, kind: u8) -> Result<u32, Error> {
+        for ino in 1..self.sb.inode_count {
+            // scanning the table shouldn't push everything else out of the cache
+            let cached = self.icache.lock().get(ino as u64);
+            let inode = match cached {
+                Some(inode) => inode,
+                None => self.load_inode(ino)?,
+            };
+            if inode.kind == KIND_FREE {
+                self.write_inode_through(ino, &Inode::new(kind))?;
+                return Ok(ino);
+            }
+            scheduler::cond_resched();
+        }
+        Err(Error::ENOSPC)
+    }
+
+    fn free_inode(&mut self, ino: u32) -> Result<(), Error> {
+        self.icache.lock().remove(ino as u64);
+        let (block, offset) = self.inode_location(ino)?;
+        let mut data = self.read_block(block)?;
+        data[offset..offset + INODE_SIZE].fill(0);
+        self.write_block(block, &data)
+    }
+
+    fn is_block_used(&self, block: u64) -> bool {
+        self.bitmap[block as usize / 8] & (1 << (block % 8)) != 0
+    }
+
+    fn set_block_used(&mut self, block: u64, used: bool) -> Result<(), Error> {
+        let byte = block as usize / 8;
+        if used {
+            self.bitmap[byte] |= 1 << (block % 8);
+        } else {
+            self.bitmap[byte] &= !(1 << (block % 8));
+        }
+        let bitmap_block = byte / BLOCK_SIZE;
+        let chunk = &self.bitmap[bitmap_block * BLOCK_SIZE..(bitmap_block + 1) * BLOCK_SIZE];
+        self.write_block(self.sb.bitmap_start + bitmap_block as u64, chunk)
+    }
+
+    /// Allocates a zeroed block, preferring `hint` so files stay contiguous.
+    fn alloc_block(&mut self, hint: Option<u64>) -> Result<u64, Error> {
+        let block = match hint.filter(|hint| *hint < self.sb.block_count && !self.is_block_used(*hint)) {
+            Some(hint) => hint,
+            None => (self.sb.data_start..self.sb.block_count)
+                .find(|block| !self.is_block_used(*block))
+                .ok_or(Error::ENOSPC)?,
+        };
+        self.set_block_used(block, true)?;
+        self.write_block(block, &[0; BLOCK_SIZE])?;
+        Ok(block)
+    }
+
+    /// Grows the inode's allocation to at least `blocks` blocks. On failure the blocks allocated
+    /// so far are freed again, as the callers don't store the inode then.
+    fn grow(&mut self, inode: &mut Inode, blocks: u64) -> Result<(), Error> {
+        let allocated = inode.allocated_blocks();
+        let result = self.allocate_until(inode, allocated, blocks);
+        if result.is_err() {
+            self.shrink(inode, allocated)?;
+        }
+        result
+    }
+
+    fn allocate_until(&mut self, inode: &mut Inode, mut allocated: u64, blocks: u64) -> Result<(), Error> {
+        while allocated < blocks {
+            let hint = inode.extents.last().map(|extent| extent.start as u64 + extent.len as u64);
+            let block = self.alloc_block(hint)?;
+            match inode.extents.last_mut() {
+                Some(extent) if extent.start as u64 + extent.len as u64 == block => extent.len += 1,
+                _ => {
+                    if inode.extents.len() == MAX_EXTENTS {
+                        self.set_block_used(block, false)?;
+                        return Err(Error::EFBIG);
+                    }
+                    inode.extents.push(Extent {
+                        start: block as u32,
+                        len: 1,
+                    });
+                },
+            }
+            allocated += 1;
+        }
+        Ok(())
+    }
+
+    /// Frees all blocks of the inode past the first `blocks` blocks.
+    fn shrink(&mut self, inode: &mut Inode, blocks: u64) -> Result<(), Error> {
+        let mut allocated = inode.allocated_blocks();
+        while allocated > blocks {
+            let extent = inode.extents.last_mut().ok_or(Error::EIO)?;
+            // a corrupted extent mustn't make us free blocks outside the data area
+            if extent.len == 0 {
+                return Err(Error::EIO);
+            }
+            extent.len -= 1;
+            let block = extent.start as u64 + extent.len as u64;
+            if block < self.sb.data_start || block >= self.sb.block_count {
+                return Err(Error::EIO);
+            }
+            if extent.len == 0 {
+                inode.extents.pop();
+            }
+            self.set_block_used(block, false)?;
+            allocated -= 1;
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((inode.size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let block_offset = (pos % BLOCK_SIZE as u64) as usize;
+            let chunk = (BLOCK_SIZE - block_offset).min(len - done);
+            let block = inode.block_for(pos / BLOCK_SIZE as u64).ok_or(Error::EIO)?;
+            let data = self.read_block(block)?;
+            buf[done..done + chunk].copy_from_slice(&data[block_offset..block_offset + chunk]);
+            done += chunk;
+            scheduler::cond_resched();
+        }
+        Ok(len)
+    }
+
+    fn write_data(&mut self, ino: u32, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let end = offset.checked_add(data.len() as u64).ok_or(Error::EFBIG)?;
+        self.grow(inode, (end + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64)?;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let block_offset = (pos % BLOCK_SIZE as u64) as usize;
+            let chunk = (BLOCK_SIZE - block_offset).min(data.len() - done);
+            let block = inode.block_for(pos / BLOCK_SIZE as u64).ok_or(Error::EIO)?;
+            let mut contents = self.read_block(block)?;
+            contents[block_offset..block_offset + chunk].copy_from_slice(&data[done..done + chunk]);
+            self.write_block(block, &contents)?;
+            done += chunk;
+            scheduler::cond_resched();
+        }
+        inode.size = inode.size.max(end);
+        inode.mtime = time::monotonic_us() / 1_000_000;
+        self.write_inode(ino, inode)?;
+        Ok(data.len())
+    }
+
+    /// Returns all used directory slots as (slot index, entry)
+    fn dir_entries(&self, dir: &Inode) -> Result<Vec<(usize, Dirent)>, Error> {
+        if dir.kind != KIND_DIR {
+            return Err(Error::ENOTDIR);
+        }
+        let mut data = vec![0; dir.size as usize];
+        self.read_data(dir, 0, &mut data)?;
+        let mut entries = vec![];
+        for (slot, raw) in data.chunks_exact(DIRENT_SIZE).enumerate() {
+            if let Some(entry) = Dirent::decode(raw)? {
+                entries.push((slot, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&self, dir: &Inode, name: &str) -> Result<Option<(usize, Dirent)>, Error> {
+        Ok(self.dir_entries(dir)?.into_iter().find(|(_, entry)| entry.name == name))
+    }
+
+    fn resolve(&self, path: &str) -> Result<u32, Error> {
+        let mut ino = ROOT_INODE;
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            let cached = self.dcache.lock().lookup(ino as u64, component);
+            let child = match cached {
+                Some(child) => child.map(|child| child as u32),
+                None => {
+                    let dir = self.read_inode(ino)?;
+                    let child = self.lookup(&dir, component)?.map(|(_, entry)| entry.inode);
+                    self.dcache.lock().insert(ino as u64, component, child.map(|child| child as u64));
+                    child
+                },
+            };
+            ino = child.ok_or(Error::ENOENT)?;
+        }
+        Ok(ino)
+    }
+
+    fn read_xattrs(&self, inode: &Inode) -> Result<Xattrs, Error> {
+        if inode.xattr_block == 0 {
+            return Ok(vec![]);
+        }
+        decode_xattrs(&self.read_block(inode.xattr_block as u64)?)
+    }
+
+    fn write_xattrs(&mut self, ino: u32, inode: &mut Inode, attrs: &Xattrs) -> Result<(), Error> {
+        if attrs.is_empty() {
+            if inode.xattr_block != 0 {
+                let block = inode.xattr_block as u64;
+                inode.xattr_block = 0;
+                self.write_inode_through(ino, inode)?;
+                self.set_block_used(block, false)?;
+            }
+            return Ok(());
+        }
+        let data = encode_xattrs(attrs)?;
+        if inode.xattr_block != 0 {
+            return self.write_block(inode.xattr_block as u64, &data);
+        }
+        // the block has to contain the attributes before the inode refers to it
+        let block = self.alloc_block(None)?;
+        if let Err(err) = self.write_block(block, &data) {
+            self.set_block_used(block, false)?;
+            return Err(err);
+        }
+        inode.xattr_block = block as u32;
+        self.write_inode(ino, inode)
+    }
+
+    /// Splits the path into the parent directory's inode and the name of the last component.
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(u32, &'a str), Error> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rfind('/') {
+            Some(idx) => (&path[..idx], &path[idx + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return Err(Error::EINVAL);
+        }
+        Ok((self.resolve(parent)?, name))
+    }
+
+}
+
+impl FileSystem for LeafFs {
+    fn name(&self) -> &'static str {
+        "leaffs"
+    }
+
+    fn source(&self) -> &str {
+        &self.device
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, Error> {
+        let ino = self.resolve(path)?;
+        let inode = self.read_inode(ino)?;
+        Ok(Metadata {
+            kind: inode.file_kind(),
+            size: inode.size,
+            inode: ino as u64,
+            mtime: inode.mtime,
+            device: None,
+        })
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let inode = self.read_inode(self.resolve(path)?)?;
+        if inode.kind != KIND_FILE {
+            return Err(Error::EISDIR);
+        }
+        self.read_data(&inode, offset, buf)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        if inode.kind != KIND_FILE {
+            return Err(Error::EISDIR);
+        }
+        self.write_data(ino, &mut inode, offset, data)
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), Error> {
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        if inode.kind != KIND_FILE {
+            return Err(Error::EISDIR);
+        }
+        let blocks = (size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+        let shrinks = size < inode.size;
+        if shrinks {
+            self.shrink(&mut inode, blocks)?;
+            // the tail of the last block has to read back as zeroes if the file grows again
+            if size % BLOCK_SIZE as u64 != 0 {
+                let block = inode.block_for(size / BLOCK_SIZE as u64).ok_or(Error::EIO)?;
+                let mut data = self.read_block(block)?;
+                data[(size % BLOCK_SIZE as u64) as usize..].fill(0);
+                self.write_block(block, &data)?;
+            }
+        } else {
+            // newly allocated blocks are zeroed already
+            self.grow(&mut inode, blocks)?;
+        }
+        inode.size = size;
+        inode.mtime = time::monotonic_us() / 1_000_000;
+        if shrinks {
+            self.write_inode_through(ino, &inode)
+        } else {
+            self.write_inode(ino, &inode)
+        }
+    }
+
+    fn create(&mut self, path: &str, kind: FileKind) -> Result<(), Error> {
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::ENAMETOOLONG);
+        }
+        let mut parent = self.read_inode(parent_ino)?;
+        let entries = self.dir_entries(&parent)?;
+        if entries.iter().any(|(_, entry)| entry.name == name) {
+            return Err(Error::EEXIST);
+        }
+        let kind = match kind {
+            FileKind::File => KIND_FILE,
+            FileKind::Directory => KIND_DIR,
+            // FIXME: Support device nodes once devices can be looked up by their id
+            FileKind::BlockDevice | FileKind::CharDevice => return Err(Error::EINVAL),
+        };
+        let ino = self.alloc_inode(kind)?;
+        let entry = Dirent {
+            inode: ino,
+            kind,
+            name: String::from(name),
+        };
+        // reuse the first free slot, if there is none the directory grows by one entry
+        let used_slots = parent.size as usize / DIRENT_SIZE;
+        let slot = (0..used_slots)
+            .find(|slot| !entries.iter().any(|(used, _)| used == slot))
+            .unwrap_or(used_slots);
+        if let Err(err) = self.write_data(parent_ino, &mut parent, (slot * DIRENT_SIZE) as u64, &entry.encode()) {
+            self.free_inode(ino)?;
+            return Err(err);
+        }
+        // replaces the negative entry from looking the name up before
+        self.dcache.lock().insert(parent_ino as u64, name, Some(ino as u64));
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), Error> {
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        let mut parent = self.read_inode(parent_ino)?;
+        let (slot, entry) = self.lookup(&parent, name)?.ok_or(Error::ENOENT)?;
+        let mut inode = self.read_inode(entry.inode)?;
+        if inode.kind == KIND_DIR && !self.dir_entries(&inode)?.is_empty() {
+            return Err(Error::ENOTEMPTY);
+        }
+        // the entry has to go before the directory changes, even if the write fails halfway
+        let mut dcache = self.dcache.lock();
+        dcache.invalidate(parent_ino as u64, name);
+        dcache.invalidate_dir(entry.inode as u64);
+        drop(dcache);
+        self.write_data(parent_ino, &mut parent, (slot * DIRENT_SIZE) as u64, &[0; DIRENT_SIZE])?;
+        self.shrink(&mut inode, 0)?;
+        self.free_inode(entry.inode)?;
+        if inode.xattr_block != 0 {
+            self.set_block_used(inode.xattr_block as u64, false)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        let dir = self.read_inode(self.resolve(path)?)?;
+        Ok(self.dir_entries(&dir)?.into_iter().map(|(_, entry)| DirEntry {
+            name: entry.name,
+            kind: if entry.kind == KIND_DIR { FileKind::Directory } else { FileKind::File },
+        }).collect())
+    }
+
+    fn get_xattr(&mut self, path: &str, name: &str) -> Result<Vec<u8>, Error> {
+        let inode = self.read_inode(self.resolve(path)?)?;
+        self.read_xattrs(&inode)?.into_iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value)
+            .ok_or(Error::ENODATA)
+    }
+
+    fn set_xattr(&mut self, path: &str, name: &str, value: Option<&[u8]>) -> Result<(), Error> {
+        let ino = self.resolve(path)?;
+        let mut inode = self.read_inode(ino)?;
+        let mut attrs = self.read_xattrs(&inode)?;
+        let existing = attrs.iter().position(|(attr, _)| attr == name);
+        match (existing, value) {
+            (Some(idx), Some(value)) => attrs[idx].1 = value.to_vec(),
+            (None, Some(value)) => attrs.push((String::from(name), value.to_vec())),
+            (Some(idx), None) => {
+                attrs.remove(idx);
+            },
+            (None, None) => return Err(Error::ENODATA),
+        }
+        self.write_xattrs(ino, &mut inode, &attrs)
+    }
+
+    fn list_xattr(&mut self, path: &str) -> Result<Vec<String>, Error> {
+        let inode = self.read_inode(self.resolve(path)?)?;
+        Ok(self.read_xattrs(&inode)?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        let dirty = self.icache.lock().take_dirty();
+        // the inodes are ordered by their number, so the ones sharing a block are next to each other
+        let mut idx = 0;
+        while idx < dirty.len() {
+            let table_block = dirty[idx].0 / INODES_PER_BLOCK as u64;
+            let end = idx + dirty[idx..].iter()
+                .take_while(|(ino, _)| *ino / INODES_PER_BLOCK as u64 == table_block)
+                .count();
+            let block = self.sb.inode_start + table_block;
+            let result = self.read_block(block).and_then(|mut data| {
+                for (ino, inode) in dirty[idx..end].iter() {
+                    let offset = (*ino as usize % INODES_PER_BLOCK) * INODE_SIZE;
+                    inode.encode(&mut data[offset..offset + INODE_SIZE]);
+                }
+                self.write_block(block, &data)
+            });
+            if let Err(err) = result {
+                let mut icache = self.icache.lock();
+                for (ino, _) in dirty[idx..].iter() {
+                    icache.mark_dirty(*ino);
+                }
+                return Err(err);
+            }
+            idx = end;
+            scheduler::cond_resched();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LeafFs {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            log_warn!("{}: failed to write back inodes: {}", self.device, err);
+        }
+    }
+}
+
+#[test_case]
+fn test_superblock_checksum() {
+    let sb = Superblock {
+        block_count: 256,
+        inode_count: 32,
+        bitmap_start: 1,
+        bitmap_blocks: 1,
+        inode_start: 2,
+        inode_blocks: 8,
+        data_start: 10,
+        mount_count: 3,
+    };
+    let mut raw = sb.encode();
+    crate::kassert_eq!(Superblock::decode(&raw).map(|sb| sb.mount_count), Ok(3));
+    raw[20] ^= 1;
+    crate::kassert_eq!(Superblock::decode(&raw).map(|sb| sb.mount_count), Err(Error::EIO));
+    raw[8] = 2;
+    crate::kassert_eq!(Superblock::decode(&raw).map(|sb| sb.mount_count), Err(Error::EINVAL));
+}
+
+/// Formats and mounts a ram disk for a test, the device is unregistered afterwards.
+#[cfg(test)]
+fn with_test_fs(test: impl FnOnce(&mut LeafFs, &crate::drivers::ramdisk::Faults)) {
+    let (device, faults) = crate::drivers::ramdisk::create_test_disk(64 * 1024);
+    format(&device).unwrap();
+    let mut fs = LeafFs::mount(&device).unwrap();
+    test(&mut fs, &faults);
+    drop(fs);
+    block::unregister(&device).unwrap();
+}
+
+/// Writes everything back and mounts the device again, as if the system was rebooted.
+#[cfg(test)]
+fn remount(fs: &mut LeafFs) {
+    fs.sync().unwrap();
+    let device = fs.device.clone();
+    *fs = LeafFs::mount(&device).unwrap();
+}
+
+#[test_case]
+fn test_files_on_ram_disk() {
+    with_test_fs(|fs, _faults| {
+        fs.create("/dir", FileKind::Directory).unwrap();
+        fs.create("/dir/file", FileKind::File).unwrap();
+        crate::kassert_eq!(fs.create("/dir/file", FileKind::File), Err(Error::EEXIST));
+        // spans more than one block
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 100).map(|idx| idx as u8).collect();
+        crate::kassert_eq!(fs.write("/dir/file", 0, &data), Ok(data.len()));
+
+        // everything has to survive a remount
+        remount(fs);
+        crate::kassert_eq!(fs.stat("/dir/file").map(|meta| meta.size), Ok(data.len() as u64));
+        let mut buf = vec![0; data.len()];
+        crate::kassert_eq!(fs.read("/dir/file", 0, &mut buf), Ok(data.len()));
+        crate::kassert_eq!(buf, data);
+        crate::kassert_eq!(fs.remove("/dir"), Err(Error::ENOTEMPTY));
+        fs.remove("/dir/file").unwrap();
+        fs.remove("/dir").unwrap();
+        crate::kassert!(fs.read_dir("/").unwrap().is_empty());
+    });
+}
+
+#[test_case]
+fn test_inode_writeback() {
+    with_test_fs(|fs, faults| {
+        fs.create("/file", FileKind::File).unwrap();
+        let ino = fs.stat("/file").unwrap().inode as u32;
+        fs.write("/file", 0, b"leaf").unwrap();
+        // the new size is only in memory until the inode gets written back
+        crate::kassert_eq!(fs.stat("/file").map(|meta| meta.size), Ok(4));
+        crate::kassert_eq!(fs.load_inode(ino).map(|inode| inode.size), Ok(0));
+        faults.fail_write_after(0);
+        crate::kassert_eq!(fs.sync(), Err(Error::EIO));
+        faults.clear();
+        // the failed writeback is retried
+        fs.sync().unwrap();
+        crate::kassert_eq!(fs.load_inode(ino).map(|inode| inode.size), Ok(4));
+        // shrinking frees blocks, so it can't wait for the writeback
+        fs.truncate("/file", 0).unwrap();
+        crate::kassert_eq!(fs.load_inode(ino).map(|inode| inode.size), Ok(0));
+    });
+}
+
+#[test_case]
+fn test_extent_errors() {
+    with_test_fs(|fs, _faults| {
+        let free_blocks = |fs: &LeafFs| (fs.sb.data_start..fs.sb.block_count).filter(|block| !fs.is_block_used(*block)).count();
+        let first_free = (fs.sb.data_start..fs.sb.block_count).find(|block| !fs.is_block_used(*block)).unwrap();
+        // the second allocation can't continue the extent of the first one
+        fs.set_block_used(first_free + 1, true).unwrap();
+        let free = free_blocks(fs);
+        let mut inode = Inode::new(KIND_FILE);
+        inode.extents = vec![Extent { start: fs.sb.block_count as u32 - 1, len: 1 }; MAX_EXTENTS - 1];
+        crate::kassert_eq!(fs.grow(&mut inode, MAX_EXTENTS as u64 + 1), Err(Error::EFBIG));
+        crate::kassert_eq!(free_blocks(fs), free);
+        crate::kassert_eq!(inode.extents.len(), MAX_EXTENTS - 1);
+
+        // corrupted extents
+        inode.extents = vec![Extent { start: 0, len: 1 }];
+        crate::kassert_eq!(fs.shrink(&mut inode, 0), Err(Error::EIO));
+        inode.extents = vec![Extent { start: first_free as u32, len: 1 }, Extent { start: first_free as u32, len: 0 }];
+        crate::kassert_eq!(fs.shrink(&mut inode, 0), Err(Error::EIO));
+        crate::kassert_eq!(free_blocks(fs), free);
+    });
+}
+
+#[test_case]
+fn test_xattrs_on_ram_disk() {
+    with_test_fs(|fs, _faults| {
+        fs.create("/file", FileKind::File).unwrap();
+        crate::kassert_eq!(fs.get_xattr("/file", "user.origin"), Err(Error::ENODATA));
+        fs.set_xattr("/file", "user.origin", Some(b"net")).unwrap();
+        fs.set_xattr("/file", "security.cap_root", Some(&[1])).unwrap();
+        fs.set_xattr("/file", "user.origin", Some(b"disk")).unwrap();
+        crate::kassert_eq!(fs.set_xattr("/file", "user.big", Some(&[0; BLOCK_SIZE])), Err(Error::ENOSPC));
+
+        remount(fs);
+        crate::kassert_eq!(fs.get_xattr("/file", "user.origin"), Ok(b"disk".to_vec()));
+        crate::kassert_eq!(fs.list_xattr("/file").unwrap().len(), 2);
+        fs.set_xattr("/file", "user.origin", None).unwrap();
+        fs.set_xattr("/file", "security.cap_root", None).unwrap();
+        crate::kassert_eq!(fs.set_xattr("/file", "user.origin", None), Err(Error::ENODATA));
+        crate::kassert!(fs.list_xattr("/file").unwrap().is_empty());
+    });
+}
+
+#[test_case]
+fn test_io_errors_on_ram_disk() {
+    with_test_fs(|fs, faults| {
+        let device = fs.device.clone();
+        // a superblock which couldn't be read doesn't make the device mountable
+        faults.fail_read_after(0);
+        crate::kassert_eq!(LeafFs::mount(&device).err(), Some(Error::EIO));
+        faults.clear();
+
+        fs.create("/file", FileKind::File).unwrap();
+        fs.write("/file", 0, b"leaf").unwrap();
+        let mut buf = [0; 4];
+        faults.short_read_after(0);
+        crate::kassert_eq!(fs.read("/file", 0, &mut buf), Err(Error::EIO));
+        faults.fail_write_after(0);
+        crate::kassert_eq!(fs.write("/file", 0, b"tree"), Err(Error::EIO));
+        faults.clear();
+        crate::kassert_eq!(fs.read("/file", 0, &mut buf), Ok(4));
+        crate::kassert_eq!(&buf, b"leaf");
+    });
+}
+
+#[test_case]
+fn test_format_errors_on_ram_disk() {
+    let (device, faults) = crate::drivers::ramdisk::create_test_disk(64 * 1024);
+    // a failed write while formatting is reported
+    faults.fail_write_after(3);
+    crate::kassert_eq!(format(&device), Err(Error::EIO));
+    faults.clear();
+    crate::kassert_eq!(format(&device), Ok(()));
+    block::unregister(&device).unwrap();
+}
@@ -0,0 +1,375 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use leafos_abi::fs::{DT_BLK, DT_CHR, DT_DIR, DT_REG, S_IFBLK, S_IFCHR, S_IFDIR, S_IFREG, Stat, StatV2, XATTR_NAME_MAX};
+use crate::drivers::registry::DeviceId;
+use crate::error_codes::Error;
+use crate::{log_warn, scheduler, workqueue};
+
+pub mod dcache;
+pub mod icache;
+pub mod devfs;
+pub mod file;
+pub mod flock;
+pub mod leaffs;
+pub mod procfs;
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(vec![]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    BlockDevice,
+    CharDevice,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub size: u64,
+    pub inode: u64,
+    pub mtime: u64,
+    /// The device a device file represents
+    pub device: Option<DeviceId>,
+}
+
+impl FileKind {
+
+    /// The type of a directory entry as seen by userspace
+    pub fn dirent_type(&self) -> u8 {
+        match self {
+            FileKind::File => DT_REG,
+            FileKind::Directory => DT_DIR,
+            FileKind::BlockDevice => DT_BLK,
+            FileKind::CharDevice => DT_CHR,
+        }
+    }
+
+}
+
+impl Metadata {
+
+    /// Converts the metadata into the layout userspace expects.
+    pub fn to_stat(&self) -> Stat {
+        const STAT_BLOCK_SIZE: u64 = 512;
+        let kind = match self.kind {
+            FileKind::File => S_IFREG,
+            FileKind::Directory => S_IFDIR,
+            FileKind::BlockDevice => S_IFBLK,
+            FileKind::CharDevice => S_IFCHR,
+        };
+        Stat {
+            ino: self.inode,
+            // FIXME: Store permissions once we have users
+            mode: kind | 0o755,
+            nlink: 1,
+            size: self.size,
+            blksize: STAT_BLOCK_SIZE,
+            blocks: (self.size + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE,
+            mtime: self.mtime,
+        }
+    }
+
+    /// Like `to_stat`, but including the device id of device files.
+    pub fn to_stat_v2(&self) -> StatV2 {
+        StatV2 {
+            stat: self.to_stat(),
+            rdev: self.device.map_or(0, |device| device.as_u64()),
+        }
+    }
+
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileKind,
+}
+
+/// All paths passed to a filesystem are absolute paths relative to the filesystem's root.
+pub trait FileSystem: Send {
+
+    fn name(&self) -> &'static str;
+
+    /// The device this filesystem lives on
+    fn source(&self) -> &str;
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, Error>;
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
+
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, Error>;
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), Error>;
+
+    fn create(&mut self, path: &str, kind: FileKind) -> Result<(), Error>;
+
+    fn remove(&mut self, path: &str) -> Result<(), Error>;
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error>;
+
+    /// Returns the value of the file's extended attribute, `ENODATA` if it isn't set.
+    // FIXME: Keep the attributes in memory for a tmpfs once we have one, only leaffs stores them so far
+    fn get_xattr(&mut self, _path: &str, _name: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::ENOTSUP)
+    }
+
+    /// Sets or, if `value` is `None`, removes the file's extended attribute.
+    fn set_xattr(&mut self, _path: &str, _name: &str, _value: Option<&[u8]>) -> Result<(), Error> {
+        Err(Error::ENOTSUP)
+    }
+
+    /// Returns the names of the file's extended attributes.
+    fn list_xattr(&mut self, _path: &str) -> Result<Vec<String>, Error> {
+        Err(Error::ENOTSUP)
+    }
+
+    /// Writes all cached data back to the underlying device.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+}
+
+struct Mount {
+    path: String,
+    fs: Box<dyn FileSystem>,
+}
+
+/// Resolves `.` and `..` components and duplicate slashes, relative paths are treated as relative to `/`.
+pub fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = vec![];
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                components.pop();
+            },
+            component => components.push(component),
+        }
+    }
+    let mut ret = String::from("/");
+    ret.push_str(&components.join("/"));
+    ret
+}
+
+fn is_below(path: &str, mount_point: &str) -> bool {
+    mount_point == "/" || path == mount_point ||
+        (path.starts_with(mount_point) && path.as_bytes()[mount_point.len()] == b'/')
+}
+
+/// Calls `f` with the filesystem responsible for `path` and the path relative to that filesystem's root.
+fn with_fs<R>(path: &str, f: impl FnOnce(&mut dyn FileSystem, &str) -> Result<R, Error>) -> Result<R, Error> {
+    let path = normalize(path);
+    // a syscall which gets switched out at a preemption point while holding the mount table would
+    // leave the next syscall (which runs with interrupts disabled) spinning on it forever
+    // FIXME: Let the filesystems be preempted once the mount table is a lock tasks can sleep on
+    let _guard = scheduler::preempt_disable();
+    let mut mounts = MOUNTS.lock();
+    let mount = mounts.iter_mut()
+        .filter(|mount| is_below(&path, &mount.path))
+        .max_by_key(|mount| mount.path.len())
+        .ok_or(Error::ENOENT)?;
+    let relative = if mount.path == "/" {
+        path.as_str()
+    } else if path.len() == mount.path.len() {
+        "/"
+    } else {
+        &path[mount.path.len()..]
+    };
+    f(mount.fs.as_mut(), relative)
+}
+
+pub fn mount(path: &str, fs: Box<dyn FileSystem>) -> Result<(), Error> {
+    let path = normalize(path);
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(Error::EBUSY);
+    }
+    mounts.push(Mount {
+        path,
+        fs,
+    });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<(), Error> {
+    let path = normalize(path);
+    // syncing reaches preemption points, see `with_fs`
+    let _guard = scheduler::preempt_disable();
+    let mut mounts = MOUNTS.lock();
+    let idx = mounts.iter().position(|mount| mount.path == path).ok_or(Error::EINVAL)?;
+    // refuse to unmount filesystems which have other filesystems mounted below them
+    if mounts.iter().any(|mount| mount.path != path && is_below(&mount.path, &path)) {
+        return Err(Error::EBUSY);
+    }
+    let mut mount = mounts.remove(idx);
+    mount.fs.sync()
+}
+
+/// Returns whether a filesystem living on the given device is mounted.
+pub fn is_source_mounted(source: &str) -> bool {
+    MOUNTS.lock().iter().any(|mount| mount.fs.source() == source)
+}
+
+/// Calls `f` for every mount with its mount point, the filesystem's name and its source.
+pub fn for_each_mount(mut f: impl FnMut(&str, &str, &str)) {
+    for mount in MOUNTS.lock().iter() {
+        f(&mount.path, mount.fs.name(), mount.fs.source());
+    }
+}
+
+pub fn stat(path: &str) -> Result<Metadata, Error> {
+    with_fs(path, |fs, path| fs.stat(path))
+}
+
+// FIXME: Reads and writes go straight to the filesystems, there is no page cache yet. Once there
+// is one, it should register a shrinker with the frame allocator which drops clean pages, least
+// recently used first, when free frames fall below a watermark and count the reclaimed pages.
+// This needs the frame allocator to free frames and to report its free count first.
+pub fn read(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    with_fs(path, |fs, path| fs.read(path, offset, buf))
+}
+
+pub fn write(path: &str, offset: u64, data: &[u8]) -> Result<usize, Error> {
+    with_fs(path, |fs, path| fs.write(path, offset, data))
+}
+
+pub fn truncate(path: &str, size: u64) -> Result<(), Error> {
+    with_fs(path, |fs, path| fs.truncate(path, size))
+}
+
+pub fn create(path: &str, kind: FileKind) -> Result<(), Error> {
+    with_fs(path, |fs, path| fs.create(path, kind))
+}
+
+pub fn remove(path: &str) -> Result<(), Error> {
+    with_fs(path, |fs, path| {
+        if path == "/" {
+            // this is a mount point
+            return Err(Error::EBUSY);
+        }
+        fs.remove(path)
+    })
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Error> {
+    with_fs(path, |fs, path| fs.read_dir(path))
+}
+
+fn check_xattr_name(name: &str) -> Result<(), Error> {
+    if name.len() > XATTR_NAME_MAX {
+        return Err(Error::ENAMETOOLONG);
+    }
+    let attr = name.strip_prefix("user.").or_else(|| name.strip_prefix("security."));
+    match attr {
+        Some(attr) if !attr.is_empty() => Ok(()),
+        Some(_) => Err(Error::EINVAL),
+        None => Err(Error::ENOTSUP),
+    }
+}
+
+pub fn get_xattr(path: &str, name: &str) -> Result<Vec<u8>, Error> {
+    check_xattr_name(name)?;
+    with_fs(path, |fs, path| fs.get_xattr(path, name))
+}
+
+// FIXME: Check the credentials of the caller instead of kernel ownership once we have users
+pub fn set_xattr(path: &str, name: &str, value: Option<&[u8]>) -> Result<(), Error> {
+    check_xattr_name(name)?;
+    if name.starts_with("security.") && !scheduler::current_is_kernel_owned() {
+        return Err(Error::EPERM);
+    }
+    with_fs(path, |fs, path| fs.set_xattr(path, name, value))
+}
+
+pub fn list_xattr(path: &str) -> Result<Vec<String>, Error> {
+    with_fs(path, |fs, path| fs.list_xattr(path))
+}
+
+/// Reads the whole file into memory.
+pub fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    with_fs(path, |fs, path| {
+        let metadata = fs.stat(path)?;
+        if metadata.kind != FileKind::File {
+            return Err(Error::EISDIR);
+        }
+        let mut data = vec![0; metadata.size as usize];
+        let read = fs.read(path, 0, &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    })
+}
+
+/// Writes `data` to the file at `path`, creating it if it doesn't exist yet.
+/// The file's previous contents are replaced unless `append` is set.
+pub fn write_file(path: &str, data: &[u8], append: bool) -> Result<(), Error> {
+    with_fs(path, |fs, path| {
+        let offset = match fs.stat(path) {
+            Ok(metadata) => {
+                if metadata.kind != FileKind::File {
+                    return Err(Error::EISDIR);
+                }
+                if append {
+                    metadata.size
+                } else {
+                    fs.truncate(path, 0)?;
+                    0
+                }
+            },
+            Err(Error::ENOENT) => {
+                fs.create(path, FileKind::File)?;
+                0
+            },
+            Err(err) => return Err(err),
+        };
+        fs.write(path, offset, data)?;
+        Ok(())
+    })
+}
+
+pub fn sync_all() -> Result<(), Error> {
+    // syncing reaches preemption points, which must not switch tasks while the mount table is held
+    let _guard = scheduler::preempt_disable();
+    let mut result = Ok(());
+    for mount in MOUNTS.lock().iter_mut() {
+        if let Err(err) = mount.fs.sync() {
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// How often dirty metadata gets written back by the periodic writeback
+pub const WRITEBACK_INTERVAL_US: u64 = 5_000_000;
+
+/// Starts writing back the cached data of all filesystems every `WRITEBACK_INTERVAL_US`,
+/// this runs on the workqueue.
+pub fn start_periodic_writeback() {
+    workqueue::queue_delayed(WRITEBACK_INTERVAL_US, || {
+        if let Err(err) = sync_all() {
+            log_warn!("periodic writeback failed: {}", err);
+        }
+        start_periodic_writeback();
+    });
+}
+
+/// Like `sync_all` but returns `None` instead of waiting if the mount table is locked.
+pub fn try_sync_all() -> Option<Result<(), Error>> {
+    // this gets called from interrupt context, where a preemption point must never enable interrupts
+    let _guard = scheduler::preempt_disable();
+    let mut mounts = MOUNTS.try_lock()?;
+    let mut result = Ok(());
+    for mount in mounts.iter_mut() {
+        if let Err(err) = mount.fs.sync() {
+            result = Err(err);
+        }
+    }
+    Some(result)
+}
@@ -0,0 +1,293 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use x86_64::VirtAddr;
+use crate::error_codes::Error;
+use crate::filesystem::{DirEntry, FileKind, FileSystem, Metadata};
+use crate::{cpustat, exceptions, irq, memory, scheduler};
+use crate::irq::CpuMask;
+
+/// A virtual filesystem exposing information about the running processes, its files
+/// are generated whenever they are read.
+///
+/// /<pid>/smaps           memory breakdown of every memory area of the process
+/// /<pid>/stat            "<pid> <utime> <stime>", the cpu time spent in user and kernel mode in `USER_HZ` ticks
+/// /stat                  "cpu  <user> <nice> <system> <idle>" summed up over all tasks like linux' /proc/stat
+///                        followed by "cpu<n> <busy> <idle>" for every cpu, measured with the tsc in the idle loop
+/// /exceptions            number of exceptions raised in kernel and in user mode by vector
+/// /irq/<n>/smp_affinity   hex mask of the cpus which handle the interrupt, this is writable
+pub struct ProcFs;
+
+/// The unit of all cpu times, one tick is 10ms
+pub const USER_HZ: u64 = 100;
+
+fn ns_to_ticks(ns: u64) -> u64 {
+    ns / (1_000_000_000 / USER_HZ)
+}
+
+enum Node {
+    Root,
+    Process(u64),
+    Smaps(u64),
+    Stat(u64),
+    CpuStat,
+    Exceptions,
+    Interrupts,
+    IrqDir,
+    Irq(u8),
+    IrqAffinity(u8),
+}
+
+impl ProcFs {
+
+    fn resolve(path: &str) -> Result<Node, Error> {
+        let mut components = path.split('/').filter(|component| !component.is_empty());
+        let pid = match components.next() {
+            None => return Ok(Node::Root),
+            Some("irq") => return Self::resolve_irq(components),
+            Some("stat") => {
+                if components.next().is_some() {
+                    return Err(Error::ENOTDIR);
+                }
+                return Ok(Node::CpuStat);
+            },
+            Some("exceptions") => {
+                if components.next().is_some() {
+                    return Err(Error::ENOTDIR);
+                }
+                return Ok(Node::Exceptions);
+            },
+            Some("interrupts") => {
+                if components.next().is_some() {
+                    return Err(Error::ENOTDIR);
+                }
+                return Ok(Node::Interrupts);
+            },
+            Some(pid) => pid.parse::<u64>().map_err(|_| Error::ENOENT)?,
+        };
+        if scheduler::process_vmas(pid).is_none() {
+            return Err(Error::ENOENT);
+        }
+        let node = match components.next() {
+            None => Node::Process(pid),
+            Some("smaps") => Node::Smaps(pid),
+            Some("stat") => Node::Stat(pid),
+            Some(_) => return Err(Error::ENOENT),
+        };
+        if components.next().is_some() {
+            return Err(Error::ENOTDIR);
+        }
+        Ok(node)
+    }
+
+    fn resolve_irq<'a>(mut components: impl Iterator<Item = &'a str>) -> Result<Node, Error> {
+        let irq = match components.next() {
+            None => return Ok(Node::IrqDir),
+            Some(irq) => irq.parse::<u8>().map_err(|_| Error::ENOENT)?,
+        };
+        if irq::affinity(irq).is_none() {
+            return Err(Error::ENOENT);
+        }
+        let node = match components.next() {
+            None => Node::Irq(irq),
+            Some("smp_affinity") => Node::IrqAffinity(irq),
+            Some(_) => return Err(Error::ENOENT),
+        };
+        if components.next().is_some() {
+            return Err(Error::ENOTDIR);
+        }
+        Ok(node)
+    }
+
+    fn contents(node: &Node) -> Result<String, Error> {
+        match node {
+            Node::Smaps(pid) => smaps(*pid).ok_or(Error::ENOENT),
+            Node::Stat(pid) => scheduler::process_cpu_time(*pid)
+                .map(|time| format!("{} {} {}\n", pid, ns_to_ticks(time.user_ns), ns_to_ticks(time.system_ns)))
+                .ok_or(Error::ENOENT),
+            Node::CpuStat => {
+                let (time, idle_ns) = scheduler::total_cpu_time();
+                let mut out = format!("cpu  {} 0 {} {}\n", ns_to_ticks(time.user_ns), ns_to_ticks(time.system_ns), ns_to_ticks(idle_ns));
+                cpustat::for_each_cpu(|cpu, busy_ns, idle_ns| {
+                    let _ = writeln!(out, "cpu{} {} {}", cpu, ns_to_ticks(busy_ns), ns_to_ticks(idle_ns));
+                });
+                Ok(out)
+            },
+            Node::Exceptions => {
+                let mut out = String::new();
+                let _ = writeln!(out, "{:>6} {:<28} {:>10} {:>10}", "vector", "name", "kernel", "user");
+                exceptions::for_each_exception(|vector, name, kernel, user| {
+                    let _ = writeln!(out, "{:>6} {:<28} {:>10} {:>10}", vector, name, kernel, user);
+                });
+                Ok(out)
+            },
+            Node::Interrupts => {
+                let mut out = String::from("    ");
+                for cpu in 0..irq::online_cpus() {
+                    let _ = write!(out, " {:>10}", format!("CPU{}", cpu));
+                }
+                out.push('\n');
+                irq::for_each_irq(|line| {
+                    let _ = write!(out, "{:>3}:", line.irq);
+                    irq::for_each_cpu_count(line.irq, |_, count| {
+                        let _ = write!(out, " {:>10}", count);
+                    });
+                    let _ = writeln!(out, "  {}", line.name);
+                });
+                Ok(out)
+            },
+            Node::IrqAffinity(irq) => irq::affinity(*irq)
+                .map(|mask| format!("{:x}\n", mask.0))
+                .ok_or(Error::ENOENT),
+            _ => Err(Error::EISDIR),
+        }
+    }
+
+}
+
+/// Formats the memory breakdown of the process in the format of linux' /proc/<pid>/smaps.
+pub fn smaps(pid: u64) -> Option<String> {
+    let vmas = scheduler::process_vmas(pid)?;
+    let mut out = String::new();
+    for vma in vmas.iter() {
+        let stats = memory::memory_stats(VirtAddr::new(vma.start), VirtAddr::new(vma.end));
+        let _ = writeln!(out, "{:016x}-{:016x} r{}{}p {}", vma.start, vma.end,
+                         if vma.writable { 'w' } else { '-' },
+                         if vma.executable { 'x' } else { '-' }, vma.name);
+        let _ = writeln!(out, "Size:           {:>8} kB", stats.size / 1024);
+        let _ = writeln!(out, "Rss:            {:>8} kB", stats.resident / 1024);
+        let _ = writeln!(out, "Shared:         {:>8} kB", stats.shared / 1024);
+        let _ = writeln!(out, "Private_Clean:  {:>8} kB", stats.private_clean / 1024);
+        let _ = writeln!(out, "Private_Dirty:  {:>8} kB", stats.private_dirty / 1024);
+        let _ = writeln!(out, "Swap:           {:>8} kB", stats.swap / 1024);
+    }
+    Some(out)
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn source(&self) -> &str {
+        "proc"
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Metadata, Error> {
+        let node = Self::resolve(path)?;
+        // process inodes are derived from their pid, so the irq inodes live in the upper half
+        const IRQ_INODES: u64 = 1 << 63;
+        let (kind, inode) = match node {
+            Node::Root => (FileKind::Directory, 1),
+            Node::Process(pid) => (FileKind::Directory, pid << 8),
+            Node::Smaps(pid) => (FileKind::File, (pid << 8) | 1),
+            Node::Stat(pid) => (FileKind::File, (pid << 8) | 2),
+            Node::CpuStat => (FileKind::File, 2),
+            Node::Exceptions => (FileKind::File, 3),
+            Node::Interrupts => (FileKind::File, 4),
+            Node::IrqDir => (FileKind::Directory, IRQ_INODES),
+            Node::Irq(irq) => (FileKind::Directory, IRQ_INODES | ((irq as u64) << 8)),
+            Node::IrqAffinity(irq) => (FileKind::File, IRQ_INODES | ((irq as u64) << 8) | 1),
+        };
+        let size = match kind {
+            FileKind::File => Self::contents(&node)?.len() as u64,
+            _ => 0,
+        };
+        Ok(Metadata {
+            kind,
+            size,
+            inode,
+            mtime: 0,
+            device: None,
+        })
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let contents = Self::contents(&Self::resolve(path)?)?;
+        let contents = contents.as_bytes();
+        if offset >= contents.len() as u64 {
+            return Ok(0);
+        }
+        let len = buf.len().min(contents.len() - offset as usize);
+        buf[..len].copy_from_slice(&contents[offset as usize..offset as usize + len]);
+        Ok(len)
+    }
+
+    fn write(&mut self, path: &str, _offset: u64, data: &[u8]) -> Result<usize, Error> {
+        match Self::resolve(path)? {
+            Node::IrqAffinity(irq) => {
+                let mask = core::str::from_utf8(data).map_err(|_| Error::EINVAL)?.trim();
+                let mask = u64::from_str_radix(mask.trim_start_matches("0x"), 16).map_err(|_| Error::EINVAL)?;
+                irq::set_affinity(irq, CpuMask(mask))?;
+                Ok(data.len())
+            },
+            _ => Err(Error::EROFS),
+        }
+    }
+
+    fn truncate(&mut self, path: &str, _size: u64) -> Result<(), Error> {
+        match Self::resolve(path)? {
+            // writing a file truncates it first, the content is replaced as a whole anyway
+            Node::IrqAffinity(_) => Ok(()),
+            _ => Err(Error::EROFS),
+        }
+    }
+
+    fn create(&mut self, _path: &str, _kind: FileKind) -> Result<(), Error> {
+        Err(Error::EROFS)
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), Error> {
+        Err(Error::EROFS)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        match Self::resolve(path)? {
+            Node::Root => {
+                let mut entries: Vec<DirEntry> = scheduler::process_ids().into_iter().map(|pid| DirEntry {
+                    name: format!("{}", pid),
+                    kind: FileKind::Directory,
+                }).collect();
+                entries.push(DirEntry {
+                    name: String::from("irq"),
+                    kind: FileKind::Directory,
+                });
+                entries.push(DirEntry {
+                    name: String::from("stat"),
+                    kind: FileKind::File,
+                });
+                entries.push(DirEntry {
+                    name: String::from("exceptions"),
+                    kind: FileKind::File,
+                });
+                entries.push(DirEntry {
+                    name: String::from("interrupts"),
+                    kind: FileKind::File,
+                });
+                Ok(entries)
+            },
+            Node::Process(_) => Ok(vec![DirEntry {
+                name: String::from("smaps"),
+                kind: FileKind::File,
+            }, DirEntry {
+                name: String::from("stat"),
+                kind: FileKind::File,
+            }]),
+            Node::IrqDir => {
+                let mut entries = vec![];
+                irq::for_each_irq(|line| entries.push(DirEntry {
+                    name: format!("{}", line.irq),
+                    kind: FileKind::Directory,
+                }));
+                Ok(entries)
+            },
+            Node::Irq(_) => Ok(vec![DirEntry {
+                name: String::from("smp_affinity"),
+                kind: FileKind::File,
+            }]),
+            Node::Smaps(_) | Node::Stat(_) | Node::CpuStat | Node::Exceptions | Node::Interrupts | Node::IrqAffinity(_) => Err(Error::ENOTDIR),
+        }
+    }
+}
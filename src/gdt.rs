@@ -1,4 +1,6 @@
-use core::ptr::addr_of_mut;
+use alloc::format;
+use alloc::string::String;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::instructions::tables::load_tss;
 // use x86::segmentation::Descriptor;
@@ -92,6 +94,41 @@ pub fn init() {
     }
 }
 
+/// Checks that the cpu uses the GDT and TSS set up by `init`, for the boot self-tests.
+pub(crate) fn verify() -> Result<(), String> {
+    let mut gdt = x86::dtables::DescriptorTablePointer::<u64>::default();
+    let tr = unsafe {
+        x86::dtables::sgdt(&mut gdt);
+        x86::task::tr()
+    };
+    if CS::get_reg() != GDT.1.kernel_code_selector {
+        return Err(String::from("cs doesn't hold the kernel code selector"));
+    }
+    if tr.index() != GDT.1.tss_selector.index() {
+        return Err(String::from("the task register doesn't hold the tss selector"));
+    }
+    // the pointer is packed, its fields can't be borrowed
+    let (base, limit) = (gdt.base, gdt.limit);
+    // the tss descriptor is a system descriptor, which spans two entries
+    let idx = GDT.1.tss_selector.index() as usize;
+    if (limit as usize + 1) < (idx + 2) * 8 {
+        return Err(String::from("the loaded gdt doesn't contain the tss descriptor"));
+    }
+    let (low, high) = unsafe { (*base.add(idx), *base.add(idx + 1)) };
+    let tss_base = ((low >> 16) & 0xff_ffff) | ((low >> 32) & 0xff00_0000) | (high << 32);
+    if tss_base != unsafe { addr_of!(TSS) } as u64 {
+        return Err(format!("the tss descriptor points to {:#x} instead of the tss", tss_base));
+    }
+    let tss = unsafe { &*addr_of!(TSS) };
+    if tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX].is_null() {
+        return Err(String::from("the tss has no double fault stack"));
+    }
+    if tss.privilege_stack_table[KERNEL_STACK_INDEX].is_null() {
+        return Err(String::from("the tss has no kernel stack"));
+    }
+    Ok(())
+}
+
 #[no_mangle]
 extern "C" fn tss_ptr() -> *mut TaskStateSegment {
     let mut tmp = unsafe { TSS };
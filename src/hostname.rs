@@ -0,0 +1,67 @@
+use alloc::string::String;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::{cmdline, log_info, log_warn};
+
+pub use leafos_abi::HOST_NAME_MAX;
+
+// The hostname is shown in the shell prompt and in panic messages. It comes from the `hostname`
+// setting of the settings store, `hostname=<name>` on the kernel command line overrides it for
+// the current boot. Changing it at runtime (e.g. through the syscall) isn't persistent, that's
+// what `setcfg hostname=<name>` is for.
+
+pub const DEFAULT_HOSTNAME: &str = "leafos";
+
+lazy_static! {
+    // the prompt gets drawn from the keyboard interrupt, so this is only locked with interrupts disabled
+    static ref HOSTNAME: Mutex<String> = Mutex::new(String::from(DEFAULT_HOSTNAME));
+}
+
+/// Applies `hostname=<name>` from the kernel command line, this has to be called after the
+/// settings were loaded.
+pub fn init() {
+    if let Some(name) = cmdline::get("hostname") {
+        if set(&name).is_err() {
+            log_warn!("ignoring the invalid hostname {:?} from the command line", name);
+        }
+    }
+}
+
+/// Hostnames consist of letters, digits, dots and dashes and don't start with a dash.
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty() && name.len() <= HOST_NAME_MAX && !name.starts_with('-') &&
+        name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.')
+}
+
+pub fn set(name: &str) -> Result<(), Error> {
+    if !is_valid(name) {
+        return Err(Error::EINVAL);
+    }
+    let old = without_interrupts(|| core::mem::replace(&mut *HOSTNAME.lock(), String::from(name)));
+    if old != name {
+        log_info!("hostname changed from {} to {}", old, name);
+    }
+    Ok(())
+}
+
+pub fn get() -> String {
+    without_interrupts(|| HOSTNAME.lock().clone())
+}
+
+/// Calls `f` with the hostname, fails instead of waiting if it is being changed right now. This
+/// doesn't allocate, for callers which may have interrupted the change or the heap (e.g. the
+/// panic handler).
+pub fn try_with<R>(f: impl FnOnce(&str) -> R) -> Option<R> {
+    HOSTNAME.try_lock().map(|name| f(&name))
+}
+
+#[test_case]
+fn test_hostname_validation() {
+    crate::kassert!(is_valid("leaf-01.local"));
+    crate::kassert!(!is_valid(""));
+    crate::kassert!(!is_valid("-leaf"));
+    crate::kassert!(!is_valid("leaf os"));
+    crate::kassert!(!is_valid(&"a".repeat(HOST_NAME_MAX + 1)));
+}
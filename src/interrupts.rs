@@ -1,21 +1,45 @@
+use alloc::format;
+use alloc::string::String;
 use core::arch::asm;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::{HandleControl, Keyboard, layouts, ScancodeSet1};
+use pc_keyboard::{HandleControl, Keyboard, ScancodeSet1};
 use pic8259::ChainedPics;
+use raw_cpuid::CpuId;
 use spin::Mutex;
 use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerDivide, TimerMode, xapic_base};
-use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{disable_interrupts, enable_interrupts, gdt, hlt_loop, println, wait_for_interrupt};
-use crate::drivers::{pic, pit};
+use crate::arch::without_interrupts;
+use crate::arch::x86::cpuid::has_cpuid;
+use crate::{gdt, println, wait_for_interrupt};
+use crate::drivers::{pic, pit, ps2, rtc};
 use crate::drivers::pit::PIT_DIVIDEND;
-use crate::events::KeyboardEvent;
-use crate::scheduler::SCHEDULER_TIMER_DELAY;
+use crate::events::{ConfiguredLayout, KeyboardEvent};
+use crate::{coredump, exceptions, irq, log_debug, log_warn, scheduler};
+use crate::coredump::Signal;
+use crate::time;
+use crate::power::WakeSource;
 
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
 static APIC_TIMER_FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+static TIMER_PERIOD_US: AtomicUsize = AtomicUsize::new(0);
+static TIMER_INITIAL_COUNT: AtomicUsize = AtomicUsize::new(0);
+static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
+static PIT_TICKS_PER_PERIOD: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// The one shot apic timer only gets rearmed at the end of its interrupt handler, the time spent
+// in between isn't covered by any period and the calibration against the pit is coarse. So the
+// monotonic clock is compared with the tsc every second, the time it lost gets added to it and
+// the apic timer frequency gets scaled so the following periods match the tsc.
+const DRIFT_CHECK_PERIOD_US: u64 = 1_000_000;
+/// Drift beyond this gets logged
+const DRIFT_WARN_PPM: u64 = 1000;
+/// The frequency changes by at most this much per check, so a single bad interval
+/// (e.g. because interrupts were disabled for a long time) doesn't throw it off
+const MAX_FREQUENCY_STEP_PERCENT: u64 = 10;
+static DRIFT_START_TSC_US: AtomicU64 = AtomicU64::new(0);
+static DRIFT_START_MONOTONIC_US: AtomicU64 = AtomicU64::new(0);
 
 pub fn init() {
     unsafe {
@@ -35,7 +59,9 @@ pub fn init() {
         IDT.x87_floating_point.set_handler_fn(x87_floating_point_handler);
         IDT.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
         IDT.virtualization.set_handler_fn(virtualization_handler);
-        // IDT.machine_check.set_handler_fn(machine_check_handler);
+        // the stack may be in any state when the machine check arrives, it's fatal like a double fault
+        IDT.machine_check.set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX as u16);
         IDT.debug.set_handler_fn(debug_handler);
         IDT.invalid_tss.set_handler_fn(invalid_tss_handler);
         IDT.page_fault.set_handler_fn(page_fault_handler);
@@ -50,9 +76,76 @@ pub fn init() {
         IDT[InterruptIndex::ApicTimer.as_usize()].set_handler_fn(apic_timer_config_handler);
         IDT[InterruptIndex::ApicError.as_usize()].set_handler_fn(apic_error_handler);
         IDT[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_handler);
+        IDT[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        IDT[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
         IDT[InterruptIndex::Syscall.as_usize()].set_handler_fn(syscall_handler);
     }
     unsafe { IDT.load(); }
+
+    let _ = irq::register(0, "timer", InterruptIndex::Timer.as_u8());
+    let _ = irq::register(ps2::KEYBOARD_IRQ, "keyboard", InterruptIndex::Keyboard.as_u8());
+    let _ = irq::register(rtc::IRQ, "rtc", InterruptIndex::Rtc.as_u8());
+    let _ = irq::register(ps2::MOUSE_IRQ, "mouse", InterruptIndex::Mouse.as_u8());
+    irq::balance();
+}
+
+/// The vectors whose handlers the boot self-tests expect in the IDT
+const SELFTEST_VECTORS: [u8; 9] = [
+    0, // divide error
+    3, // breakpoint
+    6, // invalid opcode
+    8, // double fault
+    13, // general protection fault
+    14, // page fault
+    InterruptIndex::Timer as u8,
+    InterruptIndex::Keyboard as u8,
+    InterruptIndex::Syscall as u8,
+];
+
+/// Checks that the cpu uses the IDT set up by `init` and that its handlers are present,
+/// for the boot self-tests.
+pub(crate) fn verify_idt() -> Result<(), String> {
+    let mut idt = x86::dtables::DescriptorTablePointer::<[u64; 2]>::default();
+    unsafe { x86::dtables::sidt(&mut idt); }
+    // the pointer is packed, its fields can't be borrowed
+    let (base, limit) = (idt.base, idt.limit);
+    if base as u64 != unsafe { core::ptr::addr_of!(IDT) } as u64 {
+        return Err(format!("the loaded idt is at {:p} instead of ours", base));
+    }
+    if limit as usize + 1 != 256 * 16 {
+        return Err(format!("the loaded idt has a limit of {:#x}", limit));
+    }
+    for vector in SELFTEST_VECTORS {
+        let [low, _] = unsafe { *base.add(vector as usize) };
+        // the present bit of the gate's options
+        if low & (1 << 47) == 0 {
+            return Err(format!("vector {} has no handler", vector));
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the cpu has a local apic we can use for the scheduler timer.
+pub fn has_apic() -> bool {
+    has_cpuid() && CpuId::new()
+        .get_feature_info()
+        .map_or(false, |features| features.has_apic())
+}
+
+/// Starts the scheduler timer, this uses the local apic if there is one and
+/// falls back to the legacy pic and pit otherwise.
+pub unsafe fn init_timer(physical_memory_offset: u64) {
+    if has_apic() {
+        init_apic(physical_memory_offset);
+        pit::init();
+    } else {
+        log_warn!("no local apic found, falling back to the pit for scheduling");
+        pit::init();
+        // the pic delivers the keyboard on irq 1 which collides with the apic timer's vector
+        IDT[PIC_1_OFFSET as usize + 1].set_handler_fn(keyboard_interrupt_handler);
+        IDT[InterruptIndex::Timer.as_usize()].set_handler_fn(pit_timer_handler);
+    }
+    start_timer_one_shot(scheduler::next_timer_period_us());
 }
 
 pub unsafe fn init_apic(physical_memory_offset: u64) {
@@ -82,7 +175,7 @@ pub unsafe fn init_apic(physical_memory_offset: u64) {
     }
 
     let end = pit::read_pit_count() as usize;
-    println!("pit end: {}", end);
+    log_debug!("apic timer calibration: pit end {}", end);
     let frequency = (TIMER_DELAY as usize) / ((TIMER_DELAY as usize) - end) * PIT_DIVIDEND;
     APIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
     // replace the IDT entry of the apic timer with a new one (for scheduling)
@@ -90,124 +183,158 @@ pub unsafe fn init_apic(physical_memory_offset: u64) {
 
 }
 
+/// Kills the current process if the exception was raised in user mode, the kernel can't recover
+/// from its own faults.
+fn kill_if_user(signal: Signal, stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment & 3 == 3 {
+        coredump::kill_faulting_process(signal, stack_frame);
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(3, &stack_frame);
+    kill_if_user(Signal::Trap, &stack_frame);
     panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn divide_error_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(0, &stack_frame);
+    kill_if_user(Signal::Fpe, &stack_frame);
     panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn debug_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(1, &stack_frame);
     panic!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn non_maskable_interrupt_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(2, &stack_frame);
     panic!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn overflow_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(4, &stack_frame);
     panic!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn bound_range_exceeded_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(5, &stack_frame);
     panic!("EXCEPTION: OOB\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(6, &stack_frame);
+    kill_if_user(Signal::Ill, &stack_frame);
     panic!("EXCEPTION: INVALID OP CODE\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn device_unavailable_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(7, &stack_frame);
     panic!("EXCEPTION: DEVICE UNAVAILABLE\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(10, &stack_frame);
     panic!("EXCEPTION: INVALID TSS\n{:#?}\nERROR CODE: {}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn alignment_check_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(17, &stack_frame);
+    kill_if_user(Signal::Bus, &stack_frame);
     panic!("EXCEPTION: ALIGNMENT ERROR\n{:#?}\nERROR CODE: {}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(11, &stack_frame);
     panic!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}\nERROR CODE: {}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(16, &stack_frame);
+    kill_if_user(Signal::Fpe, &stack_frame);
     panic!("EXCEPTION: X87 FLOATING POINT ERROR\n{:#?}", stack_frame);
 }
 
-/*
 extern "x86-interrupt" fn machine_check_handler(
-    stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame) -> !
 {
+    exceptions::record(18, &stack_frame);
     panic!("EXCEPTION: MACHINE CHECK ERROR\n{:#?}", stack_frame)
-}*/
+}
 
 extern "x86-interrupt" fn simd_floating_point_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(19, &stack_frame);
+    kill_if_user(Signal::Fpe, &stack_frame);
     panic!("EXCEPTION: SIMD FLOATING POINT ERROR\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn virtualization_handler(
     stack_frame: InterruptStackFrame)
 {
+    exceptions::record(20, &stack_frame);
     panic!("EXCEPTION: VIRTUALIZATION ERROR\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn vmm_communication_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(29, &stack_frame);
     panic!("EXCEPTION: VMM COMMUNICATION ERROR\n{:#?}\nERROR CODE: {}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn security_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(30, &stack_frame);
     panic!("EXCEPTION: SECURITY ERROR\n{:#?}\nERROR CODE: {}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn stack_segmentation_fault_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(12, &stack_frame);
     panic!("EXCEPTION: STACK SEGMENTATION FAULT\n{:#?}\nERROR CODE: {}", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    exceptions::record(13, &stack_frame);
+    kill_if_user(Signal::Segv, &stack_frame);
     panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nError code: {}\n", stack_frame, error_code);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, error_code: u64) -> !
 {
+    exceptions::record(8, &stack_frame);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\nError code: {}\n", stack_frame, error_code);
 }
 
@@ -217,11 +344,10 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    exceptions::record(14, &stack_frame);
+    kill_if_user(Signal::Segv, &stack_frame);
+
+    panic!("EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}\n", Cr2::read(), error_code, stack_frame);
 }
 
 extern "x86-interrupt" fn apic_timer_config_handler(
@@ -238,8 +364,122 @@ static TRIGGERED_ONCE: AtomicBool = AtomicBool::new(false);
 #[no_mangle]
 pub fn restart_apic() {
     unsafe { LAPIC.as_mut().unwrap().end_of_interrupt(); }
+    irq::account(0);
+
+    // the one shot timer expired, so the whole period elapsed
+    time::advance_monotonic(TIMER_PERIOD_US.load(Ordering::SeqCst) as u64);
+    correct_timer_drift();
 
-    start_timer_one_shot(SCHEDULER_TIMER_DELAY);
+    start_timer_one_shot(scheduler::next_timer_period_us());
+}
+
+/// Compares the monotonic clock with the tsc once a check period passed, see `DRIFT_CHECK_PERIOD_US`.
+fn correct_timer_drift() {
+    if time::tsc_hz() == 0 {
+        return;
+    }
+    let tsc = time::rdtsc_ns() / 1000;
+    let monotonic = time::monotonic_us();
+    let start_tsc = DRIFT_START_TSC_US.load(Ordering::Relaxed);
+    if start_tsc == 0 {
+        DRIFT_START_TSC_US.store(tsc, Ordering::Relaxed);
+        DRIFT_START_MONOTONIC_US.store(monotonic, Ordering::Relaxed);
+        return;
+    }
+    let monotonic_elapsed = monotonic.saturating_sub(DRIFT_START_MONOTONIC_US.load(Ordering::Relaxed));
+    if monotonic_elapsed < DRIFT_CHECK_PERIOD_US {
+        return;
+    }
+    let tsc_elapsed = tsc.saturating_sub(start_tsc).max(1);
+    let drift = tsc_elapsed as i64 - monotonic_elapsed as i64;
+    let drift_ppm = drift.unsigned_abs() * 1_000_000 / tsc_elapsed;
+    if drift_ppm > DRIFT_WARN_PPM {
+        log_warn!("the apic timer drifted by {}us within {}us ({}ppm), correcting it", drift, tsc_elapsed, drift_ppm);
+    }
+    // the monotonic clock must never go backwards, if it's ahead the longer periods let the tsc catch up
+    if drift > 0 {
+        time::advance_monotonic(drift as u64);
+    }
+    let frequency = APIC_TIMER_FREQUENCY.load(Ordering::Relaxed) as u64;
+    let scaled = (frequency as u128 * monotonic_elapsed as u128 / tsc_elapsed as u128) as u64;
+    let max_step = frequency * MAX_FREQUENCY_STEP_PERCENT / 100;
+    APIC_TIMER_FREQUENCY.store(scaled.clamp(frequency - max_step, frequency + max_step) as usize, Ordering::Relaxed);
+    DRIFT_START_TSC_US.store(tsc, Ordering::Relaxed);
+    DRIFT_START_MONOTONIC_US.store(time::monotonic_us(), Ordering::Relaxed);
+}
+
+// The task switch code is shared between the apic timer and the pit fallback
+
+macro_rules! save_registers {
+    () => {
+        concat!(
+        "push rax\n",
+        "push rbx\n",
+        "push rcx\n",
+        "push rdx\n",
+        "push rsi\n",
+        "push rdi\n",
+        "push r8\n",
+        "push r9\n",
+        "push r10\n",
+        "push r11\n",
+        "push r12\n",
+        "push r13\n",
+        "push r14\n",
+        "push r15\n",
+        "push rbp\n",
+        )
+    };
+}
+
+macro_rules! switch_task {
+    () => {
+        concat!(
+        "call current_task_ptr\n",
+        "mov [rax], rsp\n",
+
+        "call select_next_task\n",
+
+        "mov rsp, [rax]\n",
+        "mov rbx, [rax + 8]\n",
+
+        "push rbx\n",
+        "call tss_ptr\n",
+        "pop rbx\n",
+
+        "mov [rax + 4], rbx\n",
+        )
+    };
+}
+
+macro_rules! restore_registers {
+    () => {
+        concat!(
+        // "mov ax, (3 * 8) | 3\n", // ring 3 data with bottom 2 bits set for ring 3
+        "mov ax, (0 * 8) | 0\n", // ring 0 data
+        "mov ds, ax\n",
+        "mov es, ax\n",
+        "mov fs, ax\n",
+        "mov gs, ax\n", // SS is handled by iretq
+
+        "pop rbp\n",
+        "pop r15\n",
+        "pop r14\n",
+        "pop r13\n",
+        "pop r12\n",
+        "pop r11\n",
+        "pop r10\n",
+        "pop r9\n",
+        "pop r8\n",
+        "pop rdi\n",
+        "pop rsi\n",
+        "pop rdx\n",
+        "pop rcx\n",
+        "pop rbx\n",
+        "pop rax\n",
+        "iretq\n",
+        )
+    };
 }
 
 #[no_mangle]
@@ -247,62 +487,46 @@ pub fn restart_apic() {
 pub extern "x86-interrupt" fn apic_timer_handler(_interrupt_stack_frame: InterruptStackFrame) {
     unsafe {
         asm!(
-        "push rax",
-        "push rbx",
-        "push rcx",
-        "push rdx",
-        "push rsi",
-        "push rdi",
-        "push r8",
-        "push r9",
-        "push r10",
-        "push r11",
-        "push r12",
-        "push r13",
-        "push r14",
-        "push r15",
-        "push rbp",
-
+        save_registers!(),
         "call restart_apic",
+        switch_task!(),
+        restore_registers!(),
+        options(noreturn));
+    }
+}
 
-        "call current_task_ptr",
-        "mov [rax], rsp",
-
-        "call select_next_task",
-
-        "mov rsp, [rax]",
-        "mov rbx, [rax + 8]",
-
-        "push rbx",
-        "call tss_ptr",
-        "pop rbx",
-
-        "mov [rax + 4], rbx",
-
-
-        // "mov ax, (3 * 8) | 3", // ring 3 data with bottom 2 bits set for ring 3
-        "mov ax, (0 * 8) | 0", // ring 0 data
-        "mov ds, ax",
-        "mov es, ax",
-        "mov fs, ax",
-        "mov gs, ax", // SS is handled by iretq
-
-        "pop rbp",
-        "pop r15",
-        "pop r14",
-        "pop r13",
-        "pop r12",
-        "pop r11",
-        "pop r10",
-        "pop r9",
-        "pop r8",
-        "pop rdi",
-        "pop rsi",
-        "pop rdx",
-        "pop rcx",
-        "pop rbx",
-        "pop rax",
-        "iretq",
+/// Gets called on every tick of the pit fallback timer, returns whether the current
+/// scheduler period elapsed and the next task should be selected.
+#[no_mangle]
+extern "C" fn pit_tick() -> bool {
+    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()); }
+    time::advance_monotonic(pit::TICK_US as u64);
+    irq::account(0);
+    let ticks = PIT_TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    if ticks >= PIT_TICKS_PER_PERIOD.load(Ordering::SeqCst) {
+        PIT_TICKS.store(0, Ordering::SeqCst);
+        // pick up changes of the time slice and upcoming wakeups
+        start_timer_one_shot(scheduler::next_timer_period_us());
+        true
+    } else {
+        false
+    }
+}
+
+/// The scheduler tick used on systems without a local apic, the pit fires at a fixed
+/// rate, so we only switch tasks once enough ticks for a whole period accumulated.
+#[no_mangle]
+#[naked]
+pub extern "x86-interrupt" fn pit_timer_handler(_interrupt_stack_frame: InterruptStackFrame) {
+    unsafe {
+        asm!(
+        save_registers!(),
+        "call pit_tick",
+        "test al, al",
+        "jz 2f",
+        switch_task!(),
+        "2:",
+        restore_registers!(),
         options(noreturn));
     }
 }
@@ -333,11 +557,83 @@ pub static PICS: spin::Mutex<ChainedPics> =
 static mut LAPIC: Option<LocalApic> = None;
 
 pub fn start_timer_one_shot(us: usize) {
+    if !has_lapic() {
+        // the pit keeps running at its fixed rate, so we only adjust the amount of ticks per period
+        TIMER_PERIOD_US.store(us, Ordering::SeqCst);
+        PIT_TICKS_PER_PERIOD.store((us / pit::TICK_US).max(1), Ordering::SeqCst);
+        return;
+    }
+    let initial = us * APIC_TIMER_FREQUENCY.load(Ordering::SeqCst) / 1_000_000;
+    TIMER_PERIOD_US.store(us, Ordering::SeqCst);
+    TIMER_INITIAL_COUNT.store(initial, Ordering::SeqCst);
     unsafe {
         LAPIC.as_mut().unwrap().set_timer_divide(TimerDivide::Div64);
         LAPIC.as_mut().unwrap().set_timer_mode(TimerMode::OneShot);
-        LAPIC.as_mut().unwrap().set_timer_initial((us * (APIC_TIMER_FREQUENCY.load(Ordering::SeqCst) / 1000000)) as u32);
+        LAPIC.as_mut().unwrap().set_timer_initial(initial as u32);
+    }
+}
+
+/// Stops the scheduler timer until it's started again with `start_timer_one_shot`, the time which
+/// elapsed until now is accounted to the monotonic clock.
+pub fn stop_timer() {
+    without_interrupts(|| {
+        time::advance_monotonic(timer_elapsed_us() as u64);
+        TIMER_INITIAL_COUNT.store(0, Ordering::SeqCst);
+        if has_lapic() {
+            unsafe { LAPIC.as_mut().unwrap().set_timer_initial(0); }
+        }
+        // the pit keeps running, it has to be masked at the pic
+    });
+}
+
+/// Masks all pic lines except for the given ones and returns the previous masks, this does nothing
+/// if the pic was disabled in favor of the local apic.
+// FIXME: Mask the io apic redirection entries once we program them
+pub fn mask_pic_lines_except(irqs: &[u8]) -> Option<[u8; 2]> {
+    if has_lapic() {
+        return None;
+    }
+    let previous = unsafe { pic::read_masks() };
+    let mut masks = [0xff_u8; 2];
+    for irq in irqs {
+        masks[(*irq / 8) as usize] &= !(1 << (irq % 8));
+    }
+    // the slave's lines only reach the cpu through the cascade on line 2
+    if masks[1] != 0xff {
+        masks[0] &= !(1 << 2);
     }
+    unsafe { pic::write_masks(masks); }
+    Some(previous)
+}
+
+pub fn restore_pic_masks(masks: [u8; 2]) {
+    unsafe { pic::write_masks(masks); }
+}
+
+/// Makes the scheduler switch to the next task as soon as possible instead of waiting
+/// for the current period to expire.
+pub fn request_reschedule() {
+    without_interrupts(|| {
+        if has_lapic() {
+            // account for the part of the period which already elapsed as the rest gets skipped
+            time::advance_monotonic(timer_elapsed_us() as u64);
+            TIMER_PERIOD_US.store(0, Ordering::SeqCst);
+            TIMER_INITIAL_COUNT.store(1, Ordering::SeqCst);
+            unsafe { LAPIC.as_mut().unwrap().set_timer_initial(1); }
+        } else {
+            PIT_TICKS.store(PIT_TICKS_PER_PERIOD.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+    });
+}
+
+/// Returns the number of microseconds which elapsed since the timer was last armed.
+pub fn timer_elapsed_us() -> usize {
+    let initial = TIMER_INITIAL_COUNT.load(Ordering::SeqCst);
+    if initial == 0 || !has_lapic() {
+        return 0;
+    }
+    let current = unsafe { LAPIC.as_ref().unwrap().timer_current() } as usize;
+    (initial - current.min(initial)) * TIMER_PERIOD_US.load(Ordering::SeqCst) / initial
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -348,6 +644,8 @@ pub enum InterruptIndex {
     ApicError = 34,
     ApicSpurious = 35,
     Keyboard,
+    Rtc = PIC_2_OFFSET,
+    Mouse = PIC_2_OFFSET + 4,
     Syscall = 128, // 0x80
     Invalid = 255,
 }
@@ -362,49 +660,20 @@ impl InterruptIndex {
     }
 }
 
+/// Saves the complete context of the caller into a `SyscallFrame`, interrupts stay disabled
+/// for the whole syscall as the gate is an interrupt gate and we never re-enable them.
+/// Software interrupts don't need an end of interrupt.
+#[naked]
 extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame) {
     unsafe {
-        disable_interrupts();
-        // FIXME: Also save rcx and r11 which are used for syscall bookkeeping like rax
-        /*asm!(
-        "push rax",
-        "pushd gs",
-        "pushd fs",
-        "pushd es",
-        "pushd ds",
-        "pushd -{def_err}",
-        "push rdi",
-        "push rsi",
-        "push rdx",
-        "push r10",
-        "push r8",
-        "push r9",
-        );*/
-
         asm!(
-        "push 0", // default error
-        "push r9",
-        "push r8",
-        "push rcx",
-        "push rdx",
-        "push rsi",
-        "push rdi",
-        "push rax",
-
+        save_registers!(),
+        "cld",
+        // 15 saved registers on top of the 5 words pushed by the cpu keep rsp 16 byte aligned
+        "mov rdi, rsp",
         "call handle_syscall",
-
-        "add rsp, 8", // pop _
-        "pop rdi",
-        "pop rsi",
-        "pop rdx",
-        "pop rcx",
-        "pop r8",
-        "pop r9",
-        "pop rax", // (potential) error
-        );
-
-        end_of_interrupt(InterruptIndex::Syscall.as_u8());
-        enable_interrupts();
+        restore_registers!(),
+        options(noreturn));
     }
 }
 
@@ -421,21 +690,31 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
+        static ref KEYBOARD: Mutex<Keyboard<ConfiguredLayout, ScancodeSet1>> =
+            Mutex::new(Keyboard::new(ConfiguredLayout, ScancodeSet1,
                 HandleControl::Ignore)
             );
     }
 
+    irq::account(ps2::KEYBOARD_IRQ);
+    let scancode = match ps2::read_keyboard_byte() {
+        Some(scancode) => scancode,
+        None => {
+            unsafe { end_of_interrupt(InterruptIndex::Keyboard.as_u8()); }
+            return;
+        },
+    };
     let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
+    crate::power::wake(WakeSource::Keyboard);
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        let consumed = crate::events::process_hotkeys(&key_event);
+        // the keyboard has to see every event, so it can keep track of its modifier state
         if let Some(key) = keyboard.process_keyevent(key_event) {
-            crate::events::EVENT_HANDLERS.lock().call_keyboard_event(KeyboardEvent {
-                key,
-            });
+            if !consumed {
+                crate::events::EVENT_HANDLERS.lock().call_keyboard_event(KeyboardEvent {
+                    key,
+                });
+            }
         }
     }
     // This notifies the cpu that the interrupt was processed and that it can send the next one as soon as it's ready/triggered
@@ -444,6 +723,28 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 }
 
+extern "x86-interrupt" fn mouse_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    irq::account(ps2::MOUSE_IRQ);
+    ps2::handle_aux_interrupt();
+    unsafe {
+        end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    irq::account(rtc::IRQ);
+    if rtc::acknowledge_interrupt() {
+        crate::power::wake(WakeSource::RtcAlarm);
+    }
+    unsafe {
+        end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
 fn has_lapic() -> bool {
     unsafe { LAPIC.is_some() }
 }
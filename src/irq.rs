@@ -0,0 +1,133 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::error_codes::Error;
+use crate::percpu::{self, MAX_CPUS, PerCpu};
+
+// Keeps track of which cpu handles which device interrupt.
+// FIXME: Program the io apic redirection entries and msi addresses once we have drivers for them,
+// until then every interrupt is delivered to the boot cpu regardless of its affinity.
+
+lazy_static! {
+    static ref IRQS: Mutex<Vec<IrqLine>> = Mutex::new(vec![]);
+}
+
+/// The number of interrupt lines we keep statistics for, these are the lines of the legacy pics
+pub const MAX_IRQS: usize = 16;
+
+// these are only used to initialize the array, every use creates a new instance
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_IRQ_COUNTS: [AtomicU64; MAX_IRQS] = [NO_INTERRUPTS; MAX_IRQS];
+
+/// How often each line fired on each cpu
+static IRQ_COUNTS: PerCpu<[AtomicU64; MAX_IRQS]> = PerCpu::new([NO_IRQ_COUNTS; MAX_CPUS]);
+
+/// A set of cpus, bit n represents the cpu with the index n
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(pub u64);
+
+impl CpuMask {
+
+    pub const fn single(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    pub fn all() -> Self {
+        Self(u64::MAX >> (64 - online_cpus()))
+    }
+
+    #[inline]
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.0 & (1 << cpu) != 0
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+}
+
+#[derive(Debug, Clone)]
+pub struct IrqLine {
+    pub irq: u8,
+    pub name: &'static str,
+    pub vector: u8,
+    pub affinity: CpuMask,
+    /// Affinities which were set explicitly are left alone by `balance`
+    pub pinned: bool,
+}
+
+/// Returns the number of cpus which can receive interrupts.
+pub fn online_cpus() -> usize {
+    percpu::online_cpus()
+}
+
+/// Counts an interrupt of the line on the current cpu, this gets called by the interrupt handlers.
+pub fn account(irq: u8) {
+    if let Some(count) = IRQ_COUNTS.current().get(irq as usize) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Calls `f` with the index of every online cpu and how often the line fired on it.
+pub fn for_each_cpu_count(irq: u8, mut f: impl FnMut(usize, u64)) {
+    IRQ_COUNTS.for_each_online(|cpu, counts| {
+        f(cpu, counts.get(irq as usize).map_or(0, |count| count.load(Ordering::Relaxed)));
+    });
+}
+
+/// Makes a device interrupt known, it's routed to all cpus until an affinity is set.
+pub fn register(irq: u8, name: &'static str, vector: u8) -> Result<(), Error> {
+    let mut irqs = IRQS.lock();
+    if irqs.iter().any(|line| line.irq == irq) {
+        return Err(Error::EEXIST);
+    }
+    let idx = irqs.partition_point(|line| line.irq < irq);
+    irqs.insert(idx, IrqLine {
+        irq,
+        name,
+        vector,
+        affinity: CpuMask::all(),
+        pinned: false,
+    });
+    Ok(())
+}
+
+/// Restricts the interrupt to the given cpus, the mask has to contain at least one online cpu.
+pub fn set_affinity(irq: u8, mask: CpuMask) -> Result<(), Error> {
+    let mask = CpuMask(mask.0 & CpuMask::all().0);
+    if mask.is_empty() {
+        return Err(Error::EINVAL);
+    }
+    let mut irqs = IRQS.lock();
+    let line = irqs.iter_mut().find(|line| line.irq == irq).ok_or(Error::ENOENT)?;
+    line.affinity = mask;
+    line.pinned = true;
+    Ok(())
+}
+
+pub fn affinity(irq: u8) -> Option<CpuMask> {
+    IRQS.lock().iter().find(|line| line.irq == irq).map(|line| line.affinity)
+}
+
+pub fn for_each_irq(mut f: impl FnMut(&IrqLine)) {
+    for line in IRQS.lock().iter() {
+        f(line);
+    }
+}
+
+/// The default policy: spreads all interrupts which weren't pinned explicitly
+/// across the online cpus, so no single cpu has to handle all devices.
+pub fn balance() {
+    let cpus = online_cpus();
+    let mut next = 0;
+    for line in IRQS.lock().iter_mut().filter(|line| !line.pinned) {
+        line.affinity = CpuMask::single(next);
+        next = (next + 1) % cpus;
+    }
+}
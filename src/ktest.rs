@@ -0,0 +1,149 @@
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use crate::{exceptions, exit_qemu, hlt_loop, panicking, serial_print, serial_println, QemuExitCode};
+
+static CURRENT_FAILED: AtomicBool = AtomicBool::new(false);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+/// Asserts that a boolean expression is true, on failure the location and expression
+/// are reported over serial and the current test is marked as failed, but execution continues.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::ktest::report_failure(file!(), line!(),
+                format_args!("assertion failed: {}", stringify!($cond)));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::ktest::report_failure(file!(), line!(),
+                format_args!("assertion failed: {}: {}", stringify!($cond), format_args!($($arg)+)));
+        }
+    };
+}
+
+/// Asserts that two expressions are equal, on failure both expressions and their values
+/// are reported over serial and the current test is marked as failed, but execution continues.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left_val, right_val) => {
+                if !(*left_val == *right_val) {
+                    $crate::ktest::report_failure(file!(), line!(),
+                        format_args!("assertion failed: `{} == {}`\n      left: `{:?}`\n     right: `{:?}`",
+                            stringify!($left), stringify!($right), left_val, right_val));
+                }
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn report_failure(file: &str, line: u32, msg: fmt::Arguments) {
+    if !CURRENT_FAILED.swap(true, Ordering::SeqCst) {
+        // terminate the line containing the test's name
+        serial_println!();
+    }
+    serial_println!("    {}:{}: {}", file, line, msg);
+}
+
+pub(crate) fn begin_test() {
+    CURRENT_FAILED.store(false, Ordering::SeqCst);
+}
+
+/// Finishes the current test and returns whether it passed.
+pub(crate) fn end_test() -> bool {
+    let passed = !CURRENT_FAILED.load(Ordering::SeqCst);
+    if passed {
+        PASSED.fetch_add(1, Ordering::SeqCst);
+    } else {
+        FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+    passed
+}
+
+/// Returns the number of passed and failed tests so far.
+pub fn results() -> (usize, usize) {
+    (PASSED.load(Ordering::SeqCst), FAILED.load(Ordering::SeqCst))
+}
+
+// Tests which are expected to panic or to raise a cpu exception can't share a kernel with other
+// tests, so each of them is an integration test of its own with `harness = false` which runs in a
+// separate qemu instance. It calls `expect` before doing whatever should fail and forwards its
+// panic handler to `isolated_panic`. `scripts/run_isolated_tests.sh` runs all of them and checks
+// their serial output.
+
+/// How an isolated test is supposed to end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Panic,
+    /// The kernel panics because of the cpu exception with this vector
+    Exception(u8),
+}
+
+const NOTHING_EXPECTED: u16 = 0;
+const PANIC_EXPECTED: u16 = 1;
+/// Or'ed with the vector
+const EXCEPTION_EXPECTED: u16 = 0x100;
+
+static EXPECTED: AtomicU16 = AtomicU16::new(NOTHING_EXPECTED);
+/// The number of exceptions with the expected vector which were raised before the test started
+static EXCEPTIONS_BEFORE: AtomicU64 = AtomicU64::new(0);
+
+fn expected() -> Option<Expected> {
+    match EXPECTED.load(Ordering::SeqCst) {
+        NOTHING_EXPECTED => None,
+        PANIC_EXPECTED => Some(Expected::Panic),
+        vector => Some(Expected::Exception(vector as u8)),
+    }
+}
+
+/// Starts the isolated test `name`, which has to end in the given way.
+pub fn expect(name: &str, expected: Expected) {
+    let encoded = match expected {
+        Expected::Panic => PANIC_EXPECTED,
+        Expected::Exception(vector) => {
+            EXCEPTIONS_BEFORE.store(exceptions::kernel_count(vector), Ordering::SeqCst);
+            EXCEPTION_EXPECTED | vector as u16
+        },
+    };
+    EXPECTED.store(encoded, Ordering::SeqCst);
+    serial_print!("{}...\t", name);
+}
+
+fn finish_isolated(passed: bool) -> ! {
+    if passed {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+/// The panic handler of isolated tests, the test passes if the panic was expected.
+pub fn isolated_panic(info: &PanicInfo) -> ! {
+    if !panicking::enter(info) {
+        // reporting the panic panicked, the test can't have passed
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop();
+    }
+    let passed = match expected() {
+        Some(Expected::Panic) => true,
+        Some(Expected::Exception(vector)) => exceptions::kernel_count(vector) > EXCEPTIONS_BEFORE.load(Ordering::SeqCst),
+        None => false,
+    };
+    serial_println!("\n{}", info);
+    finish_isolated(passed)
+}
+
+/// Has to be called if an isolated test gets past the code which should have failed.
+pub fn isolated_no_failure() -> ! {
+    serial_println!("\nexpected {:?}, but the test completed", expected());
+    finish_isolated(false)
+}
@@ -18,7 +18,7 @@ use alloc::boxed::Box;
 use core::alloc::Layout;
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
-use crate::arch::{enable_interrupts, disable_interrupts, wait_for_interrupt};
+use crate::arch::{enable_interrupts, disable_interrupts, wait_for_interrupt, without_interrupts};
 use crate::shell::{has_shell, SHELL};
 
 pub mod vga_buffer;
@@ -38,21 +38,60 @@ pub mod filesystem;
 pub mod arch;
 pub mod syscall;
 pub mod error_codes;
+pub mod oom;
+pub mod time;
+pub mod ktest;
+pub mod elf;
+pub mod sysrq;
+pub mod irq;
+pub mod crypto;
+pub mod cmdline;
+pub mod sync;
+pub mod log;
+pub mod console;
+pub mod exceptions;
+pub mod environ;
+pub mod exec;
+pub mod address_space;
+pub mod power;
+pub mod percpu;
+pub mod cpustat;
+pub mod coredump;
+pub mod panicking;
+pub mod selftest;
+pub mod settings;
+pub mod hostname;
+pub mod workqueue;
+#[cfg(feature = "fault-injection")]
+pub mod fault_inject;
 
 pub fn init() {
     gdt::init();
     interrupts::init();
+    arch::x86::mem::init();
+    arch::x86::features::init_bsp();
+    arch::x86::topology::init_cpu(0);
+    time::calibrate_tsc();
+    time::init_wallclock();
+    log::init();
+    console::init();
+    #[cfg(feature = "fault-injection")]
+    fault_inject::init();
+    drivers::ps2::init();
     unsafe { interrupts::PICS.lock().initialize() };
     unsafe { enable_interrupts() }
 }
 
 pub fn init_kb_handler() {
-    events::EVENT_HANDLERS.lock().register_keyboard_handler(Box::new(|event| {
+    events::register_default_hotkeys();
+    sysrq::init();
+    // the keyboard interrupt calls the handlers with the lock held
+    without_interrupts(|| events::EVENT_HANDLERS.lock().register_keyboard_handler(Box::new(|event| {
         // println!("keyee: {:?}", event.key);
         if has_shell() {
             SHELL.lock().key_event(event.key.clone());
         }
-    }));
+    })));
 }
 
 // Testing machinery
@@ -67,8 +106,13 @@ impl<T> Testable for T
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
+        ktest::begin_test();
         self();
-        serial_println!("[ok]");
+        if ktest::end_test() {
+            serial_println!("[ok]");
+        } else {
+            serial_println!("[failed]");
+        }
     }
 }
 
@@ -77,12 +121,20 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     for test in tests {
         test.run();
     }
-    exit_qemu(QemuExitCode::Success);
+    let (passed, failed) = ktest::results();
+    serial_println!("\ntest result: {} passed; {} failed", passed, failed);
+    if failed == 0 {
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        exit_qemu(QemuExitCode::Failed);
+    }
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    if panicking::enter(info) {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+    }
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
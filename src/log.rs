@@ -0,0 +1,190 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use crate::{cmdline, serial_print, time};
+use crate::arch::without_interrupts;
+use crate::print::{self, Sink};
+
+// Kernel log, every line is prefixed with the time since boot in the format `[seconds.micros]`.
+// Messages up to the log level (`log=<level>`) are kept in a ring buffer and go to the serial
+// port, the screen only shows the ones up to the console log level (`console_loglevel=<level>`),
+// so verbose logging doesn't make the console unusable. By default every message is kept and
+// the screen only shows warnings and errors.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Level {
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+
+/// The size of the ring buffer in bytes, the oldest messages get overwritten
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    /// Where the next byte gets written
+    head: usize,
+    /// Set once the buffer was filled completely
+    wrapped: bool,
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[self.head] = byte;
+            self.head = (self.head + 1) % LOG_BUFFER_SIZE;
+            self.wrapped |= self.head == 0;
+        }
+        Ok(())
+    }
+}
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    data: [0; LOG_BUFFER_SIZE],
+    head: 0,
+    wrapped: false,
+});
+
+/// Appends printed output to the ring buffer.
+pub(crate) fn append_raw(args: fmt::Arguments) {
+    without_interrupts(|| {
+        let _ = LOG_BUFFER.lock().write_fmt(args);
+    });
+}
+
+/// Applies the `log=<level>` and `console_loglevel=<level>` command line options.
+pub fn init() {
+    if let Some(level) = cmdline::get("log").and_then(|level| level.parse().ok()) {
+        set_max_level(level);
+    }
+    if let Some(level) = cmdline::get("console_loglevel").and_then(|level| level.parse().ok()) {
+        set_console_level(level);
+    }
+}
+
+/// Sets the level up to which messages are logged at all
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::SeqCst))
+}
+
+/// Sets the level up to which messages are shown on the screen
+pub fn set_console_level(level: Level) {
+    CONSOLE_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn console_level() -> Level {
+    Level::from_u8(CONSOLE_LEVEL.load(Ordering::SeqCst))
+}
+
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Returns the contents of the ring buffer, the oldest message first.
+pub fn read_buffer() -> Vec<u8> {
+    // the buffer must not be locked while allocating, the allocator may log
+    let mut contents = Vec::with_capacity(LOG_BUFFER_SIZE);
+    without_interrupts(|| {
+        let buffer = LOG_BUFFER.lock();
+        if buffer.wrapped {
+            // the oldest line was partially overwritten
+            let oldest = &buffer.data[buffer.head..];
+            let start = oldest.iter().position(|byte| *byte == b'\n').map_or(oldest.len(), |idx| idx + 1);
+            contents.extend_from_slice(&oldest[start..]);
+        }
+        contents.extend_from_slice(&buffer.data[..buffer.head]);
+    });
+    contents
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let us = time::rdtsc_ns() / 1000;
+    let (secs, micros) = (us / 1_000_000, us % 1_000_000);
+    without_interrupts(|| {
+        let _ = writeln!(LOG_BUFFER.lock(), "[{:>5}.{:06}] {:<5} {}: {}", secs, micros, level.name(), module, args);
+    });
+    // the serial port and the log buffer already got the line
+    if level as u8 <= CONSOLE_LEVEL.load(Ordering::Relaxed) {
+        print::print_except(&[Sink::Serial, Sink::LogBuffer], format_args!("[{:>5}.{:06}] {}: {}\n", secs, micros, module, args));
+    }
+    serial_print!("[{:>5}.{:06}] {:<5} {}: {}\n", secs, micros, level.name(), module, args);
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
@@ -10,13 +10,17 @@ extern crate alloc;
 
 mod serial;
 
+use alloc::boxed::Box;
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
 use x86::syscall;
-use LeafOS::{hlt_loop, memory, println, scheduler};
-use LeafOS::drivers::pit;
-use LeafOS::interrupts::init_apic;
-use LeafOS::scheduler::SCHEDULER_TIMER_DELAY;
+use LeafOS::{hlt_loop, hostname, memory, panicking, power, println, scheduler, selftest, settings, workqueue};
+use LeafOS::drivers::{dma, pci, ramdisk, registry};
+use LeafOS::filesystem;
+use LeafOS::filesystem::leaffs::{self, LeafFs};
+use LeafOS::filesystem::procfs::ProcFs;
+use LeafOS::filesystem::devfs::DevFs;
+use LeafOS::interrupts::init_timer;
 use LeafOS::syscall::{do_syscall_3, STDOUT_FD, WRITE};
 
 // FIXME: Fix the keyboard handling
@@ -25,6 +29,9 @@ use LeafOS::syscall::{do_syscall_3, STDOUT_FD, WRITE};
 // cargo bootimage --release --target x86_64_target.json -Z build-std=core,compiler_builtins,alloc -Z build-std-features=compiler-builtins-mem
 // qemu-system-x86_64 -d int -D ./qemu_logs -no-reboot -M smm=off -drive format=raw,file=target/x86_64_target/release/bootimage-LeafOS.bin
 
+// FIXME: Multiboot2 support needs our own 32 bit entry stub and linker script, but the image is built
+// by the `bootloader` crate which only provides its own `BootInfo`. Once we boot through our own
+// boot info abstraction, a multiboot2 entry can translate its memory map, framebuffer and module tags into it.
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
@@ -38,12 +45,22 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     println!("Initialization succeeded!");
 
-    let (table, allocator) = memory::setup(&boot_info.memory_map, boot_info.physical_memory_offset);
+    memory::setup(&boot_info.memory_map, boot_info.physical_memory_offset);
+    dma::init();
     scheduler::init();
-    unsafe { init_apic(boot_info.physical_memory_offset); }
-    pit::init();
-    LeafOS::interrupts::start_timer_one_shot(SCHEDULER_TIMER_DELAY);
-
+    pci::init();
+    mount_root();
+    if let Err(err) = settings::load() {
+        println!("Failed to load the kernel settings: {}", err);
+    }
+    hostname::init();
+    unsafe { init_timer(boot_info.physical_memory_offset); }
+    selftest::run();
+
+    scheduler::start_proc(power::power_task, true);
+    scheduler::start_proc(workqueue::worker_task, true);
+    scheduler::start_proc(LeafOS::shell::shell_task, true);
+    filesystem::start_periodic_writeback();
     scheduler::start_proc(test_fn, true);
     scheduler::start_proc(test_fn_hello, true);
 
@@ -60,6 +77,24 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     hlt_loop();
 }
 
+/// Sets up a RAM disk with a fresh LeafFS as the root filesystem and mounts procfs and devfs.
+fn mount_root() {
+    ramdisk::register();
+    registry::probe_all();
+    let result = leaffs::format("ram0")
+        .and_then(|_| LeafFs::mount("ram0"))
+        .and_then(|fs| filesystem::mount("/", Box::new(fs)));
+    if let Err(err) = result {
+        println!("Failed to mount the root filesystem: {}", err);
+    }
+    if let Err(err) = filesystem::mount("/proc", Box::new(ProcFs)) {
+        println!("Failed to mount procfs: {}", err);
+    }
+    if let Err(err) = filesystem::mount("/dev", Box::new(DevFs)) {
+        println!("Failed to mount devfs: {}", err);
+    }
+}
+
 fn test_fn() {
     loop {
         // println!("test1");
@@ -79,15 +114,24 @@ fn test_fn_hello() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt_loop();}
+    if panicking::enter(info) {
+        // the panic may have interrupted a hostname change
+        let reported = hostname::try_with(|name| panicking::report(format_args!("kernel panic on {}:\n{}\n", name, info)));
+        if reported.is_none() {
+            panicking::report(format_args!("kernel panic:\n{}\n", info));
+        }
+    }
+    hlt_loop();
+}
 
 /// This function is called on test failure or when a panic occurs during testing.
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    if panicking::enter(info) {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+    }
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
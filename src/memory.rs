@@ -1,8 +1,26 @@
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use spin::{Mutex, Once};
 use x86_64::{PhysAddr, structures::paging::PageTable, VirtAddr};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use crate::arch::x86::mem::page_zero;
 use crate::memory;
 
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+lazy_static! {
+    /// Reference counts of frames which are mapped more than once, frames which
+    /// aren't in here are mapped exactly once
+    static ref FRAME_REFS: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+    /// The mapper of the active page tables and the frame allocator, they are set up in `setup`
+    static ref PAGING: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+}
+
 // The bigger the number of a page table, the larger the memory region (level 4 contains multiple level 3 etc.)
 // Virtual memory blocks: pages
 // Physical memory blocks: frames
@@ -49,6 +67,13 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 }
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+// FIXME: Frames are handed out linearly and never freed, so there is no per-frame metadata
+// which could get corrupted. Once this gets replaced by a buddy allocator, its free list entries
+// should carry a checksum which is verified on every traversal.
+// FIXME: The buddy allocator will need a compaction pass which migrates movable frames (page cache
+// and anonymous memory, found through a reverse mapping) to coalesce free space into higher orders.
+// It should be runnable from the shell and before suspending, but without frees, a page cache or
+// reverse mappings there is nothing to compact yet.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -87,13 +112,20 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        #[cfg(feature = "fault-injection")]
+        if crate::fault_inject::should_fail(crate::fault_inject::FailPoint::FrameAlloc) {
+            return None;
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
-pub fn setup(memory_map: &'static MemoryMap, physical_memory_offset: u64) -> (OffsetPageTable, BootInfoFrameAllocator) {
+/// Sets up paging and the kernel heap, the mapper and frame allocator are kept for later mappings.
+pub fn setup(memory_map: &'static MemoryMap, physical_memory_offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::SeqCst);
+    MEMORY_MAP.call_once(|| memory_map);
     let phys_mem_offset = VirtAddr::new(physical_memory_offset);
     // initialize a mapper
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
@@ -102,5 +134,340 @@ pub fn setup(memory_map: &'static MemoryMap, physical_memory_offset: u64) -> (Of
     };
     crate::allocators::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
-    (mapper, frame_allocator)
+    *PAGING.lock() = Some((mapper, frame_allocator));
+    if crate::cmdline::has_flag("wx_audit") {
+        audit_mappings(|violation| crate::log_warn!("{}", violation));
+    }
+}
+
+/// Maps a freshly zeroed frame at `page`, fails if the page is already mapped or we ran out of frames.
+// FIXME: Wake a background reclaim task when the free frames drop below a low watermark, which
+// reclaims (page cache first, then swap) until a high watermark is reached, so allocations rarely
+// have to reclaim directly. There is nothing to reclaim yet, see the page cache note in filesystem.
+pub fn map_zeroed_page(page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let mut paging = PAGING.lock();
+    let (mapper, frame_allocator) = paging.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+    let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe {
+        mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)?.flush();
+        // zero through the physical memory mapping, the page itself may not be writable
+        let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+        page_zero((offset + frame.start_address().as_u64()) as *mut u8);
+    }
+    Ok(())
+}
+
+/// Maps `page` to an existing frame, e.g. to access memory mapped registers.
+pub fn map_page(page: Page<Size4KiB>, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let mut paging = PAGING.lock();
+    let (mapper, frame_allocator) = paging.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe { mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)?.flush(); }
+    Ok(())
+}
+
+/// Unmaps `page` and returns the frame it was mapped to, `None` if it wasn't mapped.
+/// The frame doesn't get freed, frames can't be freed yet.
+pub fn unmap_page(page: Page<Size4KiB>) -> Option<PhysFrame> {
+    let mut paging = PAGING.lock();
+    let (mapper, _) = paging.as_mut()?;
+    let (frame, flush) = mapper.unmap(page).ok()?;
+    flush.flush();
+    Some(frame)
+}
+
+/// Allocates a physical frame without mapping it, it can be accessed through `phys_to_virt`.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    PAGING.lock().as_mut()?.1.allocate_frame()
+}
+
+/// The address at which the physical address is accessible through the mapping of the complete physical memory
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + addr.as_u64())
+}
+
+/// Checks whether the physical range lies completely in ram, reading anything else
+/// (e.g. memory mapped registers) may have side effects.
+pub fn is_ram(addr: PhysAddr, len: u64) -> bool {
+    let memory_map = match MEMORY_MAP.get() {
+        Some(memory_map) => memory_map,
+        None => return false,
+    };
+    let end = match addr.as_u64().checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut pos = addr.as_u64();
+    // regions may be adjacent, so the range can span several of them
+    while pos < end {
+        let region = memory_map.iter().find(|region| {
+            !matches!(region.region_type, MemoryRegionType::Reserved | MemoryRegionType::BadMemory | MemoryRegionType::Empty)
+                && region.range.start_addr() <= pos && pos < region.range.end_addr()
+        });
+        match region {
+            Some(region) => pos = region.range.end_addr(),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The end of the lower half of the address space, user mappings have to lie below it
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Checks whether the range lies completely in the user half of the address space.
+pub fn is_user_range(addr: VirtAddr, len: usize) -> bool {
+    addr.as_u64().checked_add(len as u64).map_or(false, |end| end <= USER_SPACE_END)
+}
+
+/// Checks whether every page of the range is mapped and accessible from user mode. The kernel
+/// image, its heap and the physical memory mapping lie in the lower half as well, so
+/// `is_user_range` alone doesn't keep user buffers from pointing at them.
+pub fn is_user_accessible(addr: VirtAddr, len: usize) -> bool {
+    if !is_user_range(addr, len) {
+        return false;
+    }
+    let end = addr.as_u64() + len as u64;
+    let mut pos = addr.as_u64();
+    while pos < end {
+        match lookup(VirtAddr::new(pos)) {
+            Some(mapping) if mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+                pos = (pos & !(mapping.page_size - 1)) + mapping.page_size;
+            },
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Copies kernel memory starting at `addr` into `buf`. Every page of the range gets looked
+/// up first, so this fails with the first unmapped address instead of faulting.
+pub fn read_virt(addr: VirtAddr, buf: &mut [u8]) -> Result<(), VirtAddr> {
+    let end = addr.as_u64().checked_add(buf.len() as u64).ok_or(addr)?;
+    let mut page = addr.align_down(4096_u64).as_u64();
+    while page < end {
+        // non canonical addresses can't be mapped
+        let page_addr = VirtAddr::try_new(page).map_err(|_| VirtAddr::new_truncate(page))?;
+        if lookup(page_addr).is_none() {
+            return Err(page_addr.max(addr));
+        }
+        page = page.saturating_add(4096);
+    }
+    unsafe { core::ptr::copy_nonoverlapping(addr.as_ptr::<u8>(), buf.as_mut_ptr(), buf.len()); }
+    Ok(())
+}
+
+/// Records an additional mapping of the frame (e.g. for copy-on-write or shared memory).
+pub fn share_frame(frame: PhysFrame) {
+    *FRAME_REFS.lock().entry(frame.start_address().as_u64()).or_insert(1) += 1;
+}
+
+/// Drops one mapping of the frame, returns true if this was the last one.
+pub fn release_frame(frame: PhysFrame) -> bool {
+    let mut refs = FRAME_REFS.lock();
+    let addr = frame.start_address().as_u64();
+    match refs.get_mut(&addr) {
+        Some(count) => {
+            *count -= 1;
+            if *count <= 1 {
+                refs.remove(&addr);
+            }
+            false
+        },
+        None => true,
+    }
+}
+
+pub fn frame_refcount(frame: PhysFrame) -> usize {
+    FRAME_REFS.lock().get(&frame.start_address().as_u64()).copied().unwrap_or(1)
+}
+
+/// A page table entry as found by `lookup`
+#[derive(Debug, Clone, Copy)]
+pub struct PageMapping {
+    pub frame: PhysAddr,
+    pub flags: PageTableFlags,
+    /// The size of the page this address belongs to, this is larger than 4KiB for huge pages
+    pub page_size: u64,
+}
+
+/// Walks the active page tables without modifying them and returns the mapping of `addr`.
+pub fn lookup(addr: VirtAddr) -> Option<PageMapping> {
+    use x86_64::registers::control::Cr3;
+
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table_addr = level_4_table_frame.start_address();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in indices.iter().enumerate() {
+        let table: &PageTable = unsafe { &*VirtAddr::new(offset + table_addr.as_u64()).as_ptr() };
+        let entry = &table[*index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // level 4 entries can't map huge pages, so there are no 512GiB pages to consider
+        let page_size = 4096_u64 << (9 * (3 - level));
+        if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            return Some(PageMapping {
+                frame: entry.addr(),
+                flags,
+                page_size,
+            });
+        }
+        table_addr = entry.addr();
+    }
+    None
+}
+
+/// A smaps style breakdown of a virtual memory area, all values are in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub size: u64,
+    pub resident: u64,
+    pub shared: u64,
+    pub private_clean: u64,
+    pub private_dirty: u64,
+    pub swap: u64,
+}
+
+/// Walks the page tables for the given range and classifies every page in it.
+pub fn memory_stats(start: VirtAddr, end: VirtAddr) -> MemoryStats {
+    let mut stats = MemoryStats {
+        size: end.as_u64() - start.as_u64(),
+        ..Default::default()
+    };
+    let mut addr = start.align_down(4096_u64).as_u64();
+    while addr < end.as_u64() {
+        match lookup(VirtAddr::new(addr)) {
+            Some(mapping) => {
+                let frame = PhysFrame::containing_address(mapping.frame);
+                // only count the part of huge pages which lies inside the range
+                let page_end = (addr / mapping.page_size + 1) * mapping.page_size;
+                let bytes = page_end.min(end.as_u64()) - addr;
+                stats.resident += bytes;
+                if frame_refcount(frame) > 1 {
+                    stats.shared += bytes;
+                } else if mapping.flags.contains(PageTableFlags::DIRTY) {
+                    stats.private_dirty += bytes;
+                } else {
+                    stats.private_clean += bytes;
+                }
+                addr = page_end;
+            },
+            None => {
+                // FIXME: count pages which were swapped out once we support swapping
+                addr += 4096;
+            },
+        }
+    }
+    stats
+}
+
+/// Calls `visit` with the start, size and flags of every present page in the active page tables.
+/// The flags are combined over all levels, a page is only writable or user accessible if every
+/// entry on the way to it allows it and it isn't executable if any entry forbids it.
+fn walk_mappings(table_addr: PhysAddr, level: usize, base: u64, inherited: PageTableFlags,
+                 visit: &mut impl FnMut(u64, u64, PageTableFlags)) {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    let table: &PageTable = unsafe { &*VirtAddr::new(offset + table_addr.as_u64()).as_ptr() };
+    let entry_size = 4096_u64 << (9 * (3 - level));
+    for (idx, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // sign extends the addresses in the upper half
+        let addr = VirtAddr::new_truncate(base + idx as u64 * entry_size).as_u64();
+        let effective = (inherited & flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)) |
+            ((inherited | flags) & PageTableFlags::NO_EXECUTE);
+        if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            visit(addr, entry_size, effective);
+        } else {
+            walk_mappings(entry.addr(), level + 1, addr, effective, visit);
+        }
+    }
+}
+
+/// Whether `addr` belongs to the kernel.
+// FIXME: The kernel image and the boot stack are in the lower half as well, recognize them
+//  once the kernel is linked into the upper half or we know where the bootloader put them.
+fn is_kernel_addr(addr: u64) -> bool {
+    let heap_start = crate::allocators::HEAP_START as u64;
+    addr >= USER_SPACE_END || (heap_start..heap_start + crate::allocators::HEAP_SIZE as u64).contains(&addr)
+}
+
+/// Pages violating the memory protection policy, they are either writable and executable
+/// or they belong to the kernel and are accessible from user mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub start: VirtAddr,
+    pub size: u64,
+    /// The combined flags of all levels
+    pub flags: PageTableFlags,
+    pub kernel: bool,
+}
+
+impl Violation {
+
+    pub fn is_writable_executable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE) && !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+
+    pub fn is_user_kernel_page(&self) -> bool {
+        self.kernel && self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    }
+
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}-{:016x} {:>8}K", self.start.as_u64(), self.start.as_u64().wrapping_add(self.size), self.size / 1024)?;
+        if self.is_writable_executable() {
+            f.write_str(" writable+executable")?;
+        }
+        if self.is_user_kernel_page() {
+            f.write_str(" user accessible kernel page")?;
+        }
+        Ok(())
+    }
+}
+
+/// Scans the active page tables for mappings violating the memory protection policy (W^X and
+/// no kernel pages accessible from user mode), adjacent pages with the same flags get merged.
+pub fn audit_mappings(mut f: impl FnMut(Violation)) {
+    use x86_64::registers::control::Cr3;
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    // without NXE every page is executable
+    let nx_enabled = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut pending: Option<Violation> = None;
+    walk_mappings(level_4_table_frame.start_address(), 0, 0, PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+                  &mut |start, size, mut flags| {
+        if !nx_enabled {
+            flags.remove(PageTableFlags::NO_EXECUTE);
+        }
+        let violation = Violation {
+            start: VirtAddr::new(start),
+            size,
+            flags,
+            kernel: is_kernel_addr(start),
+        };
+        if !violation.is_writable_executable() && !violation.is_user_kernel_page() {
+            return;
+        }
+        match pending.as_mut() {
+            Some(last) if last.start.as_u64().wrapping_add(last.size) == start && last.flags == flags && last.kernel == violation.kernel => {
+                last.size += size;
+            },
+            _ => {
+                if let Some(last) = pending.replace(violation) {
+                    f(last);
+                }
+            },
+        }
+    });
+    if let Some(last) = pending {
+        f(last);
+    }
 }
@@ -0,0 +1,105 @@
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use crate::allocators::HEAP_SIZE;
+use crate::log_warn;
+use crate::process::{OOM_SCORE_ADJ_MIN, Process};
+use crate::scheduler;
+
+static POLICY: AtomicU8 = AtomicU8::new(OomPolicy::KillLargest as u8);
+static KILLED: AtomicUsize = AtomicUsize::new(0);
+/// Kills which weren't logged yet, logging from the allocator could deadlock (see `report_kills`)
+static UNREPORTED: AtomicUsize = AtomicUsize::new(0);
+static LAST_VICTIM: AtomicU64 = AtomicU64::new(0);
+static LAST_FREED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OomPolicy {
+    /// Kill the process with the highest badness score and retry the allocation
+    KillLargest = 0,
+    /// Fail the allocation, which leads to a kernel panic
+    Panic = 1,
+}
+
+pub fn set_policy(policy: OomPolicy) {
+    POLICY.store(policy as u8, Ordering::Release);
+}
+
+pub fn policy() -> OomPolicy {
+    match POLICY.load(Ordering::Acquire) {
+        0 => OomPolicy::KillLargest,
+        _ => OomPolicy::Panic,
+    }
+}
+
+/// Returns the number of processes which were killed by the oom killer so far.
+pub fn killed_count() -> usize {
+    KILLED.load(Ordering::Relaxed)
+}
+
+/// Calculates how desirable it is to kill the given process whose death frees `freed` bytes,
+/// `None` means the process must not be killed.
+///
+/// Privileged (kernel owned) processes are only considered if they were explicitly
+/// designated as victims via a positive oom score adjustment.
+fn badness(process: &Process, freed: usize) -> Option<isize> {
+    let adj = process.oom_score_adj();
+    if adj == OOM_SCORE_ADJ_MIN || (process.is_kernel_owned() && adj <= 0) {
+        return None;
+    }
+    // every adjustment point is worth a thousandth of the heap, just like linux does it.
+    // The heap a process allocated isn't owned by it and stays allocated when it gets killed,
+    // so only the memory which actually gets freed counts.
+    Some(freed as isize + adj as isize * (HEAP_SIZE / 1000) as isize)
+}
+
+/// Gets called by the heap allocator if an allocation couldn't be satisfied.
+///
+/// Returns whether memory was freed and the allocation should be retried. Only processes whose
+/// death frees enough memory for the allocation are killed, so the retries can't kill every
+/// process without ever satisfying the allocation. Nothing gets printed here, the caller may
+/// hold the locks of the output devices.
+pub(crate) fn out_of_memory(layout: Layout) -> bool {
+    match policy() {
+        OomPolicy::Panic => false,
+        OomPolicy::KillLargest => {
+            match scheduler::oom_kill_victim(badness, layout.size()) {
+                Some((id, freed)) => {
+                    LAST_VICTIM.store(id, Ordering::Relaxed);
+                    LAST_FREED.store(freed, Ordering::Relaxed);
+                    KILLED.fetch_add(1, Ordering::Relaxed);
+                    UNREPORTED.fetch_add(1, Ordering::Release);
+                    true
+                },
+                // the allocation fails, which panics with its size
+                None => false,
+            }
+        },
+    }
+}
+
+/// Logs the kills of the oom killer since the last call, this gets called by the worker task.
+pub fn report_kills() {
+    let kills = UNREPORTED.swap(0, Ordering::Acquire);
+    if kills != 0 {
+        log_warn!("the oom killer killed {} process(es), the last one was process {} which freed {} bytes",
+            kills, LAST_VICTIM.load(Ordering::Relaxed), LAST_FREED.load(Ordering::Relaxed));
+    }
+}
+
+#[test_case]
+fn test_badness() {
+    let mut process = Process::new(1, crate::process::State::Runnable, false);
+    // the heap charged to a process doesn't matter, it isn't freed by killing it
+    process.charge(HEAP_SIZE);
+    crate::kassert_eq!(badness(&process, 4096), Some(4096));
+    process.set_oom_score_adj(1);
+    crate::kassert_eq!(badness(&process, 4096), Some(4096 + (HEAP_SIZE / 1000) as isize));
+    process.set_oom_score_adj(OOM_SCORE_ADJ_MIN);
+    crate::kassert_eq!(badness(&process, 4096), None);
+    // kernel processes are only killed if they were designated as victims
+    let mut kernel = Process::new(2, crate::process::State::Runnable, true);
+    crate::kassert_eq!(badness(&kernel, 4096), None);
+    kernel.set_oom_score_adj(1);
+    crate::kassert!(badness(&kernel, 4096).is_some());
+}
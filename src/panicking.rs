@@ -0,0 +1,63 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::arch::disable_interrupts;
+use crate::serial::{RawSerial, SERIAL1};
+use crate::vga_buffer::{self, WRITER};
+
+// Every panic handler has to call `enter` first. The code which panicked never runs again, so
+// the first panic breaks the locks of the output devices it may have held and reports over them.
+// Should reporting panic as well (e.g. because a device is broken), the recursive panic only gets
+// reported over the raw serial port and the handler has to halt right away.
+
+const RUNNING: u8 = 0;
+const PANICKING: u8 = 1;
+/// Reporting the first panic panicked
+const RECURSIVE: u8 = 2;
+/// Reporting the recursive panic panicked as well
+const GIVEN_UP: u8 = 3;
+
+static STATE: AtomicU8 = AtomicU8::new(RUNNING);
+
+pub fn is_panicking() -> bool {
+    STATE.load(Ordering::SeqCst) != RUNNING
+}
+
+/// Stops everything else which could run and returns whether this is the first panic, only then
+/// the panic may be reported (see `report`). Recursive panics get reported here.
+pub fn enter(info: &PanicInfo) -> bool {
+    // an interrupt handler would run on top of the panic and might panic again or take a lock forever
+    unsafe { disable_interrupts(); }
+    // FIXME: Stop the other cpus (with an NMI IPI) once we bring them up
+    let state = STATE.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+        Some(if state >= GIVEN_UP { GIVEN_UP } else { state + 1 })
+    }).unwrap();
+    match state {
+        RUNNING => {
+            unsafe {
+                SERIAL1.force_unlock();
+                WRITER.force_unlock();
+            }
+            true
+        },
+        PANICKING => {
+            let _ = write!(RawSerial, "\npanicked while panicking: {}\n", info);
+            false
+        },
+        RECURSIVE => {
+            // don't format anything anymore, that's the most likely thing to fail again
+            let _ = RawSerial.write_str("\npanicked while reporting a recursive panic\n");
+            false
+        },
+        _ => false,
+    }
+}
+
+/// Writes the panic message to the serial port and the vga text buffer, this doesn't take any
+/// lock the code which panicked could have held.
+pub fn report(args: fmt::Arguments) {
+    let _ = RawSerial.write_fmt(args);
+    if vga_buffer::is_present() {
+        let _ = WRITER.lock().write_fmt(args);
+    }
+}
@@ -0,0 +1,111 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use crate::error_codes::Error;
+use crate::{cmdline, log_warn};
+
+// Per-cpu data is kept in arrays with one slot per supported cpu, so it doesn't need the heap and
+// can be used while the application processors are being brought up. The number of slots is fixed
+// when building the kernel by setting `LEAFOS_MAX_CPUS` (8 by default), it can only be lowered at
+// runtime with `maxcpus=<n>`. Cpus beyond that limit are left offline.
+
+const DEFAULT_MAX_CPUS: usize = 8;
+
+const fn parse_max_cpus(value: Option<&str>) -> usize {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => return DEFAULT_MAX_CPUS,
+    };
+    let mut max = 0;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        assert!(bytes[idx].is_ascii_digit(), "LEAFOS_MAX_CPUS has to be a number");
+        max = max * 10 + (bytes[idx] - b'0') as usize;
+        idx += 1;
+    }
+    max
+}
+
+/// The number of cpus the kernel was built for
+pub const MAX_CPUS: usize = parse_max_cpus(option_env!("LEAFOS_MAX_CPUS"));
+
+// cpu masks are 64 bit wide
+const _: () = assert!(MAX_CPUS >= 1 && MAX_CPUS <= 64, "LEAFOS_MAX_CPUS has to be between 1 and 64");
+
+/// The boot cpu is always online
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static LIMIT: Once<usize> = Once::new();
+
+/// The number of cpus which may be brought online, `MAX_CPUS` unless lowered with `maxcpus=<n>`.
+pub fn max_cpus() -> usize {
+    *LIMIT.call_once(|| {
+        cmdline::get("maxcpus")
+            .and_then(|max| max.parse::<usize>().ok())
+            .map_or(MAX_CPUS, |max| max.clamp(1, MAX_CPUS))
+    })
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Assigns the next per-cpu slot to an application processor which is being brought up and
+/// returns its index. Fails if all slots are taken, the cpu has to stay offline then.
+pub fn register_cpu(apic_id: u32) -> Result<usize, Error> {
+    let max = max_cpus();
+    ONLINE_CPUS.fetch_update(Ordering::AcqRel, Ordering::Acquire, |online| (online < max).then(|| online + 1))
+        .map_err(|online| {
+            log_warn!("leaving the cpu with apic id {} offline, only {} of {} cpus are supported (MAX_CPUS is {})",
+                apic_id, online, max, MAX_CPUS);
+            Error::ENOSPC
+        })
+}
+
+/// The index of the cpu we are running on
+// FIXME: Read this from a per-cpu register (e.g. gs base) once the application processors are brought up
+#[inline]
+pub fn current_cpu() -> usize {
+    0
+}
+
+/// One value for every cpu the kernel was built for
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        Self {
+            slots,
+        }
+    }
+
+    /// Returns the value of the given cpu, `None` if there is no slot for it.
+    #[inline]
+    pub fn get(&self, cpu: usize) -> Option<&T> {
+        self.slots.get(cpu)
+    }
+
+    /// Returns the value of the cpu we are running on.
+    #[inline]
+    pub fn current(&self) -> &T {
+        // every cpu which is online got a slot from `register_cpu`
+        &self.slots[current_cpu()]
+    }
+
+    /// Calls `f` with the index and the value of every online cpu.
+    pub fn for_each_online(&self, mut f: impl FnMut(usize, &T)) {
+        for (cpu, value) in self.slots[..online_cpus()].iter().enumerate() {
+            f(cpu, value);
+        }
+    }
+
+}
+
+#[test_case]
+fn test_parse_max_cpus() {
+    crate::kassert_eq!(parse_max_cpus(None), DEFAULT_MAX_CPUS);
+    crate::kassert_eq!(parse_max_cpus(Some("16")), 16);
+    crate::kassert!(max_cpus() <= MAX_CPUS);
+    crate::kassert!(online_cpus() <= max_cpus());
+}
@@ -0,0 +1,123 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use crate::arch::disable_interrupts;
+use crate::drivers::{block, rtc};
+use crate::error_codes::Error;
+use crate::{interrupts, log_info, log_warn, scheduler, time, workqueue};
+
+// Suspend-to-idle: user tasks are frozen, the devices are quiesced, every interrupt except for the
+// wake sources is masked and the cpu halts until one of them fires. Nothing has to be saved as
+// the cpu keeps all of its state, which makes this the cheapest sleep state there is.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WakeSource {
+    Keyboard = 1,
+    RtcAlarm = 2,
+}
+
+impl WakeSource {
+
+    /// The pic line the wake source's interrupt arrives on
+    fn irq(&self) -> u8 {
+        match self {
+            WakeSource::Keyboard => 1,
+            WakeSource::RtcAlarm => 8,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(WakeSource::Keyboard),
+            2 => Some(WakeSource::RtcAlarm),
+            _ => None,
+        }
+    }
+
+}
+
+const WAKE_SOURCES: [WakeSource; 2] = [WakeSource::Keyboard, WakeSource::RtcAlarm];
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// The wake source which ended the suspend, 0 while there is none
+static WOKEN_BY: AtomicU8 = AtomicU8::new(0);
+static SUSPEND_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Gets called by the interrupt handlers of the wake sources.
+pub fn wake(source: WakeSource) {
+    if SUSPENDED.load(Ordering::Acquire) {
+        let _ = WOKEN_BY.compare_exchange(0, source as u8, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+#[inline]
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Acquire)
+}
+
+/// Suspends the system until a wake source fires and returns it. This has to be called from a
+/// kernel task other than the worker task with interrupts enabled, as the wake sources are interrupts.
+pub fn suspend_to_idle() -> Result<WakeSource, Error> {
+    if SUSPENDED.swap(true, Ordering::AcqRel) {
+        return Err(Error::EBUSY);
+    }
+    log_info!("suspending to idle");
+    scheduler::freeze_user_tasks();
+    // queued work could still access the devices
+    workqueue::flush();
+    if let Err(err) = block::suspend_all() {
+        log_warn!("suspend aborted: {}", err);
+        scheduler::thaw_user_tasks();
+        SUSPENDED.store(false, Ordering::Release);
+        return Err(err);
+    }
+
+    WOKEN_BY.store(0, Ordering::Release);
+    time::arm_wakeup_alarm();
+    let wake_irqs = WAKE_SOURCES.map(|source| source.irq());
+    unsafe { disable_interrupts(); }
+    let masks = interrupts::mask_pic_lines_except(&wake_irqs);
+    interrupts::stop_timer();
+    let suspended_at = time::rdtsc_ns();
+    let source = loop {
+        if let Some(source) = WakeSource::from_u8(WOKEN_BY.load(Ordering::Acquire)) {
+            break source;
+        }
+        // enabling interrupts only takes effect after the hlt, so a wakeup can't slip in between
+        x86_64::instructions::interrupts::enable_and_hlt();
+        unsafe { disable_interrupts(); }
+    };
+    // the timer didn't run, so the time spent suspended has to be accounted for by hand
+    time::advance_monotonic((time::rdtsc_ns() - suspended_at) / 1000);
+    if let Some(masks) = masks {
+        interrupts::restore_pic_masks(masks);
+    }
+    interrupts::start_timer_one_shot(scheduler::next_timer_period_us());
+    x86_64::instructions::interrupts::enable();
+
+    rtc::clear_alarm();
+    block::resume_all();
+    scheduler::thaw_user_tasks();
+    SUSPENDED.store(false, Ordering::Release);
+    log_info!("resumed by {:?}", source);
+    Ok(source)
+}
+
+/// Asks the power task to suspend the system, this can be called from interrupt context.
+pub fn request_suspend() {
+    SUSPEND_REQUESTED.store(true, Ordering::Release);
+}
+
+/// Carries out the requests of `request_suspend` and runs the actions registered with
+/// `time::wake_at`, it runs as a kernel task.
+pub fn power_task() {
+    const POLL_INTERVAL_US: u64 = 100_000;
+    loop {
+        time::run_due_wakeups();
+        if SUSPEND_REQUESTED.swap(false, Ordering::AcqRel) {
+            if let Err(err) = suspend_to_idle() {
+                log_warn!("failed to suspend: {}", err);
+            }
+        }
+        time::sleep(POLL_INTERVAL_US);
+    }
+}
@@ -1,7 +1,11 @@
 use core::fmt;
-use crate::arch::without_interrupts;
-use crate::shell::{has_shell, SHELL};
-use crate::vga_buffer::WRITER;
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::error_codes::Error;
+use crate::{console, log, serial, vga_buffer};
+
+// `print!` output is mirrored to every enabled sink. At boot only the console selected by
+// `console::init` is enabled, the others can be toggled at runtime (see the `console` builtin).
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::print::_print(format_args!($($arg)*)));
@@ -13,17 +17,116 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-/// Prints the given formatted string to the VGA text buffer
-/// through the global `WRITER` instance.
+/// Where printed output can go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Sink {
+    Framebuffer,
+    Vga,
+    Serial,
+    /// The kernel log's ring buffer, so printed output shows up in `dmesg`
+    LogBuffer,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 4] = [Sink::Framebuffer, Sink::Vga, Sink::Serial, Sink::LogBuffer];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sink::Framebuffer => "framebuffer",
+            Sink::Vga => "vga",
+            Sink::Serial => "serial",
+            Sink::LogBuffer => "log",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Sink> {
+        Self::ALL.iter().copied().find(|sink| sink.name() == name)
+    }
+
+    #[inline]
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Whether the device behind the sink exists
+    pub fn is_available(&self) -> bool {
+        match self {
+            Sink::Framebuffer => console::framebuffer_available(),
+            Sink::Vga => vga_buffer::is_present(),
+            Sink::Serial | Sink::LogBuffer => true,
+        }
+    }
+}
+
+/// Everything goes to the vga text buffer until a console was selected
+static SINKS: AtomicU8 = AtomicU8::new(Sink::Vga.bit());
+
+pub fn is_enabled(sink: Sink) -> bool {
+    SINKS.load(Ordering::SeqCst) & sink.bit() != 0
+}
+
+/// Replaces the enabled sinks.
+pub(crate) fn set_sinks(sinks: &[Sink]) {
+    SINKS.store(sinks.iter().fold(0, |mask, sink| mask | sink.bit()), Ordering::SeqCst);
+}
+
+/// Starts mirroring the output to the sink, fails with `ENODEV` if its device doesn't exist.
+pub fn enable(sink: Sink) -> Result<(), Error> {
+    if !sink.is_available() {
+        return Err(Error::ENODEV);
+    }
+    SINKS.fetch_or(sink.bit(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stops writing the output to the sink, the last sink can't be disabled.
+pub fn disable(sink: Sink) -> Result<(), Error> {
+    SINKS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |sinks| {
+        let remaining = sinks & !sink.bit();
+        if remaining == 0 { None } else { Some(remaining) }
+    }).map(|_| ()).map_err(|_| Error::EBUSY)
+}
+
+fn write_to(sink: Sink, args: fmt::Arguments) {
+    match sink {
+        // FIXME: Render to the framebuffer once we have a framebuffer console
+        Sink::Framebuffer => {},
+        Sink::Vga => console::write_vga(args),
+        Sink::Serial => serial::_print(args),
+        Sink::LogBuffer => log::append_raw(args),
+    }
+}
+
+/// Prints to the enabled sinks except for the given ones, for output which already
+/// got written to them some other way.
+pub(crate) fn print_except(excluded: &[Sink], args: fmt::Arguments) {
+    for sink in Sink::ALL {
+        if is_enabled(sink) && !excluded.contains(&sink) {
+            write_to(sink, args);
+        }
+    }
+}
+
+/// Prints the given formatted string to every enabled sink.
 #[inline(never)]
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    without_interrupts(|| {
-        if has_shell() {
-            SHELL.lock().write_fmt(args).unwrap();
-        } else {
-            WRITER.lock().write_fmt(args).unwrap();
+    print_except(&[], args);
+}
+
+#[test_case]
+fn test_sink_toggles() {
+    crate::kassert_eq!(Sink::from_name("serial"), Some(Sink::Serial));
+    let before = SINKS.load(Ordering::SeqCst);
+    crate::kassert!(enable(Sink::Serial).is_ok());
+    crate::kassert!(is_enabled(Sink::Serial));
+    for sink in Sink::ALL {
+        if sink != Sink::Serial {
+            let _ = disable(sink);
         }
-    });
-}
\ No newline at end of file
+    }
+    // there is always at least one sink left
+    crate::kassert_eq!(disable(Sink::Serial), Err(Error::EBUSY));
+    SINKS.store(before, Ordering::SeqCst);
+}
@@ -1,14 +1,76 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::address_space::AddressSpace;
+use crate::environ::Environment;
+use crate::time::TimeNamespace;
+
+/// The lowest possible oom score adjustment, processes with this value are never chosen by the oom killer.
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
+
 pub struct Process {
     id: u64,
     pub(crate) state: State,
+    kernel_owned: bool,
+    memory_usage: AtomicUsize, // heap bytes allocated while this process was running
+    oom_score_adj: i16,
+    time_namespace: Option<Arc<TimeNamespace>>,
+    vmas: Vec<Vma>,
+    cpu_time: CpuTime,
+    /// The deadline of a sleeping process on the monotonic clock
+    wakeup_at: Option<u64>,
+    timer_slack_us: u64,
+    env: Environment,
+    address_space: Arc<AddressSpace>,
+    /// Preemption points are skipped while this isn't 0, see `scheduler::preempt_disable`
+    preempt_count: usize,
+    /// Set while the process executes a syscall
+    #[cfg(feature = "voluntary-preempt")]
+    in_syscall: bool,
+}
+
+/// How late a sleeping process may be woken up by default, so its wakeup can be batched with others
+pub const DEFAULT_TIMER_SLACK_US: u64 = 50;
+
+/// The cpu time a process spent in user and in kernel mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTime {
+    pub user_ns: u64,
+    pub system_ns: u64,
+}
+
+/// A virtual memory area, a contiguous range of memory which belongs to a process
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub writable: bool,
+    pub executable: bool,
+    pub name: String,
 }
 
 impl Process {
 
-    pub(crate) fn new(id: u64, state: State) -> Self {
+    pub(crate) fn new(id: u64, state: State, kernel_owned: bool) -> Self {
         Self {
             id,
-            state
+            state,
+            kernel_owned,
+            memory_usage: AtomicUsize::new(0),
+            oom_score_adj: 0,
+            time_namespace: None,
+            vmas: vec![],
+            cpu_time: CpuTime::default(),
+            wakeup_at: None,
+            timer_slack_us: DEFAULT_TIMER_SLACK_US,
+            env: Environment::new(),
+            address_space: AddressSpace::kernel(),
+            preempt_count: 0,
+            #[cfg(feature = "voluntary-preempt")]
+            in_syscall: false,
         }
     }
 
@@ -16,12 +78,134 @@ impl Process {
         self.id
     }
 
+    #[inline]
+    pub fn is_kernel_owned(&self) -> bool {
+        self.kernel_owned
+    }
+
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn charge(&self, bytes: usize) {
+        self.memory_usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn uncharge(&self, bytes: usize) {
+        // FIXME: memory freed by a different process than the one that allocated it is
+        // attributed to the freeing process, so we have to saturate here
+        let _ = self.memory_usage.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+            Some(usage.saturating_sub(bytes))
+        });
+    }
+
+    #[inline]
+    pub fn oom_score_adj(&self) -> i16 {
+        self.oom_score_adj
+    }
+
+    pub fn set_oom_score_adj(&mut self, adj: i16) {
+        self.oom_score_adj = adj.clamp(OOM_SCORE_ADJ_MIN, OOM_SCORE_ADJ_MAX);
+    }
+
+    /// The time namespace this process lives in, `None` means it sees the real monotonic clock.
+    #[inline]
+    pub fn time_namespace(&self) -> Option<&Arc<TimeNamespace>> {
+        self.time_namespace.as_ref()
+    }
+
+    pub fn set_time_namespace(&mut self, ns: Option<Arc<TimeNamespace>>) {
+        self.time_namespace = ns;
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    /// Records a new memory area, the areas are kept sorted by their start address.
+    pub fn add_vma(&mut self, vma: Vma) {
+        let idx = self.vmas.partition_point(|other| other.start < vma.start);
+        self.vmas.insert(idx, vma);
+    }
+
+    #[inline]
+    pub fn wakeup_at(&self) -> Option<u64> {
+        self.wakeup_at
+    }
+
+    pub(crate) fn set_wakeup_at(&mut self, deadline: Option<u64>) {
+        self.wakeup_at = deadline;
+    }
+
+    #[inline]
+    pub fn timer_slack_us(&self) -> u64 {
+        self.timer_slack_us
+    }
+
+    pub fn set_timer_slack_us(&mut self, slack: u64) {
+        self.timer_slack_us = slack;
+    }
+
+    #[inline]
+    pub fn address_space(&self) -> &Arc<AddressSpace> {
+        &self.address_space
+    }
+
+    pub fn set_address_space(&mut self, address_space: Arc<AddressSpace>) {
+        self.address_space = address_space;
+    }
+
+    #[inline]
+    pub(crate) fn preempt_count(&self) -> usize {
+        self.preempt_count
+    }
+
+    pub(crate) fn set_preempt_count(&mut self, count: usize) {
+        self.preempt_count = count;
+    }
+
+    #[cfg(feature = "voluntary-preempt")]
+    #[inline]
+    pub(crate) fn in_syscall(&self) -> bool {
+        self.in_syscall
+    }
+
+    #[cfg(feature = "voluntary-preempt")]
+    pub(crate) fn set_in_syscall(&mut self, in_syscall: bool) {
+        self.in_syscall = in_syscall;
+    }
+
+    #[inline]
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    #[inline]
+    pub fn env_mut(&mut self) -> &mut Environment {
+        &mut self.env
+    }
+
+    #[inline]
+    pub fn cpu_time(&self) -> CpuTime {
+        self.cpu_time
+    }
+
+    pub(crate) fn account_cpu_time(&mut self, ns: u64, user: bool) {
+        if user {
+            self.cpu_time.user_ns += ns;
+        } else {
+            self.cpu_time.system_ns += ns;
+        }
+    }
+
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum State {
     Waiting,
     Runnable,
     Running,
     ShuttingDown,
-}
\ No newline at end of file
+}
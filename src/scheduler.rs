@@ -1,21 +1,32 @@
 use crate::gdt::{KERNEL_CODE_SEGMENT_IDX, USER_CODE_SEGMENT_IDX};
-use crate::process::{Process, State};
+use crate::process::{CpuTime, Process, State, Vma};
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::hint::spin_loop;
 use core::mem::size_of;
 use core::ptr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::{Mutex, Once};
-use crate::{println, wait_for_interrupt};
-
-static IDLE_TASK: Once<Arc<Mutex<(Process, Box<ProcessState>)>>> = Once::new();
+use crate::{address_space, cpustat, exec, interrupts, println, time, wait_for_interrupt, workqueue};
+use crate::arch::{is_interrupts_enabled, without_interrupts};
+use crate::environ::Environment;
+use crate::arch::x86::mem;
+use crate::arch::x86::mem::VectorState;
+use crate::percpu::{MAX_CPUS, PerCpu};
+use crate::time::TimeNamespace;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_IDLE_TASK: Once<Arc<Mutex<(Process, Box<ProcessState>)>>> = Once::new();
+static IDLE_TASKS: PerCpu<Once<Arc<Mutex<(Process, Box<ProcessState>)>>>> = PerCpu::new([NO_IDLE_TASK; MAX_CPUS]);
 static INIT: AtomicBool = AtomicBool::new(false); // FIXME: Make this per-core.
 static mut VOID_TASK: Option<Box<ProcessState>> = None;
 
 lazy_static! {
+    // FIXME: Use a run queue per cpu once the application processors are brought up
     static ref SCHEDULER: Arc<Mutex<Box<dyn Scheduler + Send>>> = {
         Arc::new(Mutex::new(Box::new(RoundRobinScheduler::new())))
     };
@@ -23,6 +34,24 @@ lazy_static! {
 
 pub const SCHEDULER_TIMER_DELAY: usize = 1000000;
 
+static TIME_SLICE_US: AtomicUsize = AtomicUsize::new(SCHEDULER_TIMER_DELAY);
+
+// cpu time accounting, a task's whole run is attributed to the privilege level it was interrupted in
+static LAST_SWITCH_NS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_USER_NS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_SYSTEM_NS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_IDLE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// The time in microseconds every task may run before the next one gets selected
+pub fn time_slice_us() -> usize {
+    TIME_SLICE_US.load(Ordering::SeqCst)
+}
+
+/// Changes the length of the time slices, this takes effect once the current slice expired.
+pub fn set_time_slice_us(us: usize) {
+    TIME_SLICE_US.store(us.max(1), Ordering::SeqCst);
+}
+
 pub trait Scheduler {
     // this is for internal use only
     fn pick_next(&mut self) -> Option<(Process, Box<ProcessState>)>;
@@ -34,6 +63,17 @@ pub trait Scheduler {
     // fn current_process(&self) -> Option<&SchedulerEntry>;
 
     fn start_process(&mut self, target_fn: fn(), kernel_owned: bool) -> u64;
+
+    /// Calls `f` for every process which is currently managed by the scheduler (except the running one)
+    fn for_each_process(&self, f: &mut dyn FnMut(&Process));
+
+    fn for_each_process_mut(&mut self, f: &mut dyn FnMut(&mut Process));
+
+    /// Like `for_each_process` but also provides the saved state of each process
+    fn for_each_task(&self, f: &mut dyn FnMut(&Process, &ProcessState));
+
+    /// Removes the process with the given id from the scheduler and returns it (if present)
+    fn remove_process(&mut self, id: u64) -> Option<(Process, Box<ProcessState>)>;
 }
 
 struct RoundRobinScheduler {
@@ -52,7 +92,34 @@ impl RoundRobinScheduler {
 
 impl Scheduler for RoundRobinScheduler {
     fn pick_next(&mut self) -> Option<(Process, Box<ProcessState>)> {
-        self.tasks.pop()
+        let now = time::monotonic_us();
+        // skip sleeping tasks which aren't due yet, they keep their position in the queue
+        for _ in 0..self.tasks.len() {
+            let mut task = self.tasks.pop()?;
+            if !task.0.is_kernel_owned() && USER_TASKS_FROZEN.load(Ordering::Acquire) {
+                self.tasks.insert(0, task);
+                continue;
+            }
+            // exited tasks stay queued until the worker task reaps them
+            if task.0.state == State::ShuttingDown {
+                self.tasks.insert(0, task);
+                continue;
+            }
+            if task.0.state == State::Waiting {
+                match task.0.wakeup_at() {
+                    Some(deadline) if deadline <= now => {
+                        task.0.state = State::Runnable;
+                        task.0.set_wakeup_at(None);
+                    },
+                    _ => {
+                        self.tasks.insert(0, task);
+                        continue;
+                    },
+                }
+            }
+            return Some(task);
+        }
+        None
     }
 
     fn reinsert_task(&mut self, task: (Process, Box<ProcessState>)) {
@@ -66,25 +133,82 @@ impl Scheduler for RoundRobinScheduler {
 
     fn start_process(&mut self, target_fn: fn(), kernel_owned: bool) -> u64 {
         self.task_id += 1;
+        let mut process = Process::new(self.task_id, State::Runnable, kernel_owned);
+        // children inherit the time namespace of their parent
+        process.set_time_namespace(current_time_namespace());
+        // as well as the environment, processes started by the kernel get the default one
+        match unsafe { TASK.as_ref() } {
+            Some(task) => {
+                process.set_timer_slack_us(task.0.timer_slack_us());
+                *process.env_mut() = task.0.env().clone();
+            },
+            None => *process.env_mut() = Environment::kernel_default(),
+        }
+        let envp = process.env().to_envp();
+        let state = Box::new(ProcessState::new(Box::new([0; 4096]), Box::new([0; 4096]), kernel_owned, target_fn, &envp)); // FIXME: Make the kernel parameter configurable
+        process.add_vma(state.kernel_stack_vma());
+        process.add_vma(state.user_stack_vma());
         self.tasks.push((
-            Process::new(self.task_id, State::Runnable),
-            Box::new(ProcessState::new(Box::new([0; 4096]), Box::new([0; 4096]), kernel_owned, target_fn)) // FIXME: Make the kernel parameter configurable
+            process,
+            state,
         ));
         self.task_id
     }
+
+    fn for_each_process(&self, f: &mut dyn FnMut(&Process)) {
+        for task in self.tasks.iter() {
+            f(&task.0);
+        }
+    }
+
+    fn for_each_process_mut(&mut self, f: &mut dyn FnMut(&mut Process)) {
+        for task in self.tasks.iter_mut() {
+            f(&mut task.0);
+        }
+    }
+
+    fn for_each_task(&self, f: &mut dyn FnMut(&Process, &ProcessState)) {
+        for task in self.tasks.iter() {
+            f(&task.0, &task.1);
+        }
+    }
+
+    fn remove_process(&mut self, id: u64) -> Option<(Process, Box<ProcessState>)> {
+        let idx = self.tasks.iter().position(|task| task.0.id() == id)?;
+        Some(self.tasks.remove(idx))
+    }
 }
 
+/// Written to the lowest address of every kernel stack, a task which overflows
+/// its stack overwrites this before it can corrupt any other memory
+const STACK_CANARY: u64 = 0x4c45_4146_5354_4b21; // "LEAFSTK!"
+
 #[repr(C)]
 pub struct ProcessState {
     kernel_rsp: u64,
     kernel_top_rsp: u64,
     kernel_stack: Box<[u8]>,
+    // FIXME: Grow user stacks on demand when a task faults in an unmapped gap right below them (up
+    //  to a `stack.growth_gap=` from the command line and a maximum size). This needs user stacks
+    //  to be their own USER_ACCESSIBLE mappings with nothing mapped below them, but they are boxes
+    //  on the kernel heap, which is mapped completely and whose pages below them are other objects.
     user_stack: Box<[u8]>,
+    /// The vector registers while the task isn't running
+    vector_state: Box<VectorState>,
 }
 
 impl ProcessState {
-    fn new(mut kernel_stack: Box<[u8]>, mut user_stack: Box<[u8]>, kernel: bool, start_fn: fn()) -> Self {
+    /// `envp` gets laid out on the user stack of user tasks, see `exec::build_initial_stack`.
+    fn new(mut kernel_stack: Box<[u8]>, mut user_stack: Box<[u8]>, kernel: bool, start_fn: fn(), envp: &[String]) -> Self {
+        kernel_stack[..size_of::<u64>()].copy_from_slice(&STACK_CANARY.to_ne_bytes());
         let kernel_addr = kernel_stack.as_mut().as_mut_ptr().expose_addr() + kernel_stack.len();
+        let user_rsp = if kernel {
+            0
+        } else {
+            let user_top = (user_stack.as_mut().as_mut_ptr().expose_addr() + user_stack.len()) as u64;
+            // the task gets started without an environment if it doesn't fit on its stack
+            exec::build_initial_stack(&mut user_stack, user_top, &[], envp, None).unwrap_or(user_top & !0xf)
+        };
         {
             // FIXME: What about the direction flag?
             // TODO: Maybe change this (for io privilege level) when we work on io in userspace
@@ -115,7 +239,7 @@ impl ProcessState {
                         // FIXME: Is this the correct thing to do if the privilege level doesn't change?
                         kernel_addr as usize
                     } else {
-                        user_stack.as_mut().as_mut_ptr().expose_addr() as usize
+                        user_rsp as usize
                     });                   // rsp (for user stack)
                 kernel_stack.offset(-1).write(DEFAULT_FLAGS);
                 kernel_stack.offset(-2).write(code_selector);
@@ -169,16 +293,94 @@ impl ProcessState {
             kernel_top_rsp: (kernel_addr + kernel_stack.len()) as u64,
             kernel_stack,
             user_stack,
+            vector_state: Box::new(VectorState::new()),
         }
     }
 }
 
+impl ProcessState {
+
+    /// The heap memory which gets freed when the process is dropped, its stacks and its register state
+    pub(crate) fn reclaimable_bytes(&self) -> usize {
+        size_of::<Self>() + self.kernel_stack.len() + self.user_stack.len() + size_of::<VectorState>()
+    }
+
+    pub(crate) fn is_canary_intact(&self) -> bool {
+        self.kernel_stack[..size_of::<u64>()] == STACK_CANARY.to_ne_bytes()
+    }
+
+    /// The stack pointer which was saved when the task was last switched out
+    pub(crate) fn saved_rsp(&self) -> u64 {
+        self.kernel_rsp
+    }
+
+    /// The instruction pointer the task resumes at, this is only meaningful for tasks which aren't running.
+    pub(crate) fn saved_rip(&self) -> Option<u64> {
+        self.saved_frame_word(0)
+    }
+
+    /// The code segment the task was interrupted in, the lowest 2 bits are its privilege level.
+    pub(crate) fn saved_cs(&self) -> Option<u64> {
+        self.saved_frame_word(1)
+    }
+
+    fn saved_frame_word(&self, idx: usize) -> Option<u64> {
+        // the saved registers are followed by the interrupt stack frame which starts with rip
+        const FRAME_OFFSET: usize = 15 * size_of::<u64>();
+        let offset = (self.kernel_rsp as usize).checked_sub(self.kernel_stack.as_ptr().expose_addr())? +
+            FRAME_OFFSET + idx * size_of::<u64>();
+        let bytes = self.kernel_stack.get(offset..offset + size_of::<u64>())?;
+        Some(u64::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn kernel_stack_vma(&self) -> Vma {
+        let start = self.kernel_stack.as_ptr().expose_addr() as u64;
+        Vma {
+            start,
+            end: start + self.kernel_stack.len() as u64,
+            writable: true,
+            executable: false,
+            name: String::from("[kstack]"),
+        }
+    }
+
+    fn user_stack_vma(&self) -> Vma {
+        let start = self.user_stack.as_ptr().expose_addr() as u64;
+        Vma {
+            start,
+            end: start + self.user_stack.len() as u64,
+            writable: true,
+            executable: false,
+            name: String::from("[stack]"),
+        }
+    }
+
+    /// Returns the number of bytes between the saved stack pointer and the top of the kernel stack.
+    pub(crate) fn kernel_stack_usage(&self) -> (usize, usize) {
+        let top = self.kernel_stack.as_ptr().expose_addr() + self.kernel_stack.len();
+        (top.saturating_sub(self.kernel_rsp as usize), self.kernel_stack.len())
+    }
+
+}
+
 struct SchedulerEntry {
     process: Process,
     state: Box<ProcessState>,
     balance: u64,
 }
 
+/// Set while the system suspends, user tasks aren't scheduled until they are thawed again
+static USER_TASKS_FROZEN: AtomicBool = AtomicBool::new(false);
+
+// FIXME: A user task which is currently running keeps running until its time slice ends
+pub fn freeze_user_tasks() {
+    USER_TASKS_FROZEN.store(true, Ordering::Release);
+}
+
+pub fn thaw_user_tasks() {
+    USER_TASKS_FROZEN.store(false, Ordering::Release);
+}
+
 /// This function is for testing purposes only!
 pub fn start_proc(target: fn(), kernel_owned: bool) {
     SCHEDULER
@@ -189,14 +391,16 @@ pub fn start_proc(target: fn(), kernel_owned: bool) {
 fn idle() {
     loop {
         // println!("idling...!");
+        cpustat::idle_enter();
         unsafe { wait_for_interrupt(); }
+        cpustat::idle_exit();
     }
 }
 
 fn get_idle_task() -> Arc<Mutex<(Process, Box<ProcessState>)>> {
-    IDLE_TASK.call_once(|| {
-        Arc::new(Mutex::new((Process::new(0, State::Runnable),
-                             Box::new(ProcessState::new(Box::new([0; 4096]), Box::new([0; 4096]), true, idle)))))
+    IDLE_TASKS.current().call_once(|| {
+        Arc::new(Mutex::new((Process::new(0, State::Runnable, true),
+                             Box::new(ProcessState::new(Box::new([0; 4096]), Box::new([0; 4096]), true, idle, &[])))))
     }).clone()
 }
 
@@ -204,15 +408,41 @@ fn get_idle_task() -> Arc<Mutex<(Process, Box<ProcessState>)>> {
 static mut TASK: Option<(Process, Box<ProcessState>)> = None;
 
 pub fn init() {
-    unsafe { VOID_TASK = Some(Box::new(ProcessState::new(Box::new([0; 256]), Box::new([0; 0]), true, idle))); }; // FIXME: Use as little data as possible
+    unsafe { VOID_TASK = Some(Box::new(ProcessState::new(Box::new([0; 256]), Box::new([0; 0]), true, idle, &[]))); }; // FIXME: Use as little data as possible
 }
 
 fn get_scheduler() -> Arc<Mutex<Box<dyn Scheduler + Send>>> {
     SCHEDULER.clone()
 }
 
+/// Panics if the task we are switching away from overflowed its kernel stack.
+fn check_stack_canary() {
+    match unsafe { TASK.as_ref() } {
+        Some((process, state)) => {
+            if !state.is_canary_intact() {
+                panic!("kernel stack overflow detected in task {}", process.id());
+            }
+        },
+        None => {
+            let idle = get_idle_task();
+            let idle = idle.lock();
+            if !idle.1.is_canary_intact() {
+                panic!("kernel stack overflow detected in idle task {}", idle.0.id());
+            }
+        },
+    }
+}
+
 #[no_mangle]
 extern "C" fn select_next_task() -> *mut ProcessState {
+    check_stack_canary();
+    account_cpu_time();
+    cpustat::tick();
+    // the idle task never uses the vector registers
+    if let Some((_, state)) = unsafe { TASK.as_mut() } {
+        mem::save_vector_state(&mut state.vector_state);
+    }
+
     let next = get_scheduler().lock()
         .pick_next();
 
@@ -220,12 +450,40 @@ extern "C" fn select_next_task() -> *mut ProcessState {
         replace_curr_task(None);
         get_idle_task().clone().lock().1.as_mut() as *mut ProcessState // FIXME: This is a dirty workaround and potentially dangerous, improve this!
     }, |task| {
+        address_space::switch_to(task.0.address_space());
         replace_curr_task(Some(task));
         unsafe { TASK.as_mut().unwrap() }.1.as_mut()
     }) as *mut ProcessState;
+    mem::restore_vector_state(unsafe { &(*next).vector_state });
     next
 }
 
+/// Attributes the time since the last switch to the task which is being switched out,
+/// depending on whether it was interrupted in user or in kernel mode.
+fn account_cpu_time() {
+    let now = time::rdtsc_ns();
+    let elapsed = now.saturating_sub(LAST_SWITCH_NS.swap(now, Ordering::Relaxed));
+    match unsafe { TASK.as_mut() } {
+        Some((process, state)) => {
+            let user = state.saved_cs().map_or(false, |cs| cs & 3 == 3);
+            process.account_cpu_time(elapsed, user);
+            let total = if user { &TOTAL_USER_NS } else { &TOTAL_SYSTEM_NS };
+            total.fetch_add(elapsed, Ordering::Relaxed);
+        },
+        None => {
+            TOTAL_IDLE_NS.fetch_add(elapsed, Ordering::Relaxed);
+        },
+    }
+}
+
+/// The cpu time spent in user mode, kernel mode and idle summed up over all tasks since boot
+pub fn total_cpu_time() -> (CpuTime, u64) {
+    (CpuTime {
+        user_ns: TOTAL_USER_NS.load(Ordering::Relaxed),
+        system_ns: TOTAL_SYSTEM_NS.load(Ordering::Relaxed),
+    }, TOTAL_IDLE_NS.load(Ordering::Relaxed))
+}
+
 fn replace_curr_task(task: Option<(Process, Box<ProcessState>)>) {
     if let Some(old_task) = unsafe { TASK.take() } {
         get_scheduler().lock().reinsert_task(old_task);
@@ -249,3 +507,371 @@ extern "C" fn current_task_ptr() -> *mut ProcessState {
         tmp
     }
 }
+
+/// Returns the id of the running process, this is 0 for the idle task.
+pub fn current_task_id() -> u64 {
+    unsafe { TASK.as_ref() }.map_or(0, |task| task.0.id())
+}
+
+/// Returns whether the running process is owned by the kernel, this is true for the idle task.
+pub fn current_is_kernel_owned() -> bool {
+    unsafe { TASK.as_ref() }.map_or(true, |task| task.0.is_kernel_owned())
+}
+
+/// Returns whether the process is running on a different cpu than the caller right now.
+pub fn is_running_on_other_cpu(_id: u64) -> bool {
+    // FIXME: Check the current task of the other cpus once we support SMP
+    false
+}
+
+/// Puts the current task to sleep until the monotonic clock reaches `deadline`,
+/// returns false if there is no current task which could sleep.
+pub fn sleep_until(deadline: u64) -> bool {
+    let sleeping = without_interrupts(|| match unsafe { TASK.as_mut() } {
+        Some(task) => {
+            task.0.state = State::Waiting;
+            task.0.set_wakeup_at(Some(deadline));
+            true
+        },
+        None => false,
+    });
+    if sleeping {
+        yield_now();
+    }
+    sleeping
+}
+
+/// Marks the current task as waiting until `wake_task` gets called for it, the caller has to give
+/// up the cpu afterwards. The task stops being scheduled right away, so whoever is supposed to
+/// wake it up has to know about it before interrupts are enabled again.
+/// Returns false if there is no current task which could wait.
+pub fn prepare_to_wait() -> bool {
+    prepare_to_wait_until(None)
+}
+
+/// Like `prepare_to_wait`, but the task also becomes runnable again once the monotonic clock
+/// reaches `deadline`, the caller has to check whether it was woken up or timed out.
+pub fn prepare_to_wait_until(deadline: Option<u64>) -> bool {
+    without_interrupts(|| match unsafe { TASK.as_mut() } {
+        Some(task) => {
+            task.0.state = State::Waiting;
+            task.0.set_wakeup_at(deadline);
+            true
+        },
+        None => false,
+    })
+}
+
+/// Undoes `prepare_to_wait`, e.g. because the condition the task was about to wait for is met already.
+pub fn finish_wait() {
+    without_interrupts(|| {
+        if let Some(task) = unsafe { TASK.as_mut() } {
+            task.0.state = State::Runnable;
+        }
+    });
+}
+
+/// Makes the waiting task with the given id runnable again, returns false if there is no such task.
+// FIXME: Queue the woken task on the cpu `topology::pick_idle_cpu(cpustat::is_idle)` picks (an idle
+//  cpu on an otherwise idle core, so it doesn't compete with a busy SMT sibling) and kick that cpu
+//  once the application processors run tasks. Until then every task runs on the boot processor.
+pub fn wake_task(id: u64) -> bool {
+    without_interrupts(|| {
+        if let Some(task) = unsafe { TASK.as_mut() }.filter(|task| task.0.id() == id) {
+            task.0.state = State::Runnable;
+            return true;
+        }
+        let mut found = false;
+        get_scheduler().lock().for_each_process_mut(&mut |process| {
+            if process.id() == id {
+                if process.state == State::Waiting {
+                    process.state = State::Runnable;
+                    process.set_wakeup_at(None);
+                }
+                found = true;
+            }
+        });
+        found
+    })
+}
+
+/// Keeps the preemption points of the current task from switching tasks while it's alive,
+/// see `preempt_disable`.
+pub struct PreemptGuard(());
+
+/// Marks a section which must not be preempted at a preemption point, e.g. because it holds a lock
+/// which is also taken in interrupt context. Sections can be nested.
+pub fn preempt_disable() -> PreemptGuard {
+    without_interrupts(|| {
+        if let Some(task) = unsafe { TASK.as_mut() } {
+            task.0.set_preempt_count(task.0.preempt_count() + 1);
+        }
+    });
+    PreemptGuard(())
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            if let Some(task) = unsafe { TASK.as_mut() } {
+                task.0.set_preempt_count(task.0.preempt_count().saturating_sub(1));
+            }
+        });
+    }
+}
+
+/// Gets called by the syscall entry and exit, only syscalls are preempted at preemption points.
+#[cfg(feature = "voluntary-preempt")]
+pub(crate) fn set_in_syscall(in_syscall: bool) {
+    without_interrupts(|| {
+        if let Some(task) = unsafe { TASK.as_mut() } {
+            task.0.set_in_syscall(in_syscall);
+        }
+    });
+}
+
+/// A voluntary preemption point for long running kernel code, e.g. every iteration of a loop over
+/// a large file. Syscalls run with interrupts disabled, so the timer can't preempt them. With the
+/// `voluntary-preempt` feature this opens a short window for interrupts, so the timer interrupt which
+/// ends the time slice can switch to another task. Kernel tasks run with interrupts enabled and are
+/// preempted by the timer anyway, so this does nothing for them.
+///
+/// This must not be called while holding a lock which is also taken in interrupt context,
+/// such sections have to be marked with `preempt_disable`.
+pub fn cond_resched() {
+    #[cfg(feature = "voluntary-preempt")]
+    {
+        let preemptible = unsafe { TASK.as_ref() }
+            .map_or(false, |task| task.0.in_syscall() && task.0.preempt_count() == 0);
+        if !preemptible || is_interrupts_enabled() {
+            return;
+        }
+        // interrupt handlers running in the window must not find a preemptible syscall
+        set_in_syscall(false);
+        unsafe {
+            crate::arch::enable_interrupts();
+            // interrupts only get enabled after the instruction following sti
+            crate::arch::nop();
+            crate::arch::disable_interrupts();
+        }
+        // we may have been switched out and back in, but the current task is still ours
+        set_in_syscall(true);
+    }
+}
+
+/// Returns how long the timer should run until the next scheduler tick, this is the time slice
+/// unless a sleeping task has to be woken up earlier. Nearby wakeups share a single tick.
+pub(crate) fn next_timer_period_us() -> usize {
+    let slice = time_slice_us();
+    // this runs in the timer interrupt, which must not allocate
+    let mut wakeup = None;
+    let mut collect = |process: &Process| {
+        if let Some(deadline) = process.wakeup_at() {
+            wakeup = time::coalesce_wakeup(wakeup, deadline, process.timer_slack_us());
+        }
+    };
+    if let Some(task) = unsafe { TASK.as_ref() } {
+        collect(&task.0);
+    }
+    // we are called from the timer interrupt, so we can't wait for the scheduler
+    match get_scheduler().try_lock() {
+        Some(scheduler) => scheduler.for_each_process(&mut collect),
+        None => return slice,
+    }
+    match wakeup {
+        Some(wakeup) => (wakeup.saturating_sub(time::monotonic_us()) as usize).min(slice).max(1),
+        None => slice,
+    }
+}
+
+/// Sets how late the current task may be woken up from sleeps, returns false if there is no current task.
+pub fn set_current_timer_slack_us(slack: u64) -> bool {
+    match unsafe { TASK.as_mut() } {
+        Some(task) => {
+            task.0.set_timer_slack_us(slack);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Gives up the rest of the current time slice, so other tasks can run.
+pub fn yield_now() {
+    if !is_interrupts_enabled() {
+        // the timer can't fire, so there is no way to give up the cpu. A task would keep spinning
+        // on whatever it waits for, which can never happen as nothing else gets to run.
+        assert!(unsafe { TASK.is_none() }, "task {} yielded with interrupts disabled", current_task_id());
+        spin_loop();
+        return;
+    }
+    interrupts::request_reschedule();
+    unsafe { wait_for_interrupt(); }
+}
+
+/// Terminates the running process. It's removed from the scheduler by the worker task
+/// as it can't free the stack it's still running on.
+pub fn exit_current() -> ! {
+    let id = without_interrupts(|| unsafe { TASK.as_mut() }.map(|task| {
+        task.0.state = State::ShuttingDown;
+        task.0.id()
+    }));
+    match id {
+        Some(id) => workqueue::queue(move || reap(id)),
+        None => panic!("the idle task can't exit"),
+    }
+    loop {
+        yield_now();
+    }
+}
+
+fn reap(id: u64) {
+    // release the scheduler before dropping the process, freeing its memory requires the heap lock
+    let process = without_interrupts(|| get_scheduler().lock().remove_process(id));
+    drop(process);
+}
+
+/// Calls `f` for every task including the running one (which gets passed `true`),
+/// returns false without calling `f` if the scheduler is currently locked.
+pub(crate) fn try_for_each_task(mut f: impl FnMut(&Process, &ProcessState, bool)) -> bool {
+    let scheduler = get_scheduler();
+    let scheduler = match scheduler.try_lock() {
+        Some(scheduler) => scheduler,
+        None => return false,
+    };
+    if let Some(task) = unsafe { TASK.as_ref() } {
+        f(&task.0, &task.1, true);
+    }
+    scheduler.for_each_task(&mut |process, state| f(process, state, false));
+    true
+}
+
+/// Returns the memory areas of the process with the given id.
+pub fn process_vmas(id: u64) -> Option<Vec<Vma>> {
+    with_process(id, |process| process.vmas().to_vec())
+}
+
+/// Returns the value of the environment variable of the current process.
+pub fn current_env_var(name: &str) -> Option<String> {
+    unsafe { TASK.as_ref() }.and_then(|task| task.0.env().get(name).map(String::from))
+}
+
+/// Calls `f` with the environment of the current process, the processes it starts inherit it.
+pub fn with_current_env<R>(f: impl FnOnce(&mut Environment) -> R) -> Option<R> {
+    without_interrupts(|| unsafe { TASK.as_mut() }.map(|task| f(task.0.env_mut())))
+}
+
+pub fn process_cpu_time(id: u64) -> Option<CpuTime> {
+    with_process(id, |process| process.cpu_time())
+}
+
+/// Calls `f` with the process with the given id, the timer interrupt takes the scheduler's lock
+/// as well so this runs with interrupts disabled.
+fn with_process<R>(id: u64, f: impl FnOnce(&Process) -> R) -> Option<R> {
+    without_interrupts(|| {
+        if let Some(task) = unsafe { TASK.as_ref() } {
+            if task.0.id() == id {
+                return Some(f(&task.0));
+            }
+        }
+        let mut f = Some(f);
+        let mut ret = None;
+        get_scheduler().lock().for_each_process(&mut |process| {
+            if process.id() == id {
+                ret = f.take().map(|f| f(process));
+            }
+        });
+        ret
+    })
+}
+
+/// Returns the ids of all processes, including the running one.
+pub fn process_ids() -> Vec<u64> {
+    let mut ret = without_interrupts(|| {
+        let mut ret = vec![];
+        if let Some(task) = unsafe { TASK.as_ref() } {
+            ret.push(task.0.id());
+        }
+        get_scheduler().lock().for_each_process(&mut |process| ret.push(process.id()));
+        ret
+    });
+    ret.sort_unstable();
+    ret
+}
+
+/// Attributes `bytes` of heap memory to the currently running process.
+pub(crate) fn charge_current(bytes: usize) {
+    if let Some(task) = unsafe { TASK.as_ref() } {
+        task.0.charge(bytes);
+    }
+}
+
+pub(crate) fn uncharge_current(bytes: usize) {
+    if let Some(task) = unsafe { TASK.as_ref() } {
+        task.0.uncharge(bytes);
+    }
+}
+
+pub fn current_time_namespace() -> Option<Arc<TimeNamespace>> {
+    unsafe { TASK.as_ref() }.and_then(|task| task.0.time_namespace().cloned())
+}
+
+/// Replaces the time namespace of the current process, returns false if there is no current process.
+pub fn set_current_time_namespace(ns: Option<Arc<TimeNamespace>>) -> bool {
+    match unsafe { TASK.as_mut() } {
+        Some(task) => {
+            task.0.set_time_namespace(ns);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Sets the oom score adjustment of the process with the given id, returns whether the process was found.
+pub fn set_oom_score_adj(id: u64, adj: i16) -> bool {
+    without_interrupts(|| {
+        if let Some(task) = unsafe { TASK.as_mut() } {
+            if task.0.id() == id {
+                task.0.set_oom_score_adj(adj);
+                return true;
+            }
+        }
+        let mut found = false;
+        get_scheduler().lock().for_each_process_mut(&mut |process| {
+            if process.id() == id {
+                process.set_oom_score_adj(adj);
+                found = true;
+            }
+        });
+        found
+    })
+}
+
+/// Picks the process with the highest badness score whose death frees at least `min_freed`
+/// bytes of heap and removes it from the scheduler. `badness` gets called with every process and
+/// the bytes freed by killing it. The running process is never picked as its stacks can't be
+/// freed while it's executing.
+///
+/// Returns the id of the killed process and the bytes freed.
+pub(crate) fn oom_kill_victim(badness: fn(&Process, usize) -> Option<isize>, min_freed: usize) -> Option<(u64, usize)> {
+    // we can't wait for the scheduler here as the allocation may have been issued while it was locked
+    let scheduler = get_scheduler();
+    let mut scheduler = scheduler.try_lock()?;
+    let mut victim: Option<(u64, isize)> = None;
+    scheduler.for_each_task(&mut |process, state| {
+        let freed = state.reclaimable_bytes();
+        if freed < min_freed {
+            return;
+        }
+        if let Some(score) = badness(process, freed) {
+            if victim.map_or(true, |(_, max)| score > max) {
+                victim = Some((process.id(), score));
+            }
+        }
+    });
+    let victim = scheduler.remove_process(victim?.0)?;
+    // release the scheduler before dropping the victim, freeing its memory requires the heap lock
+    drop(scheduler);
+    let ret = (victim.0.id(), victim.1.reclaimable_bytes());
+    drop(victim);
+    Some(ret)
+}
@@ -0,0 +1,166 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::syscall::{do_syscall_2, GETHOSTNAME};
+use crate::{allocators, cmdline, gdt, hostname, interrupts, memory, println, serial_print, serial_println, time};
+
+// With `selftest=1` on the kernel command line, quick invariant checks run once the kernel is
+// initialized. They are meant for real hardware, where the qemu based tests don't run, so every
+// result is reported over the serial port. The checks run in order and later ones rely on what
+// earlier ones checked (e.g. the syscall path on the IDT), so the first failure skips the rest.
+// The boot continues either way.
+
+struct SelfTest {
+    name: &'static str,
+    run: fn() -> Result<(), String>,
+}
+
+static SELFTESTS: &[SelfTest] = &[
+    SelfTest { name: "gdt", run: gdt::verify },
+    SelfTest { name: "idt", run: interrupts::verify_idt },
+    SelfTest { name: "frames", run: check_frames },
+    SelfTest { name: "heap", run: check_heap },
+    SelfTest { name: "timer", run: check_timer },
+    SelfTest { name: "syscall", run: check_syscall },
+];
+
+/// Far away from the heap and everything user programs get loaded to
+const USER_PAGE: u64 = 0x_6666_6666_0000;
+/// In the kernel half, the syscall has to refuse writing there
+const KERNEL_BUF: usize = 0xffff_8000_0000_0000;
+/// How long the timer gets to advance the monotonic clock
+const TIMER_TIMEOUT_NS: u64 = 100_000_000;
+const PATTERN: u8 = 0xa5;
+
+pub fn is_enabled() -> bool {
+    cmdline::get("selftest").as_deref() == Some("1")
+}
+
+/// Runs the self-tests if they are enabled, this has to be called once the timer is running.
+/// Returns whether every self-test passed.
+pub fn run() -> bool {
+    if !is_enabled() {
+        return true;
+    }
+    serial_println!("Running {} self-tests", SELFTESTS.len());
+    for (idx, selftest) in SELFTESTS.iter().enumerate() {
+        serial_print!("selftest {}...\t", selftest.name);
+        if let Err(reason) = (selftest.run)() {
+            serial_println!("[failed] {}", reason);
+            for skipped in &SELFTESTS[idx + 1..] {
+                serial_println!("selftest {}...\t[skipped]", skipped.name);
+            }
+            println!("self-test {} failed: {}", selftest.name, reason);
+            return false;
+        }
+        serial_println!("[ok]");
+    }
+    serial_println!("self-tests passed");
+    true
+}
+
+/// Writes a pattern to freshly allocated frames through the physical memory mapping.
+// FIXME: Free the frames again (and check that they get handed out again) once the frame
+//  allocator is replaced by one which can free frames, e.g. a buddy allocator.
+fn check_frames() -> Result<(), String> {
+    let first = memory::allocate_frame().ok_or("no frame left")?;
+    let second = memory::allocate_frame().ok_or("no frame left")?;
+    if first == second {
+        return Err(format!("{:?} was handed out twice", first));
+    }
+    for frame in [first, second] {
+        if !memory::is_ram(frame.start_address(), frame.size()) {
+            return Err(format!("{:?} isn't usable ram", frame));
+        }
+        let virt = memory::phys_to_virt(frame.start_address());
+        let data = unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), frame.size() as usize) };
+        data.fill(PATTERN);
+        if data.iter().any(|byte| *byte != PATTERN) {
+            return Err(format!("{:?} doesn't hold what was written to it", frame));
+        }
+    }
+    Ok(())
+}
+
+/// Makes small and large allocations from the kernel heap and checks that everything
+/// allocated got freed again.
+fn check_heap() -> Result<(), String> {
+    // interrupt handlers may allocate as well, which would throw off the numbers
+    without_interrupts(|| {
+        let before = allocators::try_heap_stats().ok_or("the heap is locked")?.used;
+        {
+            let small = (0..16).map(Box::new).collect::<Vec<_>>();
+            let large = vec![PATTERN; 16 * 1024];
+            if small.iter().enumerate().any(|(idx, value)| **value != idx) || large.iter().any(|byte| *byte != PATTERN) {
+                return Err(String::from("allocations don't hold what was written to them"));
+            }
+        }
+        let after = allocators::try_heap_stats().ok_or("the heap is locked")?.used;
+        if after != before {
+            return Err(format!("{} bytes were in use before and {} after freeing everything", before, after));
+        }
+        Ok(())
+    })
+}
+
+/// Checks that timer interrupts arrive and advance the monotonic clock, the tsc measures the timeout.
+/// `monotonic_us` reads the timer's counter, so it moves even if the interrupts never arrive,
+/// only the part the interrupt handler accumulates shows that they do.
+fn check_timer() -> Result<(), String> {
+    if time::tsc_hz() == 0 {
+        return Err(String::from("the tsc isn't calibrated, there is nothing to measure the timer against"));
+    }
+    let start = time::monotonic_base_us();
+    let deadline = time::rdtsc_ns() + TIMER_TIMEOUT_NS;
+    while time::monotonic_base_us() == start {
+        if time::rdtsc_ns() > deadline {
+            return Err(format!("the monotonic clock didn't advance within {}ms", TIMER_TIMEOUT_NS / 1_000_000));
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Maps a user page and lets the syscall path write the hostname into it, a kernel buffer has to
+/// be rejected.
+// FIXME: Run code from the page in ring 3 once there is a way to enter user mode, this only
+//  covers the `int 0x80` entry from the kernel and the argument checks.
+fn check_syscall() -> Result<(), String> {
+    let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    memory::map_zeroed_page(page, PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)
+        .map_err(|err| format!("mapping the user page failed: {:?}", err))?;
+    let result = call_gethostname(page);
+    memory::unmap_page(page);
+    result
+}
+
+fn call_gethostname(page: Page) -> Result<(), String> {
+    let mapping = memory::lookup(page.start_address()).ok_or("the user page isn't mapped")?;
+    if !mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err(String::from("the user page isn't accessible from user mode"));
+    }
+    let buf = page.start_address().as_u64() as usize;
+    let len = unsafe { do_syscall_2(GETHOSTNAME, buf, hostname::HOST_NAME_MAX) };
+    let expected = hostname::get();
+    let written = unsafe { core::slice::from_raw_parts(buf as *const u8, expected.len()) };
+    if len != expected.len() || written != expected.as_bytes() {
+        return Err(format!("gethostname returned {} and wrote {:?}", len as isize, String::from_utf8_lossy(written)));
+    }
+    let result = unsafe { do_syscall_2(GETHOSTNAME, KERNEL_BUF, hostname::HOST_NAME_MAX) };
+    if result != (Error::EFAULT as usize).wrapping_neg() {
+        return Err(format!("gethostname accepted the kernel buffer {:#x}", KERNEL_BUF));
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_descriptor_tables() {
+    crate::kassert_eq!(gdt::verify(), Ok(()));
+    crate::kassert_eq!(interrupts::verify_idt(), Ok(()));
+}
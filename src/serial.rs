@@ -2,14 +2,35 @@ use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
+const COM1: u16 = 0x3F8;
+/// Line status register, bit 5 is set when the transmitter can take the next byte
+const COM1_LINE_STATUS: u16 = COM1 + 5;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Writes to the first serial port without taking `SERIAL1`'s lock, for the panic path where the
+/// lock may be held by the code which panicked. Output may interleave with other writers.
+pub struct RawSerial;
+
+impl core::fmt::Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            unsafe {
+                while x86::io::inb(COM1_LINE_STATUS) & TRANSMIT_EMPTY == 0 {}
+                x86::io::outb(COM1, byte);
+            }
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -0,0 +1,239 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::crypto::crc32c::crc32c;
+use crate::error_codes::Error;
+use crate::events::{self, Layout};
+use crate::filesystem::{self, FileKind};
+use crate::sync::AdaptiveMutex;
+use crate::{hostname, log, log_warn, time};
+
+// Kernel settings which have to survive reboots are stored as `key=value` lines in
+// `SETTINGS_PATH`. The file has two slots, each holding a complete copy of the settings with a
+// generation number and a checksum. Updates always overwrite the older slot, so a write which
+// gets interrupted leaves the other slot intact and loading picks the newest valid slot.
+// Known settings are applied to the kernel when they are loaded or changed, unknown ones are
+// only stored.
+// FIXME: The root filesystem is a fresh RAM disk on every boot, so nothing survives a reboot
+//  until the root filesystem lives on a real disk.
+
+pub const SETTINGS_PATH: &str = "/etc/kernel.cfg";
+const SLOT_SIZE: usize = 4096;
+const MAGIC: &[u8; 4] = b"LCFG";
+/// magic, generation, payload length and payload checksum
+const HEADER_SIZE: usize = 4 + 8 + 4 + 4;
+
+struct Setting {
+    key: &'static str,
+    /// Gets called with `None` if the setting was removed, to restore the default
+    apply: fn(Option<&str>) -> Result<(), Error>,
+}
+
+static KNOWN: &[Setting] = &[
+    Setting { key: "loglevel", apply: apply_loglevel },
+    Setting { key: "keymap", apply: apply_keymap },
+    Setting { key: "timezone", apply: apply_timezone },
+    Setting { key: "hostname", apply: apply_hostname },
+];
+
+/// Serializes updates, it is held while the settings get written, so other tasks
+/// can still read them from `STORE` in the meantime
+static UPDATE: AdaptiveMutex<()> = AdaptiveMutex::new(());
+
+lazy_static! {
+    static ref STORE: Mutex<Store> = Mutex::new(Store {
+        values: BTreeMap::new(),
+        generation: 0,
+    });
+}
+
+struct Store {
+    values: BTreeMap<String, String>,
+    /// The generation of the newest slot on disk
+    generation: u64,
+}
+
+fn apply_loglevel(value: Option<&str>) -> Result<(), Error> {
+    let level = value.unwrap_or("warn").parse::<log::Level>().map_err(|_| Error::EINVAL)?;
+    log::set_console_level(level);
+    Ok(())
+}
+
+fn apply_keymap(value: Option<&str>) -> Result<(), Error> {
+    let layout = Layout::from_name(value.unwrap_or("us")).ok_or(Error::EINVAL)?;
+    events::set_layout(layout);
+    Ok(())
+}
+
+fn apply_timezone(value: Option<&str>) -> Result<(), Error> {
+    let offset = time::parse_utc_offset(value.unwrap_or("UTC")).ok_or(Error::EINVAL)?;
+    time::set_utc_offset_min(offset);
+    Ok(())
+}
+
+fn apply_hostname(value: Option<&str>) -> Result<(), Error> {
+    hostname::set(value.unwrap_or(hostname::DEFAULT_HOSTNAME))
+}
+
+fn apply(key: &str, value: Option<&str>) -> Result<(), Error> {
+    match KNOWN.iter().find(|setting| setting.key == key) {
+        Some(setting) => (setting.apply)(value),
+        None => Ok(()),
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'.' || byte == b'-')
+}
+
+fn encode(values: &BTreeMap<String, String>, generation: u64) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    for (key, value) in values.iter() {
+        payload.extend_from_slice(key.as_bytes());
+        payload.push(b'=');
+        payload.extend_from_slice(value.as_bytes());
+        payload.push(b'\n');
+    }
+    if HEADER_SIZE + payload.len() > SLOT_SIZE {
+        return Err(Error::E2BIG);
+    }
+    let mut slot = Vec::with_capacity(SLOT_SIZE);
+    slot.extend_from_slice(MAGIC);
+    slot.extend_from_slice(&generation.to_le_bytes());
+    slot.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    slot.extend_from_slice(&crc32c(&payload).to_le_bytes());
+    slot.extend_from_slice(&payload);
+    slot.resize(SLOT_SIZE, 0);
+    Ok(slot)
+}
+
+/// Returns the generation and the settings stored in the slot, `None` if it isn't valid.
+fn decode(slot: &[u8]) -> Option<(u64, BTreeMap<String, String>)> {
+    if slot.len() < HEADER_SIZE || &slot[0..4] != MAGIC {
+        return None;
+    }
+    let generation = u64::from_le_bytes(slot[4..12].try_into().unwrap());
+    let len = u32::from_le_bytes(slot[12..16].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(slot[16..20].try_into().unwrap());
+    let payload = slot.get(HEADER_SIZE..HEADER_SIZE + len)?;
+    if crc32c(payload) != checksum {
+        return None;
+    }
+    let mut values = BTreeMap::new();
+    for line in core::str::from_utf8(payload).ok()?.lines() {
+        let (key, value) = line.split_once('=')?;
+        values.insert(String::from(key), String::from(value));
+    }
+    Some((generation, values))
+}
+
+/// Loads the settings and applies the known ones, this gets called once the root filesystem is mounted.
+pub fn load() -> Result<(), Error> {
+    let data = match filesystem::read_file(SETTINGS_PATH) {
+        Ok(data) => data,
+        // nothing was stored yet
+        Err(Error::ENOENT) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let newest = data.chunks(SLOT_SIZE)
+        .take(2)
+        .filter_map(decode)
+        .max_by_key(|(generation, _)| *generation);
+    let (generation, values) = newest.ok_or(Error::EIO)?;
+    for (key, value) in values.iter() {
+        if let Err(err) = apply(key, Some(value)) {
+            log_warn!("ignoring the stored setting {}={}: {}", key, value, err.description());
+        }
+    }
+    let mut store = STORE.lock();
+    store.values = values;
+    store.generation = generation;
+    Ok(())
+}
+
+/// Writes the settings into the older slot.
+fn persist(values: &BTreeMap<String, String>, generation: u64) -> Result<(), Error> {
+    let slot = encode(values, generation)?;
+    if let Err(Error::ENOENT) = filesystem::stat(SETTINGS_PATH) {
+        if let Err(Error::ENOENT) = filesystem::stat("/etc") {
+            filesystem::create("/etc", FileKind::Directory)?;
+        }
+        filesystem::create(SETTINGS_PATH, FileKind::File)?;
+    }
+    let offset = (generation % 2) * SLOT_SIZE as u64;
+    let written = filesystem::write(SETTINGS_PATH, offset, &slot)?;
+    if written != slot.len() {
+        return Err(Error::EIO);
+    }
+    Ok(())
+}
+
+pub fn get(key: &str) -> Option<String> {
+    STORE.lock().values.get(key).cloned()
+}
+
+/// Calls `f` with every stored setting.
+pub fn for_each(mut f: impl FnMut(&str, &str)) {
+    for (key, value) in STORE.lock().values.iter() {
+        f(key, value);
+    }
+}
+
+/// Sets (or removes if the value is `None`) all the given settings at once. Either every change
+/// gets applied and stored or, if any value is invalid or storing fails, none of them.
+pub fn update(changes: &[(&str, Option<&str>)]) -> Result<(), Error> {
+    let _update = UPDATE.lock();
+    let (old_values, generation) = {
+        let store = STORE.lock();
+        (store.values.clone(), store.generation)
+    };
+    let mut values = old_values.clone();
+    for (key, value) in changes.iter() {
+        if !is_valid_key(key) || value.map_or(false, |value| value.contains('\n')) {
+            return Err(Error::EINVAL);
+        }
+        match value {
+            Some(value) => values.insert(String::from(*key), String::from(*value)),
+            None => values.remove(*key),
+        };
+    }
+    // apply the changes first, so invalid values are rejected before anything gets stored
+    let mut applied = Vec::new();
+    let mut result = Ok(());
+    for (key, _) in changes.iter() {
+        result = apply(key, values.get(*key).map(String::as_str));
+        if result.is_err() {
+            break;
+        }
+        applied.push(*key);
+    }
+    if result.is_ok() {
+        result = persist(&values, generation + 1);
+    }
+    if let Err(err) = result {
+        for key in applied {
+            let _ = apply(key, old_values.get(key).map(String::as_str));
+        }
+        return Err(err);
+    }
+    let mut store = STORE.lock();
+    store.values = values;
+    store.generation = generation + 1;
+    Ok(())
+}
+
+#[test_case]
+fn test_slot_encoding() {
+    let mut values = BTreeMap::new();
+    values.insert(String::from("keymap"), String::from("uk"));
+    values.insert(String::from("answer"), String::from("a=42"));
+    let mut slot = encode(&values, 7).unwrap();
+    crate::kassert_eq!(slot.len(), SLOT_SIZE);
+    crate::kassert_eq!(decode(&slot), Some((7, values)));
+    // a torn write
+    slot[HEADER_SIZE] ^= 0xff;
+    crate::kassert_eq!(decode(&slot), None);
+    crate::kassert!(!is_valid_key("a=b"));
+}
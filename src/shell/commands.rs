@@ -0,0 +1,747 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::drivers::block;
+use crate::drivers::rtc::DateTime;
+use crate::environ::Environment;
+use crate::error_codes::Error;
+use crate::filesystem::{self, FileKind};
+use crate::filesystem::leaffs::{self, LeafFs};
+use crate::{arch, hostname, log, memory, percpu, power, print, scheduler, settings, time};
+use crate::print::Sink;
+use crate::arch::x86::{features, topology};
+use crate::shell::jobs;
+use crate::shell::pager::{self, Pager};
+use crate::shell::parser::{self, Pipeline, Redirect};
+
+/// The environment a command gets executed in.
+pub struct CommandContext<'a> {
+    /// Everything the command can read, this is either the output of the
+    /// previous command in the pipeline or the contents of a redirected file
+    pub stdin: &'a [u8],
+    pub stdout: Vec<u8>,
+}
+
+impl fmt::Write for CommandContext<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.stdout.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub type CommandFn = fn(args: &[String], ctx: &mut CommandContext) -> Result<(), Error>;
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+}
+
+// FIXME: Add a `wget <url>` builtin as an end to end test of the network stack (nic driver, ip,
+// tcp, dns and vfs writes) once we have one, there is no nic driver or socket api to build it on yet.
+// FIXME: Add a dns stub resolver (queries over udp to the server learned from dhcp, a positive and
+//  negative cache and /proc/net/dns) once there is a nic driver and udp sockets to send the queries with.
+// FIXME: Add a neighbor cache with arp resolution and revalidation, shown in /proc/net/arp and by an
+//  `arp` builtin, once there is a nic driver to send the requests through.
+// FIXME: Add a packet buffer with head and tail room and a frame backed pool for it once there is a
+//  nic rx ring and ip and tcp layers to pass it along, there is nothing to shape its api after yet.
+// FIXME: Add a `ping <ip>` builtin measuring the rtt of icmp echo requests and let udp and ip send
+//  destination unreachable messages once there is an ip layer on top of a nic driver.
+// FIXME: Add `netstat` with per protocol counters and socket states, also exposed through
+//  /proc/net/{dev,tcp,udp}, once there are sockets to report on.
+static BUILTINS: &[Builtin] = &[
+    Builtin { name: "help", help: "lists all available commands", run: help },
+    Builtin { name: "echo", help: "prints its arguments", run: echo },
+    Builtin { name: "wc", help: "counts lines, words and bytes of its input", run: wc },
+    Builtin { name: "diskinfo", help: "shows identification and health of block devices", run: diskinfo },
+    Builtin { name: "cpuinfo", help: "shows the online cpus with their topology and features", run: cpuinfo },
+    Builtin { name: "mkfs", help: "creates an empty LeafFS on a block device", run: mkfs },
+    Builtin { name: "mount", help: "lists mounts or mounts a LeafFS device at a path", run: mount },
+    Builtin { name: "umount", help: "unmounts the filesystem at a path", run: umount },
+    Builtin { name: "ls", help: "lists the contents of a directory", run: ls },
+    Builtin { name: "cat", help: "prints its input or the given files", run: cat },
+    Builtin { name: "less", help: "shows a file page by page", run: less },
+    Builtin { name: "xxd", help: "shows a hex dump of its input or the given file", run: xxd },
+    #[cfg(debug_assertions)]
+    Builtin { name: "peek", help: "dumps kernel memory: peek <phys|virt> <addr> <len>", run: peek },
+    #[cfg(feature = "heap-profile")]
+    Builtin { name: "heapprofile", help: "shows the allocation sites holding the most heap memory", run: heapprofile },
+    #[cfg(debug_assertions)]
+    Builtin { name: "wxaudit", help: "lists writable+executable mappings and user accessible kernel pages", run: wxaudit },
+    Builtin { name: "mkdir", help: "creates a directory", run: mkdir },
+    Builtin { name: "rm", help: "removes a file or an empty directory", run: rm },
+    Builtin { name: "pmap", help: "shows the memory areas of a process", run: pmap },
+    Builtin { name: "export", help: "sets environment variables given as NAME=value", run: export },
+    Builtin { name: "unset", help: "removes environment variables", run: unset },
+    Builtin { name: "env", help: "lists the environment variables", run: env },
+    Builtin { name: "suspend", help: "suspends the system until a key is pressed", run: suspend },
+    Builtin { name: "shutdown", help: "powers off (-r reboots) now or after -t <seconds>, -c cancels", run: shutdown },
+    Builtin { name: "console", help: "lists the output sinks, add/remove <sink> toggles them", run: console },
+    Builtin { name: "dmesg", help: "prints the kernel log", run: dmesg },
+    Builtin { name: "loglevel", help: "shows or sets the console log level, -s sets the stored one", run: loglevel },
+    Builtin { name: "hostname", help: "shows or sets the hostname until the next boot", run: hostname },
+    Builtin { name: "getcfg", help: "prints the persistent kernel settings or the given ones", run: getcfg },
+    Builtin { name: "setcfg", help: "changes persistent kernel settings given as key=value (key= removes them)", run: setcfg },
+    Builtin { name: "jobs", help: "lists the jobs started with a trailing &", run: jobs },
+    Builtin { name: "fg", help: "waits for a job (%n, the latest by default) in the foreground and shows its output", run: fg },
+    Builtin { name: "bg", help: "continues a job (%n, the latest by default) in the background", run: bg },
+];
+
+fn find_builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+fn help(_args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    for builtin in BUILTINS {
+        let _ = writeln!(ctx, "{:<12}{}", builtin.name, builtin.help);
+    }
+    Ok(())
+}
+
+fn echo(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let mut first = true;
+    for arg in &args[1..] {
+        if !first {
+            ctx.stdout.push(b' ');
+        }
+        ctx.stdout.extend_from_slice(arg.as_bytes());
+        first = false;
+    }
+    ctx.stdout.push(b'\n');
+    Ok(())
+}
+
+fn wc(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let lines = ctx.stdin.iter().filter(|b| **b == b'\n').count();
+    let words = ctx.stdin
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .count();
+    let bytes = ctx.stdin.len();
+    let result = match args.get(1).map(|arg| arg.as_str()) {
+        None => writeln!(ctx, "{} {} {}", lines, words, bytes),
+        Some("-l") => writeln!(ctx, "{}", lines),
+        Some("-w") => writeln!(ctx, "{}", words),
+        Some("-c") => writeln!(ctx, "{}", bytes),
+        Some(_) => return Err(Error::EINVAL),
+    };
+    result.map_err(|_| Error::EIO)
+}
+
+fn format_size(bytes: u64) -> (u64, &'static str) {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    (size, UNITS[unit])
+}
+
+fn diskinfo(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let filter = args.get(1);
+    let mut found = false;
+    let mut out = String::new();
+    block::for_each_device(|device| {
+        if filter.map_or(false, |name| *name != device.name) {
+            return;
+        }
+        found = true;
+        let _ = writeln!(out, "{}:", device.name);
+        match unsafe { device.driver.identify() } {
+            Some(identity) => {
+                let (size, unit) = format_size(identity.capacity());
+                let _ = writeln!(out, "  model:      {}", identity.model);
+                let _ = writeln!(out, "  serial:     {}", identity.serial);
+                let _ = writeln!(out, "  firmware:   {}", identity.firmware);
+                let _ = writeln!(out, "  capacity:   {} {} ({} blocks of {} bytes)", size, unit, identity.block_count, identity.block_size);
+            },
+            None => {
+                let _ = writeln!(out, "  identification not supported");
+            },
+        }
+        match unsafe { device.driver.health() } {
+            Some(health) => {
+                let _ = writeln!(out, "  health:     {}", if health.healthy { "ok" } else { "FAILING" });
+                if let Some(temperature) = health.temperature_celsius {
+                    let _ = writeln!(out, "  temperature: {} C", temperature);
+                }
+                if let Some(hours) = health.power_on_hours {
+                    let _ = writeln!(out, "  power on:   {} hours", hours);
+                }
+                if let Some(cycles) = health.power_cycles {
+                    let _ = writeln!(out, "  power cycles: {}", cycles);
+                }
+                if let Some(errors) = health.media_errors {
+                    let _ = writeln!(out, "  media errors: {}", errors);
+                }
+                if let Some(used) = health.percentage_used {
+                    let _ = writeln!(out, "  wear:       {}%", used);
+                }
+            },
+            None => {
+                let _ = writeln!(out, "  health reporting not supported");
+            },
+        }
+    });
+    ctx.stdout.extend_from_slice(out.as_bytes());
+    if !found {
+        if filter.is_some() {
+            return Err(Error::ENOENT);
+        }
+        let _ = writeln!(ctx, "no block devices found");
+    }
+    Ok(())
+}
+
+fn cpuinfo(_args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let mut cores: Vec<(u32, u32)> = vec![];
+    let mut packages: Vec<u32> = vec![];
+    for cpu in 0..percpu::online_cpus() {
+        let topology = match topology::get(cpu) {
+            Some(topology) => topology,
+            None => {
+                let _ = writeln!(ctx, "cpu{}: topology unknown", cpu);
+                continue;
+            },
+        };
+        let _ = writeln!(ctx, "cpu{}: package {} core {} thread {} (apic id {})",
+                 cpu, topology.package, topology.core, topology.thread, topology.apic_id);
+        let mut siblings = String::new();
+        topology::siblings(cpu, |sibling| { let _ = write!(siblings, " cpu{}", sibling); });
+        if !siblings.is_empty() {
+            let _ = writeln!(ctx, "  siblings:{}", siblings);
+        }
+        if !cores.contains(&(topology.package, topology.core)) {
+            cores.push((topology.package, topology.core));
+        }
+        if !packages.contains(&topology.package) {
+            packages.push(topology.package);
+        }
+    }
+    let _ = writeln!(ctx, "{} packages, {} cores, {} threads", packages.len(), cores.len(), percpu::online_cpus());
+    if let Some(features) = features::bsp_features() {
+        let mut names = vec![];
+        let flags = [("nx", features.nx), ("syscall", features.syscall), ("x2apic", features.x2apic),
+            ("sse4.2", features.sse4_2), ("xsave", features.xsave)];
+        for (name, present) in flags {
+            if present {
+                names.push(name);
+            }
+        }
+        let _ = writeln!(ctx, "features: {}", names.join(" "));
+        let _ = writeln!(ctx, "apic mode: {}", if features.x2apic_enabled { "x2apic" } else { "xapic" });
+        let _ = writeln!(ctx, "microcode: {:#x}", features.microcode);
+    }
+    Ok(())
+}
+
+fn mkfs(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let device = args.get(1).ok_or(Error::EINVAL)?;
+    if filesystem::is_source_mounted(device) {
+        return Err(Error::EBUSY);
+    }
+    leaffs::format(device)?;
+    let _ = writeln!(ctx, "created leaffs v{} on {}", leaffs::VERSION, device);
+    Ok(())
+}
+
+fn mount(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    match (args.get(1), args.get(2)) {
+        (None, _) => {
+            let mut out = String::new();
+            filesystem::for_each_mount(|path, fs, source| {
+                let _ = writeln!(out, "{} on {} type {}", source, path, fs);
+            });
+            ctx.stdout.extend_from_slice(out.as_bytes());
+            Ok(())
+        },
+        (Some(device), Some(path)) => {
+            if filesystem::is_source_mounted(device) {
+                return Err(Error::EBUSY);
+            }
+            filesystem::mount(path, Box::new(LeafFs::mount(device)?))
+        },
+        _ => Err(Error::EINVAL),
+    }
+}
+
+fn umount(args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    filesystem::unmount(args.get(1).ok_or(Error::EINVAL)?)
+}
+
+fn ls(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let path = args.get(1).map_or("/", |path| path.as_str());
+    for entry in filesystem::read_dir(path)? {
+        let suffix = if entry.kind == FileKind::Directory { "/" } else { "" };
+        let _ = writeln!(ctx, "{}{}", entry.name, suffix);
+    }
+    Ok(())
+}
+
+fn cat(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    if args.len() == 1 {
+        ctx.stdout.extend_from_slice(ctx.stdin);
+        return Ok(());
+    }
+    let mut chunk = [0; pager::CHUNK_SIZE];
+    for path in &args[1..] {
+        if filesystem::stat(path)?.kind != FileKind::File {
+            return Err(Error::EISDIR);
+        }
+        let mut offset = 0;
+        loop {
+            let read = filesystem::read(path, offset, &mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            ctx.stdout.extend_from_slice(&chunk[..read]);
+            offset += read as u64;
+        }
+    }
+    Ok(())
+}
+
+fn less(args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    let path = args.get(1).ok_or(Error::EINVAL)?;
+    pager::open_pending(Pager::open(path)?);
+    Ok(())
+}
+
+/// Writes `data` in the format of xxd, 16 bytes per line, `base` is the address of the first byte.
+fn hexdump(out: &mut impl Write, base: u64, data: &[u8]) {
+    for (idx, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x}: ", base + idx as u64 * 16);
+        for col in 0..16 {
+            match line.get(col) {
+                Some(byte) => { let _ = write!(out, "{:02x}", byte); },
+                None => { let _ = write!(out, "  "); },
+            }
+            if col % 2 == 1 {
+                let _ = write!(out, " ");
+            }
+        }
+        let _ = write!(out, " ");
+        for byte in line {
+            let _ = write!(out, "{}", if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' });
+        }
+        let _ = writeln!(out);
+    }
+}
+
+fn xxd(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let path = match args.get(1) {
+        Some(path) => path,
+        None => {
+            let mut out = String::new();
+            hexdump(&mut out, 0, ctx.stdin);
+            ctx.stdout.extend_from_slice(out.as_bytes());
+            return Ok(());
+        },
+    };
+    if filesystem::stat(path)?.kind != FileKind::File {
+        return Err(Error::EISDIR);
+    }
+    // a multiple of the line length, so every chunk starts on a new line
+    let mut chunk = [0; pager::CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = filesystem::read(path, offset, &mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        hexdump(ctx, offset, &chunk[..read]);
+        offset += read as u64;
+    }
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+fn peek(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    const MAX_LEN: usize = 4096;
+    let parse = |arg: Option<&String>| {
+        let arg = arg.ok_or(Error::EINVAL)?;
+        match arg.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => arg.parse::<u64>(),
+        }.map_err(|_| Error::EINVAL)
+    };
+    let addr = parse(args.get(2))?;
+    let len = parse(args.get(3))? as usize;
+    if len > MAX_LEN {
+        return Err(Error::E2BIG);
+    }
+    let mut buf = vec![0; len];
+    let virt = match args.get(1).map(|arg| arg.as_str()) {
+        Some("phys") => {
+            let phys = PhysAddr::try_new(addr).map_err(|_| Error::EINVAL)?;
+            // memory mapped registers may have side effects when being read
+            if !memory::is_ram(phys, len as u64) {
+                return Err(Error::EFAULT);
+            }
+            memory::phys_to_virt(phys)
+        },
+        Some("virt") => VirtAddr::try_new(addr).map_err(|_| Error::EINVAL)?,
+        _ => return Err(Error::EINVAL),
+    };
+    if let Err(unmapped) = memory::read_virt(virt, &mut buf) {
+        let _ = writeln!(ctx, "{:#x} is not mapped", unmapped.as_u64());
+        return Err(Error::EFAULT);
+    }
+    hexdump(ctx, addr, &buf);
+    Ok(())
+}
+
+#[cfg(feature = "heap-profile")]
+fn heapprofile(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    use crate::allocators::profile::{self, Sample, SAMPLE_INTERVAL};
+
+    let top = match args.get(1) {
+        Some(count) => count.parse::<usize>().map_err(|_| Error::EINVAL)?,
+        None => 10,
+    };
+    // collecting the samples must not allocate, as the allocator records into the same table
+    let mut samples: Vec<Sample> = Vec::with_capacity(profile::MAX_SAMPLES);
+    profile::for_each_sample(|sample| {
+        if samples.len() < samples.capacity() {
+            samples.push(*sample);
+        }
+    });
+    samples.sort_unstable_by_key(|sample| sample.site);
+    // (site, bytes, allocations) of every site
+    let mut sites: Vec<(profile::Site, usize, usize)> = vec![];
+    for sample in samples.iter() {
+        match sites.last_mut() {
+            Some((site, bytes, count)) if *site == sample.site => {
+                *bytes += sample.size;
+                *count += 1;
+            },
+            _ => sites.push((sample.site, sample.size, 1)),
+        }
+    }
+    sites.sort_unstable_by_key(|(_, bytes, _)| core::cmp::Reverse(*bytes));
+    let _ = writeln!(ctx, "{} live samples, 1 in {} allocations is sampled, {} samples dropped",
+                     samples.len(), SAMPLE_INTERVAL, profile::dropped_samples());
+    let _ = writeln!(ctx, "{:>10} {:>8}  return addresses (estimated totals)", "bytes", "allocs");
+    for (site, bytes, count) in sites.iter().take(top) {
+        let _ = write!(ctx, "{:>10} {:>8} ", bytes * SAMPLE_INTERVAL, count * SAMPLE_INTERVAL);
+        for addr in site.iter().take_while(|addr| **addr != 0) {
+            let _ = write!(ctx, " {:#x}", addr);
+        }
+        let _ = writeln!(ctx);
+    }
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+fn wxaudit(_args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let mut count = 0;
+    memory::audit_mappings(|violation| {
+        let _ = writeln!(ctx, "{}", violation);
+        count += 1;
+    });
+    let _ = writeln!(ctx, "{} violations", count);
+    Ok(())
+}
+
+fn mkdir(args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    filesystem::create(args.get(1).ok_or(Error::EINVAL)?, FileKind::Directory)
+}
+
+fn rm(args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    filesystem::remove(args.get(1).ok_or(Error::EINVAL)?)
+}
+
+fn pmap(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let pid = args.get(1).ok_or(Error::EINVAL)?.parse::<u64>().map_err(|_| Error::EINVAL)?;
+    let vmas = scheduler::process_vmas(pid).ok_or(Error::ENOENT)?;
+    let _ = writeln!(ctx, "{}:", pid);
+    let _ = writeln!(ctx, "{:<18} {:>8} {:>8} {:>8} {:>8} {:>8} mode  mapping", "address", "kbytes", "rss", "shared", "dirty", "swap");
+    let mut total = memory::MemoryStats::default();
+    for vma in vmas.iter() {
+        let stats = memory::memory_stats(VirtAddr::new(vma.start), VirtAddr::new(vma.end));
+        let _ = writeln!(ctx, "{:016x}   {:>8} {:>8} {:>8} {:>8} {:>8} r{}{}-  {}", vma.start,
+                         stats.size / 1024, stats.resident / 1024, stats.shared / 1024,
+                         stats.private_dirty / 1024, stats.swap / 1024,
+                         if vma.writable { 'w' } else { '-' }, if vma.executable { 'x' } else { '-' }, vma.name);
+        total.size += stats.size;
+        total.resident += stats.resident;
+        total.shared += stats.shared;
+        total.private_dirty += stats.private_dirty;
+        total.swap += stats.swap;
+    }
+    let _ = writeln!(ctx, "{:<18} {:>8} {:>8} {:>8} {:>8} {:>8}", "total kB", total.size / 1024,
+                     total.resident / 1024, total.shared / 1024, total.private_dirty / 1024, total.swap / 1024);
+    Ok(())
+}
+
+fn export(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    if args.len() == 1 {
+        return env(args, ctx);
+    }
+    // the shell task's own environment, the jobs it starts inherit it
+    with_shell_env(|env| {
+        for arg in &args[1..] {
+            let (name, value) = arg.split_once('=').ok_or(Error::EINVAL)?;
+            if !env.set(name, value) {
+                return Err(Error::EINVAL);
+            }
+        }
+        Ok(())
+    })
+}
+
+fn unset(args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    with_shell_env(|env| {
+        for name in &args[1..] {
+            env.unset(name);
+        }
+        Ok(())
+    })
+}
+
+fn env(_args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    for var in with_shell_env(|env| Ok(env.to_envp()))? {
+        let _ = writeln!(ctx, "{}", var);
+    }
+    Ok(())
+}
+
+fn with_shell_env<R>(f: impl FnOnce(&mut Environment) -> Result<R, Error>) -> Result<R, Error> {
+    scheduler::with_current_env(f).unwrap_or(Err(Error::ESRCH))
+}
+
+fn suspend(_args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    // the power task does the actual work, so the prompt gets drawn before the system sleeps
+    power::request_suspend();
+    Ok(())
+}
+
+/// The wakeup of a delayed shutdown, 0 if there is none
+static PENDING_SHUTDOWN: AtomicU64 = AtomicU64::new(0);
+
+fn shutdown(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let mut reboot = false;
+    let mut delay = None;
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" => reboot = true,
+            "-t" => delay = Some(args.next().and_then(|secs| secs.parse::<u64>().ok()).ok_or(Error::EINVAL)?),
+            "-c" => {
+                let id = PENDING_SHUTDOWN.swap(0, Ordering::SeqCst);
+                if id == 0 || !time::cancel_wakeup(id) {
+                    return Err(Error::ENOENT);
+                }
+                let _ = writeln!(ctx, "shutdown cancelled");
+                return Ok(());
+            },
+            _ => return Err(Error::EINVAL),
+        }
+    }
+    let action: fn() = if reboot {
+        || arch::x86::reboot()
+    } else {
+        || arch::x86::power_off()
+    };
+    match delay {
+        None => action(),
+        Some(secs) => {
+            let at = time::wallclock() + secs;
+            let previous = PENDING_SHUTDOWN.swap(time::wake_at(at, action), Ordering::SeqCst);
+            if previous != 0 {
+                time::cancel_wakeup(previous);
+            }
+            let at = DateTime::from_unix(at);
+            let _ = writeln!(ctx, "{} scheduled for {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", if reboot { "reboot" } else { "power off" },
+                             at.year, at.month, at.day, at.hour, at.minute, at.second);
+        },
+    }
+    Ok(())
+}
+
+fn dmesg(_args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    ctx.stdout.extend_from_slice(&log::read_buffer());
+    Ok(())
+}
+
+fn console(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let sink = || args.get(2).and_then(|name| Sink::from_name(name)).ok_or(Error::EINVAL);
+    match args.get(1).map(|arg| arg.as_str()) {
+        None => {
+            for sink in Sink::ALL {
+                let state = if print::is_enabled(sink) {
+                    "enabled"
+                } else if sink.is_available() {
+                    "disabled"
+                } else {
+                    "unavailable"
+                };
+                let _ = writeln!(ctx, "{:<12}{}", sink.name(), state);
+            }
+            Ok(())
+        },
+        Some("add") => print::enable(sink()?),
+        Some("remove") => print::disable(sink()?),
+        Some(_) => Err(Error::EINVAL),
+    }
+}
+
+fn loglevel(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let parse = |level: Option<&String>| level.ok_or(Error::EINVAL)?.parse::<log::Level>().map_err(|_| Error::EINVAL);
+    match args.get(1).map(|arg| arg.as_str()) {
+        None => {
+            let _ = writeln!(ctx, "stored: {}, console: {}", log::max_level().name(), log::console_level().name());
+        },
+        Some("-s") => log::set_max_level(parse(args.get(2))?),
+        Some(_) => log::set_console_level(parse(args.get(1))?),
+    }
+    Ok(())
+}
+
+fn hostname(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    match args.get(1) {
+        Some(name) => hostname::set(name),
+        None => {
+            let _ = writeln!(ctx, "{}", hostname::get());
+            Ok(())
+        },
+    }
+}
+
+fn getcfg(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    if args.len() < 2 {
+        let mut out = String::new();
+        settings::for_each(|key, value| {
+            let _ = writeln!(out, "{}={}", key, value);
+        });
+        ctx.stdout.extend_from_slice(out.as_bytes());
+        return Ok(());
+    }
+    for key in &args[1..] {
+        let value = settings::get(key).ok_or(Error::ENOENT)?;
+        let _ = writeln!(ctx, "{}", value);
+    }
+    Ok(())
+}
+
+fn setcfg(args: &[String], _ctx: &mut CommandContext) -> Result<(), Error> {
+    if args.len() < 2 {
+        return Err(Error::EINVAL);
+    }
+    let mut changes = vec![];
+    for arg in &args[1..] {
+        let (key, value) = arg.split_once('=').ok_or(Error::EINVAL)?;
+        changes.push((key, if value.is_empty() { None } else { Some(value) }));
+    }
+    settings::update(&changes)
+}
+
+fn jobs(_args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let mut out = String::new();
+    jobs::for_each(|id, state, line| {
+        let _ = writeln!(out, "[{}]  {:<8}{}", id, state.name(), line);
+    });
+    ctx.stdout.extend_from_slice(out.as_bytes());
+    Ok(())
+}
+
+fn fg(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let (line, output) = jobs::foreground(args.get(1))?;
+    let _ = writeln!(ctx, "{}", line);
+    ctx.stdout.extend_from_slice(output.as_bytes());
+    Ok(())
+}
+
+fn bg(args: &[String], ctx: &mut CommandContext) -> Result<(), Error> {
+    let (id, line) = jobs::background(args.get(1))?;
+    let _ = writeln!(ctx, "[{}]  {} &", id, line);
+    Ok(())
+}
+
+fn read_redirect(path: &str) -> Result<Vec<u8>, Error> {
+    filesystem::read_file(path)
+}
+
+fn write_redirect(redirect: &Redirect, data: &[u8]) -> Result<(), Error> {
+    filesystem::write_file(&redirect.path, data, redirect.append)
+}
+
+/// Runs all commands of the pipeline one after another, connecting each command's
+/// output to the input of the next one, and returns the output of the last command.
+// FIXME: Fork and exec a process per command, connected by pipes with the redirections set up
+//  through dup2, and wait for them as a job once the kernel can enter user mode and processes
+//  have file descriptor tables. Until then only builtins exist and the pipes are buffers.
+fn run_pipeline(pipeline: &Pipeline, output: &mut String) {
+    let mut pipe: Vec<u8> = vec![];
+    for command in pipeline.commands.iter() {
+        let name = command.args[0].as_str();
+        let stdin = match &command.stdin {
+            Some(path) => match read_redirect(path) {
+                Ok(data) => data,
+                Err(err) => {
+                    let _ = writeln!(output, "{}: {}", path, err);
+                    return;
+                },
+            },
+            None => core::mem::take(&mut pipe),
+        };
+        let mut ctx = CommandContext {
+            stdin: &stdin,
+            stdout: vec![],
+        };
+        match find_builtin(name) {
+            Some(builtin) => {
+                if let Err(err) = (builtin.run)(&command.args, &mut ctx) {
+                    let _ = writeln!(ctx, "{}: {}", name, err);
+                }
+            },
+            None => {
+                let _ = writeln!(ctx, "{}: command not found", name);
+            },
+        }
+        pipe = match &command.stdout {
+            Some(redirect) => {
+                if let Err(err) = write_redirect(redirect, &ctx.stdout) {
+                    let _ = writeln!(output, "{}: {}", redirect.path, err);
+                    return;
+                }
+                vec![]
+            },
+            None => ctx.stdout,
+        };
+    }
+    output.push_str(&String::from_utf8_lossy(&pipe));
+}
+
+/// Parses and executes the given command line and returns everything it printed.
+pub fn execute(line: &str) -> String {
+    // report the background jobs which finished since the last command line
+    let mut output = jobs::take_finished();
+    match parser::parse(line) {
+        Ok(Some(pipeline)) if pipeline.background => {
+            let id = jobs::spawn(line, pipeline, run_pipeline);
+            let _ = writeln!(output, "[{}]", id);
+        },
+        Ok(Some(pipeline)) => run_pipeline(&pipeline, &mut output),
+        Ok(None) => {},
+        Err(err) => {
+            let _ = writeln!(output, "syntax error: {}", err);
+        },
+    }
+    output
+}
+
+#[test_case]
+fn test_hexdump() {
+    let mut out = String::new();
+    hexdump(&mut out, 0x10, b"Hello, world!\n\x00\xffxy");
+    crate::kassert_eq!(out.as_str(), "00000010: 4865 6c6c 6f2c 2077 6f72 6c64 210a 00ff  Hello, world!...\n\
+                                      00000020: 7879                                     xy\n");
+}
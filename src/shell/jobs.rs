@@ -0,0 +1,162 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::shell::parser::Pipeline;
+use crate::sync::Completion;
+use crate::{scheduler, workqueue};
+
+// Commands started with a trailing `&` run on the worker task while the shell keeps accepting input.
+// Their output is collected and shown once they are done, either before the output of the next
+// command line or by `fg`, which waits for the job to finish.
+// FIXME: Jobs have no process groups and there is no TTY layer, so there is no foreground group
+//  which `fg` could hand the terminal to, and no signals. Jobs can neither be stopped (there is
+//  no ^Z), so `bg` has nothing to resume, nor interrupted while `fg` waits for them (there is no
+//  ^C). All of this needs processes launched from the shell, signals and a TTY layer.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Done,
+}
+
+impl JobState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobState::Running => "Running",
+            JobState::Done => "Done",
+        }
+    }
+}
+
+struct Job {
+    line: String,
+    state: JobState,
+    output: String,
+    /// Gets completed once the job finished
+    done: Arc<Completion>,
+}
+
+struct JobTable {
+    next_id: usize,
+    jobs: BTreeMap<usize, Job>,
+}
+
+lazy_static! {
+    /// The jobs of the shell, they get removed once their output was shown
+    static ref JOBS: Mutex<JobTable> = Mutex::new(JobTable {
+        next_id: 1,
+        jobs: BTreeMap::new(),
+    });
+}
+
+/// The task which runs the jobs while it runs one, it must not wait for a job as it would never run
+static JOB_TASK: AtomicU64 = AtomicU64::new(0);
+
+/// Starts running the pipeline in the background and returns its job id.
+pub fn spawn(line: &str, pipeline: Pipeline, run: fn(&Pipeline, &mut String)) -> usize {
+    let id = without_interrupts(|| {
+        let mut table = JOBS.lock();
+        // like other shells we reuse the ids once all jobs are done
+        if table.jobs.is_empty() {
+            table.next_id = 1;
+        }
+        let id = table.next_id;
+        table.next_id += 1;
+        table.jobs.insert(id, Job {
+            line: String::from(line.trim_end().trim_end_matches('&').trim_end()),
+            state: JobState::Running,
+            output: String::new(),
+            done: Arc::new(Completion::new()),
+        });
+        id
+    });
+    workqueue::queue(move || {
+        JOB_TASK.store(scheduler::current_task_id(), Ordering::SeqCst);
+        let mut output = String::new();
+        run(&pipeline, &mut output);
+        JOB_TASK.store(0, Ordering::SeqCst);
+        finish(id, output);
+    });
+    id
+}
+
+fn finish(id: usize, output: String) {
+    without_interrupts(|| {
+        if let Some(job) = JOBS.lock().jobs.get_mut(&id) {
+            job.state = JobState::Done;
+            job.output = output;
+            job.done.complete_all();
+        }
+    });
+}
+
+/// Removes the jobs which are done and returns their status lines and output.
+pub fn take_finished() -> String {
+    let mut out = String::new();
+    without_interrupts(|| {
+        let mut table = JOBS.lock();
+        let done = table.jobs.iter()
+            .filter(|(_, job)| job.state == JobState::Done)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in done {
+            let job = table.jobs.remove(&id).unwrap();
+            out.push_str(&job.output);
+            let _ = writeln!(out, "[{}]  Done  {}", id, job.line);
+        }
+    });
+    out
+}
+
+/// Parses a job spec as given to `fg` and `bg` (`%1` or `1`), defaults to the most recent job.
+fn resolve(table: &JobTable, spec: Option<&String>) -> Result<usize, Error> {
+    match spec {
+        Some(spec) => {
+            let id = spec.trim_start_matches('%').parse::<usize>().map_err(|_| Error::EINVAL)?;
+            if table.jobs.contains_key(&id) { Ok(id) } else { Err(Error::ESRCH) }
+        },
+        None => table.jobs.keys().next_back().copied().ok_or(Error::ESRCH),
+    }
+}
+
+/// Calls `f` with the id, state and command line of every job.
+pub fn for_each(mut f: impl FnMut(usize, JobState, &str)) {
+    without_interrupts(|| {
+        for (id, job) in JOBS.lock().jobs.iter() {
+            f(*id, job.state, &job.line);
+        }
+    });
+}
+
+/// Brings a job to the foreground, waits for it to finish and returns its command line and output.
+pub fn foreground(spec: Option<&String>) -> Result<(String, String), Error> {
+    let (id, done) = without_interrupts(|| {
+        let table = JOBS.lock();
+        let id = resolve(&table, spec)?;
+        Ok((id, table.jobs[&id].done.clone()))
+    })?;
+    if JOB_TASK.load(Ordering::SeqCst) == scheduler::current_task_id() {
+        // a job waiting for another job, which can only run once this one is done
+        return Err(Error::EBUSY);
+    }
+    // the shell runs as a task, so it can sleep until the worker finished the job
+    done.wait_for();
+    let job = without_interrupts(|| JOBS.lock().jobs.remove(&id)).ok_or(Error::ESRCH)?;
+    Ok((job.line, job.output))
+}
+
+/// Continues a job in the background, returns its id and command line.
+pub fn background(spec: Option<&String>) -> Result<(usize, String), Error> {
+    without_interrupts(|| {
+        let table = JOBS.lock();
+        let id = resolve(&table, spec)?;
+        Ok((id, table.jobs[&id].line.clone()))
+    })
+}
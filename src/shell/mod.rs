@@ -1,16 +1,57 @@
 use alloc::string::String;
-use core::fmt;
+use core::{fmt, mem};
 use core::fmt::Write;
 use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use pc_keyboard::{DecodedKey, KeyCode};
 use spin::{Mutex, MutexGuard};
 use crate::arch::without_interrupts;
+use crate::{hostname, time};
 use crate::vga_buffer::{ColoredString, Writer};
 
+pub mod parser;
+pub mod commands;
+pub mod jobs;
+pub mod pager;
+
 lazy_static! {
-    pub static ref SHELL: Mutex<Shell> = Mutex::new(Shell::new(ColoredString::from_string(String::from("Test: "))));
+    pub static ref SHELL: Mutex<Shell> = Mutex::new(Shell::new(ColoredString::from_string(String::from(": "))));
     pub static ref INITIALIZED: AtomicBool = AtomicBool::new(false);
+    /// The command line the shell task has to execute next
+    static ref PENDING_LINE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Executes the command lines entered in the shell, it runs as a kernel task.
+// The keyboard interrupt may have interrupted code holding locks the commands take (e.g. the
+// mount table), so the commands can't run in the interrupt itself.
+// FIXME: Block until a line was entered instead of polling once wait queues can be woken from interrupts
+pub fn shell_task() {
+    const POLL_INTERVAL_US: u64 = 10_000;
+    // the pager reads the file for every page, so it lives here instead of in the shell
+    let mut pager: Option<pager::Pager> = None;
+    loop {
+        if let Some(line) = without_interrupts(|| PENDING_LINE.lock().take()) {
+            let output = commands::execute(&line);
+            pager = pager::take_pending();
+            let page = pager.as_mut().and_then(|pager| pager.render().ok());
+            if page.is_none() {
+                pager = None;
+            }
+            without_interrupts(|| SHELL.lock().finish_line(&output, page));
+        }
+        while let Some(open) = pager.as_mut() {
+            let key = match pager::take_key() {
+                Some(key) => key,
+                None => break,
+            };
+            let page = open.key_event(key);
+            if page.is_none() {
+                pager = None;
+            }
+            without_interrupts(|| SHELL.lock().show_page(page));
+        }
+        time::sleep(POLL_INTERVAL_US);
+    }
 }
 
 pub fn has_shell() -> bool {
@@ -21,6 +62,11 @@ pub struct Shell {
     prompt: ColoredString,
     written_char_count: usize,
     prompt_enabled: bool,
+    line: String,
+    /// While a pager is open the shell task gets all key presses for it
+    paging: bool,
+    /// Set while the shell task executes a command line, key presses are ignored until it's done
+    running: bool,
 }
 
 impl Shell {
@@ -30,6 +76,9 @@ impl Shell {
             prompt,
             written_char_count: 0,
             prompt_enabled: true,
+            line: String::new(),
+            paging: false,
+            running: false,
         }
     }
 
@@ -38,7 +87,7 @@ impl Shell {
         if !writer.is_current_row_clear() {
             writer.new_line();
         }
-        writer.write_colored_string(&self.prompt);
+        self.write_prompt(&mut writer);
         INITIALIZED.store(true, Ordering::Release);
     }
 
@@ -48,7 +97,7 @@ impl Shell {
             match char.raw_char() {
                 b'\n' => {
                     writer.new_line();
-                    writer.write_colored_string(&self.prompt);
+                    self.write_prompt(&mut writer);
                     self.written_char_count = 0;
                 },
                 // printable ASCII byte or newline
@@ -61,10 +110,16 @@ impl Shell {
 
     fn print_prompt(&self, writer: &mut MutexGuard<Writer>) {
         if self.prompt_enabled {
-            writer.write_colored_string(&self.prompt);
+            self.write_prompt(writer);
         }
     }
 
+    /// The prompt is preceded by the hostname, which may change at any time.
+    fn write_prompt(&self, writer: &mut Writer) {
+        let _ = writer.write_str(&hostname::get());
+        writer.write_colored_string(&self.prompt);
+    }
+
     fn newline(&mut self, writer: &mut MutexGuard<Writer>) {
         writer.new_line();
         self.print_prompt(writer);
@@ -87,6 +142,13 @@ impl Shell {
     }
 
     pub fn key_event(&mut self, key: DecodedKey) {
+        if self.running {
+            return;
+        }
+        if self.paging {
+            pager::queue_key(key);
+            return;
+        }
         match key {
             DecodedKey::RawKey(key) => {
                 if key == KeyCode::Backspace {
@@ -100,6 +162,7 @@ impl Shell {
                             writer.set_column_position(pos - 1);
                         }
                         self.written_char_count -= 1;
+                        self.line.pop();
                     }
                 } else {
                     // FIXME: Only print a-Z, 0-9
@@ -124,6 +187,7 @@ impl Shell {
                         }
                         writer.set_byte(b' ');
                         self.written_char_count -= 1;
+                        self.line.pop();
                     }
                 } else {
                     // FIXME: Only print a-Z, 0-9
@@ -131,10 +195,13 @@ impl Shell {
 
                     let mut writer = crate::vga_buffer::WRITER.lock();
                     if key == ENTER {
-                        self.newline(&mut writer);
+                        writer.new_line();
+                        drop(writer);
+                        self.execute_line();
                     } else {
                         writer.write_fmt(format_args!("{}", key)).unwrap();
                         self.written_char_count += 1;
+                        self.line.push(key);
                     }
 
                 }
@@ -142,6 +209,45 @@ impl Shell {
         }
     }
 
+    fn execute_line(&mut self) {
+        let line = mem::take(&mut self.line);
+        self.written_char_count = 0;
+        self.running = true;
+        *PENDING_LINE.lock() = Some(line);
+    }
+
+    /// Shows the output of the command line the shell task executed.
+    fn finish_line(&mut self, output: &str, page: Option<pager::Page>) {
+        self.running = false;
+        let prompt_enabled = self.prompt_enabled;
+        self.prompt_enabled = false;
+        self.write(output);
+        if !output.is_empty() && !output.ends_with('\n') {
+            self.write("\n");
+        }
+        self.prompt_enabled = prompt_enabled;
+        let mut writer = crate::vga_buffer::WRITER.lock();
+        if let Some(page) = page {
+            page.draw(&mut writer);
+            self.paging = true;
+            return;
+        }
+        self.print_prompt(&mut writer);
+    }
+
+    /// Shows the page the shell task rendered for the open pager, `None` closes the pager.
+    fn show_page(&mut self, page: Option<pager::Page>) {
+        let mut writer = crate::vga_buffer::WRITER.lock();
+        match page {
+            Some(page) => page.draw(&mut writer),
+            None => {
+                self.paging = false;
+                writer.new_line();
+                self.print_prompt(&mut writer);
+            },
+        }
+    }
+
     pub fn set_enable_prompt(&mut self, enabled: bool) {
         self.prompt_enabled = enabled;
     }
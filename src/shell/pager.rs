@@ -0,0 +1,189 @@
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::filesystem::{self, FileKind};
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH, Writer};
+
+// A minimal `less`, the file is never loaded as a whole. Only the offsets of the lines we
+// scrolled past are remembered, every redraw reads the visible lines from the filesystem again.
+// Lines longer than the screen is wide get cut off. The pager runs on the shell task, the keyboard
+// interrupt only queues the key presses for it and the rendered pages get drawn without reading files.
+
+/// The number of bytes read from the file at once
+pub const CHUNK_SIZE: usize = 512;
+/// The last row shows the status line
+const PAGE_LINES: usize = BUFFER_HEIGHT - 1;
+/// Key presses beyond this which the shell task didn't handle yet get dropped
+const MAX_PENDING_KEYS: usize = 16;
+
+lazy_static! {
+    /// A pager opened by `less`, the shell task takes it over once the command line was executed
+    static ref PENDING: Mutex<Option<Pager>> = Mutex::new(None);
+    /// The key presses for the open pager, allocated up front as they get queued from the keyboard interrupt
+    static ref KEYS: Mutex<VecDeque<DecodedKey>> = Mutex::new(VecDeque::with_capacity(MAX_PENDING_KEYS));
+}
+
+/// A rendered screen of the pager
+pub struct Page {
+    lines: Vec<Vec<u8>>,
+    status: String,
+}
+
+impl Page {
+
+    /// Draws the visible lines and the status line.
+    pub fn draw(&self, writer: &mut Writer) {
+        writer.clear_screen();
+        for idx in 0..PAGE_LINES {
+            match self.lines.get(idx) {
+                Some(line) => for byte in line.iter().copied() {
+                    match byte {
+                        0x20..=0x7e => writer.write_byte(byte),
+                        // tabs and other control characters
+                        _ => writer.write_byte(b' '),
+                    }
+                },
+                None => writer.write_byte(b'~'),
+            }
+            writer.new_line();
+        }
+        let _ = writer.write_str(&self.status);
+    }
+
+}
+
+pub struct Pager {
+    path: String,
+    /// The offsets at which the lines we know of start
+    lines: Vec<u64>,
+    /// Set once `lines` contains every line of the file
+    complete: bool,
+    /// The first line on the screen
+    top: usize,
+}
+
+impl Pager {
+
+    pub fn open(path: &str) -> Result<Self, Error> {
+        if filesystem::stat(path)?.kind != FileKind::File {
+            return Err(Error::EISDIR);
+        }
+        Ok(Self {
+            path: String::from(path),
+            lines: vec![0],
+            complete: false,
+            top: 0,
+        })
+    }
+
+    /// Reads the line starting at `offset` and returns its beginning (at most a screen width)
+    /// and the offset of the next line, which is `None` at the end of the file.
+    fn read_line(&self, offset: u64) -> Result<(Vec<u8>, Option<u64>), Error> {
+        let mut line = vec![];
+        let mut chunk = [0; CHUNK_SIZE];
+        let mut pos = offset;
+        loop {
+            let read = filesystem::read(&self.path, pos, &mut chunk)?;
+            if read == 0 {
+                return Ok((line, None));
+            }
+            let chunk = &chunk[..read];
+            let end = chunk.iter().position(|b| *b == b'\n');
+            let content = &chunk[..end.unwrap_or(read)];
+            let remaining = BUFFER_WIDTH - line.len();
+            line.extend_from_slice(&content[..content.len().min(remaining)]);
+            match end {
+                Some(end) => {
+                    let next = pos + end as u64 + 1;
+                    // a trailing newline doesn't start another line
+                    let more = filesystem::read(&self.path, next, &mut [0])? != 0;
+                    return Ok((line, if more { Some(next) } else { None }));
+                },
+                None => pos += read as u64,
+            }
+        }
+    }
+
+    /// Makes sure the start of `line` is known, unless the file is shorter.
+    fn index_until(&mut self, line: usize) -> Result<(), Error> {
+        while !self.complete && self.lines.len() <= line {
+            match self.read_line(*self.lines.last().unwrap())?.1 {
+                Some(next) => self.lines.push(next),
+                None => self.complete = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn scroll_to(&mut self, top: usize) -> Result<(), Error> {
+        self.index_until(top.saturating_add(PAGE_LINES))?;
+        // don't scroll past the last page
+        let last_top = if self.complete { self.lines.len().saturating_sub(PAGE_LINES) } else { usize::MAX };
+        self.top = top.min(last_top);
+        Ok(())
+    }
+
+    /// Reads the visible lines and builds the status line.
+    pub fn render(&mut self) -> Result<Page, Error> {
+        self.scroll_to(self.top)?;
+        let mut lines = vec![];
+        for offset in self.lines.iter().skip(self.top).take(PAGE_LINES) {
+            lines.push(self.read_line(*offset)?.0);
+        }
+        let end = (self.top + PAGE_LINES).min(self.lines.len());
+        let status = if self.complete && end == self.lines.len() { "(END)" } else { "" };
+        Ok(Page {
+            lines,
+            status: format!("{} lines {}-{} {} [q: quit, space/b: page, j/k: line]", self.path, self.top + 1, end, status),
+        })
+    }
+
+    /// Handles a key press and returns the page to show, `None` if the pager should be closed.
+    pub fn key_event(&mut self, key: DecodedKey) -> Option<Page> {
+        let top = match key {
+            DecodedKey::Unicode('q') => return None,
+            DecodedKey::Unicode(' ') | DecodedKey::RawKey(KeyCode::PageDown) => self.top.saturating_add(PAGE_LINES),
+            DecodedKey::Unicode('b') | DecodedKey::RawKey(KeyCode::PageUp) => self.top.saturating_sub(PAGE_LINES),
+            DecodedKey::Unicode('j') | DecodedKey::Unicode('\n') | DecodedKey::RawKey(KeyCode::ArrowDown) => self.top.saturating_add(1),
+            DecodedKey::Unicode('k') | DecodedKey::RawKey(KeyCode::ArrowUp) => self.top.saturating_sub(1),
+            DecodedKey::Unicode('g') | DecodedKey::RawKey(KeyCode::Home) => 0,
+            DecodedKey::Unicode('G') | DecodedKey::RawKey(KeyCode::End) => usize::MAX,
+            _ => self.top,
+        };
+        // the file went away or the filesystem failed if this fails, there is nothing left to show
+        self.scroll_to(top).and_then(|_| self.render()).ok()
+    }
+
+}
+
+/// Hands the pager over to the shell.
+pub fn open_pending(pager: Pager) {
+    *PENDING.lock() = Some(pager);
+}
+
+pub fn take_pending() -> Option<Pager> {
+    // keys left over from a previous pager aren't meant for this one
+    without_interrupts(|| KEYS.lock().clear());
+    PENDING.lock().take()
+}
+
+/// Queues a key press for the open pager, this gets called from the keyboard interrupt.
+pub fn queue_key(key: DecodedKey) {
+    let mut keys = KEYS.lock();
+    // pushing beyond the capacity would allocate
+    if keys.len() < MAX_PENDING_KEYS {
+        keys.push_back(key);
+    }
+}
+
+pub fn take_key() -> Option<DecodedKey> {
+    without_interrupts(|| KEYS.lock().pop_front())
+}
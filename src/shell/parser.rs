@@ -0,0 +1,174 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Pipe,       // |
+    Input,      // <
+    Output,     // >
+    Append,     // >>
+    Background, // &
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub path: String,
+    pub append: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Command {
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    pub stdout: Option<Redirect>,
+}
+
+/// A list of commands whose outputs are connected to the inputs of their successors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// Set by a trailing `&`, the shell doesn't wait for the pipeline to finish
+    pub background: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    UnterminatedQuote,
+    MissingRedirectTarget,
+    EmptyCommand,
+    MisplacedBackground,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::UnterminatedQuote => "unterminated quote",
+            ParseError::MissingRedirectTarget => "missing redirection target",
+            ParseError::EmptyCommand => "empty command in pipeline",
+            ParseError::MisplacedBackground => "& is only allowed at the end of a command line",
+        })
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' => {
+                chars.next();
+            },
+            '|' => {
+                chars.next();
+                tokens.push(Token::Pipe);
+            },
+            '<' => {
+                chars.next();
+                tokens.push(Token::Input);
+            },
+            '&' => {
+                chars.next();
+                tokens.push(Token::Background);
+            },
+            '>' => {
+                chars.next();
+                if chars.peek() == Some(&'>') {
+                    chars.next();
+                    tokens.push(Token::Append);
+                } else {
+                    tokens.push(Token::Output);
+                }
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        ' ' | '\t' | '|' | '<' | '>' | '&' => break,
+                        '"' | '\'' => {
+                            chars.next();
+                            loop {
+                                match chars.next() {
+                                    Some(end) if end == c => break,
+                                    Some(other) => word.push(other),
+                                    None => return Err(ParseError::UnterminatedQuote),
+                                }
+                            }
+                        },
+                        _ => {
+                            word.push(c);
+                            chars.next();
+                        },
+                    }
+                }
+                tokens.push(Token::Word(word));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a command line, returns `None` if the line doesn't contain any command.
+pub fn parse(line: &str) -> Result<Option<Pipeline>, ParseError> {
+    let tokens = tokenize(line)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    let mut commands = vec![];
+    let mut current = Command::default();
+    let mut background = false;
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => current.args.push(word),
+            Token::Pipe => {
+                if current.args.is_empty() {
+                    return Err(ParseError::EmptyCommand);
+                }
+                commands.push(core::mem::take(&mut current));
+            },
+            Token::Input => match tokens.next() {
+                Some(Token::Word(path)) => current.stdin = Some(path),
+                _ => return Err(ParseError::MissingRedirectTarget),
+            },
+            Token::Output | Token::Append => match tokens.next() {
+                Some(Token::Word(path)) => current.stdout = Some(Redirect {
+                    path,
+                    append: token == Token::Append,
+                }),
+                _ => return Err(ParseError::MissingRedirectTarget),
+            },
+            Token::Background => {
+                if tokens.next().is_some() {
+                    return Err(ParseError::MisplacedBackground);
+                }
+                background = true;
+            },
+        }
+    }
+    if current.args.is_empty() {
+        return Err(ParseError::EmptyCommand);
+    }
+    commands.push(current);
+    Ok(Some(Pipeline {
+        commands,
+        background,
+    }))
+}
+
+#[test_case]
+fn test_parse_pipeline_with_redirects() {
+    let pipeline = parse("echo \"a b\" > out | wc -l < in >> log").unwrap().unwrap();
+    crate::kassert_eq!(pipeline.commands.len(), 2);
+    crate::kassert_eq!(pipeline.commands[0].args, vec![String::from("echo"), String::from("a b")]);
+    crate::kassert_eq!(pipeline.commands[0].stdout, Some(Redirect { path: String::from("out"), append: false }));
+    crate::kassert_eq!(pipeline.commands[1].stdin, Some(String::from("in")));
+    crate::kassert_eq!(pipeline.commands[1].stdout, Some(Redirect { path: String::from("log"), append: true }));
+    crate::kassert_eq!(parse("echo |"), Err(ParseError::EmptyCommand));
+    crate::kassert_eq!(parse("   "), Ok(None));
+    crate::kassert!(!pipeline.background);
+    crate::kassert!(parse("ping host&").unwrap().unwrap().background);
+    crate::kassert_eq!(parse("echo a & echo b"), Err(ParseError::MisplacedBackground));
+}
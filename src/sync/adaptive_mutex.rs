@@ -0,0 +1,148 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::error_codes::Error;
+use crate::{scheduler, time};
+
+const UNLOCKED: u64 = 0;
+/// How often we check the lock while its owner is running before going to sleep anyway
+const SPIN_LIMIT: usize = 1000;
+/// How long `lock` waits in debug builds before it assumes a deadlock
+#[cfg(debug_assertions)]
+const DEADLOCK_TIMEOUT_US: u64 = 10_000_000;
+
+/// A mutex which only spins while the task holding it is running on another cpu and
+/// gives up the cpu otherwise, as the owner can't release the lock before it runs again.
+pub struct AdaptiveMutex<T: ?Sized> {
+    // the id of the owning task plus one, so the idle task (id 0) can hold locks as well
+    owner: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AdaptiveMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AdaptiveMutex<T> {}
+
+impl<T> AdaptiveMutex<T> {
+
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicU64::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+}
+
+impl<T: ?Sized> AdaptiveMutex<T> {
+
+    pub fn try_lock(&self) -> Option<AdaptiveMutexGuard<T>> {
+        let me = scheduler::current_task_id() + 1;
+        self.owner.compare_exchange(UNLOCKED, me, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        Some(AdaptiveMutexGuard {
+            mutex: self,
+        })
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub fn lock(&self) -> AdaptiveMutexGuard<T> {
+        self.lock_until(None).unwrap()
+    }
+
+    /// In debug builds waiting longer than `DEADLOCK_TIMEOUT_US` is considered to be a deadlock.
+    #[cfg(debug_assertions)]
+    pub fn lock(&self) -> AdaptiveMutexGuard<T> {
+        self.lock_timeout(DEADLOCK_TIMEOUT_US).unwrap_or_else(|_| {
+            panic!("task {} waited more than {}us for an adaptive mutex held by task {:?}, this is probably a deadlock",
+                   scheduler::current_task_id(), DEADLOCK_TIMEOUT_US, self.owner())
+        })
+    }
+
+    /// Fails with `ETIMEDOUT` if the lock couldn't be taken within `timeout_us`.
+    pub fn lock_timeout(&self, timeout_us: u64) -> Result<AdaptiveMutexGuard<T>, Error> {
+        self.lock_until(Some(time::monotonic_us().saturating_add(timeout_us)))
+    }
+
+    fn lock_until(&self, deadline: Option<u64>) -> Result<AdaptiveMutexGuard<T>, Error> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Ok(guard);
+            }
+            if deadline.map_or(false, |deadline| time::monotonic_us() >= deadline) {
+                return Err(Error::ETIMEDOUT);
+            }
+            let owner = self.owner.load(Ordering::Relaxed);
+            if owner == UNLOCKED {
+                continue;
+            }
+            if owner == scheduler::current_task_id() + 1 {
+                panic!("task {} tried to lock an adaptive mutex it already holds", owner - 1);
+            }
+            if scheduler::is_running_on_other_cpu(owner - 1) {
+                // the owner is making progress, so the lock will probably be released soon
+                for _ in 0..SPIN_LIMIT {
+                    if self.owner.load(Ordering::Relaxed) == UNLOCKED {
+                        break;
+                    }
+                    spin_loop();
+                }
+            } else {
+                scheduler::yield_now();
+            }
+        }
+    }
+
+    /// Returns the id of the task which currently holds the lock.
+    pub fn owner(&self) -> Option<u64> {
+        match self.owner.load(Ordering::Relaxed) {
+            UNLOCKED => None,
+            owner => Some(owner - 1),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+}
+
+pub struct AdaptiveMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AdaptiveMutex<T>,
+}
+
+impl<T: ?Sized> Deref for AdaptiveMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AdaptiveMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for AdaptiveMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.owner.store(UNLOCKED, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn test_adaptive_mutex() {
+    let mutex = AdaptiveMutex::new(5);
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        crate::kassert!(mutex.try_lock().is_none());
+        crate::kassert_eq!(mutex.owner(), Some(scheduler::current_task_id()));
+    }
+    crate::kassert!(!mutex.is_locked());
+    crate::kassert_eq!(*mutex.lock(), 6);
+}
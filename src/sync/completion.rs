@@ -0,0 +1,91 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::error_codes::Error;
+use crate::sync::WaitQueue;
+
+/// Set by `complete_all`, every waiter passes from then on
+const ALL_DONE: usize = usize::MAX;
+
+/// Signals that an operation (e.g. an I/O request or queued work) finished to tasks which sleep
+/// until it did. Every `complete` lets one `wait_for` pass, `complete_all` lets all current and
+/// future ones pass. Completing can be done from interrupt context.
+pub struct Completion {
+    done: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Completion {
+
+    pub const fn new() -> Self {
+        Self {
+            done: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Lets one waiter pass.
+    pub fn complete(&self) {
+        let _ = self.done.fetch_update(Ordering::AcqRel, Ordering::Acquire, |done| {
+            if done >= ALL_DONE - 1 { None } else { Some(done + 1) }
+        });
+        self.queue.wake_one();
+    }
+
+    /// Lets every waiter pass until `reinit` gets called.
+    pub fn complete_all(&self) {
+        self.done.store(ALL_DONE, Ordering::Release);
+        self.queue.wake_all();
+    }
+
+    /// Consumes one completion without blocking, returns false if there was none.
+    pub fn try_wait(&self) -> bool {
+        self.done.fetch_update(Ordering::AcqRel, Ordering::Acquire, |done| match done {
+            0 => None,
+            ALL_DONE => Some(ALL_DONE),
+            done => Some(done - 1),
+        }).is_ok()
+    }
+
+    /// Blocks until the completion was completed.
+    pub fn wait_for(&self) {
+        self.queue.wait_until(|| self.try_wait());
+    }
+
+    /// Like `wait_for`, but fails with `ETIMEDOUT` if it didn't get completed within `timeout_us`.
+    pub fn wait_for_timeout(&self, timeout_us: u64) -> Result<(), Error> {
+        self.queue.wait_until_timeout(timeout_us, || self.try_wait())
+    }
+
+    /// Whether a waiter would pass right now
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire) != 0
+    }
+
+    /// Resets the completion so it can be used for the next operation, nobody may wait for it.
+    pub fn reinit(&self) {
+        self.done.store(0, Ordering::Release);
+    }
+
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_completion_counts() {
+    let completion = Completion::new();
+    crate::kassert!(!completion.try_wait());
+    crate::kassert_eq!(completion.wait_for_timeout(0), Err(Error::ETIMEDOUT));
+    completion.complete();
+    completion.complete();
+    completion.wait_for();
+    crate::kassert!(completion.try_wait());
+    crate::kassert!(!completion.is_done());
+    completion.complete_all();
+    completion.wait_for();
+    crate::kassert_eq!(completion.wait_for_timeout(0), Ok(()));
+    completion.reinit();
+    crate::kassert!(!completion.is_done());
+}
@@ -0,0 +1,7 @@
+pub mod adaptive_mutex;
+pub mod completion;
+pub mod wait_queue;
+
+pub use adaptive_mutex::{AdaptiveMutex, AdaptiveMutexGuard};
+pub use completion::Completion;
+pub use wait_queue::{with_timeout, WaitQueue};
@@ -0,0 +1,134 @@
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::{scheduler, time};
+
+/// A list of tasks which sleep until some condition becomes true, whoever changes the condition
+/// has to wake them up. Waking up a task only makes it check its condition again.
+pub struct WaitQueue {
+    waiters: Mutex<Vec<u64>>,
+}
+
+impl WaitQueue {
+
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Blocks the current task until `condition` returns true, outside of a task this spins instead.
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.wait(condition, None);
+    }
+
+    /// Like `wait_until`, but gives up with `ETIMEDOUT` once the condition didn't become true
+    /// within `timeout_us`, so a wakeup which never comes (e.g. because of a driver bug) doesn't
+    /// block the caller forever.
+    pub fn wait_until_timeout(&self, timeout_us: u64, condition: impl FnMut() -> bool) -> Result<(), Error> {
+        let deadline = time::monotonic_us().saturating_add(timeout_us);
+        if self.wait(condition, Some(deadline)) { Ok(()) } else { Err(Error::ETIMEDOUT) }
+    }
+
+    /// Returns false if the deadline passed before the condition became true.
+    fn wait(&self, mut condition: impl FnMut() -> bool, deadline: Option<u64>) -> bool {
+        loop {
+            if condition() {
+                return true;
+            }
+            if deadline.map_or(false, |deadline| time::monotonic_us() >= deadline) {
+                return false;
+            }
+            let id = scheduler::current_task_id();
+            // the task has to be queued before interrupts are enabled again, otherwise a wakeup
+            // which happens in between would be lost
+            let waiting = without_interrupts(|| {
+                let waiting = scheduler::prepare_to_wait_until(deadline);
+                if waiting {
+                    self.waiters.lock().push(id);
+                }
+                waiting
+            });
+            if !waiting {
+                spin_loop();
+                continue;
+            }
+            // the condition could have become true before we were queued
+            if condition() {
+                scheduler::finish_wait();
+                self.remove(id);
+                return true;
+            }
+            scheduler::yield_now();
+            self.remove(id);
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        without_interrupts(|| self.waiters.lock().retain(|waiter| *waiter != id));
+    }
+
+    /// Wakes up the task which waits the longest, returns false if there was none.
+    pub fn wake_one(&self) -> bool {
+        let waiter = without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                None
+            } else {
+                Some(waiters.remove(0))
+            }
+        });
+        waiter.map_or(false, scheduler::wake_task)
+    }
+
+    /// Wakes up all waiting tasks and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for waiter in waiters.iter() {
+            scheduler::wake_task(*waiter);
+        }
+        waiters.len()
+    }
+
+    pub fn len(&self) -> usize {
+        without_interrupts(|| self.waiters.lock().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        without_interrupts(|| self.waiters.lock().is_empty())
+    }
+
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// FIXME: Bound pipe reads and socket accepts with this once we have them
+/// Runs a blocking operation, `poll` tries to complete it and gets called again whenever `queue`
+/// is woken up. Fails with `ETIMEDOUT` if the operation didn't complete within `timeout_us`.
+pub fn with_timeout<T>(timeout_us: u64, queue: &WaitQueue, mut poll: impl FnMut() -> Option<T>) -> Result<T, Error> {
+    let mut result = None;
+    queue.wait_until_timeout(timeout_us, || {
+        result = poll();
+        result.is_some()
+    })?;
+    Ok(result.unwrap())
+}
+
+#[test_case]
+fn test_with_timeout() {
+    let queue = WaitQueue::new();
+    crate::kassert_eq!(with_timeout(0, &queue, || Some(42)), Ok(42));
+    crate::kassert_eq!(with_timeout(0, &queue, || None::<()>), Err(Error::ETIMEDOUT));
+    let mut polls = 0;
+    crate::kassert_eq!(with_timeout(10_000, &queue, || {
+        polls += 1;
+        if polls == 3 { Some(polls) } else { None }
+    }), Ok(3));
+    crate::kassert_eq!(queue.len(), 0);
+}
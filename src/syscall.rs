@@ -1,37 +1,206 @@
 use alloc::string::String;
 use core::arch::asm;
-use core::mem;
+use x86_64::VirtAddr;
+use crate::arch::x86::mem;
 use crate::error_codes::Error;
-use crate::println;
+use crate::{filesystem, hostname, memory, println, scheduler};
 
-#[no_mangle]
-extern "C" fn handle_syscall(mut args: SyscallArgs) {
-    match args.syscall_id {
-        1 => handle_write(&mut args),
-        _ => unimplemented!("syscall: {}, {}", args.syscall_id, args.error),
+pub use leafos_abi::syscall::{GETENV, GETHOSTNAME, GETXATTR, SETHOSTNAME, SETXATTR, SYNC, WRITE};
+pub use leafos_abi::STDOUT_FD;
+
+/// How the dispatcher treats a syscall argument
+#[derive(Debug, Clone, Copy)]
+enum Arg {
+    /// Passed to the handler as is
+    Value,
+    /// A pointer to a user buffer whose length is passed in the given argument
+    Buf { len: usize },
+}
+
+struct Syscall {
+    id: usize,
+    handler: fn(&mut SyscallFrame) -> usize,
+    args: &'static [Arg],
+    /// Whether the syscall returns errors as negated errnos
+    negated_errors: bool,
+}
+
+static SYSCALLS: &[Syscall] = &[
+    Syscall { id: WRITE, handler: handle_write, args: &[Arg::Value, Arg::Buf { len: 2 }, Arg::Value], negated_errors: false },
+    Syscall { id: GETENV, handler: handle_getenv, args: &[Arg::Buf { len: 1 }, Arg::Value, Arg::Buf { len: 3 }, Arg::Value], negated_errors: true },
+    Syscall { id: SYNC, handler: |_| handle_sync(), args: &[], negated_errors: false },
+    Syscall { id: GETXATTR, handler: handle_getxattr, args: &XATTR_ARGS, negated_errors: true },
+    Syscall { id: SETXATTR, handler: handle_setxattr, args: &XATTR_ARGS, negated_errors: true },
+    Syscall { id: SETHOSTNAME, handler: handle_sethostname, args: &[Arg::Buf { len: 1 }, Arg::Value], negated_errors: true },
+    Syscall { id: GETHOSTNAME, handler: handle_gethostname, args: &[Arg::Buf { len: 1 }, Arg::Value], negated_errors: true },
+];
+
+const XATTR_ARGS: [Arg; 6] = [Arg::Buf { len: 1 }, Arg::Value, Arg::Buf { len: 3 }, Arg::Value, Arg::Buf { len: 5 }, Arg::Value];
+
+impl Syscall {
+
+    /// Checks that every page of every buffer is mapped and accessible from user mode, so a
+    /// process can't make the kernel read or write kernel memory on its behalf.
+    // FIXME: This checks the active page tables, check the address space of the calling process
+    //  once processes have their own
+    fn check_args(&self, frame: &SyscallFrame) -> Result<(), Error> {
+        for (idx, arg) in self.args.iter().enumerate() {
+            if let Arg::Buf { len } = arg {
+                let addr = VirtAddr::try_new(frame.arg(idx) as u64).map_err(|_| Error::EFAULT)?;
+                if !memory::is_user_accessible(addr, frame.arg(*len)) {
+                    return Err(Error::EFAULT);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, err: Error) -> usize {
+        if self.negated_errors {
+            (err as usize).wrapping_neg()
+        } else {
+            err as usize
+        }
     }
-    // we forget the args value as its on the stack and the assembly code calling this will handle it for us
-    mem::forget(args);
+
 }
 
+/// Gets called by the `int 0x80` entry stub with the complete register state of the caller,
+/// every register except for `rax` which receives the result is restored from the frame on return.
+#[no_mangle]
+extern "C" fn handle_syscall(frame: &mut SyscallFrame) {
+    #[cfg(feature = "voluntary-preempt")]
+    scheduler::set_in_syscall(true);
+    let result = match SYSCALLS.iter().find(|syscall| syscall.id == frame.syscall_id()) {
+        Some(syscall) => match syscall.check_args(frame) {
+            Ok(()) => (syscall.handler)(frame),
+            Err(err) => syscall.error(err),
+        },
+        None => Error::ENOSYS as usize,
+    };
+    #[cfg(feature = "voluntary-preempt")]
+    scheduler::set_in_syscall(false);
+    frame.rax = result;
+}
+
+/// The register state of the caller as laid out on the stack by the syscall entry stub,
+/// the first fields are pushed by `save_registers!` and the rest is the interrupt stack frame.
 #[repr(C)]
-pub struct SyscallArgs {
-    syscall_id: usize, // rax
-    arg0: usize, // rdi
-    arg1: usize, // rsi
-    arg2: usize, // rdx
-    arg3: usize, // (r10) - curr: rcx
-    arg4: usize, // r8 - curr: r8
-    arg5: usize, // r9 - curr: r9
-    error: usize, // rax
+#[derive(Debug, Clone)]
+pub struct SyscallFrame {
+    pub rbp: usize,
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rbx: usize,
+    pub rax: usize,
+    // pushed by the cpu
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize,
+}
+
+impl SyscallFrame {
+
+    #[inline]
+    pub fn syscall_id(&self) -> usize {
+        self.rax
+    }
+
+    /// Returns the nth argument, the arguments are passed in rdi, rsi, rdx, r10, r8 and r9.
+    pub fn arg(&self, idx: usize) -> usize {
+        match idx {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => panic!("syscalls only take 6 arguments, tried to access argument {}", idx),
+        }
+    }
+
+}
+
+fn handle_write(frame: &mut SyscallFrame) -> usize {
+    _handle_write(frame.arg(0), frame.arg(1) as *const _, frame.arg(2))
+}
+
+fn handle_getenv(frame: &mut SyscallFrame) -> usize {
+    let value = match str_arg(frame, 0, 1).ok().and_then(scheduler::current_env_var) {
+        Some(value) => value,
+        None => return usize::MAX,
+    };
+    copy_to_user(frame, 2, 3, value.as_bytes())
+}
+
+fn handle_sync() -> usize {
+    match filesystem::sync_all() {
+        Ok(()) => 0,
+        Err(err) => err as usize,
+    }
+}
+
+/// Copies `data` to the buffer passed as a pointer and a length in the given arguments if it
+/// fits and returns the length of `data`, so the caller can retry with a larger buffer.
+fn copy_to_user(frame: &SyscallFrame, ptr: usize, len: usize, data: &[u8]) -> usize {
+    if data.len() <= frame.arg(len) {
+        // the dispatcher checked the buffer
+        unsafe { mem::memcpy(frame.arg(ptr) as *mut u8, data.as_ptr(), data.len()); }
+    }
+    data.len()
+}
+
+/// Returns the string passed as a pointer and a length in the given arguments.
+fn str_arg(frame: &SyscallFrame, ptr: usize, len: usize) -> Result<&str, Error> {
+    let bytes = unsafe { core::slice::from_raw_parts(frame.arg(ptr) as *const u8, frame.arg(len)) };
+    core::str::from_utf8(bytes).map_err(|_| Error::EINVAL)
 }
 
-fn handle_write(args: &mut SyscallArgs) {
-    let result = _handle_write(args.arg0, args.arg1 as *mut _, args.arg2);
-    args.error = result;
+fn handle_getxattr(frame: &mut SyscallFrame) -> usize {
+    let value = str_arg(frame, 0, 1)
+        .and_then(|path| Ok((path, str_arg(frame, 2, 3)?)))
+        .and_then(|(path, name)| filesystem::get_xattr(path, name));
+    let value = match value {
+        Ok(value) => value,
+        Err(err) => return (err as usize).wrapping_neg(),
+    };
+    copy_to_user(frame, 4, 5, &value)
 }
 
-pub const STDOUT_FD: usize = 1;
+fn handle_setxattr(frame: &mut SyscallFrame) -> usize {
+    let value = unsafe { core::slice::from_raw_parts(frame.arg(4) as *const u8, frame.arg(5)) };
+    let result = str_arg(frame, 0, 1)
+        .and_then(|path| Ok((path, str_arg(frame, 2, 3)?)))
+        .and_then(|(path, name)| filesystem::set_xattr(path, name, Some(value)));
+    match result {
+        Ok(()) => 0,
+        Err(err) => (err as usize).wrapping_neg(),
+    }
+}
+
+fn handle_sethostname(frame: &mut SyscallFrame) -> usize {
+    match str_arg(frame, 0, 1).and_then(hostname::set) {
+        Ok(()) => 0,
+        Err(err) => (err as usize).wrapping_neg(),
+    }
+}
+
+fn handle_gethostname(frame: &mut SyscallFrame) -> usize {
+    let name = hostname::get();
+    copy_to_user(frame, 0, 1, name.as_bytes())
+}
 
 fn _handle_write(fd: usize, msg: *const u8, msg_len: usize) -> usize {
     if fd == STDOUT_FD {
@@ -45,14 +214,12 @@ fn _handle_write(fd: usize, msg: *const u8, msg_len: usize) -> usize {
     }
 }
 
-fn exit(args: &mut SyscallArgs) {
-
-}
-
-fn _exit(code: usize) {
-
-}
-
+/// Makes the syscall `syscall_id` with no arguments and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// Whatever the syscall does has to be sound for the caller at this point.
 pub unsafe extern "C" fn do_syscall_0(syscall_id: usize) -> usize {
     let result: usize;
     asm!(
@@ -62,6 +229,13 @@ pub unsafe extern "C" fn do_syscall_0(syscall_id: usize) -> usize {
     result
 }
 
+/// Makes the syscall `syscall_id` with one argument and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the syscall, pointers it reads from or writes to have to be
+/// valid for the lengths passed along with them.
 pub unsafe extern "C" fn do_syscall_1(syscall_id: usize, arg0: usize) -> usize {
     let result: usize;
     asm!(
@@ -72,6 +246,13 @@ pub unsafe extern "C" fn do_syscall_1(syscall_id: usize, arg0: usize) -> usize {
     result
 }
 
+/// Makes the syscall `syscall_id` with two arguments and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the syscall, pointers it reads from or writes to have to be
+/// valid for the lengths passed along with them.
 pub unsafe extern "C" fn do_syscall_2(syscall_id: usize, arg0: usize, arg1: usize) -> usize {
     let result: usize;
     asm!(
@@ -83,6 +264,13 @@ pub unsafe extern "C" fn do_syscall_2(syscall_id: usize, arg0: usize, arg1: usiz
     result
 }
 
+/// Makes the syscall `syscall_id` with three arguments and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the syscall, pointers it reads from or writes to have to be
+/// valid for the lengths passed along with them.
 pub unsafe extern "C" fn do_syscall_3(syscall_id: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let result: usize;
     asm!(
@@ -95,6 +283,13 @@ pub unsafe extern "C" fn do_syscall_3(syscall_id: usize, arg0: usize, arg1: usiz
     result
 }
 
+/// Makes the syscall `syscall_id` with four arguments and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the syscall, pointers it reads from or writes to have to be
+/// valid for the lengths passed along with them.
 pub unsafe extern "C" fn do_syscall_4(syscall_id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
     let result: usize;
     asm!(
@@ -103,11 +298,18 @@ pub unsafe extern "C" fn do_syscall_4(syscall_id: usize, arg0: usize, arg1: usiz
     in("rdi") arg0,
     in("rsi") arg1,
     in("rdx") arg2,
-    in("rcx") arg3,
+    in("r10") arg3,
     );
     result
 }
 
+/// Makes the syscall `syscall_id` with five arguments and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the syscall, pointers it reads from or writes to have to be
+/// valid for the lengths passed along with them.
 pub unsafe extern "C" fn do_syscall_5(syscall_id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> usize {
     let result: usize;
     asm!(
@@ -116,12 +318,19 @@ pub unsafe extern "C" fn do_syscall_5(syscall_id: usize, arg0: usize, arg1: usiz
     in("rdi") arg0,
     in("rsi") arg1,
     in("rdx") arg2,
-    in("rcx") arg3,
+    in("r10") arg3,
     in("r8") arg4,
     );
     result
 }
 
+/// Makes the syscall `syscall_id` with six arguments and returns its result, errors are returned
+/// as negated errnos.
+///
+/// # Safety
+///
+/// The arguments have to be valid for the syscall, pointers it reads from or writes to have to be
+/// valid for the lengths passed along with them.
 pub unsafe extern "C" fn do_syscall_6(syscall_id: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, arg5: usize) -> usize {
     let result: usize;
     asm!(
@@ -130,11 +339,62 @@ pub unsafe extern "C" fn do_syscall_6(syscall_id: usize, arg0: usize, arg1: usiz
     in("rdi") arg0,
     in("rsi") arg1,
     in("rdx") arg2,
-    in("rcx") arg3,
+    in("r10") arg3,
     in("r8") arg4,
     in("r9") arg5,
     );
     result
 }
 
-pub const WRITE: usize = 1;
+#[test_case]
+fn test_unknown_syscall() {
+    let result = unsafe { do_syscall_0(usize::MAX) };
+    crate::kassert_eq!(result, Error::ENOSYS as usize);
+}
+
+#[test_case]
+fn test_kernel_half_pointers_are_rejected() {
+    let kernel = 0xffff_8000_0000_0000;
+    let result = unsafe { do_syscall_3(WRITE, STDOUT_FD, kernel, 4) };
+    crate::kassert_eq!(result, Error::EFAULT as usize);
+    // the buffer must not reach into the kernel half either
+    let result = unsafe { do_syscall_3(WRITE, STDOUT_FD, memory::USER_SPACE_END as usize - 2, 4) };
+    crate::kassert_eq!(result, Error::EFAULT as usize);
+    let result = unsafe { do_syscall_4(GETENV, kernel, 4, 0, 0) };
+    crate::kassert_eq!(result, (Error::EFAULT as usize).wrapping_neg());
+    // the heap lies in the lower half as well, but isn't accessible from user mode
+    let heap = alloc::vec![0_u8; 4];
+    let result = unsafe { do_syscall_3(WRITE, STDOUT_FD, heap.as_ptr() as usize, heap.len()) };
+    crate::kassert_eq!(result, Error::EFAULT as usize);
+}
+
+#[test_case]
+fn test_syscall_preserves_registers() {
+    // rbx and rbp can't be used as asm operands, they are covered by the frame as well though
+    let (mut rcx, mut rdx, mut rsi, mut rdi): (usize, usize, usize, usize);
+    let (mut r8, mut r9, mut r10, mut r11): (usize, usize, usize, usize);
+    let (mut r12, mut r13, mut r14, mut r15): (usize, usize, usize, usize);
+    let result: usize;
+    unsafe {
+        asm!(
+        "int 0x80",
+        inout("rax") usize::MAX => result,
+        inout("rcx") 0x1111_usize => rcx,
+        inout("rdx") 0x2222_usize => rdx,
+        inout("rsi") 0x3333_usize => rsi,
+        inout("rdi") 0x4444_usize => rdi,
+        inout("r8") 0x8888_usize => r8,
+        inout("r9") 0x9999_usize => r9,
+        inout("r10") 0x1010_usize => r10,
+        inout("r11") 0x1111_1111_usize => r11,
+        inout("r12") 0x1212_usize => r12,
+        inout("r13") 0x1313_usize => r13,
+        inout("r14") 0x1414_usize => r14,
+        inout("r15") 0x1515_usize => r15,
+        );
+    }
+    crate::kassert_eq!(result, Error::ENOSYS as usize);
+    crate::kassert_eq!((rcx, rdx, rsi, rdi), (0x1111, 0x2222, 0x3333, 0x4444));
+    crate::kassert_eq!((r8, r9, r10, r11), (0x8888, 0x9999, 0x1010, 0x1111_1111));
+    crate::kassert_eq!((r12, r13, r14, r15), (0x1212, 0x1313, 0x1414, 0x1515));
+}
@@ -0,0 +1,132 @@
+use alloc::boxed::Box;
+use core::fmt;
+use core::fmt::Write;
+use pc_keyboard::KeyCode;
+use crate::events::{Hotkey, Modifiers, register_hotkey};
+use crate::serial::SERIAL1;
+use crate::vga_buffer::WRITER;
+use crate::drivers::block;
+use crate::{allocators, arch, filesystem, interrupts, oom, scheduler};
+
+// SysRq commands get triggered by Alt+SysRq+<key> and run directly in the keyboard interrupt.
+// They are meant to work even if the rest of the system is stuck, so they only try to take the
+// locks they need and report whatever is locked instead. Syncing has to go through the
+// filesystems, which take the block device and heap locks as usual, so it checks beforehand
+// that the interrupted code doesn't hold those.
+
+struct SysRqCommand {
+    key: KeyCode,
+    name: &'static str,
+    help: &'static str,
+    run: fn(),
+}
+
+static COMMANDS: &[SysRqCommand] = &[
+    SysRqCommand { key: KeyCode::H, name: "h", help: "show this help", run: help },
+    SysRqCommand { key: KeyCode::T, name: "t", help: "dump all tasks and their stacks", run: dump_tasks },
+    SysRqCommand { key: KeyCode::M, name: "m", help: "show memory and allocator stats", run: dump_memory },
+    SysRqCommand { key: KeyCode::R, name: "r", help: "force an immediate reschedule", run: reschedule },
+    SysRqCommand { key: KeyCode::S, name: "s", help: "sync all filesystems", run: sync },
+    SysRqCommand { key: KeyCode::B, name: "b", help: "reboot immediately", run: reboot },
+];
+
+/// Writes to the serial port and the screen without going through the shell, so we
+/// don't have to wait for whatever is holding it.
+struct SysRqWriter;
+
+impl fmt::Write for SysRqWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(mut serial) = SERIAL1.try_lock() {
+            let _ = serial.write_str(s);
+        }
+        if let Some(mut writer) = WRITER.try_lock() {
+            let _ = writer.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+macro_rules! sysrq_println {
+    ($($arg:tt)*) => {
+        let _ = writeln!(SysRqWriter, $($arg)*);
+    };
+}
+
+pub fn init() {
+    for command in COMMANDS {
+        let run = command.run;
+        let _ = register_hotkey(Hotkey::new(Modifiers::ALT | Modifiers::SYSRQ, command.key), Box::new(move |_| {
+            run();
+        }));
+    }
+}
+
+fn help() {
+    sysrq_println!("SysRq: available commands (Alt+SysRq+<key>):");
+    for command in COMMANDS {
+        sysrq_println!("  {}  {}", command.name, command.help);
+    }
+}
+
+fn dump_tasks() {
+    sysrq_println!("SysRq: task dump");
+    let complete = scheduler::try_for_each_task(|process, state, running| {
+        let (used, size) = state.kernel_stack_usage();
+        sysrq_println!("  task {}: {:?}{} kernel_owned={} memory={} bytes",
+            process.id(), process.state, if running { " (current)" } else { "" },
+            process.is_kernel_owned(), process.memory_usage());
+        if running {
+            // the saved state of the running task is stale
+            sysrq_println!("    stack: {} bytes, canary {}", size, if state.is_canary_intact() { "intact" } else { "OVERWRITTEN" });
+        } else {
+            sysrq_println!("    rsp={:#x} rip={:#x} stack: {}/{} bytes used, canary {}",
+                state.saved_rsp(), state.saved_rip().unwrap_or(0), used, size,
+                if state.is_canary_intact() { "intact" } else { "OVERWRITTEN" });
+        }
+    });
+    if !complete {
+        sysrq_println!("  scheduler is locked, can't dump tasks");
+    }
+}
+
+fn dump_memory() {
+    sysrq_println!("SysRq: memory");
+    match allocators::try_heap_stats() {
+        Some(stats) => {
+            sysrq_println!("  heap: {} bytes used, {} bytes free, {} bytes total", stats.used, stats.free, stats.size);
+        },
+        None => {
+            sysrq_println!("  heap is locked");
+        },
+    }
+    sysrq_println!("  oom policy: {:?}, {} processes killed", oom::policy(), oom::killed_count());
+}
+
+fn reschedule() {
+    sysrq_println!("SysRq: forcing reschedule");
+    interrupts::request_reschedule();
+}
+
+fn sync() {
+    sysrq_println!("SysRq: syncing filesystems");
+    if block::is_locked() || allocators::try_heap_stats().is_none() {
+        sysrq_println!("  block devices or heap are locked, can't sync");
+        return;
+    }
+    match filesystem::try_sync_all() {
+        Some(Ok(())) => {
+            sysrq_println!("  done");
+        },
+        Some(Err(err)) => {
+            sysrq_println!("  sync failed: {}", err);
+        },
+        None => {
+            sysrq_println!("  mount table is locked, can't sync");
+        },
+    }
+}
+
+fn reboot() {
+    sysrq_println!("SysRq: rebooting");
+    arch::x86::reboot();
+}
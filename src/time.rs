@@ -0,0 +1,289 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::{interrupts, scheduler, wait_for_interrupt};
+use crate::drivers::pit;
+use crate::drivers::rtc::{self, DateTime};
+
+/// Microseconds accumulated by all timer periods which fully elapsed
+static MONOTONIC_BASE_US: AtomicU64 = AtomicU64::new(0);
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Measures the tsc's frequency against the pit, this has to be called once during boot.
+pub fn calibrate_tsc() {
+    // FIXME: Check for an invariant tsc (cpuid 0x80000007), otherwise the frequency may change at runtime
+    TSC_BOOT.store(unsafe { core::arch::x86_64::_rdtsc() }, Ordering::SeqCst);
+    TSC_HZ.store(pit::calibrate_tsc(), Ordering::SeqCst);
+}
+
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::SeqCst)
+}
+
+/// Returns the nanoseconds since `calibrate_tsc` was called, this falls back
+/// to the (much coarser) monotonic clock if the tsc wasn't calibrated.
+pub fn rdtsc_ns() -> u64 {
+    let hz = tsc_hz();
+    if hz == 0 {
+        return monotonic_us() * 1000;
+    }
+    let ticks = unsafe { core::arch::x86_64::_rdtsc() } - TSC_BOOT.load(Ordering::SeqCst);
+    (ticks as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+/// Gets called from the timer interrupt whenever a timer period expired.
+pub(crate) fn advance_monotonic(us: u64) {
+    MONOTONIC_BASE_US.fetch_add(us, Ordering::SeqCst);
+}
+
+/// The monotonic clock as of the last timer period which elapsed, unlike `monotonic_us` this
+/// only moves when timer interrupts arrive.
+pub fn monotonic_base_us() -> u64 {
+    MONOTONIC_BASE_US.load(Ordering::SeqCst)
+}
+
+/// Returns the microseconds elapsed since the scheduler timer was first started,
+/// this ignores the time namespace of the current process.
+pub fn monotonic_us() -> u64 {
+    without_interrupts(|| {
+        MONOTONIC_BASE_US.load(Ordering::SeqCst) + interrupts::timer_elapsed_us() as u64
+    })
+}
+
+/// A virtualized monotonic clock which is shared by a process and all of its descendants.
+///
+/// The clock only moves forward if it's advanced explicitly or if a process inside
+/// the namespace sleeps, which makes timer based code deterministic and lets tests
+/// skip over long timeouts instantly.
+pub struct TimeNamespace {
+    now: AtomicU64,
+}
+
+impl TimeNamespace {
+
+    pub fn new(start_us: u64) -> Self {
+        Self {
+            now: AtomicU64::new(start_us),
+        }
+    }
+
+    #[inline]
+    pub fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    /// Fast-forwards the clock by the given amount of microseconds.
+    pub fn advance(&self, us: u64) -> u64 {
+        self.now.fetch_add(us, Ordering::SeqCst) + us
+    }
+
+    /// Fast-forwards the clock to `deadline`, the clock never goes backwards.
+    pub fn advance_to(&self, deadline: u64) -> u64 {
+        self.now.fetch_max(deadline, Ordering::SeqCst).max(deadline)
+    }
+
+}
+
+/// Returns the current monotonic time in microseconds as seen by the current process.
+pub fn now() -> u64 {
+    match scheduler::current_time_namespace() {
+        Some(ns) => ns.now(),
+        None => monotonic_us(),
+    }
+}
+
+/// Blocks the caller for at least `us` microseconds, the caller may be woken up
+/// up to its timer slack later so its wakeup can be batched with others.
+///
+/// Inside a time namespace this returns immediately after advancing the namespace's clock.
+pub fn sleep(us: u64) {
+    if let Some(ns) = scheduler::current_time_namespace() {
+        ns.advance_to(ns.now() + us);
+        return;
+    }
+    let deadline = monotonic_us() + us;
+    while monotonic_us() < deadline {
+        // outside of a task (e.g. during boot) there is nothing we could switch to
+        if !scheduler::sleep_until(deadline) {
+            unsafe { wait_for_interrupt(); }
+        }
+    }
+}
+
+/// Picks a single time at which all sleepers can be woken up within their windows, every window
+/// consists of the deadline and the slack. Firing at the earliest end of any window doesn't wake anyone
+/// late and wakes all sleepers whose deadline passed by then at once.
+pub fn coalesced_wakeup(windows: impl Iterator<Item = (u64, u64)>) -> Option<u64> {
+    windows.fold(None, |wakeup, (deadline, slack)| coalesce_wakeup(wakeup, deadline, slack))
+}
+
+/// Adds another sleeper's window to the wakeup `coalesced_wakeup` picked for the others so far,
+/// this allows picking the wakeup without collecting the windows first.
+pub fn coalesce_wakeup(wakeup: Option<u64>, deadline: u64, slack: u64) -> Option<u64> {
+    let end = deadline.saturating_add(slack);
+    Some(wakeup.map_or(end, |wakeup| wakeup.min(end)))
+}
+
+/// Moves the current process (and all processes it spawns from now on) into a new time namespace
+/// whose clock starts at the current time.
+pub fn unshare() -> Option<Arc<TimeNamespace>> {
+    let ns = Arc::new(TimeNamespace::new(now()));
+    if scheduler::set_current_time_namespace(Some(ns.clone())) {
+        Some(ns)
+    } else {
+        None
+    }
+}
+
+#[test_case]
+fn test_time_namespace_advance() {
+    let ns = TimeNamespace::new(1000);
+    crate::kassert_eq!(ns.now(), 1000);
+    crate::kassert_eq!(ns.advance(500), 1500);
+    crate::kassert_eq!(ns.advance_to(1200), 1500);
+    crate::kassert_eq!(ns.advance_to(1_000_000), 1_000_000);
+    crate::kassert_eq!(ns.now(), 1_000_000);
+}
+
+#[test_case]
+fn test_coalesced_wakeup() {
+    crate::kassert_eq!(coalesced_wakeup([].into_iter()), None);
+    // the first window ends at 150, which also covers the sleeper at 120
+    let windows = [(100, 50), (120, 50), (400, 50)];
+    crate::kassert_eq!(coalesced_wakeup(windows.into_iter()), Some(150));
+    crate::kassert_eq!(coalesced_wakeup([(100, 200), (120, 0)].into_iter()), Some(120));
+}
+
+/// The wallclock time at which the monotonic clock started in seconds since the unix epoch
+static BOOT_WALLCLOCK: AtomicU64 = AtomicU64::new(0);
+/// The offset of the local time zone from UTC in minutes
+static UTC_OFFSET_MIN: AtomicI32 = AtomicI32::new(0);
+static NEXT_WAKEUP_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    /// Actions which have to run at a given wallclock time, sorted by that time
+    static ref WAKEUPS: Mutex<Vec<Wakeup>> = Mutex::new(Vec::new());
+}
+
+struct Wakeup {
+    id: u64,
+    at: u64,
+    action: fn(),
+}
+
+/// Reads the rtc once, the wallclock is derived from the monotonic clock afterwards.
+pub fn init_wallclock() {
+    let now = rtc::read_time().to_unix();
+    BOOT_WALLCLOCK.store(now.saturating_sub(monotonic_us() / 1_000_000), Ordering::SeqCst);
+}
+
+/// The current time in seconds since the unix epoch
+pub fn wallclock() -> u64 {
+    BOOT_WALLCLOCK.load(Ordering::SeqCst) + monotonic_us() / 1_000_000
+}
+
+pub fn utc_offset_min() -> i32 {
+    UTC_OFFSET_MIN.load(Ordering::Relaxed)
+}
+
+pub fn set_utc_offset_min(offset: i32) {
+    UTC_OFFSET_MIN.store(offset, Ordering::Relaxed);
+}
+
+/// The current local time in seconds since the unix epoch
+pub fn local_wallclock() -> u64 {
+    (wallclock() as i64 + utc_offset_min() as i64 * 60).max(0) as u64
+}
+
+/// Parses a time zone given as its offset from UTC (`UTC`, `UTC+2`, `+05:30`, `-0800`)
+/// and returns the offset in minutes.
+pub fn parse_utc_offset(zone: &str) -> Option<i32> {
+    let offset = zone.strip_prefix("UTC").unwrap_or(zone);
+    if offset.is_empty() {
+        return Some(0);
+    }
+    let (sign, offset) = match offset.as_bytes()[0] {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    // only digits may follow the sign, `parse` would accept another sign and splitting
+    // a non-ascii string could cut a character in half
+    if !offset.bytes().all(|byte| byte.is_ascii_digit() || byte == b':') {
+        return None;
+    }
+    let (hours, minutes) = match offset.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if offset.len() == 4 => offset.split_at(2),
+        None => (offset, "0"),
+    };
+    let hours = hours.parse::<i32>().ok().filter(|hours| *hours <= 14)?;
+    let minutes = minutes.parse::<i32>().ok().filter(|minutes| *minutes < 60)?;
+    Some(sign * (hours * 60 + minutes))
+}
+
+#[test_case]
+fn test_parse_utc_offset() {
+    crate::kassert_eq!(parse_utc_offset("UTC"), Some(0));
+    crate::kassert_eq!(parse_utc_offset("UTC+2"), Some(120));
+    crate::kassert_eq!(parse_utc_offset("+05:30"), Some(330));
+    crate::kassert_eq!(parse_utc_offset("-0800"), Some(-480));
+    crate::kassert_eq!(parse_utc_offset("CET"), None);
+    crate::kassert_eq!(parse_utc_offset("+-5:00"), None);
+    crate::kassert_eq!(parse_utc_offset("+0é0"), None);
+}
+
+/// Runs `action` from the power task once the wallclock reaches `at`, this is meant for long
+/// timeouts as it only has a resolution of seconds. If the system is suspended until then the rtc
+/// alarm wakes it up. Returns an id for `cancel_wakeup`.
+pub fn wake_at(at: u64, action: fn()) -> u64 {
+    let id = NEXT_WAKEUP_ID.fetch_add(1, Ordering::Relaxed);
+    let mut wakeups = WAKEUPS.lock();
+    let idx = wakeups.partition_point(|wakeup| wakeup.at <= at);
+    wakeups.insert(idx, Wakeup {
+        id,
+        at,
+        action,
+    });
+    id
+}
+
+pub fn cancel_wakeup(id: u64) -> bool {
+    let mut wakeups = WAKEUPS.lock();
+    let len = wakeups.len();
+    wakeups.retain(|wakeup| wakeup.id != id);
+    wakeups.len() != len
+}
+
+/// The wallclock time of the next wakeup
+pub fn next_wakeup() -> Option<u64> {
+    WAKEUPS.lock().first().map(|wakeup| wakeup.at)
+}
+
+/// Programs the rtc alarm for the next wakeup so it also happens while the system is suspended.
+pub fn arm_wakeup_alarm() {
+    match next_wakeup() {
+        Some(at) => rtc::set_alarm(DateTime::from_unix(at)),
+        None => rtc::clear_alarm(),
+    }
+}
+
+/// Runs the actions which are due, this gets called by the power task.
+pub(crate) fn run_due_wakeups() {
+    let now = wallclock();
+    loop {
+        let action = {
+            let mut wakeups = WAKEUPS.lock();
+            match wakeups.first() {
+                Some(wakeup) if wakeup.at <= now => wakeups.remove(0).action,
+                _ => break,
+            }
+        };
+        action();
+    }
+}
@@ -14,6 +14,8 @@ lazy_static! {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        cursor_visible: true,
+        escape: Escape::None,
     });
 }
 
@@ -128,7 +130,7 @@ impl ColoredString {
 
 }
 
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 pub const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
@@ -136,15 +138,77 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+// CRT controller registers used for the hardware cursor
+const CRTC_ADDRESS: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+/// Setting this bit in the cursor start register hides the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+// scanlines of the cursor, this is the usual underline cursor
+const CURSOR_SCANLINE_START: u8 = 14;
+const CURSOR_SCANLINE_END: u8 = 15;
+
+const ESC: u8 = 0x1b;
+const MAX_ESCAPE_PARAMS: usize = 2;
+
+/// The state of the parser for ANSI escape sequences
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// We got an ESC and wait for the `[`
+    Esc,
+    /// Control sequence, the parameters are collected until the final byte arrives
+    Csi {
+        params: [u16; MAX_ESCAPE_PARAMS],
+        count: usize,
+        private: bool,
+    },
+}
+
+unsafe fn crtc_write(register: u8, value: u8) {
+    x86::io::outb(CRTC_ADDRESS, register);
+    x86::io::outb(CRTC_DATA, value);
+}
+
+unsafe fn crtc_read(register: u8) -> u8 {
+    x86::io::outb(CRTC_ADDRESS, register);
+    x86::io::inb(CRTC_DATA)
+}
+
+/// Checks whether there is a vga compatible display adapter by writing
+/// to a crtc register and reading the value back.
+pub fn is_present() -> bool {
+    const PROBE: u8 = 0x5a;
+    unsafe {
+        let old = crtc_read(CRTC_CURSOR_LOW);
+        crtc_write(CRTC_CURSOR_LOW, PROBE);
+        let present = crtc_read(CRTC_CURSOR_LOW) == PROBE;
+        crtc_write(CRTC_CURSOR_LOW, old);
+        present
+    }
+}
+
 pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    cursor_visible: bool,
+    escape: Escape,
 }
 
 impl Writer {
+    /// Writes the string, supported ANSI escape sequences are interpreted:
+    /// `ESC[nC` and `ESC[nD` move the cursor forward or back, `ESC[nG` moves it to column n,
+    /// `ESC[K` clears the rest of the line, `ESC[2K` the whole line and `ESC[?25l`/`ESC[?25h` hide or show the cursor.
     pub fn write_string(&mut self, s: &str) {
         for char in s.bytes() {
+            if self.escape != Escape::None || char == ESC {
+                self.escape_byte(char);
+                continue;
+            }
             match char {
                 // printable ASCII byte or newline
                 0x20..=0x7e | b'\n' => self.write_byte(char),
@@ -154,6 +218,96 @@ impl Writer {
         }
     }
 
+    fn escape_byte(&mut self, char: u8) {
+        self.escape = match (self.escape, char) {
+            (Escape::None, ESC) => Escape::Esc,
+            (Escape::Esc, b'[') => Escape::Csi {
+                params: [0; MAX_ESCAPE_PARAMS],
+                count: 0,
+                private: false,
+            },
+            (Escape::Csi { params, count: 0, .. }, b'?') => Escape::Csi {
+                params,
+                count: 0,
+                private: true,
+            },
+            (Escape::Csi { mut params, count, private }, b'0'..=b'9') => {
+                let idx = count.min(MAX_ESCAPE_PARAMS - 1);
+                params[idx] = params[idx].saturating_mul(10).saturating_add((char - b'0') as u16);
+                Escape::Csi {
+                    params,
+                    count: count.max(1),
+                    private,
+                }
+            },
+            (Escape::Csi { params, count, private }, b';') => Escape::Csi {
+                params,
+                count: count.max(1) + 1,
+                private,
+            },
+            (Escape::Csi { params, count, private }, final_byte) => {
+                self.execute_escape(final_byte, &params[..count.min(MAX_ESCAPE_PARAMS)], private);
+                Escape::None
+            },
+            // unsupported sequences are dropped
+            _ => Escape::None,
+        };
+    }
+
+    fn execute_escape(&mut self, final_byte: u8, params: &[u16], private: bool) {
+        let param = params.first().copied().unwrap_or(0) as usize;
+        match (private, final_byte) {
+            (false, b'C') => self.set_column_position((self.column_position + param.max(1)).min(BUFFER_WIDTH - 1)),
+            (false, b'D') => self.set_column_position(self.column_position.saturating_sub(param.max(1))),
+            // columns start at 1
+            (false, b'G') => self.set_column_position(param.max(1).min(BUFFER_WIDTH) - 1),
+            (false, b'K') => {
+                let start = if param == 2 { 0 } else { self.column_position };
+                let blank = ScreenChar {
+                    ascii_character: b' ',
+                    color_code: self.color_code,
+                };
+                for col in start..BUFFER_WIDTH {
+                    self.buffer.chars[BUFFER_HEIGHT - 1][col].write(blank);
+                }
+            },
+            (true, b'h') if param == 25 => self.set_cursor_visible(true),
+            (true, b'l') if param == 25 => self.set_cursor_visible(false),
+            _ => {},
+        }
+    }
+
+    /// Shows or hides the blinking hardware cursor, it's hidden while another console is active.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        unsafe {
+            if visible {
+                crtc_write(CRTC_CURSOR_START, (crtc_read(CRTC_CURSOR_START) & 0xc0) | CURSOR_SCANLINE_START);
+                crtc_write(CRTC_CURSOR_END, (crtc_read(CRTC_CURSOR_END) & 0xe0) | CURSOR_SCANLINE_END);
+            } else {
+                crtc_write(CRTC_CURSOR_START, CURSOR_DISABLE);
+            }
+        }
+        self.update_cursor();
+    }
+
+    #[inline]
+    pub fn is_cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    /// Moves the hardware cursor to where the next character will be written.
+    fn update_cursor(&self) {
+        if !self.cursor_visible {
+            return;
+        }
+        let pos = (BUFFER_HEIGHT - 1) * BUFFER_WIDTH + self.column_position.min(BUFFER_WIDTH - 1);
+        unsafe {
+            crtc_write(CRTC_CURSOR_LOW, pos as u8);
+            crtc_write(CRTC_CURSOR_HIGH, (pos >> 8) as u8);
+        }
+    }
+
     pub fn write_colored_string(&mut self, colored: &ColoredString) {
         for char in &colored.chars {
             if self.column_position >= BUFFER_WIDTH {
@@ -166,6 +320,7 @@ impl Writer {
             self.buffer.chars[row][col].write(*char);
             self.column_position += 1;
         }
+        self.update_cursor();
     }
 
     pub fn write_byte(&mut self, char: u8) {
@@ -175,6 +330,7 @@ impl Writer {
     pub fn write_byte_colored(&mut self, char: u8, color: ColorCode) {
         if !self.set_byte_colored(char, color) {
             self.column_position += 1;
+            self.update_cursor();
         }
     }
 
@@ -214,6 +370,7 @@ impl Writer {
         }
         self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
+        self.update_cursor();
     }
 
     pub fn old_line(&mut self) {
@@ -225,6 +382,15 @@ impl Writer {
         }
         self.clear_row(0);
         self.column_position = 0;
+        self.update_cursor();
+    }
+
+    /// Blanks the whole screen and moves to the start of the last row.
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_column_position(0);
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -255,6 +421,7 @@ impl Writer {
     #[inline]
     pub fn set_column_position(&mut self, column_pos: usize) {
         self.column_position = column_pos;
+        self.update_cursor();
     }
 
 }
@@ -269,7 +436,7 @@ impl fmt::Write for Writer {
 unsafe impl Driver for Writer {
     #[inline]
     unsafe fn init(&mut self, _idt: &mut InterruptDescriptorTable) -> bool {
-        // We don't have to do anything here
+        self.set_cursor_visible(true);
         true
     }
 
@@ -338,4 +505,23 @@ fn test_println_output() {
         let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
         assert_eq!(char::from(screen_char.ascii_character), c);
     }
-}
\ No newline at end of file
+}
+#[test_case]
+fn test_escape_sequences() {
+    let mut writer = WRITER.lock();
+    writer.new_line();
+    writer.write_string("abcdef\x1b[3D");
+    crate::kassert_eq!(writer.get_column_position(), 3);
+    writer.write_string("\x1b[K\x1b[2G");
+    crate::kassert_eq!(writer.get_column_position(), 1);
+    let mut row = [0; 6];
+    for (col, char) in row.iter_mut().enumerate() {
+        *char = writer.buffer.chars[BUFFER_HEIGHT - 1][col].read().ascii_character;
+    }
+    crate::kassert_eq!(&row, b"abc   ");
+    writer.write_string("\x1b[?25l");
+    crate::kassert!(!writer.is_cursor_visible());
+    writer.write_string("\x1b[?25h");
+    crate::kassert!(writer.is_cursor_visible());
+    writer.new_line();
+}
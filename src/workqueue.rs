@@ -0,0 +1,164 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::arch::without_interrupts;
+use crate::error_codes::Error;
+use crate::sync::Completion;
+use crate::{oom, time};
+
+// Work which shouldn't or can't run where it gets triggered (e.g. in interrupt context or while
+// holding a lock) is queued here and run one item after another by the kernel worker task.
+
+type Work = Box<dyn FnOnce() + Send>;
+
+struct DelayedWork {
+    at: u64,
+    work: Work,
+}
+
+lazy_static! {
+    static ref PENDING: Mutex<VecDeque<Work>> = Mutex::new(VecDeque::new());
+    /// Ordered by the time they are due at
+    static ref DELAYED: Mutex<Vec<DelayedWork>> = Mutex::new(vec![]);
+}
+
+/// How much work can be queued from interrupt context before the worker runs it
+const IRQ_SLOTS: usize = 8;
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: AtomicUsize = AtomicUsize::new(0);
+/// The functions queued with `queue_irq`, 0 marks a free slot
+static IRQ_WORK: [AtomicUsize; IRQ_SLOTS] = [EMPTY_SLOT; IRQ_SLOTS];
+
+/// Runs `work` on the worker task as soon as possible. This allocates, so it must not be called
+/// from interrupt context (the interrupted code could hold the heap's lock), use `queue_irq` there.
+pub fn queue(work: impl FnOnce() + Send + 'static) {
+    let work: Work = Box::new(work);
+    without_interrupts(|| PENDING.lock().push_back(work));
+}
+
+/// Runs `work` on the worker task like `queue`, but without allocating, so this can be called
+/// from interrupt context. Returns false if all slots for such work are taken.
+pub fn queue_irq(work: fn()) -> bool {
+    IRQ_WORK.iter().any(|slot| slot.compare_exchange(0, work as usize, Ordering::AcqRel, Ordering::Relaxed).is_ok())
+}
+
+fn run_irq_work() {
+    for slot in IRQ_WORK.iter() {
+        let work = slot.swap(0, Ordering::AcqRel);
+        if work != 0 {
+            // only `queue_irq` stores anything but 0 in the slots
+            let work: fn() = unsafe { core::mem::transmute(work) };
+            work();
+        }
+    }
+}
+
+struct Outcome<T> {
+    done: Completion,
+    result: Mutex<Option<T>>,
+}
+
+/// Work queued with `submit`, its submitter can sleep until it finished and take its result.
+pub struct Submitted<T> {
+    outcome: Arc<Outcome<T>>,
+}
+
+impl<T> Submitted<T> {
+
+    /// Whether the work finished already
+    pub fn is_done(&self) -> bool {
+        self.outcome.done.is_done()
+    }
+
+    /// Blocks until the work finished and returns its result.
+    pub fn wait(self) -> T {
+        self.outcome.done.wait_for();
+        self.take()
+    }
+
+    /// Like `wait`, but gives up with `ETIMEDOUT` if the work didn't finish within `timeout_us`.
+    /// The work still runs in that case, its result gets dropped.
+    pub fn wait_timeout(self, timeout_us: u64) -> Result<T, Error> {
+        self.outcome.done.wait_for_timeout(timeout_us)?;
+        Ok(self.take())
+    }
+
+    fn take(&self) -> T {
+        without_interrupts(|| self.outcome.result.lock().take()).expect("the work completed without a result")
+    }
+
+}
+
+/// Runs `work` on the worker task like `queue` and returns a handle to wait for its result.
+/// The worker task itself must not wait for it, as the work would never run.
+pub fn submit<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Submitted<T> {
+    let outcome = Arc::new(Outcome {
+        done: Completion::new(),
+        result: Mutex::new(None),
+    });
+    let worker_outcome = outcome.clone();
+    queue(move || {
+        let result = work();
+        without_interrupts(|| *worker_outcome.result.lock() = Some(result));
+        worker_outcome.done.complete_all();
+    });
+    Submitted {
+        outcome,
+    }
+}
+
+/// Blocks until all work which was queued so far (except the delayed one) ran.
+pub fn flush() {
+    // the work runs in order, so everything before the marker is done once it ran
+    submit(|| ()).wait();
+}
+
+/// Runs `work` on the worker task once `delay_us` microseconds of monotonic time have passed,
+/// unlike `queue` this must not be called from interrupt context.
+pub fn queue_delayed(delay_us: u64, work: impl FnOnce() + Send + 'static) {
+    let at = time::monotonic_us() + delay_us;
+    let mut delayed = DELAYED.lock();
+    let idx = delayed.partition_point(|work| work.at <= at);
+    delayed.insert(idx, DelayedWork {
+        at,
+        work: Box::new(work),
+    });
+}
+
+/// Moves the delayed work which is due to the pending work.
+fn promote_due_work() {
+    let now = time::monotonic_us();
+    let mut delayed = DELAYED.lock();
+    let due = delayed.partition_point(|work| work.at <= now);
+    without_interrupts(|| {
+        let mut pending = PENDING.lock();
+        for work in delayed.drain(..due) {
+            pending.push_back(work.work);
+        }
+    });
+}
+
+/// Runs all queued work, it runs as a kernel task.
+// FIXME: Block until work gets queued instead of polling once we have wait queues
+pub fn worker_task() {
+    const POLL_INTERVAL_US: u64 = 10_000;
+    loop {
+        oom::report_kills();
+        promote_due_work();
+        run_irq_work();
+        loop {
+            // the lock must not be held while the work runs, as it may queue more work
+            let work = without_interrupts(|| PENDING.lock().pop_front());
+            match work {
+                Some(work) => work(),
+                None => break,
+            }
+        }
+        time::sleep(POLL_INTERVAL_US);
+    }
+}
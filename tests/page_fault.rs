@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+// expect-output: EXCEPTION: PAGE FAULT
+// Accessing unmapped memory from the kernel has to end in a page fault, this runs in its own
+// qemu instance (see ktest::expect).
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use LeafOS::ktest::{self, Expected};
+
+/// Nothing is mapped in this part of the lower half
+const UNMAPPED: u64 = 0x0000_7fff_dead_0000;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    LeafOS::init();
+    ktest::expect("page_fault::unmapped_write", Expected::Exception(14));
+    unsafe { core::ptr::write_volatile(UNMAPPED as *mut u64, 42); }
+    ktest::isolated_no_failure();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ktest::isolated_panic(info)
+}
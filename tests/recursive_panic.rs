@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+// expect-output: panicked while panicking
+// A panic while reporting a panic has to be detected and reported instead of deadlocking.
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use LeafOS::{exit_qemu, hlt_loop, panicking, QemuExitCode};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    panic!("first panic");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if panicking::enter(info) {
+        panic!("reporting failed");
+    }
+    // only the recursive panic gets here, it was reported over the raw serial port already
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
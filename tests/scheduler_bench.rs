@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(LeafOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Scheduler benchmarks, these spawn a mix of cpu bound and interactive tasks and check
+// that the measured latencies and the runtime distribution stay within fixed bounds.
+// The tests themselves run inside a controller task, as the boot context is never
+// resumed once the scheduler took over.
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use bootloader::{BootInfo, entry_point};
+use LeafOS::{hlt_loop, memory, scheduler, serial_println, time};
+use LeafOS::arch::wait_for_interrupt;
+use LeafOS::interrupts::init_timer;
+
+const CPU_TASKS: usize = 4;
+const INTERACTIVE_TASKS: usize = 2;
+const TIME_SLICE_US: usize = 10_000;
+const BENCH_US: u64 = 500_000;
+const SLEEP_US: u64 = 5_000;
+
+/// Every task which isn't running has to get its turn before we are scheduled again
+const MAX_WAKEUP_LATENCY_US: u64 = ((CPU_TASKS + INTERACTIVE_TASKS + 2) * TIME_SLICE_US) as u64;
+const MAX_SWITCH_LATENCY_US: u64 = 1_000;
+/// No cpu bound task may get less than half of the runtime of the luckiest one
+const MIN_FAIRNESS_PERCENT: u64 = 50;
+
+const CONTROLLER: usize = usize::MAX - 1;
+const NO_RUNNER: usize = usize::MAX;
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_INTERACTIVE_SLOT: AtomicUsize = AtomicUsize::new(CPU_TASKS);
+static ITERATIONS: [AtomicU64; CPU_TASKS] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+static LAST_RUNNER: AtomicUsize = AtomicUsize::new(NO_RUNNER);
+static LAST_SEEN_US: AtomicU64 = AtomicU64::new(0);
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+static SWITCH_LATENCY_MAX_US: AtomicU64 = AtomicU64::new(0);
+
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+static WAKEUP_LATENCY_MAX_US: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    LeafOS::init();
+    let _ = memory::setup(&boot_info.memory_map, boot_info.physical_memory_offset);
+    scheduler::init();
+    scheduler::set_time_slice_us(TIME_SLICE_US);
+
+    scheduler::start_proc(controller, true);
+    for _ in 0..CPU_TASKS {
+        scheduler::start_proc(cpu_bound, true);
+    }
+    for _ in 0..INTERACTIVE_TASKS {
+        scheduler::start_proc(interactive, true);
+    }
+    unsafe { init_timer(boot_info.physical_memory_offset); }
+
+    hlt_loop();
+}
+
+fn controller() {
+    test_main();
+    hlt_loop();
+}
+
+/// Records that `runner` is running right now, if another task ran right before
+/// the time since it was last seen is the latency of the context switch.
+fn observe(runner: usize) {
+    let now = time::monotonic_us();
+    let last = LAST_RUNNER.swap(runner, Ordering::SeqCst);
+    let seen = LAST_SEEN_US.swap(now, Ordering::SeqCst);
+    if last != runner && last != NO_RUNNER {
+        SWITCHES.fetch_add(1, Ordering::SeqCst);
+        SWITCH_LATENCY_MAX_US.fetch_max(now.saturating_sub(seen), Ordering::SeqCst);
+    }
+}
+
+fn park() -> ! {
+    loop {
+        unsafe { wait_for_interrupt(); }
+    }
+}
+
+fn cpu_bound() {
+    let slot = NEXT_SLOT.fetch_add(1, Ordering::SeqCst);
+    while !STOP.load(Ordering::SeqCst) {
+        ITERATIONS[slot].fetch_add(1, Ordering::SeqCst);
+        observe(slot);
+    }
+    park();
+}
+
+fn interactive() {
+    let slot = NEXT_INTERACTIVE_SLOT.fetch_add(1, Ordering::SeqCst);
+    while !STOP.load(Ordering::SeqCst) {
+        observe(slot);
+        let deadline = time::monotonic_us() + SLEEP_US;
+        time::sleep(SLEEP_US);
+        let latency = time::monotonic_us().saturating_sub(deadline);
+        WAKEUPS.fetch_add(1, Ordering::SeqCst);
+        WAKEUP_LATENCY_MAX_US.fetch_max(latency, Ordering::SeqCst);
+    }
+    park();
+}
+
+/// Keeps the controller busy for `us` microseconds, it takes part in the measurements
+/// so its own time slices don't show up as switch latency of the other tasks.
+fn run_for(us: u64) {
+    let deadline = time::monotonic_us() + us;
+    while time::monotonic_us() < deadline {
+        observe(CONTROLLER);
+    }
+}
+
+fn snapshot() -> [u64; CPU_TASKS] {
+    let mut ret = [0; CPU_TASKS];
+    for (idx, iterations) in ITERATIONS.iter().enumerate() {
+        ret[idx] = iterations.load(Ordering::SeqCst);
+    }
+    ret
+}
+
+#[test_case]
+fn runtime_distribution() {
+    // let every task get scheduled at least once before measuring
+    run_for(BENCH_US / 5);
+    let start = snapshot();
+    run_for(BENCH_US);
+    let end = snapshot();
+    let mut min = u64::MAX;
+    let mut max = 0;
+    for idx in 0..CPU_TASKS {
+        let runtime = end[idx] - start[idx];
+        min = min.min(runtime);
+        max = max.max(runtime);
+    }
+    serial_println!("runtime distribution: min {} max {} iterations", min, max);
+    LeafOS::kassert!(min > 0);
+    LeafOS::kassert!(min * 100 >= max * MIN_FAIRNESS_PERCENT);
+}
+
+#[test_case]
+fn context_switch_latency() {
+    SWITCH_LATENCY_MAX_US.store(0, Ordering::SeqCst);
+    let switches = SWITCHES.load(Ordering::SeqCst);
+    run_for(BENCH_US);
+    let switches = SWITCHES.load(Ordering::SeqCst) - switches;
+    let latency = SWITCH_LATENCY_MAX_US.load(Ordering::SeqCst);
+    serial_println!("context switches: {}, max latency {}us", switches, latency);
+    LeafOS::kassert!(switches > 0);
+    LeafOS::kassert!(latency <= MAX_SWITCH_LATENCY_US);
+}
+
+#[test_case]
+fn wakeup_latency() {
+    WAKEUP_LATENCY_MAX_US.store(0, Ordering::SeqCst);
+    let wakeups = WAKEUPS.load(Ordering::SeqCst);
+    run_for(BENCH_US);
+    STOP.store(true, Ordering::SeqCst);
+    let wakeups = WAKEUPS.load(Ordering::SeqCst) - wakeups;
+    let latency = WAKEUP_LATENCY_MAX_US.load(Ordering::SeqCst);
+    serial_println!("wakeups: {}, max latency {}us", wakeups, latency);
+    LeafOS::kassert!(wakeups > 0);
+    LeafOS::kassert!(latency <= MAX_WAKEUP_LATENCY_US);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    LeafOS::test_panic_handler(info)
+}
@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+// expect-output: 1 isn't 2
+// A failing assertion has to panic, this runs in its own qemu instance (see ktest::expect).
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use LeafOS::ktest::{self, Expected};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    ktest::expect("should_panic::failing_assertion", Expected::Panic);
+    // the message of a failed assertion differs between compiler versions, the custom one doesn't
+    assert_eq!(1, 2, "1 isn't 2");
+    ktest::isolated_no_failure();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ktest::isolated_panic(info)
+}